@@ -0,0 +1,206 @@
+use super::resample::Resampler;
+use super::util::remix;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Length of the echo path the filter can model. Covers the device latency of common setups.
+const FILTER_TIME: f32 = 0.064;
+/// NLMS step size: higher adapts faster, lower leaves less residual once converged.
+const STEP_SIZE: f32 = 0.3;
+/// Keeps the update stable while the reference is (nearly) silent.
+const REGULARIZATION: f32 = 1e-6;
+
+/// What was played, in mono at the capture rate, waiting to be matched with captured audio.
+pub struct EchoReference {
+    /// Queued reference, the resampler from the output rate and the output channel count.
+    queue: Mutex<(VecDeque<f32>, Resampler, usize)>,
+    input_rate: u32,
+    /// More queued audio than this means capture fell behind, the oldest part is dropped.
+    max_len: usize,
+}
+
+impl EchoReference {
+    pub fn new(output_rate: u32, output_channels: usize, input_rate: u32) -> Self {
+        EchoReference {
+            queue: Mutex::new((
+                VecDeque::new(),
+                Resampler::new(output_rate, input_rate, 1),
+                output_channels,
+            )),
+            input_rate,
+            max_len: (FILTER_TIME * input_rate as f32) as usize,
+        }
+    }
+
+    /// Called with every buffer handed to the output device.
+    pub fn push(&self, played: &[f32]) {
+        if let Ok(mut queue) = self.queue.lock() {
+            let (samples, resampler, output_channels) = &mut *queue;
+            samples.extend(resampler.process(&remix(played, *output_channels, 1)));
+            let excess = samples.len().saturating_sub(self.max_len);
+            samples.drain(..excess);
+        }
+    }
+
+    /// Follows a switch to an output device with a different format.
+    pub fn set_output_format(&self, output_rate: u32, output_channels: usize) {
+        if let Ok(mut queue) = self.queue.lock() {
+            queue.1 = Resampler::new(output_rate, self.input_rate, 1);
+            queue.2 = output_channels;
+        }
+    }
+
+    /// Takes the reference for `frames` captured frames, padded with silence if short.
+    fn take(&self, frames: usize) -> Vec<f32> {
+        let mut reference = vec![0.0; frames];
+        if let Ok(mut queue) = self.queue.lock() {
+            let samples = &mut queue.0;
+            let available = frames.min(samples.len());
+            for (slot, sample) in reference.iter_mut().zip(samples.drain(..available)) {
+                *slot = sample;
+            }
+        }
+        reference
+    }
+}
+
+/// Acoustic echo canceller.
+///
+/// An NLMS adaptive filter learns how the played audio (the far end) leaks back into the mic
+/// and subtracts that estimate from the captured audio (the near end).
+pub struct EchoCanceller {
+    reference: Arc<EchoReference>,
+    channels: usize,
+    weights: Vec<f32>,
+    /// The most recent reference samples, newest first.
+    history: VecDeque<f32>,
+    /// Sum of squares of `history`.
+    energy: f32,
+}
+
+impl EchoCanceller {
+    pub fn new(reference: Arc<EchoReference>, input_rate: u32, channels: usize) -> Self {
+        let taps = ((FILTER_TIME * input_rate as f32) as usize).max(1);
+        EchoCanceller {
+            reference,
+            channels: channels.max(1),
+            weights: vec![0.0; taps],
+            history: VecDeque::from(vec![0.0; taps]),
+            energy: 0.0,
+        }
+    }
+
+    /// Removes the estimated echo from a buffer of captured, interleaved audio.
+    pub fn process(&mut self, captured: &[f32]) -> Vec<f32> {
+        let reference = self.reference.take(captured.len() / self.channels);
+        let mut cleaned = captured.to_vec();
+        for (frame, far) in cleaned.chunks_exact_mut(self.channels).zip(reference) {
+            if let Some(oldest) = self.history.pop_back() {
+                self.energy -= oldest * oldest;
+            }
+            self.history.push_front(far);
+            self.energy = (self.energy + far * far).max(0.0);
+
+            let echo: f32 = self
+                .weights
+                .iter()
+                .zip(&self.history)
+                .map(|(w, h)| w * h)
+                .sum();
+            let near = frame.iter().sum::<f32>() / frame.len() as f32;
+            let error = near - echo;
+            let step = STEP_SIZE * error / (self.energy + REGULARIZATION);
+            for (w, h) in self.weights.iter_mut().zip(&self.history) {
+                *w += step * h;
+            }
+            for sample in frame {
+                *sample -= echo;
+            }
+        }
+        cleaned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::Rng;
+
+    const RATE: u32 = 16000;
+    /// Samples processed at a time, like a device's 10 ms buffers.
+    const BLOCK: usize = 160;
+
+    /// Deterministic white noise in ±0.5, standing in for the far end's voice.
+    fn noise(len: usize) -> Vec<f32> {
+        let mut rng = Rng::with_seed(0x1234_5678);
+        (0..len).map(|_| rng.next_f32() - 0.5).collect()
+    }
+
+    /// What the mic picks up of `played` through a simple room: a quieter copy `delay`
+    /// samples later.
+    fn echo(played: &[f32], delay: usize) -> Vec<f32> {
+        (0..played.len())
+            .map(|i| i.checked_sub(delay).map_or(0.0, |i| 0.6 * played[i]))
+            .collect()
+    }
+
+    /// Plays `played` and captures `captured` block by block, returning the cleaned capture.
+    fn cancel(played: &[f32], captured: &[f32]) -> Vec<f32> {
+        let reference = Arc::new(EchoReference::new(RATE, 1, RATE));
+        let mut canceller = EchoCanceller::new(reference.clone(), RATE, 1);
+        played
+            .chunks(BLOCK)
+            .zip(captured.chunks(BLOCK))
+            .flat_map(|(played, captured)| {
+                reference.push(played);
+                canceller.process(captured)
+            })
+            .collect()
+    }
+
+    fn power(samples: &[f32]) -> f32 {
+        samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32
+    }
+
+    #[test]
+    fn echo_is_removed() {
+        let played = noise(3 * RATE as usize);
+        let captured = echo(&played, 160);
+        let cleaned = cancel(&played, &captured);
+        // After a second to adapt, at least 20 dB of the echo are gone.
+        let tail = RATE as usize..;
+        assert!(power(&cleaned[tail.clone()]) < power(&captured[tail]) / 100.0);
+    }
+
+    #[test]
+    fn near_end_speech_is_kept() {
+        let played = noise(3 * RATE as usize);
+        let voice: Vec<f32> = (0..played.len())
+            .map(|i| 0.3 * (std::f32::consts::TAU * 300.0 * i as f32 / RATE as f32).sin())
+            .collect();
+        let echoed = echo(&played, 160);
+        let captured: Vec<f32> = echoed
+            .iter()
+            .zip(&voice)
+            .map(|(echo, voice)| echo + voice)
+            .collect();
+        let cleaned = cancel(&played, &captured);
+        let tail = 2 * RATE as usize..;
+        let residual: Vec<f32> = cleaned[tail.clone()]
+            .iter()
+            .zip(&voice[tail.clone()])
+            .map(|(cleaned, voice)| cleaned - voice)
+            .collect();
+        // Without double-talk detection, the filter adapts less well while both talk, but the
+        // echo still shrinks and the voice gets through.
+        assert!(power(&residual) < power(&echoed[tail.clone()]) / 2.0);
+        assert!(power(&cleaned[tail.clone()]) > power(&voice[tail]) / 2.0);
+    }
+
+    #[test]
+    fn silence_on_the_far_end_leaves_the_mic_alone() {
+        let captured: Vec<f32> = noise(RATE as usize);
+        let cleaned = cancel(&vec![0.0; captured.len()], &captured);
+        assert_eq!(cleaned, captured);
+    }
+}
@@ -0,0 +1,105 @@
+use crate::processor::AudioProcessor;
+
+/// Time constant of the level envelope, long enough to follow the speaker rather than syllables.
+const ENVELOPE_TIME: f32 = 0.5;
+/// Time constant for gain changes.
+const GAIN_TIME: f32 = 0.2;
+const MIN_GAIN: f32 = 0.1;
+const MAX_GAIN: f32 = 10.0;
+/// Samples below this power don't move the envelope, so pauses aren't boosted.
+const SILENCE_POWER: f32 = 1e-8;
+
+/// Automatic gain control.
+///
+/// Tracks a slow-moving RMS envelope of the signal and smoothly moves the gain so speech sits
+/// around the target level. Unlike [`normalize`](crate::util::normalize), single buffers are not
+/// stretched to full scale, and silence is left alone.
+pub struct Agc {
+    target: f32,
+    /// Smoothed signal power (mean square).
+    power: f32,
+    gain: f32,
+    envelope_coefficient: f32,
+    gain_coefficient: f32,
+}
+
+impl Agc {
+    /// Creates an AGC for the given target RMS level (between 0.0 and 1.0).
+    pub fn new(target: f32, sample_rate: u32) -> Self {
+        let coefficient = |time: f32| 1.0 - (-1.0 / (time * sample_rate as f32)).exp();
+        Agc {
+            target,
+            power: target * target,
+            gain: 1.0,
+            envelope_coefficient: coefficient(ENVELOPE_TIME),
+            gain_coefficient: coefficient(GAIN_TIME),
+        }
+    }
+}
+
+impl AudioProcessor for Agc {
+    fn process(&mut self, samples: &mut [f32], _sample_rate: u32) {
+        for sample in samples {
+            let power = *sample * *sample;
+            if power > SILENCE_POWER {
+                self.power += (power - self.power) * self.envelope_coefficient;
+            }
+            let desired = (self.target / self.power.sqrt().max(1e-6)).clamp(MIN_GAIN, MAX_GAIN);
+            self.gain += (desired - self.gain) * self.gain_coefficient;
+            *sample *= self.gain;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 16000;
+
+    /// Runs `seconds` of a tone at `level` (its peak) through `agc` and returns the RMS level
+    /// of the last quarter second.
+    fn settle(agc: &mut Agc, level: f32, seconds: f32) -> f32 {
+        let len = (seconds * RATE as f32) as usize;
+        let mut samples: Vec<f32> = (0..len)
+            .map(|i| level * (std::f32::consts::TAU * 300.0 * i as f32 / RATE as f32).sin())
+            .collect();
+        agc.process(&mut samples, RATE);
+        let tail = &samples[len - RATE as usize / 4..];
+        (tail.iter().map(|s| s * s).sum::<f32>() / tail.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn quiet_speech_is_raised_to_the_target() {
+        let mut agc = Agc::new(0.1, RATE);
+        let rms = settle(&mut agc, 0.02, 5.0);
+        assert!((rms - 0.1).abs() < 0.01, "{rms}");
+    }
+
+    #[test]
+    fn loud_speech_is_lowered_to_the_target() {
+        let mut agc = Agc::new(0.1, RATE);
+        let rms = settle(&mut agc, 0.8, 5.0);
+        assert!((rms - 0.1).abs() < 0.01, "{rms}");
+    }
+
+    #[test]
+    fn gain_stays_within_its_limits() {
+        let mut agc = Agc::new(0.1, RATE);
+        // Far too quiet to reach the target without more than the maximum gain.
+        let rms = settle(&mut agc, 0.0005, 5.0);
+        assert!(rms <= 0.0005 * MAX_GAIN, "{rms}");
+    }
+
+    #[test]
+    fn silence_is_left_alone() {
+        let mut agc = Agc::new(0.1, RATE);
+        settle(&mut agc, 0.1, 2.0);
+        let mut silence = vec![0.0; RATE as usize];
+        agc.process(&mut silence, RATE);
+        assert!(silence.iter().all(|&sample| sample == 0.0));
+        // Pauses don't move the envelope, so speech afterwards isn't boosted.
+        let rms = settle(&mut agc, 0.1, 0.5);
+        assert!(rms < 0.12, "{rms}");
+    }
+}
@@ -0,0 +1,179 @@
+//! Command line helpers shared by the library options and the binary.
+//!
+//! Arguments starting with `--` are flags, except for the MODE names. Everything else is a
+//! positional argument: MODE, TARGET and the devices, in that order.
+
+use log::warn;
+use std::str::FromStr;
+
+/// MODE names, which look like flags but are positional.
+const MODES: &[&str] = &[
+    "-s",
+    "--server",
+    "-c",
+    "--client",
+    "-l",
+    "--loopback",
+    "-m",
+    "--mixer",
+    "-t",
+    "--test-tone",
+    "--measure-latency",
+    "--rtp",
+    "--status",
+];
+
+/// Flags that take a value as the following argument.
+const VALUE_FLAGS: &[&str] = &[
+    "--channels",
+    "--agc-target",
+    "--config",
+    "--sample-rate",
+    "--mic-gain",
+    "--volume",
+    "--buffer-frames",
+    "--comfort-noise-level",
+    "--input-file",
+    "--greeting",
+    "--duration",
+    "--host",
+    "--limiter-threshold",
+    "--limiter-release",
+    "--dump-samples",
+    "--room",
+    "--name",
+    "--max-clients",
+    "--max-bitrate",
+    "--gate-open",
+    "--gate-close",
+    "--gate-attack",
+    "--gate-release",
+    "--wire-format",
+    "--max-latency",
+    "--frame-samples",
+    "--prefer-sample-rate",
+    "--vad-aggressiveness",
+    "--channel-gains",
+    "--tcp-send-buffer",
+    "--tcp-recv-buffer",
+    "--sidetone",
+    "--monitor-level",
+    "--jitter-target",
+    "--rtp-port",
+    "--send-rate",
+    "--log-file",
+    "--record-split",
+    "--input-format",
+    "--output-format",
+    "--connect-timeout",
+    "--timeout",
+];
+
+/// Returns the program name followed by all positional arguments, without flags.
+pub fn positional(args: &[String]) -> Vec<String> {
+    let mut positional: Vec<String> = args.iter().take(1).cloned().collect();
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        if VALUE_FLAGS.contains(&arg.as_str()) {
+            rest.next();
+        } else if !arg.starts_with("--") || MODES.contains(&arg.as_str()) {
+            positional.push(arg.clone());
+        }
+    }
+    positional
+}
+
+/// Checks whether a flag was passed.
+pub fn flag(args: &[String], name: &str) -> bool {
+    args.iter().skip(1).any(|arg| arg == name)
+}
+
+/// Returns the value passed after a flag, e.g. `2` for `--channels 2`.
+pub fn value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    let position = args.iter().skip(1).position(|arg| arg == name)?;
+    args.get(position + 2).map(String::as_str)
+}
+
+/// Parses the value passed after a flag. Values that don't parse are ignored with a warning.
+pub fn parsed<T: FromStr>(args: &[String], name: &str) -> Option<T> {
+    let value = value(args, name)?;
+    let parsed = value.parse().ok();
+    if parsed.is_none() {
+        warn!("Invalid value '{value}' for {name}, ignoring it");
+    }
+    parsed
+}
+
+/// Parses the comma-separated values passed after a flag, e.g. `48000,44100`. Values that don't
+/// parse are ignored with a warning, and `None` is returned if none are left.
+pub fn list<T: FromStr>(args: &[String], name: &str) -> Option<Vec<T>> {
+    let values: Vec<T> = value(args, name)?
+        .split(',')
+        .filter_map(|value| {
+            let parsed = value.trim().parse().ok();
+            if parsed.is_none() {
+                warn!("Invalid value '{value}' for {name}, ignoring it");
+            }
+            parsed
+        })
+        .collect();
+    (!values.is_empty()).then_some(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| String::from(*arg)).collect()
+    }
+
+    #[test]
+    fn flags_and_their_values_are_not_positional() {
+        let all = args(&[
+            "voice-chat",
+            "--meter",
+            "-c",
+            "--channels",
+            "2",
+            "10.0.0.2:8888",
+            "mic",
+            "--agc",
+        ]);
+        assert_eq!(
+            positional(&all),
+            args(&["voice-chat", "-c", "10.0.0.2:8888", "mic"])
+        );
+    }
+
+    #[test]
+    fn modes_are_positional() {
+        let all = args(&["voice-chat", "--mixer", "9000"]);
+        assert_eq!(positional(&all), all);
+    }
+
+    #[test]
+    fn values_follow_their_flags() {
+        let all = args(&["voice-chat", "-s", "--sample-rate", "48000", "--agc"]);
+        assert!(flag(&all, "--agc"));
+        assert!(!flag(&all, "--meter"));
+        assert_eq!(value(&all, "--sample-rate"), Some("48000"));
+        assert_eq!(parsed::<u32>(&all, "--sample-rate"), Some(48000));
+        assert_eq!(value(&all, "--channels"), None);
+    }
+
+    #[test]
+    fn values_that_do_not_parse_are_ignored() {
+        let all = args(&["voice-chat", "-s", "--sample-rate", "fast"]);
+        assert_eq!(parsed::<u32>(&all, "--sample-rate"), None);
+        assert_eq!(
+            value(&args(&["voice-chat", "--channels"]), "--channels"),
+            None
+        );
+    }
+
+    #[test]
+    fn program_name_is_not_a_flag() {
+        assert!(!flag(&args(&["--agc"]), "--agc"));
+    }
+}
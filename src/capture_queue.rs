@@ -0,0 +1,142 @@
+//! A lock-free queue for captured audio, from the input callback to the loop that sends it.
+//!
+//! A lock would make the callback wait for (or drop its buffer while) the send loop takes the
+//! samples. Here, each side only ever touches its own end: the samples are stored as the bits
+//! of atomics, and a position is handed over only after the samples up to it are in place.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+struct Ring {
+    slots: Box<[AtomicU32]>,
+    channels: usize,
+    /// Samples taken and pushed since the start, so `tail - head` is the number queued.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    /// Samples that didn't fit since the consumer last asked.
+    dropped: AtomicUsize,
+}
+
+impl Ring {
+    fn slot(&self, position: usize) -> &AtomicU32 {
+        &self.slots[position % self.slots.len()]
+    }
+}
+
+/// The input callback's end.
+pub struct Producer(Arc<Ring>);
+
+/// The send loop's end.
+pub struct Consumer(Arc<Ring>);
+
+/// A queue holding up to `capacity` samples (rounded down to whole frames of `channels`).
+pub fn queue(capacity: usize, channels: usize) -> (Producer, Consumer) {
+    let channels = channels.max(1);
+    let capacity = (capacity / channels * channels).max(channels);
+    let ring = Arc::new(Ring {
+        slots: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+        channels,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        dropped: AtomicUsize::new(0),
+    });
+    (Producer(ring.clone()), Consumer(ring))
+}
+
+impl Producer {
+    /// Queues as many whole frames of `samples` as fit, never waiting. The rest is dropped and
+    /// counted, see [`Consumer::take_dropped`].
+    pub fn push(&mut self, samples: &[f32]) {
+        let ring = &*self.0;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let head = ring.head.load(Ordering::Acquire);
+        let free = ring.slots.len() - tail.wrapping_sub(head);
+        let len = samples.len().min(free / ring.channels * ring.channels);
+        for (position, sample) in (tail..).zip(&samples[..len]) {
+            ring.slot(position)
+                .store(sample.to_bits(), Ordering::Relaxed);
+        }
+        ring.tail.store(tail.wrapping_add(len), Ordering::Release);
+        if len < samples.len() {
+            ring.dropped
+                .fetch_add(samples.len() - len, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Consumer {
+    /// Moves everything queued to the end of `samples`.
+    pub fn drain_into(&mut self, samples: &mut Vec<f32>) {
+        let ring = &*self.0;
+        let head = ring.head.load(Ordering::Relaxed);
+        let tail = ring.tail.load(Ordering::Acquire);
+        let len = tail.wrapping_sub(head);
+        samples.reserve(len);
+        for position in 0..len {
+            let bits = ring
+                .slot(head.wrapping_add(position))
+                .load(Ordering::Relaxed);
+            samples.push(f32::from_bits(bits));
+        }
+        ring.head.store(tail, Ordering::Release);
+    }
+
+    /// Samples dropped because the queue was full, since the last call.
+    pub fn take_dropped(&self) -> usize {
+        self.0.dropped.swap(0, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn samples_come_out_in_order_across_the_wrap() {
+        let (mut producer, mut consumer) = queue(8, 2);
+        let mut taken = Vec::new();
+        for round in 0..5 {
+            let samples: Vec<f32> = (0..6).map(|i| (round * 6 + i) as f32).collect();
+            producer.push(&samples);
+            consumer.drain_into(&mut taken);
+        }
+        let expected: Vec<f32> = (0..30).map(|i| i as f32).collect();
+        assert_eq!(taken, expected);
+        assert_eq!(consumer.take_dropped(), 0);
+    }
+
+    #[test]
+    fn full_queue_drops_whole_frames() {
+        let (mut producer, mut consumer) = queue(7, 2);
+        producer.push(&[1.0; 10]);
+        let mut taken = Vec::new();
+        consumer.drain_into(&mut taken);
+        assert_eq!(taken, [1.0; 6]);
+        assert_eq!(consumer.take_dropped(), 4);
+        assert_eq!(consumer.take_dropped(), 0);
+    }
+
+    #[test]
+    fn nothing_is_lost_between_threads() {
+        let (mut producer, mut consumer) = queue(1024, 1);
+        let pushing = thread::spawn(move || {
+            for block in 0..1000 {
+                let samples: Vec<f32> = (0..64).map(|i| (block * 64 + i) as f32).collect();
+                producer.push(&samples);
+                thread::yield_now();
+            }
+            producer
+        });
+        let mut taken = Vec::new();
+        while !pushing.is_finished() {
+            consumer.drain_into(&mut taken);
+        }
+        pushing.join().unwrap();
+        consumer.drain_into(&mut taken);
+        let dropped = consumer.take_dropped();
+        assert_eq!(taken.len() + dropped, 64_000);
+        // Whatever was dropped, what made it through is in order.
+        assert!(taken.windows(2).all(|w| w[0] < w[1]));
+    }
+}
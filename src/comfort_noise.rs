@@ -0,0 +1,56 @@
+use super::util::Rng;
+
+/// Generates very quiet, low-passed noise to play instead of dead digital silence, so a
+/// silent peer doesn't sound like a dropped call.
+pub struct ComfortNoise {
+    level: f32,
+    rng: Rng,
+    /// Output of the low-pass filter that softens the white noise.
+    filtered: f32,
+}
+
+impl ComfortNoise {
+    /// Creates a generator whose output stays within ±`level`.
+    pub fn new(level: f32) -> Self {
+        ComfortNoise {
+            level,
+            rng: Rng::new(),
+            filtered: 0.0,
+        }
+    }
+
+    pub fn fill(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            let white = self.rng.next_f32() * 2.0 - 1.0;
+            self.filtered += (white - self.filtered) * 0.3;
+            *sample = self.filtered * self.level;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noise_stays_within_its_level() {
+        let mut noise = ComfortNoise::new(0.001);
+        let mut samples = vec![0.0; 48000];
+        noise.fill(&mut samples);
+        assert!(samples.iter().all(|f| f.abs() <= 0.001));
+        assert!(samples.iter().any(|&f| f != 0.0));
+    }
+
+    #[test]
+    fn noise_is_softened() {
+        let mut noise = ComfortNoise::new(1.0);
+        let mut samples = vec![0.0; 48000];
+        noise.fill(&mut samples);
+        // White noise in ±1.0 steps by 2/3 on average from one sample to the next.
+        let step = samples.windows(2).map(|w| (w[1] - w[0]).abs()).sum::<f32>()
+            / (samples.len() - 1) as f32;
+        assert!(step < 0.3, "{step}");
+        let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+        assert!(mean.abs() < 0.05, "{mean}");
+    }
+}
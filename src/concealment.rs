@@ -0,0 +1,96 @@
+/// How long audio fades back in after a concealed frame.
+const FADE_IN_TIME: f32 = 0.005;
+
+/// Fills in for lost frames, so a gap doesn't click.
+///
+/// A lost frame is replaced by the previous one played backwards while it fades out. Played
+/// backwards, it starts exactly where the previous frame ended. The next received frame then
+/// fades back in from silence.
+pub struct LossConcealer {
+    channels: usize,
+    /// The last frame played (received or concealed), interleaved.
+    last: Vec<f32>,
+    /// Whether `last` was concealed, so a second lost frame in a row is plain silence.
+    concealed: bool,
+    fade_in_frames: usize,
+}
+
+impl LossConcealer {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        LossConcealer {
+            channels: channels.max(1),
+            last: Vec::new(),
+            concealed: false,
+            fade_in_frames: ((FADE_IN_TIME * sample_rate as f32) as usize).max(1),
+        }
+    }
+
+    /// Passes a received frame through, fading it in if the one before was concealed.
+    pub fn receive(&mut self, mut samples: Vec<f32>) -> Vec<f32> {
+        if self.concealed {
+            for (i, frame) in samples
+                .chunks_mut(self.channels)
+                .take(self.fade_in_frames)
+                .enumerate()
+            {
+                let gain = i as f32 / self.fade_in_frames as f32;
+                frame.iter_mut().for_each(|sample| *sample *= gain);
+            }
+        }
+        self.concealed = false;
+        self.last.clone_from(&samples);
+        samples
+    }
+
+    /// Returns audio to play in place of a lost frame, as long as the last one.
+    pub fn conceal(&mut self) -> Vec<f32> {
+        if self.concealed {
+            return vec![0.0; self.last.len()];
+        }
+        let frames = self.last.len() / self.channels;
+        let mut samples = Vec::with_capacity(self.last.len());
+        for (i, frame) in self.last.chunks_exact(self.channels).rev().enumerate() {
+            let gain = 1.0 - i as f32 / frames as f32;
+            samples.extend(frame.iter().map(|sample| sample * gain));
+        }
+        self.concealed = true;
+        self.last.clone_from(&samples);
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lost_frame_continues_where_the_last_ended() {
+        let mut concealer = LossConcealer::new(1000, 1);
+        concealer.receive(vec![0.1, 0.2, 0.3, 0.4]);
+        let concealed = concealer.conceal();
+        assert_eq!(concealed.len(), 4);
+        // Backwards, so it starts at the last sample, and fading out.
+        assert_eq!(concealed[0], 0.4);
+        assert!(concealed.windows(2).all(|w| w[1].abs() < w[0].abs()));
+    }
+
+    #[test]
+    fn second_lost_frame_is_silence() {
+        let mut concealer = LossConcealer::new(1000, 2);
+        concealer.receive(vec![0.5; 8]);
+        concealer.conceal();
+        assert_eq!(concealer.conceal(), vec![0.0; 8]);
+    }
+
+    #[test]
+    fn audio_fades_back_in_after_a_loss() {
+        // 5 frames of fade at 1000 Hz.
+        let mut concealer = LossConcealer::new(1000, 1);
+        assert_eq!(concealer.receive(vec![0.5; 10]), vec![0.5; 10]);
+        concealer.conceal();
+        let received = concealer.receive(vec![0.5; 10]);
+        assert_eq!(received[0], 0.0);
+        assert!(received[..5].windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(&received[5..], &[0.5; 5]);
+    }
+}
@@ -0,0 +1,140 @@
+//! Optional TOML config file with defaults for the command line arguments.
+//!
+//! Every field is optional. Arguments given on the command line override the file.
+
+use super::args;
+use super::error::VoiceChatError;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/// Used when no `--config <path>` is given, if it exists.
+const DEFAULT_PATH: &str = "voice-chat.toml";
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub mode: Option<String>,
+    pub target: Option<String>,
+    pub input_device: Option<String>,
+    pub output_device: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub mic_gain: Option<f32>,
+    pub volume: Option<f32>,
+    pub noise_gate: Option<bool>,
+    pub host: Option<String>,
+    pub jack: Option<bool>,
+}
+
+impl Config {
+    /// Loads the file given by `--config`, or else `voice-chat.toml` in the working directory.
+    /// Without a file, all values are unset.
+    pub fn load(args: &[String]) -> Result<Self, VoiceChatError> {
+        let path = match args::value(args, "--config") {
+            Some(path) => Path::new(path),
+            None if Path::new(DEFAULT_PATH).exists() => Path::new(DEFAULT_PATH),
+            None => return Ok(Config::default()),
+        };
+        let text = fs::read_to_string(path).map_err(|e| {
+            VoiceChatError::Config(format!(
+                "Failed to read config file '{}': {e}",
+                path.display()
+            ))
+        })?;
+        let config = toml::from_str(&text).map_err(|e| {
+            VoiceChatError::Config(format!("Invalid config file '{}': {e}", path.display()))
+        })?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Opt;
+    use std::path::PathBuf;
+
+    /// Writes `text` to a config file of its own for a test, returning its path.
+    fn config_file(name: &str, text: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("voice-chat-{}-{name}.toml", std::process::id()));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| String::from(*arg)).collect()
+    }
+
+    #[test]
+    fn values_are_read() {
+        let path = config_file(
+            "values",
+            "mode = \"-c\"\ntarget = \"10.0.0.2:8888\"\nsample_rate = 48000\nmic_gain = 2.0\nnoise_gate = false\n",
+        );
+        let config =
+            Config::load(&args(&["voice-chat", "--config", path.to_str().unwrap()])).unwrap();
+        assert_eq!(config.mode.as_deref(), Some("-c"));
+        assert_eq!(config.target.as_deref(), Some("10.0.0.2:8888"));
+        assert_eq!(config.sample_rate, Some(48000));
+        assert_eq!(config.mic_gain, Some(2.0));
+        assert_eq!(config.noise_gate, Some(false));
+        assert_eq!(config.volume, None);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn command_line_overrides_the_file() {
+        let path = config_file("overrides", "sample_rate = 48000\nvolume = 0.5\n");
+        let path = path.to_str().unwrap();
+        let opt = Opt::from_args(&args(&[
+            "voice-chat",
+            "-c",
+            "x",
+            "--config",
+            path,
+            "--sample-rate",
+            "16000",
+        ]))
+        .unwrap();
+        assert_eq!(opt.sample_rate, 16000);
+        assert_eq!(opt.playback_volume, 0.5);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn unknown_keys_are_refused() {
+        let path = config_file("unknown", "sample_rte = 48000\n");
+        let loaded = Config::load(&args(&["voice-chat", "--config", path.to_str().unwrap()]));
+        assert!(matches!(loaded, Err(VoiceChatError::Config(_))));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        let loaded = Config::load(&args(&[
+            "voice-chat",
+            "--config",
+            "/nonexistent/voice-chat.toml",
+        ]));
+        assert!(matches!(loaded, Err(VoiceChatError::Config(_))));
+    }
+
+    #[test]
+    fn noise_gate_can_be_turned_off() {
+        let opt = |flags: &[&str]| {
+            let all_args: Vec<&str> = ["voice-chat", "-c", "x"]
+                .iter()
+                .chain(flags)
+                .copied()
+                .collect();
+            Opt::from_args(&args(&all_args)).unwrap()
+        };
+        assert!(opt(&[]).noise_gate);
+        assert!(!opt(&["--no-noise-gate"]).noise_gate);
+        assert!(!opt(&["--raw"]).noise_gate);
+        let path = config_file("gate", "noise_gate = false\n");
+        assert!(!opt(&["--config", path.to_str().unwrap()]).noise_gate);
+        fs::remove_file(path).unwrap();
+    }
+}
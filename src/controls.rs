@@ -0,0 +1,165 @@
+//! Handle for changing a call while it is running.
+
+use super::playback::ChannelGains;
+use super::session::{SessionState, SharedState};
+use super::stats::Stats;
+use smol::channel::Sender;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Requests handled by the running call, in the order they were sent.
+#[derive(Clone)]
+pub(crate) enum Command {
+    SetOutputDevice(String),
+    SendText(String),
+    SetPeerGain(u64, f32),
+    SetPeerPan(u64, Option<f32>),
+    /// The mic was muted or unmuted, which the peer is told about.
+    MuteChanged,
+    /// Ends the current call, telling the peer it was on purpose.
+    HangUp,
+    /// Sent by a stream's error callback once its device is gone, e.g. unplugged.
+    InputLost,
+    OutputLost,
+}
+
+/// Controls a [`Client`](crate::Client) from another thread or task, see
+/// [`Client::controls`](crate::Client::controls).
+///
+/// Commands only take effect during a call. Ones sent while no call is running are applied
+/// once the next call starts.
+#[derive(Clone)]
+pub struct Controls {
+    pub(crate) commands: Sender<Command>,
+    pub(crate) deafened: Arc<AtomicBool>,
+    pub(crate) muted: Arc<AtomicBool>,
+    pub(crate) monitoring: Arc<AtomicBool>,
+    pub(crate) peer_muted: Arc<AtomicBool>,
+    pub(crate) peer_name: Arc<Mutex<String>>,
+    pub(crate) channel_gains: ChannelGains,
+    pub(crate) state: SharedState,
+    pub(crate) stats: Arc<Stats>,
+}
+
+impl Controls {
+    /// Switches playback to the named output device (or `"default"`), e.g. after plugging in
+    /// headphones. Audio that is already buffered is kept. If the device can't be used, the call
+    /// stays on the current device and the error is logged.
+    pub fn set_output_device(&self, name: &str) {
+        let _ = self
+            .commands
+            .try_send(Command::SetOutputDevice(name.to_string()));
+    }
+
+    /// Sends a text message to the peer, e.g. a link. Messages longer than 1000 bytes are cut.
+    pub fn send_text(&self, text: &str) {
+        let _ = self.commands.try_send(Command::SendText(text.to_string()));
+    }
+
+    /// Through a mixer, plays the client with the given ID at `gain` (e.g. 0.5 for half as
+    /// loud, 0.0 to mute them), without changing what anyone else hears. The mixer announces
+    /// the IDs as text messages when clients join. Without a mixer, this does nothing.
+    pub fn set_peer_gain(&self, id: u64, gain: f32) {
+        let _ = self.commands.try_send(Command::SetPeerGain(id, gain));
+    }
+
+    /// Through a mixer with `--pan`, places the client with the given ID in the stereo field,
+    /// from -1.0 (left) to 1.0 (right), or back where the mixer put them with `None`. Only your
+    /// own mix changes. Without a panning mixer, this does nothing.
+    pub fn set_peer_pan(&self, id: u64, pan: Option<f32>) {
+        let _ = self.commands.try_send(Command::SetPeerPan(id, pan));
+    }
+
+    /// Stops (or resumes) playing the peer's audio. Unlike muting, the peer still hears you.
+    /// Received audio is still processed while deafened, so undeafening is instant.
+    pub fn set_deafened(&self, deafened: bool) {
+        self.deafened.store(deafened, Ordering::Relaxed);
+    }
+
+    /// Toggles deafening and returns whether playback is now deafened.
+    pub fn toggle_deafen(&self) -> bool {
+        !self.deafened.fetch_xor(true, Ordering::Relaxed)
+    }
+
+    /// Ends the current call: the peer is told it was on purpose, and both sides' `listen`,
+    /// `connect` or `call` return `Ok(())`. Without a call, the next one ends right away.
+    pub fn hang_up(&self) {
+        let _ = self.commands.try_send(Command::HangUp);
+    }
+
+    /// Stops (or resumes) sending your mic. Unlike deafening, you still hear the peer. The
+    /// peer is told, so it can show that you are muted rather than just quiet.
+    pub fn set_muted(&self, muted: bool) {
+        if self.muted.swap(muted, Ordering::Relaxed) != muted {
+            let _ = self.commands.try_send(Command::MuteChanged);
+        }
+    }
+
+    /// Toggles muting and returns whether the mic is now muted.
+    pub fn toggle_mute(&self) -> bool {
+        let muted = !self.muted.fetch_xor(true, Ordering::Relaxed);
+        let _ = self.commands.try_send(Command::MuteChanged);
+        muted
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    /// Starts (or stops) playing your mic locally, mixed in with the peer, e.g. to check how
+    /// it sounds. You hear it as it is sent, at `--monitor-level`, even while muted or
+    /// deafened. Like sidetone, only use it with headphones, or it feeds back.
+    pub fn set_monitoring(&self, monitoring: bool) {
+        self.monitoring.store(monitoring, Ordering::Relaxed);
+    }
+
+    /// Toggles monitoring and returns whether your mic is now played locally.
+    pub fn toggle_monitor(&self) -> bool {
+        !self.monitoring.fetch_xor(true, Ordering::Relaxed)
+    }
+
+    pub fn is_monitoring(&self) -> bool {
+        self.monitoring.load(Ordering::Relaxed)
+    }
+
+    /// Whether the peer said it muted its mic, see [`Client::peer_muted`](crate::Client::peer_muted).
+    pub fn peer_muted(&self) -> bool {
+        self.peer_muted.load(Ordering::Relaxed)
+    }
+
+    /// The name the peer of the current (or last) call gave with `--name`, cleaned of control
+    /// characters and cut to 32 bytes. `None` if it gave none, like peers of older releases
+    /// and mixers.
+    pub fn peer_name(&self) -> Option<String> {
+        self.peer_name
+            .lock()
+            .ok()
+            .map(|name| name.clone())
+            .filter(|name| !name.is_empty())
+    }
+
+    /// See [`Client::set_channel_gains`](crate::Client::set_channel_gains).
+    pub fn set_channel_gains(&self, gains: &[f32]) {
+        self.channel_gains.set(gains);
+    }
+
+    pub fn is_deafened(&self) -> bool {
+        self.deafened.load(Ordering::Relaxed)
+    }
+
+    /// Where the client is in a call, see [`Client::state`](crate::Client::state).
+    pub fn state(&self) -> SessionState {
+        self.state.get()
+    }
+
+    /// See [`Client::call_duration`](crate::Client::call_duration).
+    pub fn call_duration(&self) -> Duration {
+        self.state.call_duration()
+    }
+
+    /// See [`Client::stats`](crate::Client::stats).
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+}
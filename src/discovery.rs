@@ -0,0 +1,96 @@
+//! Finding servers on the LAN via mDNS, so nobody has to type IP addresses.
+
+use super::error::VoiceChatError;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// Service type servers are advertised under.
+const SERVICE_TYPE: &str = "_voicechat._tcp.local.";
+
+/// A server found by [`discover`].
+pub struct Server {
+    pub name: String,
+    pub address: SocketAddr,
+}
+
+/// Keeps a server advertised until it is dropped.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// The name other machines see this server as: the host name, or else its IP address.
+fn server_name(ip: IpAddr) -> String {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .unwrap_or_else(|_| ip.to_string())
+}
+
+fn discovery_error(e: mdns_sd::Error) -> VoiceChatError {
+    VoiceChatError::Discovery(e.to_string())
+}
+
+/// Advertises a server listening on `address`. A wildcard address is advertised as the local IP.
+pub fn advertise(address: SocketAddr) -> Result<Advertisement, VoiceChatError> {
+    let ip = if address.ip().is_unspecified() {
+        local_ip_address::local_ip().map_err(|e| VoiceChatError::Discovery(e.to_string()))?
+    } else {
+        address.ip()
+    };
+    let name = server_name(ip);
+    let host = format!("{}.local.", name.replace(['.', ':'], "-"));
+    let properties = [("name", name.as_str())];
+    let info = ServiceInfo::new(
+        SERVICE_TYPE,
+        &name,
+        &host,
+        ip,
+        address.port(),
+        &properties[..],
+    )
+    .map_err(discovery_error)?;
+    let daemon = ServiceDaemon::new().map_err(discovery_error)?;
+    daemon.register(info).map_err(discovery_error)?;
+    Ok(Advertisement { daemon })
+}
+
+/// Looks for advertised servers for `timeout` and returns all that were found.
+pub fn discover(timeout: Duration) -> Result<Vec<Server>, VoiceChatError> {
+    let daemon = ServiceDaemon::new().map_err(discovery_error)?;
+    let events = daemon.browse(SERVICE_TYPE).map_err(discovery_error)?;
+    let deadline = Instant::now() + timeout;
+    let mut servers: Vec<Server> = Vec::new();
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let Ok(event) = events.recv_timeout(remaining) else {
+            break;
+        };
+        if let ServiceEvent::ServiceResolved(service) = event {
+            let name = service
+                .txt_properties
+                .get_property_val_str("name")
+                .unwrap_or(&service.fullname)
+                .to_string();
+            // One entry per server, preferring IPv4 like the rest of the LAN setup.
+            let Some(ip) = service
+                .addresses
+                .iter()
+                .map(|ip| ip.to_ip_addr())
+                .min_by_key(|ip| ip.is_ipv6())
+            else {
+                continue;
+            };
+            let address = SocketAddr::new(ip, service.port);
+            if !servers.iter().any(|server| server.address == address) {
+                servers.push(Server { name, address });
+            }
+        }
+    }
+    let _ = daemon.shutdown();
+    Ok(servers)
+}
@@ -0,0 +1,70 @@
+//! Writing the sent and received audio to files, to look at it e.g. in Audacity or numpy.
+
+use super::error::VoiceChatError;
+use log::info;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Raw interleaved 32-bit float samples (little endian), without any header.
+///
+/// The files are buffered and flushed when the dump is dropped, i.e. when the call ends.
+pub struct SampleDump {
+    captured: BufWriter<File>,
+    received: BufWriter<File>,
+}
+
+/// `<path>.<kind>.f32`, so both files of a dump end up next to each other.
+fn dump_path(path: &Path, kind: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{kind}.f32"));
+    PathBuf::from(name)
+}
+
+fn create(path: &Path) -> Result<BufWriter<File>, VoiceChatError> {
+    File::create(path).map(BufWriter::new).map_err(|e| {
+        VoiceChatError::DumpFile(format!("Failed to create '{}': {e}", path.display()))
+    })
+}
+
+impl SampleDump {
+    /// Creates (or overwrites) `<path>.captured.f32` for the audio that is sent and
+    /// `<path>.received.f32` for the audio from the peer.
+    pub fn create(
+        path: &Path,
+        (captured_rate, captured_channels): (u32, usize),
+        (received_rate, received_channels): (u32, usize),
+    ) -> Result<Self, VoiceChatError> {
+        let captured_path = dump_path(path, "captured");
+        let received_path = dump_path(path, "received");
+        let dump = SampleDump {
+            captured: create(&captured_path)?,
+            received: create(&received_path)?,
+        };
+        info!(
+            "Dumping sent audio ({captured_rate} Hz, {captured_channels} channel(s)) to '{}'",
+            captured_path.display()
+        );
+        info!(
+            "Dumping received audio ({received_rate} Hz, {received_channels} channel(s)) to '{}'",
+            received_path.display()
+        );
+        Ok(dump)
+    }
+
+    pub fn write_captured(&mut self, samples: &[f32]) -> io::Result<()> {
+        write_samples(&mut self.captured, samples)
+    }
+
+    pub fn write_received(&mut self, samples: &[f32]) -> io::Result<()> {
+        write_samples(&mut self.received, samples)
+    }
+}
+
+fn write_samples(writer: &mut impl Write, samples: &[f32]) -> io::Result<()> {
+    for sample in samples {
+        writer.write_all(&sample.to_le_bytes())?;
+    }
+    Ok(())
+}
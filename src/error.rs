@@ -0,0 +1,159 @@
+//! The error type returned by the library, so callers can tell failures apart.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum VoiceChatError {
+    /// The audio host (named) has no default input device.
+    NoInputDevice(String),
+    /// The audio host (named) has no default output device.
+    NoOutputDevice(String),
+    /// The audio host given by `--host` isn't available in this build or on this platform.
+    HostNotFound(String),
+    /// No device of the given kind ("input" or "output") has the requested name.
+    DeviceNotFound { kind: &'static str, name: String },
+    /// The input or output device disappeared during a call, and the default one couldn't
+    /// be used instead.
+    DeviceLost(&'static str),
+    /// The device offers no usable stream config.
+    UnsupportedConfig(String),
+    /// The audio backend failed, e.g. while building or starting a stream.
+    Audio(Box<dyn Error + Send + Sync>),
+    /// Binding, connecting or talking to the peer failed.
+    Network(io::Error),
+    /// The peer didn't complete the handshake, or the call would lead back to ourselves.
+    Handshake(String),
+    /// The peer speaks another protocol version (given), so neither side could understand the
+    /// other.
+    VersionMismatch(u8),
+    /// Nothing was received from the peer for too long.
+    PeerTimedOut,
+    /// The server turned the call down, e.g. because it is full.
+    Rejected(String),
+    /// The config file couldn't be read or parsed.
+    Config(String),
+    /// The WAV file given by `--input-file` or `--greeting` couldn't be read.
+    InputFile(String),
+    /// The files given by `--dump-samples` couldn't be created.
+    DumpFile(String),
+    /// The files given by `--record-split` couldn't be created.
+    RecordingFile(String),
+    /// A mixer didn't answer a `--status` query.
+    Status(String),
+    /// Advertising or looking for servers on the LAN failed.
+    Discovery(String),
+}
+
+impl fmt::Display for VoiceChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoiceChatError::NoInputDevice(host) => write!(
+                f,
+                "Audio host '{host}' has no default input device! Give one by name or number"
+            ),
+            VoiceChatError::NoOutputDevice(host) => write!(
+                f,
+                "Audio host '{host}' has no default output device! Give one by name or number"
+            ),
+            VoiceChatError::HostNotFound(name) => write!(f, "Failed to find audio host '{name}'!"),
+            VoiceChatError::DeviceNotFound { kind, name } => {
+                write!(f, "Failed to find {kind} device '{name}'!")
+            }
+            VoiceChatError::DeviceLost(kind) => {
+                write!(f, "The {kind} device is gone (was it unplugged?)")
+            }
+            VoiceChatError::UnsupportedConfig(e) => write!(f, "Unsupported stream config: {e}"),
+            VoiceChatError::Audio(e) => write!(f, "Audio error: {e}"),
+            VoiceChatError::Network(e) => write!(f, "Network error: {e}"),
+            VoiceChatError::Handshake(e) => write!(f, "Handshake failed: {e}"),
+            VoiceChatError::VersionMismatch(version) => write!(
+                f,
+                "The peer speaks protocol version {version} (or is a release from before versions), \
+                 but this one speaks version {}. Use the same release on both sides.",
+                crate::protocol::PROTOCOL_VERSION
+            ),
+            VoiceChatError::PeerTimedOut => write!(f, "Peer timed out"),
+            VoiceChatError::Rejected(reason) => write!(f, "The server rejected the call: {reason}"),
+            VoiceChatError::Config(e) => write!(f, "{e}"),
+            VoiceChatError::InputFile(e) => write!(f, "{e}"),
+            VoiceChatError::DumpFile(e) => write!(f, "{e}"),
+            VoiceChatError::RecordingFile(e) => write!(f, "{e}"),
+            VoiceChatError::Status(e) => write!(f, "Status query failed: {e}"),
+            VoiceChatError::Discovery(e) => write!(f, "Discovery failed: {e}"),
+        }
+    }
+}
+
+impl Error for VoiceChatError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VoiceChatError::Audio(e) => Some(e.as_ref()),
+            VoiceChatError::Network(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VoiceChatError {
+    fn from(e: io::Error) -> Self {
+        VoiceChatError::Network(e)
+    }
+}
+
+impl From<cpal::HostUnavailable> for VoiceChatError {
+    fn from(e: cpal::HostUnavailable) -> Self {
+        VoiceChatError::Audio(Box::new(e))
+    }
+}
+
+impl From<cpal::DevicesError> for VoiceChatError {
+    fn from(e: cpal::DevicesError) -> Self {
+        VoiceChatError::Audio(Box::new(e))
+    }
+}
+
+impl From<cpal::DeviceNameError> for VoiceChatError {
+    fn from(e: cpal::DeviceNameError) -> Self {
+        VoiceChatError::Audio(Box::new(e))
+    }
+}
+
+impl From<cpal::SupportedStreamConfigsError> for VoiceChatError {
+    fn from(e: cpal::SupportedStreamConfigsError) -> Self {
+        VoiceChatError::UnsupportedConfig(e.to_string())
+    }
+}
+
+impl From<cpal::BuildStreamError> for VoiceChatError {
+    fn from(e: cpal::BuildStreamError) -> Self {
+        VoiceChatError::Audio(Box::new(e))
+    }
+}
+
+impl From<cpal::PlayStreamError> for VoiceChatError {
+    fn from(e: cpal::PlayStreamError) -> Self {
+        VoiceChatError::Audio(Box::new(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_default_device_names_the_host() {
+        let input = VoiceChatError::NoInputDevice(String::from("JACK")).to_string();
+        assert!(
+            input.contains("'JACK'") && input.contains("input"),
+            "{input}"
+        );
+        let output = VoiceChatError::NoOutputDevice(String::from("ALSA")).to_string();
+        assert!(
+            output.contains("'ALSA'") && output.contains("output"),
+            "{output}"
+        );
+        assert!(output.contains("by name or number"), "{output}");
+    }
+}
@@ -0,0 +1,71 @@
+/// Cuts captured audio into frames of a fixed number of samples, as codecs like Opus need
+/// them (e.g. 960 samples per channel at 48 kHz for 20 ms).
+///
+/// Samples that don't fill a whole frame are kept for the next call of [`Framer::push`].
+pub struct Framer {
+    /// Interleaved samples per frame, i.e. over all channels.
+    frame_len: usize,
+    pending: Vec<f32>,
+}
+
+impl Framer {
+    /// Creates a framer for frames of `samples` samples per channel.
+    pub fn new(samples: usize, channels: usize) -> Self {
+        Framer {
+            frame_len: (samples * channels.max(1)).max(1),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Adds interleaved samples and returns the frames that are complete now.
+    pub fn push(&mut self, samples: &[f32]) -> Vec<Vec<f32>> {
+        self.pending.extend_from_slice(samples);
+        let complete = self.pending.len() / self.frame_len * self.frame_len;
+        let frames = self.pending[..complete]
+            .chunks_exact(self.frame_len)
+            .map(<[f32]>::to_vec)
+            .collect();
+        self.pending.drain(..complete);
+        frames
+    }
+
+    /// Returns the remaining samples padded with zeros to a whole frame, if there are any, e.g.
+    /// once the input pauses.
+    pub fn flush(&mut self) -> Option<Vec<f32>> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let mut frame = std::mem::take(&mut self.pending);
+        frame.resize(self.frame_len, 0.0);
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_is_cut_into_whole_frames() {
+        let mut framer = Framer::new(3, 2);
+        assert!(framer.push(&[0.1; 4]).is_empty());
+        let frames = framer.push(&[0.2; 10]);
+        assert_eq!(
+            frames,
+            vec![vec![0.1, 0.1, 0.1, 0.1, 0.2, 0.2], vec![0.2; 6]]
+        );
+        assert_eq!(
+            framer.push(&[0.3; 6]),
+            vec![vec![0.2, 0.2, 0.3, 0.3, 0.3, 0.3]]
+        );
+    }
+
+    #[test]
+    fn rest_is_padded_with_silence() {
+        let mut framer = Framer::new(4, 1);
+        assert_eq!(framer.flush(), None);
+        framer.push(&[0.5; 5]);
+        assert_eq!(framer.flush(), Some(vec![0.5, 0.0, 0.0, 0.0]));
+        assert_eq!(framer.flush(), None);
+    }
+}
@@ -0,0 +1,80 @@
+//! Running a [`Client`](crate::Client) without any audio devices, e.g. for bots, tests or
+//! servers, see [`Client::new_headless`](crate::Client::new_headless).
+
+use std::sync::{Arc, Mutex};
+
+pub(crate) type CaptureCallback = Box<dyn FnMut(&[f32]) + Send>;
+pub(crate) type PlaybackCallback = Box<dyn FnMut(&mut [f32]) + Send>;
+
+/// Stands in for the mic and the speakers of a headless client: whatever is pushed is sent
+/// like captured audio, and pulling takes the audio that would have been played.
+///
+/// Audio is interleaved, at `--sample-rate` with `--channels` channels (mono by default), and
+/// goes through the same processing as with devices. Nothing paces it: the caller has to push
+/// and pull about as many samples per second as the sample rate, like a device would.
+#[derive(Clone, Default)]
+pub struct HeadlessAudio {
+    capture: Arc<Mutex<Option<CaptureCallback>>>,
+    playback: Arc<Mutex<Option<PlaybackCallback>>>,
+}
+
+impl HeadlessAudio {
+    /// Hands samples to the running call as if the mic had captured them. Without a call,
+    /// they are dropped.
+    pub fn push_capture(&self, samples: &[f32]) {
+        if let Ok(mut capture) = self.capture.lock()
+            && let Some(callback) = capture.as_mut()
+        {
+            callback(samples);
+        }
+    }
+
+    /// Fills `samples` with the next audio the running call plays, or with silence if there
+    /// is no call.
+    pub fn pull_playback(&self, samples: &mut [f32]) {
+        match self.playback.lock() {
+            Ok(mut playback) if playback.is_some() => {
+                if let Some(callback) = playback.as_mut() {
+                    callback(samples);
+                }
+            }
+            _ => samples.fill(0.0),
+        }
+    }
+
+    /// Lets `callback` receive the pushed samples until the returned guard is dropped.
+    pub(crate) fn start_capture(
+        &self,
+        callback: impl FnMut(&[f32]) + Send + 'static,
+    ) -> Attached<CaptureCallback> {
+        Attached::new(&self.capture, Box::new(callback))
+    }
+
+    /// Lets `callback` fill the pulled samples until the returned guard is dropped.
+    pub(crate) fn start_playback(
+        &self,
+        callback: impl FnMut(&mut [f32]) + Send + 'static,
+    ) -> Attached<PlaybackCallback> {
+        Attached::new(&self.playback, Box::new(callback))
+    }
+}
+
+/// A callback attached to [`HeadlessAudio`], detached again when this is dropped.
+pub(crate) struct Attached<T>(Arc<Mutex<Option<T>>>);
+
+impl<T> Attached<T> {
+    fn new(slot: &Arc<Mutex<Option<T>>>, callback: T) -> Self {
+        if let Ok(mut slot) = slot.lock() {
+            *slot = Some(callback);
+        }
+        Attached(slot.clone())
+    }
+}
+
+impl<T> Drop for Attached<T> {
+    fn drop(&mut self) {
+        if let Ok(mut slot) = self.0.lock() {
+            *slot = None;
+        }
+    }
+}
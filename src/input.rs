@@ -0,0 +1,184 @@
+use super::error::VoiceChatError;
+use super::headless::{Attached, CaptureCallback, HeadlessAudio};
+use super::resample::Resampler;
+use super::util::build_input_stream;
+use cpal::traits::StreamTrait;
+use cpal::{Device, SampleFormat, SampleRate, StreamConfig};
+use log::info;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How much audio a file source hands to the callback at once.
+const FILE_CHUNK: Duration = Duration::from_millis(10);
+
+/// Where captured audio comes from.
+pub enum InputSource {
+    /// A live input device, opened in the given sample format.
+    Device(Device, SampleFormat),
+    /// A WAV file played on a loop, for deterministic tests without a mic.
+    File(FileSource),
+    /// Pushed by the app through [`HeadlessAudio::push_capture`].
+    Headless(HeadlessAudio),
+}
+
+/// A WAV file, already converted to `f32` at the session sample rate.
+pub struct FileSource {
+    samples: Arc<Vec<f32>>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+/// Reads a whole WAV file, resampled to `sample_rate` if needed. `what` names the file in
+/// errors, e.g. `"input file"`. The spec is the file's own.
+pub fn read_wav(
+    path: &Path,
+    sample_rate: u32,
+    what: &str,
+) -> Result<(Vec<f32>, hound::WavSpec), VoiceChatError> {
+    let invalid = |e: hound::Error| {
+        VoiceChatError::InputFile(format!("Failed to read {what} '{}': {e}", path.display()))
+    };
+    let mut reader = hound::WavReader::open(path).map_err(invalid)?;
+    let spec = reader.spec();
+    if spec.sample_rate == 0 {
+        return Err(VoiceChatError::InputFile(format!(
+            "The {what} '{}' has a sample rate of 0 Hz!",
+            path.display()
+        )));
+    }
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader
+            .samples::<f32>()
+            .collect::<Result<_, _>>()
+            .map_err(invalid)?,
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / scale))
+                .collect::<Result<_, _>>()
+                .map_err(invalid)?
+        }
+    };
+    if samples.is_empty() {
+        return Err(VoiceChatError::InputFile(format!(
+            "The {what} '{}' contains no audio!",
+            path.display()
+        )));
+    }
+    let mut resampler = Resampler::new(spec.sample_rate, sample_rate, spec.channels as usize);
+    Ok((resampler.process(&samples), spec))
+}
+
+impl FileSource {
+    /// Reads the WAV file and resamples it to `sample_rate` if needed.
+    pub fn open(path: &Path, sample_rate: u32) -> Result<Self, VoiceChatError> {
+        let (samples, spec) = read_wav(path, sample_rate, "input file")?;
+        info!(
+            "Input file: {} ({} Hz, {} channel(s))",
+            path.display(),
+            spec.sample_rate,
+            spec.channels
+        );
+        Ok(FileSource {
+            samples: Arc::new(samples),
+            channels: spec.channels,
+            sample_rate,
+        })
+    }
+
+    /// The config the file's audio is delivered in.
+    pub fn config(&self) -> StreamConfig {
+        StreamConfig {
+            channels: self.channels,
+            sample_rate: SampleRate(self.sample_rate),
+            buffer_size: cpal::BufferSize::Default,
+        }
+    }
+}
+
+/// Running capture. Capturing stops when this is dropped.
+pub enum Capture {
+    /// Records while kept alive. Dropping it pauses the stream before closing it, so the
+    /// device is free to be opened again right away.
+    Stream(cpal::Stream),
+    File {
+        stop: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    },
+    Headless(#[allow(dead_code)] Attached<CaptureCallback>),
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        match self {
+            // Stopped before the stream closes the device, which some backends otherwise
+            // leave busy for a moment, so a restart right away fails to open it.
+            Capture::Stream(stream) => {
+                let _ = stream.pause();
+            }
+            Capture::File { stop, thread } => {
+                stop.store(true, Ordering::Relaxed);
+                if let Some(thread) = thread.take() {
+                    let _ = thread.join();
+                }
+            }
+            Capture::Headless(_) => {}
+        }
+    }
+}
+
+impl InputSource {
+    /// Starts capturing, calling `callback` with each chunk of captured samples. `on_lost` is
+    /// called if the device disappears.
+    pub fn start(
+        &self,
+        config: &StreamConfig,
+        on_lost: impl FnMut() + Send + 'static,
+        mut callback: impl FnMut(&[f32]) + Send + 'static,
+    ) -> Result<Capture, VoiceChatError> {
+        match self {
+            InputSource::Device(device, format) => {
+                let stream = build_input_stream(device, config, *format, on_lost, callback)?;
+                stream.play()?;
+                Ok(Capture::Stream(stream))
+            }
+            InputSource::File(file) => {
+                let samples = file.samples.clone();
+                let channels = file.channels as usize;
+                let chunk_len =
+                    (file.sample_rate as f64 * FILE_CHUNK.as_secs_f64()) as usize * channels;
+                let stop = Arc::new(AtomicBool::new(false));
+                let stop_ref = stop.clone();
+                let thread = thread::spawn(move || {
+                    // Pace the chunks by the clock like a real device, wrapping around at the end.
+                    let start = Instant::now();
+                    let mut position = 0;
+                    let mut chunks: u32 = 0;
+                    let mut chunk = Vec::with_capacity(chunk_len);
+                    while !stop_ref.load(Ordering::Relaxed) {
+                        chunk.clear();
+                        while chunk.len() < chunk_len {
+                            let end = (position + chunk_len - chunk.len()).min(samples.len());
+                            chunk.extend_from_slice(&samples[position..end]);
+                            position = end % samples.len();
+                        }
+                        callback(&chunk);
+                        chunks += 1;
+                        if let Some(wait) = (FILE_CHUNK * chunks).checked_sub(start.elapsed()) {
+                            thread::sleep(wait);
+                        }
+                    }
+                });
+                Ok(Capture::File {
+                    stop,
+                    thread: Some(thread),
+                })
+            }
+            InputSource::Headless(audio) => Ok(Capture::Headless(audio.start_capture(callback))),
+        }
+    }
+}
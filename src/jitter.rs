@@ -0,0 +1,111 @@
+use std::time::{Duration, Instant};
+
+/// The range the target playback level is kept in.
+const MIN_TARGET: Duration = Duration::from_millis(20);
+const MAX_TARGET: Duration = Duration::from_millis(500);
+/// A dropout raises the target by this factor, and by at least `MIN_STEP`.
+const RAISE_FACTOR: f64 = 1.5;
+const MIN_STEP: Duration = Duration::from_millis(10);
+/// After this long without trouble, the target is lowered by `LOWER_STEP`, and again after as
+/// long, so a clean network slowly gets the latency back.
+const CLEAN_TIME: Duration = Duration::from_secs(30);
+const LOWER_STEP: Duration = Duration::from_millis(10);
+/// Trouble this early is ignored, as the buffer runs dry until the peer's first audio arrives.
+const STARTUP_TIME: Duration = Duration::from_secs(5);
+
+/// Chooses how much audio the playback buffer keeps at least, from how the network behaves.
+///
+/// Underruns (the buffer ran dry) mean the peer's audio arrives less steadily than the buffer
+/// allows for, so the target goes up at once. Skips (audio piled up beyond `--max-latency`)
+/// mean the same, as they follow a stall. Once neither happened for a while, the target comes
+/// down again in small steps.
+pub struct JitterTuner {
+    target: Duration,
+    underruns: u64,
+    skips: u64,
+    started: Instant,
+    /// When the target last changed or trouble last happened.
+    since: Instant,
+}
+
+impl JitterTuner {
+    pub fn new(underruns: u64, skips: u64) -> Self {
+        JitterTuner {
+            target: MIN_TARGET,
+            underruns,
+            skips,
+            started: Instant::now(),
+            since: Instant::now(),
+        }
+    }
+
+    pub fn target(&self) -> Duration {
+        self.target
+    }
+
+    /// Takes the totals of underruns and skips so far. Returns the new target if it changed.
+    pub fn update(&mut self, underruns: u64, skips: u64, now: Instant) -> Option<Duration> {
+        let troubled = (underruns > self.underruns || skips > self.skips)
+            && now.duration_since(self.started) >= STARTUP_TIME;
+        self.underruns = underruns;
+        self.skips = skips;
+        let target = if troubled {
+            self.since = now;
+            self.target
+                .mul_f64(RAISE_FACTOR)
+                .max(self.target + MIN_STEP)
+                .min(MAX_TARGET)
+        } else if now.duration_since(self.since) >= CLEAN_TIME {
+            self.since = now;
+            self.target.saturating_sub(LOWER_STEP).max(MIN_TARGET)
+        } else {
+            self.target
+        };
+        if target == self.target {
+            return None;
+        }
+        self.target = target;
+        Some(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropouts_at_the_start_are_ignored() {
+        let mut tuner = JitterTuner::new(0, 0);
+        let start = Instant::now();
+        assert_eq!(tuner.update(3, 1, start + Duration::from_secs(1)), None);
+        assert_eq!(tuner.target(), MIN_TARGET);
+    }
+
+    #[test]
+    fn dropouts_raise_the_target_up_to_the_limit() {
+        let mut tuner = JitterTuner::new(0, 0);
+        let later = Instant::now() + STARTUP_TIME;
+        assert_eq!(tuner.update(1, 0, later), Some(Duration::from_millis(30)));
+        assert_eq!(tuner.update(1, 1, later), Some(Duration::from_millis(45)));
+        // Nothing new happened.
+        assert_eq!(tuner.update(1, 1, later), None);
+        for underruns in 2..20 {
+            tuner.update(underruns, 1, later);
+        }
+        assert_eq!(tuner.target(), MAX_TARGET);
+    }
+
+    #[test]
+    fn clean_network_lowers_the_target_slowly() {
+        let mut tuner = JitterTuner::new(0, 0);
+        let later = Instant::now() + STARTUP_TIME;
+        tuner.update(1, 0, later);
+        tuner.update(2, 0, later);
+        assert_eq!(tuner.update(2, 0, later + CLEAN_TIME / 2), None);
+        let lowered = [35, 25, 20].map(Duration::from_millis);
+        for (times, target) in (1..).zip(lowered) {
+            assert_eq!(tuner.update(2, 0, later + CLEAN_TIME * times), Some(target));
+        }
+        assert_eq!(tuner.update(2, 0, later + CLEAN_TIME * 4), None);
+    }
+}
@@ -0,0 +1,62 @@
+use std::time::{Duration, Instant};
+
+/// Impulses with silence less than this far above the noise aren't told apart from it.
+const NOISE_FACTOR: f32 = 4.0;
+/// The lowest level an arriving impulse is detected at, for very quiet inputs.
+const MIN_THRESHOLD: f32 = 0.02;
+
+/// Measures how long audio takes from the output callback back to the input callback, by
+/// timing impulses that are played and picked up again (through a cable or acoustically).
+#[derive(Default)]
+pub struct LatencyProbe {
+    /// Calibrating: the loudest input seen so far, before any impulse was played.
+    noise_peak: f32,
+    threshold: Option<f32>,
+    /// When the pending impulse was handed to the output device.
+    emitted: Option<Instant>,
+    pub measurements: Vec<Duration>,
+}
+
+impl LatencyProbe {
+    /// Ends calibration: from now on, inputs well above the noise heard so far are impulses.
+    pub fn calibrate(&mut self) -> f32 {
+        *self
+            .threshold
+            .get_or_insert((self.noise_peak * NOISE_FACTOR).max(MIN_THRESHOLD))
+    }
+
+    /// Called by the output callback once an impulse was written at the start of its buffer.
+    pub fn emitted(&mut self) {
+        self.emitted = Some(Instant::now());
+    }
+
+    /// Whether an impulse is still on its way.
+    pub fn pending(&self) -> bool {
+        self.emitted.is_some()
+    }
+
+    /// Gives up on the pending impulse, e.g. because it was never picked up.
+    pub fn cancel(&mut self) {
+        self.emitted = None;
+    }
+
+    /// Looks for the pending impulse in captured audio. The samples are assumed to have been
+    /// captured right up to now, at `samples_per_second` (over all channels).
+    pub fn captured(&mut self, samples: &[f32], samples_per_second: f64) {
+        let Some(threshold) = self.threshold else {
+            let peak = samples.iter().fold(0.0f32, |peak, f| peak.max(f.abs()));
+            self.noise_peak = self.noise_peak.max(peak);
+            return;
+        };
+        let Some(emitted) = self.emitted else {
+            return;
+        };
+        if let Some(index) = samples.iter().position(|f| f.abs() > threshold) {
+            let age = Duration::from_secs_f64((samples.len() - index) as f64 / samples_per_second);
+            let arrived = Instant::now().checked_sub(age).unwrap_or(emitted);
+            self.measurements
+                .push(arrived.saturating_duration_since(emitted));
+            self.emitted = None;
+        }
+    }
+}
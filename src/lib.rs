@@ -1,18 +1,177 @@
 #![forbid(unsafe_code)]
 
-use cpal::traits::{DeviceTrait, StreamTrait};
-use cpal::{Device, StreamConfig};
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::{Device, SampleFormat, StreamConfig};
+use log::{debug, error, info, warn};
+use smol::channel::{self, Receiver, Sender};
+use smol::{Async, Timer, future};
 
-use std::error::Error;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::collections::VecDeque;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "aec")]
+mod aec;
+#[cfg(feature = "aec")]
+use aec::{EchoCanceller, EchoReference};
+
+mod concealment;
+use concealment::LossConcealer;
+
+mod agc;
+use agc::Agc;
+
+mod vad;
+use vad::VoiceDetector;
+
+mod noise_gate;
+use noise_gate::{GateSettings, NoiseGate};
+
+pub mod args;
+pub mod config;
+use config::Config;
+pub mod controls;
+#[cfg(feature = "mdns")]
+pub mod discovery;
+mod dump;
+use controls::{Command, Controls};
+use dump::SampleDump;
+mod recording;
+use recording::SplitRecording;
+pub mod error;
+pub use error::VoiceChatError;
+pub mod log_file;
+pub mod meter;
+use meter::{LevelMeter, LevelStats};
+pub mod mixer;
+pub use mixer::Mixer;
+
+mod capture_queue;
+
+mod comfort_noise;
+use comfort_noise::ComfortNoise;
+
+mod framing;
+use framing::Framer;
+
+pub mod headless;
+use headless::HeadlessAudio;
+
+mod latency;
+use latency::LatencyProbe;
+
+mod jitter;
+use jitter::JitterTuner;
+
+mod input;
+use input::{FileSource, InputSource};
+
+pub mod processor;
+use processor::{AudioProcessor, Gain, Normalize, ProcessorChain};
+
+mod output;
+use output::OutputSink;
+
+mod peak_limiter;
+use peak_limiter::PeakLimiter;
+
+mod sidetone;
+use sidetone::Sidetone;
+
+mod playback;
+use playback::{ChannelGains, Fade, PlaybackBuffer};
+
+pub mod transport;
+use transport::Transport;
+
+mod throttle;
+use throttle::Throttle;
+
+mod protocol;
+use protocol::{
+    ByteOrder, FrameReader, FrameType, FrameWriter, Handshake, MAX_TEXT_LEN, Reorderer, SilenceRun,
+    WireFormat, audio_payload, clean_name, held_intervals, transmit,
+};
+
+pub mod session;
+use session::SharedState;
+pub use session::{CallEnd, SessionState};
+
+pub mod socket;
+
+pub mod stats;
+use stats::Stats;
+
+#[cfg(feature = "rtp")]
+pub mod rtp;
+
+mod resample;
+use resample::Resampler;
+
+mod state;
+use state::DeviceState;
 
 mod util;
+pub use util::Limiter;
+
+/// Binds `address`. With `auto_port`, a port that is in use is not an error: the next few
+/// ports are tried, and then any free one the system picks.
+fn bind(address: &str, auto_port: bool) -> std::io::Result<TcpListener> {
+    match TcpListener::bind(address) {
+        Err(e) if auto_port && e.kind() == std::io::ErrorKind::AddrInUse => {
+            let Some(addr) = address.to_socket_addrs()?.next() else {
+                return Err(e);
+            };
+            let fallbacks = (1..=AUTO_PORT_ATTEMPTS)
+                .filter_map(|offset| addr.port().checked_add(offset))
+                .chain([0]);
+            for port in fallbacks {
+                if let Ok(listener) = TcpListener::bind(SocketAddr::new(addr.ip(), port)) {
+                    warn!(
+                        "Port {} is in use, using {} instead",
+                        addr.port(),
+                        listener.local_addr()?.port()
+                    );
+                    return Ok(listener);
+                }
+            }
+            Err(e)
+        }
+        result => result,
+    }
+}
+
+/// The names of the audio hosts available in this build and on this platform, as `--host`
+/// takes them, and the name of the default one.
+pub fn host_names() -> (Vec<String>, String) {
+    let names = cpal::available_hosts()
+        .iter()
+        .map(|id| id.name().to_lowercase())
+        .collect();
+    (names, cpal::default_host().id().name().to_lowercase())
+}
+
+/// The names of the input and of the output devices, in the order their numbers refer to (the
+/// first device being number 1). Uses the audio host given by `--host`, and with
+/// `--loopback-input` only lists the inputs that capture system audio.
+pub fn device_names() -> Result<(Vec<String>, Vec<String>), VoiceChatError> {
+    let opt = Opt::new()?;
+    let audio_host = get_audio_host(&opt)?;
+    Ok((
+        util::device_names(util::input_devices(&audio_host, opt.loopback_input)?),
+        util::device_names(audio_host.output_devices()?),
+    ))
+}
 use util::{
-    buffer_to_audio_data, get_audio_host, get_input_config, get_input_device, get_output_config,
-    get_output_device, normalize,
+    StreamFormat, apply_volume, decode_audio, get_audio_host, get_input_config, get_input_device,
+    get_output_config, get_output_device, remix,
 };
 
 pub struct Opt {
@@ -20,17 +179,233 @@ pub struct Opt {
     input_device: String,
     output_device: String,
 
-    /// Use the JACK host
-    #[allow(dead_code)]
+    /// Whether the devices are the remembered ones, which fall back to the default if missing
+    input_remembered: bool,
+    output_remembered: bool,
+
+    /// Capture from this WAV file (on a loop) instead of the input device
+    input_file: Option<PathBuf>,
+
+    /// WAV file sent once at the start of every call, before the mic
+    greeting: Option<PathBuf>,
+
+    /// Write the sent and received samples to files starting with this path
+    dump_samples: Option<PathBuf>,
+
+    /// Record the sent and received audio to WAV files starting with this path
+    record_split: Option<PathBuf>,
+
+    /// Audio host to use, e.g. `jack` or `alsa`
+    host: Option<String>,
+
+    /// Use the JACK host if no other host is given
     jack: bool,
+
+    /// Preferred sample rate for capture and playback
+    sample_rate: u32,
+
+    /// Sample rates the devices are opened with, the first one a device supports winning
+    sample_rates: Vec<u32>,
+
+    /// Preferred channel count for capture and playback
+    channels: Option<u16>,
+
+    /// Mix the captured audio down to a single channel before sending it
+    mono: bool,
+
+    /// Downsample the captured audio to this rate before sending it
+    send_rate: Option<u32>,
+
+    /// Fixed device buffer size in frames, trading latency against glitches
+    buffer_frames: Option<u32>,
+
+    /// Exact capture and playback formats, instead of picking from what the devices support
+    input_format: Option<StreamFormat>,
+    output_format: Option<StreamFormat>,
+
+    /// Pick the device (unless one is given) and config with the highest sample rate, then
+    /// the most channels, instead of the default ones
+    best_input: bool,
+    best_output: bool,
+
+    /// Prefer the configs with the smallest buffers, and use a small one
+    low_latency: bool,
+
+    /// Only consider input devices that capture system audio, e.g. monitor sources
+    loopback_input: bool,
+
+    /// Fail instead of warning if the input and output configs don't match
+    strict_config: bool,
+
+    /// Send small frames at once instead of letting Nagle's algorithm batch them
+    tcp_nodelay: bool,
+
+    /// OS send and receive buffer sizes for the socket in bytes, or the OS default
+    tcp_send_buffer: Option<usize>,
+    tcp_recv_buffer: Option<usize>,
+
+    /// Send the captured audio as it is, without the noise gate, VAD, AGC, normalizing, echo
+    /// cancellation, the app's processors or the mic gain
+    raw: bool,
+
+    /// Factor applied to the captured audio, i.e. how loud you are for the peer
+    mic_gain: f32,
+
+    /// Factor applied to the played audio, i.e. how loud the peer is for you
+    playback_volume: f32,
+
+    /// Factors applied to the individual output channels, first channel first
+    channel_gains: Vec<f32>,
+
+    /// How samples are kept within [-1.0, 1.0] after gain
+    limiter: Limiter,
+
+    /// Peaks of the received audio are turned down smoothly above this level, recovering over
+    /// the release time (in seconds)
+    limiter_threshold: f32,
+    limiter_release: f32,
+
+    /// Silence the mic while nobody speaks, and how the gate opens and closes
+    noise_gate: bool,
+    gate: GateSettings,
+
+    /// Only send audio while speech is detected, with this aggressiveness (0 to 3)
+    vad: Option<u8>,
+
+    /// Dither the played audio on 16-bit output devices
+    dither: bool,
+
+    /// Use automatic gain control instead of normalizing every buffer, and its target level
+    agc: bool,
+    agc_target: f32,
+
+    /// Level of the noise played while the peer is silent, if enabled
+    comfort_noise: Option<f32>,
+
+    /// Mute the mic while the peer's audio is playing, against speaker-to-mic echo
+    half_duplex: bool,
+
+    /// Subtract the echo of the peer's audio from the mic (needs the `aec` feature)
+    #[cfg_attr(not(feature = "aec"), allow(dead_code))]
+    aec: bool,
+
+    /// How loud you hear your own mic, 0.0 for not at all
+    sidetone: f32,
+
+    /// Start with monitoring on, see [`Controls::set_monitoring`]
+    monitor: bool,
+
+    /// How loud you hear your mic while monitoring
+    monitor_level: f32,
+
+    /// Hang up on our own after this long
+    duration: Option<Duration>,
+
+    /// Chat even if the connection turns out to lead back to this instance
+    allow_loopback: bool,
+
+    /// Room to join on a mixer, only clients in the same room hear each other
+    room: String,
+
+    /// Shown to the peer (or the others on a mixer) instead of our address, may be empty
+    name: String,
+
+    /// Clients a mixer accepts at once, further ones are turned away
+    max_clients: Option<usize>,
+
+    /// A mixer plays a chime to the room when someone joins or leaves
+    join_sounds: bool,
+
+    /// A mixer spreads the others in each client's mix across the stereo field
+    pan: bool,
+
+    /// Ceiling for the average send rate, in bits per second
+    max_bitrate: Option<f64>,
+
+    /// Samples per channel of every audio frame sent, for codecs that need fixed frames
+    frame_samples: Option<usize>,
+
+    /// Fall back to another port if the one to listen to is in use
+    auto_port: bool,
+
+    /// How the samples of sent audio frames are encoded
+    wire_format: WireFormat,
+
+    /// Received audio that piles up beyond this is skipped, to keep the call live
+    max_latency: Duration,
+
+    /// How long without a frame until the peer is considered gone (see [`Client::timeout`])
+    timeout: Duration,
+
+    /// How long `connect` keeps retrying while the server isn't up yet, if not derived from
+    /// `timeout`
+    connect_timeout: Option<Duration>,
+
+    /// A fixed playback level to keep at least, instead of adapting it to the network
+    jitter_target: Option<Duration>,
+
+    /// Where `--rtp` receives, which is also the port it sends from
+    #[cfg_attr(not(feature = "rtp"), allow(dead_code))]
+    rtp_port: u16,
 }
 
 impl Opt {
-    fn new() -> Self {
-        let args: Vec<String> = std::env::args().collect();
-        Opt {
-            input_device: args.get(3).unwrap_or(&String::from("default")).to_string(),
-            output_device: args.get(4).unwrap_or(&String::from("default")).to_string(),
+    fn new() -> Result<Self, VoiceChatError> {
+        Opt::from_args(&std::env::args().collect::<Vec<_>>())
+    }
+
+    /// The options a command line would give, e.g. to set up a client from code or in tests.
+    /// Like `std::env::args`, the first argument is the program name, then the mode and its
+    /// arguments, then the flags. The config file and the remembered devices apply as usual.
+    pub fn from_args(all_args: &[String]) -> Result<Self, VoiceChatError> {
+        let args = args::positional(all_args);
+        let config = Config::load(all_args)?;
+        let raw = args::flag(all_args, "--raw") || args::flag(all_args, "--no-processing");
+        if args::flag(all_args, "--aec") && !cfg!(feature = "aec") {
+            warn!("--aec needs the aec feature, build with --features aec");
+        }
+        // Loopback and test tone have no target, so the devices come one argument earlier.
+        let first_device = match args.get(1).map(String::as_str) {
+            Some("-l" | "--loopback" | "-t" | "--test-tone" | "--measure-latency") => 2,
+            _ => 3,
+        };
+        let remembered = if args::flag(all_args, "--forget-devices") {
+            DeviceState::forget();
+            DeviceState::default()
+        } else {
+            DeviceState::load()
+        };
+        let input_device = args.get(first_device).cloned().or(config.input_device);
+        let output_device = args.get(first_device + 1).cloned().or(config.output_device);
+        let preferred_rates: Option<Vec<u32>> = args::list(all_args, "--prefer-sample-rate");
+        // Parsed here rather than with `args::parsed`, so the warning says what is wrong.
+        let stream_format = |name| {
+            let spec = args::value(all_args, name)?;
+            spec.parse::<StreamFormat>()
+                .map_err(|e| warn!("Invalid value for {name}: {e}, ignoring it"))
+                .ok()
+        };
+        let sample_rate = args::parsed(all_args, "--sample-rate")
+            .or(config.sample_rate)
+            .filter(|&hz| hz > 0)
+            .or(preferred_rates.as_ref().map(|rates| rates[0]))
+            .unwrap_or(DEFAULT_SAMPLE_RATE);
+        Ok(Opt {
+            input_remembered: input_device.is_none() && remembered.input_device.is_some(),
+            output_remembered: output_device.is_none() && remembered.output_device.is_some(),
+            input_device: input_device
+                .or(remembered.input_device)
+                .unwrap_or(String::from("default")),
+            output_device: output_device
+                .or(remembered.output_device)
+                .unwrap_or(String::from("default")),
+            input_file: args::value(all_args, "--input-file").map(PathBuf::from),
+            greeting: args::value(all_args, "--greeting").map(PathBuf::from),
+            dump_samples: args::value(all_args, "--dump-samples").map(PathBuf::from),
+            record_split: args::value(all_args, "--record-split").map(PathBuf::from),
+            host: args::value(all_args, "--host")
+                .map(String::from)
+                .or(config.host),
             jack: cfg!(all(
                 any(
                     target_os = "linux",
@@ -39,128 +414,2398 @@ impl Opt {
                     target_os = "netbsd"
                 ),
                 feature = "jack"
-            )),
-        }
+            )) && config.jack.unwrap_or(true),
+            sample_rate,
+            sample_rates: preferred_rates.unwrap_or(vec![sample_rate]),
+            channels: args::parsed(all_args, "--channels").filter(|&n| n > 0),
+            mono: args::flag(all_args, "--mono"),
+            send_rate: args::parsed(all_args, "--send-rate")
+                .filter(|&hz| hz > 0)
+                .map(|hz: u32| {
+                    hz.clamp(
+                        *Handshake::SAMPLE_RATES.start(),
+                        *Handshake::SAMPLE_RATES.end(),
+                    )
+                }),
+            max_clients: args::parsed(all_args, "--max-clients"),
+            join_sounds: args::flag(all_args, "--join-sounds"),
+            pan: args::flag(all_args, "--pan"),
+            auto_port: args::flag(all_args, "--auto-port"),
+            max_latency: args::parsed(all_args, "--max-latency")
+                .filter(|&ms: &u64| ms > 0)
+                .map_or(DEFAULT_MAX_LATENCY, Duration::from_millis),
+            jitter_target: args::parsed(all_args, "--jitter-target").map(Duration::from_millis),
+            timeout: args::parsed(all_args, "--timeout")
+                .map_or(PEER_TIMEOUT, Duration::from_secs)
+                .max(MIN_PEER_TIMEOUT),
+            connect_timeout: args::parsed(all_args, "--connect-timeout").map(Duration::from_secs),
+            rtp_port: args::parsed(all_args, "--rtp-port").unwrap_or(DEFAULT_RTP_PORT),
+            wire_format: match args::value(all_args, "--wire-format") {
+                Some(name) => WireFormat::from_name(name).unwrap_or_else(|| {
+                    warn!("Unknown wire format '{name}', expected 'f32' or 'i16'");
+                    WireFormat::default()
+                }),
+                None => WireFormat::default(),
+            },
+            max_bitrate: args::parsed::<f64>(all_args, "--max-bitrate")
+                .filter(|&kbps| kbps > 0.0)
+                .map(|kbps| kbps * 1000.0),
+            frame_samples: args::parsed(all_args, "--frame-samples").filter(|&n| n > 0),
+            room: args::value(all_args, "--room")
+                .map(String::from)
+                .unwrap_or(String::from(DEFAULT_ROOM)),
+            name: args::value(all_args, "--name").map_or(String::new(), clean_name),
+            buffer_frames: args::parsed(all_args, "--buffer-frames").filter(|&n| n > 0),
+            input_format: stream_format("--input-format"),
+            output_format: stream_format("--output-format"),
+            best_input: args::flag(all_args, "--best-input"),
+            best_output: args::flag(all_args, "--best-output"),
+            low_latency: args::flag(all_args, "--low-latency"),
+            loopback_input: args::flag(all_args, "--loopback-input"),
+            strict_config: args::flag(all_args, "--strict-config"),
+            tcp_nodelay: !args::flag(all_args, "--no-tcp-nodelay"),
+            tcp_send_buffer: args::parsed(all_args, "--tcp-send-buffer").filter(|&n| n > 0),
+            tcp_recv_buffer: args::parsed(all_args, "--tcp-recv-buffer").filter(|&n| n > 0),
+            mic_gain: args::parsed(all_args, "--mic-gain")
+                .or(config.mic_gain)
+                .unwrap_or(DEFAULT_MIC_GAIN),
+            playback_volume: args::parsed(all_args, "--volume")
+                .or(config.volume)
+                .unwrap_or(DEFAULT_PLAYBACK_VOLUME),
+            channel_gains: args::list(all_args, "--channel-gains").unwrap_or_default(),
+            limiter: if args::flag(all_args, "--soft-limit") {
+                Limiter::Soft
+            } else {
+                Limiter::Clamp
+            },
+            limiter_threshold: args::parsed(all_args, "--limiter-threshold")
+                .filter(|threshold| (0.0..=1.0).contains(threshold) && *threshold > 0.0)
+                .unwrap_or(DEFAULT_LIMITER_THRESHOLD),
+            limiter_release: args::parsed(all_args, "--limiter-release")
+                .filter(|&ms: &f32| ms > 0.0)
+                .map_or(DEFAULT_LIMITER_RELEASE, |ms| ms / 1000.0),
+            raw,
+            noise_gate: !raw
+                && !args::flag(all_args, "--no-noise-gate")
+                && config.noise_gate.unwrap_or(true),
+            gate: GateSettings {
+                open: args::parsed(all_args, "--gate-open")
+                    .filter(|level| (0.0..=1.0).contains(level))
+                    .unwrap_or(DEFAULT_GATE_OPEN),
+                close: args::parsed(all_args, "--gate-close")
+                    .filter(|level| (0.0..=1.0).contains(level))
+                    .unwrap_or(DEFAULT_GATE_CLOSE),
+                attack: args::parsed(all_args, "--gate-attack")
+                    .filter(|&ms: &f32| ms >= 0.0)
+                    .map_or(DEFAULT_GATE_ATTACK, |ms| ms / 1000.0),
+                release: args::parsed(all_args, "--gate-release")
+                    .filter(|&ms: &f32| ms >= 0.0)
+                    .map_or(DEFAULT_GATE_RELEASE, |ms| ms / 1000.0),
+            },
+            vad: (!raw && args::flag(all_args, "--vad")).then(|| {
+                args::parsed(all_args, "--vad-aggressiveness")
+                    .filter(|&level| level <= 3)
+                    .unwrap_or(DEFAULT_VAD_AGGRESSIVENESS)
+            }),
+            dither: !args::flag(all_args, "--no-dither"),
+            agc: !raw && args::flag(all_args, "--agc"),
+            agc_target: args::parsed(all_args, "--agc-target")
+                .filter(|target| (0.0..=1.0).contains(target))
+                .unwrap_or(DEFAULT_AGC_TARGET),
+            comfort_noise: args::flag(all_args, "--comfort-noise").then(|| {
+                args::parsed(all_args, "--comfort-noise-level")
+                    .filter(|level| (0.0..=1.0).contains(level))
+                    .unwrap_or(DEFAULT_COMFORT_NOISE_LEVEL)
+            }),
+            half_duplex: args::flag(all_args, "--half-duplex"),
+            aec: cfg!(feature = "aec") && !raw && args::flag(all_args, "--aec"),
+            sidetone: args::parsed(all_args, "--sidetone")
+                .filter(|level: &f32| level.is_finite())
+                .map_or(0.0, |level| level.clamp(0.0, 1.0)),
+            monitor: args::flag(all_args, "--monitor"),
+            monitor_level: args::parsed(all_args, "--monitor-level")
+                .filter(|level: &f32| level.is_finite())
+                .map_or(DEFAULT_MONITOR_LEVEL, |level| level.clamp(0.0, 1.0)),
+            duration: args::parsed(all_args, "--duration")
+                .filter(|&secs: &f64| secs > 0.0)
+                .map(Duration::from_secs_f64),
+            allow_loopback: args::flag(all_args, "--allow-loopback"),
+        })
     }
 }
 
-const VOLUME: f32 = 1.0;
+const DEFAULT_MIC_GAIN: f32 = 1.0;
+const DEFAULT_MONITOR_LEVEL: f32 = 1.0;
+/// Room of clients that don't pick one.
+const DEFAULT_ROOM: &str = "default";
+const DEFAULT_PLAYBACK_VOLUME: f32 = 1.0;
+const DEFAULT_SAMPLE_RATE: u32 = 22050;
+/// Length and level of the clicks played by `--measure-latency`.
+const LATENCY_IMPULSE_TIME: f32 = 0.001;
+const LATENCY_IMPULSE_LEVEL: f32 = 0.5;
+/// How long `--measure-latency` listens to the noise before the first click, how many clicks
+/// it plays and how long it waits for each of them to arrive (and its echo to die down).
+const LATENCY_CALIBRATION_TIME: Duration = Duration::from_secs(1);
+const LATENCY_CLICKS: u32 = 5;
+const LATENCY_CLICK_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long `--calibrate` listens each time, how much of it has to be speech, and how often it
+/// asks you to speak before giving up.
+const CALIBRATION_TIME: Duration = Duration::from_secs(3);
+const CALIBRATION_MIN_SPEECH: f32 = 0.5;
+const CALIBRATION_ATTEMPTS: u32 = 3;
+/// Captured buffers peaking above this count as speech for `--calibrate`.
+const CALIBRATION_SPEECH_LEVEL: f32 = 0.02;
+/// Range of the gain `--calibrate` picks.
+const CALIBRATION_MIN_GAIN: f32 = 0.1;
+const CALIBRATION_MAX_GAIN: f32 = 10.0;
+/// How picky `--vad` is if `--vad-aggressiveness` isn't given.
+const DEFAULT_VAD_AGGRESSIVENESS: u8 = 1;
+/// Peak level of the comfort noise, if none is given (about -60 dBFS).
+const DEFAULT_COMFORT_NOISE_LEVEL: f32 = 0.001;
+/// Level above which the peaks of received audio are turned down, if none is given (-1 dBFS).
+const DEFAULT_LIMITER_THRESHOLD: f32 = 0.89;
+/// How long the output limiter takes to recover after a peak, if none is given.
+const DEFAULT_LIMITER_RELEASE: f32 = 0.1;
+/// RMS level the AGC aims for, if none is given.
+const DEFAULT_AGC_TARGET: f32 = 0.1;
+const DEFAULT_MAX_LATENCY: Duration = Duration::from_secs(3);
+/// How many peer timeouts a client waits for the server to come up, if `--connect-timeout`
+/// isn't given: 30 seconds by default.
+const CONNECT_TIMEOUT_FACTOR: u32 = 3;
+/// Pause between two attempts to connect to a server that isn't up yet.
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Audio kept beyond the frame that just arrived when playback skips ahead.
+const LATENCY_MARGIN: Duration = Duration::from_millis(100);
+/// Noise gate thresholds (about -40 and -46 dBFS) and fade times in seconds.
+const DEFAULT_GATE_OPEN: f32 = 0.01;
+const DEFAULT_GATE_CLOSE: f32 = 0.005;
+const DEFAULT_GATE_ATTACK: f32 = 0.005;
+const DEFAULT_GATE_RELEASE: f32 = 0.15;
 const SLEEP_DURATION: std::time::Duration = std::time::Duration::from_secs(1);
+/// How often a call sends what was captured, so audio frames are about this long.
+const SEND_INTERVAL: Duration = Duration::from_millis(20);
+/// Silence held back during a pause goes out at least this often. Below [`HEARTBEAT_INTERVAL`],
+/// so a pause keeps the connection alive by itself.
+const MAX_SILENCE_RUN: Duration = Duration::from_secs(1);
+/// Seconds of captured audio the send loop can fall behind by before samples are dropped.
+const CAPTURE_QUEUE_TIME: u32 = 4;
+/// Frames that may wait to be written. Audio that doesn't fit is dropped, other frames wait.
+const SEND_QUEUE_LEN: usize = 64;
+/// How long the sending side may stay quiet before it sends an empty heartbeat frame.
+const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+/// How long without any frame (audio or heartbeat) until the peer is considered gone, if
+/// `--timeout` isn't given.
+const PEER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// Shorter timeouts would drop a quiet peer between two heartbeats.
+const MIN_PEER_TIMEOUT: std::time::Duration =
+    std::time::Duration::from_secs(2 * HEARTBEAT_INTERVAL.as_secs());
+/// Time over which the effective send rate is averaged.
+const BITRATE_WINDOW: std::time::Duration = std::time::Duration::from_secs(5);
+/// The usual RTP port (RFC 3551), where `--rtp` receives unless `--rtp-port` says otherwise.
+const DEFAULT_RTP_PORT: u16 = 5004;
+/// Audio per RTP packet, the usual packet time for voice.
+#[cfg(feature = "rtp")]
+const RTP_PACKET_TIME: Duration = Duration::from_millis(20);
+/// How often `--rtp` sends what was captured and plays what arrived.
+#[cfg(feature = "rtp")]
+const RTP_POLL_INTERVAL: Duration = Duration::from_millis(5);
+/// How often the mute state is repeated, in case the peer missed a change.
+const MUTE_ANNOUNCE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+/// How often the round-trip time to the peer is measured.
+const PING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+/// Played audio louder than this counts as the peer speaking in half-duplex mode. Above the
+/// comfort noise, so that doesn't keep the mic muted.
+const REMOTE_SPEAKING_LEVEL: f32 = 0.01;
+/// Ports after the given one that `--auto-port` tries before letting the system pick one.
+const AUTO_PORT_ATTEMPTS: u16 = 10;
+/// Pitch and level of the test tone, loud enough to hear but not startling.
+const TEST_TONE_FREQUENCY: f32 = 440.0;
+const TEST_TONE_LEVEL: f32 = 0.25;
+
+/// What woke up the chat loop.
+enum Wake {
+    Tick,
+    /// Time to send the audio captured since the last frame.
+    Send,
+    Readable,
+    Shutdown,
+    Command(Command),
+}
+
+/// Hook that gets to see audio frames, see [`Client::on_input_frame`].
+type FrameHook = Arc<dyn Fn(&[f32]) + Send + Sync>;
+type TextHook = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Runs `hook` after `first`, if there is one.
+fn chain_hooks(
+    first: Option<FrameHook>,
+    hook: impl Fn(&[f32]) + Send + Sync + 'static,
+) -> FrameHook {
+    match first {
+        Some(first) => Arc::new(move |frame: &[f32]| {
+            first(frame);
+            hook(frame);
+        }),
+        None => Arc::new(hook),
+    }
+}
+
+/// The mic gain `--calibrate` picks for a voice measured at `rms` and peaking at `peak`: the
+/// one that brings it to the AGC's target level, unless that would push the peaks into the
+/// limiter.
+fn calibrated_gain(rms: f32, peak: f32) -> f32 {
+    (DEFAULT_AGC_TARGET / rms)
+        .min(DEFAULT_LIMITER_THRESHOLD / peak.max(f32::EPSILON))
+        .clamp(CALIBRATION_MIN_GAIN, CALIBRATION_MAX_GAIN)
+}
+
+/// Tells the peer that we hang up on purpose. A peer that is gone already doesn't matter, so
+/// failing to send is fine.
+async fn say_bye(outgoing: &Sender<Vec<u8>>, writer: &mut FrameWriter) {
+    writer.build(|frame| frame.push(FrameType::Bye as u8));
+    if let Err(e) = writer.queue(outgoing).await {
+        debug!("Failed to say bye: {e}");
+    }
+}
+
+/// Processing applied to captured audio before it is sent or played.
+/// Voice activity detection, the noise gate, then AGC (or normalizing), then the app's
+/// processors, then the gain. None of it with `--raw`.
+struct InputProcessing {
+    raw: bool,
+    sample_rate: u32,
+    levelling: Vec<Box<dyn AudioProcessor + Send>>,
+    custom: ProcessorChain,
+    gain: Gain,
+}
+
+impl InputProcessing {
+    fn new(opt: &Opt, custom: &ProcessorChain, sample_rate: u32, channels: usize) -> Self {
+        if opt.raw {
+            info!("Processing is off (--raw): the mic is sent as captured");
+        }
+        let mut levelling: Vec<Box<dyn AudioProcessor + Send>> = Vec::new();
+        if let Some(aggressiveness) = opt.vad {
+            levelling.push(Box::new(VoiceDetector::new(
+                aggressiveness,
+                sample_rate,
+                channels,
+            )));
+        }
+        if opt.noise_gate {
+            levelling.push(Box::new(NoiseGate::new(opt.gate, sample_rate, channels)));
+        }
+        if opt.agc {
+            levelling.push(Box::new(Agc::new(opt.agc_target, sample_rate)));
+        } else {
+            levelling.push(Box::new(Normalize));
+        }
+        InputProcessing {
+            raw: opt.raw,
+            sample_rate,
+            levelling,
+            custom: custom.clone(),
+            gain: Gain {
+                gain: opt.mic_gain,
+                limiter: opt.limiter,
+            },
+        }
+    }
+
+    fn process(&mut self, data: &[f32]) -> Vec<f32> {
+        let mut processed = data.to_vec();
+        if self.raw {
+            return processed;
+        }
+        for processor in &mut self.levelling {
+            processor.process(&mut processed, self.sample_rate);
+        }
+        self.custom.process(&mut processed, self.sample_rate);
+        self.gain.process(&mut processed, self.sample_rate);
+        processed
+    }
+}
 
 pub struct Client {
     pub address: String,
-    input_device: Device,
+    input: InputSource,
     input_config: StreamConfig,
-    output_device: Device,
+    output: OutputSink,
     output_config: StreamConfig,
+    opt: Opt,
+    /// Random value sent in the handshake, to recognize a connection to ourselves.
+    nonce: u64,
+    /// Random value sent in the handshake, for a mixer to recognize us when we reconnect.
+    session: u64,
+    /// Ends the call when a message arrives or all senders are dropped.
+    shutdown: Option<Receiver<()>>,
+    /// Commands from [`Controls`], handled by the running call.
+    commands: (Sender<Command>, Receiver<Command>),
+    /// Plays silence instead of the peer's audio while set.
+    deafened: Arc<AtomicBool>,
+    /// Sends silence instead of the mic while set.
+    muted: Arc<AtomicBool>,
+    /// Plays the mic locally while set.
+    monitoring: Arc<AtomicBool>,
+    /// Set while the peer says its mic is muted.
+    peer_muted: Arc<AtomicBool>,
+    /// The name in the peer's handshake, empty if it has none.
+    peer_name: Arc<Mutex<String>>,
+    channel_gains: ChannelGains,
+    input_hook: Option<FrameHook>,
+    output_hook: Option<FrameHook>,
+    text_hook: Option<TextHook>,
+    input_processors: ProcessorChain,
+    output_processors: ProcessorChain,
+    state: SharedState,
+    stats: Arc<Stats>,
+    bound_addr: Option<SocketAddr>,
 }
 
 impl Client {
-    pub fn new(address: String) -> Result<Self, Box<dyn Error>> {
-        let opt = Opt::new();
-        let audio_host = get_audio_host(&opt);
-        let input_device = get_input_device(&audio_host, &opt)?;
-        let input_config = get_input_config(&input_device);
-        let output_device = get_output_device(&audio_host, &opt)?;
-        let output_config = get_output_config(&output_device);
-
-        Ok(Client {
+    pub fn new(address: String) -> Result<Self, VoiceChatError> {
+        let mut opt = Opt::new()?;
+        let audio_host = get_audio_host(&opt)?;
+        let (input, input_config) = match &opt.input_file {
+            Some(path) => {
+                let file = FileSource::open(path, opt.sample_rate)?;
+                let config = file.config();
+                (InputSource::File(file), config)
+            }
+            None => {
+                // A device given on the command line is kept, only its config is picked.
+                let best_input =
+                    opt.best_input && (opt.input_remembered || opt.input_device == "default");
+                let device = if best_input {
+                    util::best_device(
+                        util::input_devices(&audio_host, opt.loopback_input)?,
+                        audio_host
+                            .default_input_device()
+                            .and_then(|d| d.name().ok()),
+                        "input",
+                        |device| Ok(device.supported_input_configs()?.collect()),
+                    )?
+                } else {
+                    match get_input_device(&audio_host, &opt.input_device, opt.loopback_input) {
+                        Err(VoiceChatError::DeviceNotFound { name, .. })
+                            if opt.input_remembered =>
+                        {
+                            warn!("The last used input device '{name}' is gone, using the default");
+                            opt.input_device = String::from("default");
+                            get_input_device(&audio_host, &opt.input_device, opt.loopback_input)?
+                        }
+                        device => device?,
+                    }
+                };
+                // Remembered by name, as the numbers change when devices come and go.
+                if best_input || opt.input_device.parse::<usize>().is_ok() {
+                    opt.input_device = device.name()?;
+                }
+                let (config, format) = get_input_config(&device, &opt)?;
+                (InputSource::Device(device, format), config)
+            }
+        };
+        let best_output =
+            opt.best_output && (opt.output_remembered || opt.output_device == "default");
+        let output_device = if best_output {
+            util::best_device(
+                audio_host.output_devices()?,
+                audio_host
+                    .default_output_device()
+                    .and_then(|d| d.name().ok()),
+                "output",
+                |device| Ok(device.supported_output_configs()?.collect()),
+            )?
+        } else {
+            match get_output_device(&audio_host, &opt.output_device) {
+                Err(VoiceChatError::DeviceNotFound { name, .. }) if opt.output_remembered => {
+                    warn!("The last used output device '{name}' is gone, using the default");
+                    opt.output_device = String::from("default");
+                    get_output_device(&audio_host, &opt.output_device)?
+                }
+                device => device?,
+            }
+        };
+        if best_output || opt.output_device.parse::<usize>().is_ok() {
+            opt.output_device = output_device.name()?;
+        }
+        let (output_config, output_format) = get_output_config(&output_device, &opt)?;
+        let mismatches = util::config_mismatches(&input_config, &output_config);
+        if opt.strict_config && !mismatches.is_empty() {
+            return Err(VoiceChatError::UnsupportedConfig(mismatches.join(" ")));
+        }
+        for mismatch in &mismatches {
+            warn!("{mismatch}");
+        }
+        DeviceState::remember(
+            opt.input_file
+                .is_none()
+                .then_some(opt.input_device.as_str()),
+            &opt.output_device,
+        );
+
+        Ok(Client::with_audio(
             address,
-            input_device,
+            opt,
+            (input, input_config),
+            (
+                OutputSink::Device(output_device, output_format),
+                output_config,
+            ),
+        ))
+    }
+
+    /// Creates a client without audio devices: the app pushes the captured audio and pulls the
+    /// played audio itself, through [`Client::headless_audio`]. Everything else, from the
+    /// processing to the network, works as with devices.
+    ///
+    /// Real-time pacing becomes the caller's responsibility, see [`HeadlessAudio`].
+    pub fn new_headless(address: String) -> Result<Self, VoiceChatError> {
+        Client::new_headless_with(address, Opt::new()?)
+    }
+
+    /// [`Client::new_headless`] with the options given in code, see [`Opt::from_args`], rather
+    /// than taken from the command line.
+    pub fn new_headless_with(address: String, opt: Opt) -> Result<Self, VoiceChatError> {
+        let config = StreamConfig {
+            channels: opt.channels.unwrap_or(1),
+            sample_rate: cpal::SampleRate(opt.sample_rate),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        info!(
+            "Headless: {} Hz, {} channel(s)",
+            config.sample_rate.0, config.channels
+        );
+        let audio = HeadlessAudio::default();
+        Ok(Client::with_audio(
+            address,
+            opt,
+            (InputSource::Headless(audio.clone()), config.clone()),
+            (OutputSink::Headless(audio), config),
+        ))
+    }
+
+    fn with_audio(
+        address: String,
+        opt: Opt,
+        (input, input_config): (InputSource, StreamConfig),
+        (output, output_config): (OutputSink, StreamConfig),
+    ) -> Self {
+        let channel_gains = ChannelGains::default();
+        channel_gains.set(&opt.channel_gains);
+        let monitoring = Arc::new(AtomicBool::new(opt.monitor));
+        Client {
+            address,
+            input,
             input_config,
-            output_device,
+            output,
             output_config,
+            opt,
+            nonce: RandomState::new().build_hasher().finish(),
+            // Never 0, which means no session.
+            session: RandomState::new().build_hasher().finish().max(1),
+            shutdown: None,
+            commands: channel::unbounded(),
+            deafened: Arc::new(AtomicBool::new(false)),
+            muted: Arc::new(AtomicBool::new(false)),
+            monitoring,
+            peer_muted: Arc::new(AtomicBool::new(false)),
+            peer_name: Arc::new(Mutex::new(String::new())),
+            channel_gains,
+            input_hook: None,
+            output_hook: None,
+            text_hook: None,
+            input_processors: ProcessorChain::default(),
+            output_processors: ProcessorChain::default(),
+            state: SharedState::default(),
+            stats: Arc::new(Stats::default()),
+            bound_addr: None,
+        }
+    }
+
+    /// The handle to feed and drain the audio of a client made by [`Client::new_headless`].
+    /// `None` for clients with devices.
+    pub fn headless_audio(&self) -> Option<HeadlessAudio> {
+        match &self.input {
+            InputSource::Headless(audio) => Some(audio.clone()),
+            _ => None,
+        }
+    }
+
+    /// The address `listen` actually bound to, e.g. to find out which port the OS picked for
+    /// port `0`. `None` until listening has started.
+    pub fn bound_addr(&self) -> Option<SocketAddr> {
+        self.bound_addr
+    }
+
+    /// The latest round-trip time to the peer, see [`Stats::rtt`].
+    pub fn rtt(&self) -> Option<Duration> {
+        self.stats.rtt()
+    }
+
+    /// Bytes of all frames sent, over all calls of this client. Like the other counters, it
+    /// only goes up and starts at 0 for every new `Client`.
+    pub fn bytes_sent(&self) -> u64 {
+        self.stats.bytes_sent()
+    }
+
+    /// Bytes of all intact frames received, over all calls of this client.
+    pub fn bytes_received(&self) -> u64 {
+        self.stats.bytes_received()
+    }
+
+    /// Frames of any type sent, over all calls of this client.
+    pub fn frames_sent(&self) -> u64 {
+        self.stats.frames_sent()
+    }
+
+    /// Intact frames of any type received, over all calls of this client.
+    pub fn frames_received(&self) -> u64 {
+        self.stats.frames_received()
+    }
+
+    /// Counters about the current and past calls of this client.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Calls `hook` with every processed frame of captured audio, e.g. for a VU meter. Hooks
+    /// that were added before keep running, first.
+    ///
+    /// The hook runs on the audio thread, so it must be cheap and must not block.
+    pub fn on_input_frame(&mut self, hook: impl Fn(&[f32]) + Send + Sync + 'static) {
+        self.input_hook = Some(chain_hooks(self.input_hook.take(), hook));
+    }
+
+    /// Calls `hook` with every frame of audio handed to the output device. Hooks that were
+    /// added before keep running, first.
+    ///
+    /// The hook runs on the audio thread, so it must be cheap and must not block.
+    pub fn on_output_frame(&mut self, hook: impl Fn(&[f32]) + Send + Sync + 'static) {
+        self.output_hook = Some(chain_hooks(self.output_hook.take(), hook));
+    }
+
+    /// Adds a step to the processing of captured audio, e.g. an equalizer. Processors run in the
+    /// order they were added, after the noise gate and AGC but before `--mic-gain` and the
+    /// limiter, and are kept for every later call.
+    pub fn add_input_processor(&mut self, processor: impl AudioProcessor + Send + 'static) {
+        self.input_processors.push(Box::new(processor));
+    }
+
+    /// Adds a step to the processing of the peer's audio, run in the order they were added on
+    /// every frame as it arrives (resampled to the output device), before the peak limiter.
+    pub fn add_output_processor(&mut self, processor: impl AudioProcessor + Send + 'static) {
+        self.output_processors.push(Box::new(processor));
+    }
+
+    /// Registers a callback for text messages from the peer. Without one, they are logged.
+    pub fn on_text(&mut self, hook: impl Fn(&str) + Send + Sync + 'static) {
+        self.text_hook = Some(Arc::new(hook));
+    }
+
+    /// Lets an embedding app end the call: once `()` is sent on the channel (or every sender
+    /// is dropped), `listen`/`connect` stop the audio streams and return `Ok(())`. `listen`
+    /// also stops waiting for a connection, and `loopback`, `test_tone` and `rtp` stop too.
+    /// Closing the channel stops every one of them for good, sending `()` only the one that
+    /// takes it.
+    pub fn set_shutdown(&mut self, shutdown: Receiver<()>) {
+        self.shutdown = Some(shutdown);
+    }
+
+    /// How long the peer may go without sending anything (audio or a heartbeat) before the call
+    /// ends with [`VoiceChatError::PeerTimedOut`]. `connect` also waits three times as long for
+    /// the server to come up, unless `--connect-timeout` says otherwise. Set with `--timeout`
+    /// (default: 10 seconds).
+    pub fn timeout(&self) -> Duration {
+        self.opt.timeout
+    }
+
+    /// Changes [`Client::timeout`] for the calls that start after this, at least 4 seconds (two
+    /// heartbeats).
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.opt.timeout = timeout.max(MIN_PEER_TIMEOUT);
+    }
+
+    /// A handle to change the call while it is running, e.g. from a UI thread.
+    pub fn controls(&self) -> Controls {
+        Controls {
+            commands: self.commands.0.clone(),
+            deafened: self.deafened.clone(),
+            muted: self.muted.clone(),
+            monitoring: self.monitoring.clone(),
+            peer_muted: self.peer_muted.clone(),
+            peer_name: self.peer_name.clone(),
+            channel_gains: self.channel_gains.clone(),
+            state: self.state.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// Sets how loud each output channel plays, first channel first, e.g. `&[1.0, 0.5]` for
+    /// the right ear at half the volume of the left. Each gain is clamped to 0.0 to 4.0, a
+    /// single gain applies to all channels (as on a mono device), and channels without a gain
+    /// play at 1.0. Takes effect at once, also during a call.
+    pub fn set_channel_gains(&self, gains: &[f32]) {
+        self.channel_gains.set(gains);
+    }
+
+    /// Where the client is in a call. While `listen`, `connect` or `call` is running, the state
+    /// can be polled through [`Client::controls`] from another thread.
+    pub fn state(&self) -> SessionState {
+        self.state.get()
+    }
+
+    /// How long the current call has been connected (counted from the handshake), or else how
+    /// long the last call lasted. Waiting for or reconnecting to a peer doesn't count.
+    pub fn call_duration(&self) -> Duration {
+        self.state.call_duration()
+    }
+
+    /// Stops (or resumes) playing the peer's audio, see [`Controls::set_deafened`].
+    pub fn set_deafened(&self, deafened: bool) {
+        self.controls().set_deafened(deafened);
+    }
+
+    /// Toggles deafening and returns whether playback is now deafened.
+    pub fn toggle_deafen(&self) -> bool {
+        self.controls().toggle_deafen()
+    }
+
+    /// Stops (or resumes) sending your mic, see [`Controls::set_muted`].
+    pub fn set_muted(&self, muted: bool) {
+        self.controls().set_muted(muted);
+    }
+
+    /// Toggles muting and returns whether the mic is now muted.
+    pub fn toggle_mute(&self) -> bool {
+        self.controls().toggle_mute()
+    }
+
+    /// Starts (or stops) playing your mic locally, see [`Controls::set_monitoring`].
+    pub fn set_monitoring(&self, monitoring: bool) {
+        self.controls().set_monitoring(monitoring);
+    }
+
+    /// Toggles monitoring and returns whether your mic is now played locally.
+    pub fn toggle_monitor(&self) -> bool {
+        self.controls().toggle_monitor()
+    }
+
+    /// Whether the peer muted its mic, as it last told us. Peers of older releases, and
+    /// peers through a mixer, never say so. Reset at the start of every call.
+    pub fn peer_muted(&self) -> bool {
+        self.peer_muted.load(Ordering::Relaxed)
+    }
+
+    /// The name the peer of the current (or last) call gave with `--name`, see
+    /// [`Controls::peer_name`].
+    pub fn peer_name(&self) -> Option<String> {
+        self.controls().peer_name()
+    }
+
+    /// Ends the current call, see [`Controls::hang_up`]. As a call holds on to the client,
+    /// it is usually ended through [`Client::controls`] from elsewhere.
+    pub fn hang_up(&self) {
+        self.controls().hang_up();
+    }
+
+    /// Switches to the named output device (or `"default"`) for the following calls. If the
+    /// device can't be found, the current one is kept and the error is returned. To switch
+    /// during a call, use [`Controls::set_output_device`].
+    pub fn set_output_device(&mut self, name: &str) -> Result<(), VoiceChatError> {
+        let (device, config, format) = self.find_output_device(name)?;
+        self.output = OutputSink::Device(device, format);
+        self.output_config = config;
+        Ok(())
+    }
+
+    fn find_output_device(
+        &self,
+        name: &str,
+    ) -> Result<(Device, StreamConfig, SampleFormat), VoiceChatError> {
+        let device = get_output_device(&get_audio_host(&self.opt)?, name)?;
+        let (config, format) = get_output_config(&device, &self.opt)?;
+        Ok((device, config, format))
+    }
+
+    /// Whether the shutdown channel asked to stop, for loops that don't wait on it.
+    fn shutdown_requested(&self) -> bool {
+        self.shutdown.as_ref().is_some_and(|shutdown| {
+            !matches!(shutdown.try_recv(), Err(channel::TryRecvError::Empty))
         })
     }
 
-    async fn chat(&mut self, mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
-        println!("Entering chat...\n");
-        stream.set_nonblocking(true)?;
+    /// Tells the running call that a device is gone, see [`Command::InputLost`].
+    fn on_lost(&self, command: Command) -> impl FnMut() + Send + 'static {
+        let commands = self.commands.0.clone();
+        move || {
+            let _ = commands.try_send(command.clone());
+        }
+    }
 
-        let buffer_size = (4
-            * SLEEP_DURATION.as_secs() as u32
-            * self.output_config.sample_rate.0
-            * self.output_config.channels as u32) as usize;
+    /// Number of channels of the audio that is sent.
+    fn send_channels(&self) -> u16 {
+        if self.opt.mono {
+            1
+        } else {
+            self.input_config.channels
+        }
+    }
 
-        loop {
-            let mut buffer: Vec<u8> = vec![0; buffer_size];
-            if stream.read_exact(&mut buffer).is_ok() {
-                // println!("Received bytes!");
+    /// Sample rate of the audio that is sent. `--send-rate` only ever lowers it, sending more
+    /// samples than were captured wouldn't add anything.
+    fn send_rate(&self) -> u32 {
+        let input_rate = self.input_config.sample_rate.0;
+        self.opt
+            .send_rate
+            .map_or(input_rate, |rate| rate.min(input_rate))
+    }
+
+    /// Converts the processed, remixed mic audio to [`send_rate`](Client::send_rate), if that
+    /// is lower than the input's.
+    fn send_resampler(&self) -> Option<Resampler> {
+        let input_rate = self.input_config.sample_rate.0;
+        let send_rate = self.send_rate();
+        (send_rate != input_rate).then(|| {
+            info!("Downsampling the mic from {input_rate} Hz to {send_rate} Hz before sending");
+            Resampler::new(input_rate, send_rate, self.send_channels() as usize)
+        })
+    }
+
+    /// Runs a call over an already established connection, until it is hung up, and returns
+    /// which side did.
+    ///
+    /// Like [`listen`](Client::listen), [`connect`](Client::connect) and [`call`](Client::call),
+    /// this doesn't need a particular executor: the socket is driven by its own reactor thread,
+    /// so the future can be awaited from `smol`, `tokio` or anything else.
+    pub async fn chat(&mut self, stream: TcpStream) -> Result<CallEnd, VoiceChatError> {
+        // Audio goes out in small frames, every `SEND_INTERVAL`. With Nagle's algorithm, a frame
+        // can wait for the peer's ACK of the previous one (up to 40 ms with delayed ACKs).
+        let options = socket::configure(
+            &stream,
+            self.opt.tcp_nodelay,
+            self.opt.tcp_send_buffer,
+            self.opt.tcp_recv_buffer,
+        )?;
+        info!("Socket options: {options}");
+        self.stats.set_socket_options(options);
+        // Waiting for the socket to become readable lets pings be answered right away.
+        self.chat_over(&Async::new(stream)?).await
+    }
+
+    /// Runs a call over any [`Transport`], e.g. a [`MemoryTransport`](transport::MemoryTransport)
+    /// to another client in the same process, until it is hung up, and returns which side did.
+    /// [`chat`](Client::chat) is this over TCP.
+    ///
+    /// The mic and the speakers are served on the audio devices' own threads, through bounded
+    /// queues. Reading and writing `stream` happen in this future, not on a thread of their own
+    /// (the transport is only borrowed), but frames are written from a queue next to the rest
+    /// of the call, so a slow write holds up neither receiving nor taking captured audio.
+    pub async fn chat_over(&mut self, stream: &impl Transport) -> Result<CallEnd, VoiceChatError> {
+        info!("Entering chat...");
+        let _disconnect = self.state.disconnect_on_drop();
+        // Exchange sample rates and channel counts so the receiving side knows what it gets, and
+        // nonces to detect a connection that leads back to this very instance.
+        let handshake = Handshake {
+            sample_rate: self.send_rate(),
+            channels: self.send_channels(),
+            format: self.opt.wire_format,
+            byte_order: ByteOrder::Little,
+            nonce: self.nonce,
+            room: self.opt.room.clone(),
+            session: self.session,
+            monitor: false,
+            name: self.opt.name.clone(),
+        };
+        transport::write_all(stream, &handshake.encode()).await?;
+        let receive_error = |e: std::io::Error| {
+            VoiceChatError::Handshake(format!("Failed to receive the peer's handshake: {e}"))
+        };
+        let mut prefix = [0u8; Handshake::PREFIX_SIZE];
+        transport::read_exact(stream, &mut prefix)
+            .await
+            .map_err(receive_error)?;
+        let mut body = vec![0; Handshake::decode_prefix(prefix)?];
+        transport::read_exact(stream, &mut body)
+            .await
+            .map_err(receive_error)?;
+        let peer = Handshake::decode(&body)?;
+        if peer.monitor {
+            return Err(VoiceChatError::Handshake(String::from(
+                "Got a status query, which only a mixer answers",
+            )));
+        }
+        let peer_rate = peer.sample_rate;
+        let peer_channels = peer.channels as usize;
+        let peer_nonce = peer.nonce;
+        let peer_format = peer.format;
+        if let Ok(mut name) = self.peer_name.lock() {
+            name.clone_from(&peer.name);
+        }
+        // Used where the peer is named, e.g. `[Alice] hi` for a text message.
+        let peer_label = match peer.name.as_str() {
+            "" => "peer",
+            name => {
+                info!("Talking to {name}");
+                name
+            }
+        };
+        if peer.room != self.opt.room {
+            warn!(
+                "The peer uses room '{}' and we use '{}', but rooms only matter through a mixer",
+                peer.room, self.opt.room
+            );
+        }
+
+        if peer_nonce == self.nonce || stream.is_loopback()? {
+            if !self.opt.allow_loopback {
+                return Err(VoiceChatError::Handshake(String::from(
+                    "Connected to yourself! Pass --allow-loopback if this is intended.",
+                )));
             }
+            warn!("Connected to yourself, expect feedback loops and echoes.");
+        }
+        self.state.set(SessionState::Connected);
 
-            let audio_data = buffer_to_audio_data(&buffer);
+        // Both sides as they go over the wire, so the two files can be compared directly.
+        let mut dump = match &self.opt.dump_samples {
+            Some(path) => Some(SampleDump::create(
+                path,
+                (self.send_rate(), self.send_channels() as usize),
+                (peer_rate, peer_channels),
+            )?),
+            None => None,
+        };
+        let mut recording = match &self.opt.record_split {
+            Some(path) => Some(SplitRecording::create(
+                path,
+                (self.send_rate(), self.send_channels() as usize),
+                (peer_rate, peer_channels),
+            )?),
+            None => None,
+        };
 
-            // Collect output audio
-            let mut i: usize = 0;
-            let output_data_fn = move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                for sample in data {
-                    *sample = *audio_data.get(i).unwrap_or(&0.0);
-                    i += 1;
+        let mut output_rate = self.output_config.sample_rate.0;
+        if peer_rate != output_rate {
+            info!("{}", util::rate_mismatch(peer_rate, output_rate));
+        }
+        let mut output_channels = self.output_config.channels as usize;
+        if peer_channels != output_channels {
+            info!("Mixing incoming audio from {peer_channels} to {output_channels} channel(s)");
+        }
+        let mut resampler = Resampler::new(peer_rate, output_rate, output_channels);
+        let (limiter_threshold, limiter_release) =
+            (self.opt.limiter_threshold, self.opt.limiter_release);
+        let output_limiter = move |rate, channels| {
+            PeakLimiter::new(limiter_threshold, limiter_release, rate, channels)
+        };
+        let mut peak_limiter = output_limiter(output_rate, output_channels);
+
+        // Received audio is played from this buffer by a single, persistent output stream.
+        let playback = Arc::new(Mutex::new(PlaybackBuffer::with_drift_correction(
+            output_rate,
+            output_channels,
+        )));
+        // Without `--jitter-target`, the level playback keeps follows the network.
+        let (mut jitter_tuner, mut jitter_target) = match self.opt.jitter_target {
+            Some(target) => (None, target),
+            None => {
+                let tuner = JitterTuner::new(self.stats.underruns(), self.stats.latency_skips());
+                let target = tuner.target();
+                (Some(tuner), target)
+            }
+        };
+        if let Ok(mut playback) = playback.lock() {
+            playback.set_min_level(jitter_target);
+        }
+        self.stats.set_jitter_target(jitter_target);
+        let playback_ref = playback.clone();
+        let output_hook = self.output_hook.clone();
+        let stats = self.stats.clone();
+        let playback_volume = self.opt.playback_volume;
+        let limiter = self.opt.limiter;
+        let deafened = self.deafened.clone();
+        // Set while the peer's audio is playing, so half-duplex mode can mute the mic.
+        let remote_speaking = Arc::new(AtomicBool::new(false));
+        let remote_speaking_ref = remote_speaking.clone();
+        // The played audio is the reference the echo canceller subtracts from the mic.
+        #[cfg(feature = "aec")]
+        let echo_reference = self.opt.aec.then(|| {
+            Arc::new(EchoReference::new(
+                output_rate,
+                output_channels,
+                self.input_config.sample_rate.0,
+            ))
+        });
+        #[cfg(feature = "aec")]
+        let echo_reference_ref = echo_reference.clone();
+        // Captured audio played back to you, after the noise gate so you hear what the peer hears.
+        let sidetone = (self.opt.sidetone > 0.0).then(|| {
+            Arc::new(Sidetone::new(
+                self.opt.sidetone,
+                self.send_rate(),
+                self.send_channels() as usize,
+                output_rate,
+                output_channels,
+            ))
+        });
+        let sidetone_ref = sidetone.clone();
+        // The same for monitoring, but taken before muting, so the mic can be checked without
+        // the peer hearing it. Set up even while it is off, so it can be turned on at any time.
+        let monitor = Arc::new(Sidetone::new(
+            self.opt.monitor_level,
+            self.send_rate(),
+            self.send_channels() as usize,
+            output_rate,
+            output_channels,
+        ));
+        let monitor_ref = monitor.clone();
+        let monitoring = self.monitoring.clone();
+        if monitoring.load(Ordering::Relaxed) {
+            warn!("Monitoring your mic, use headphones: on speakers it feeds back");
+        }
+        let mut fade = Fade::new(output_rate, output_channels);
+        let fade_out = fade.fade_out_on_drop();
+        // Set while the peer doesn't send audio because it isn't talking (`--vad`).
+        let peer_paused = Arc::new(AtomicBool::new(false));
+        let peer_paused_ref = peer_paused.clone();
+        let channel_gains = self.channel_gains.clone();
+        // Follows the output device, for the channel gains.
+        let stream_channels = Arc::new(AtomicUsize::new(output_channels));
+        let stream_channels_ref = stream_channels.clone();
+        let output_data_fn = move |data: &mut [f32]| {
+            let filled = match playback_ref.lock() {
+                Ok(mut playback) => playback.drain_into(data),
+                Err(_) => {
+                    data.fill(0.0);
+                    false
                 }
             };
-            let output_stream = self.output_device.build_output_stream(
-                &self.output_config,
-                output_data_fn,
-                |e| eprintln!("Stream error: {e}"),
-                None,
-            )?;
-
-            // Record input audio
-            let input_samples: Arc<Mutex<Vec<f32>>> = Arc::new(Mutex::new(Vec::with_capacity(
-                (SLEEP_DURATION.as_secs() as u32
-                    * self.input_config.sample_rate.0
-                    * self.input_config.channels as u32) as usize,
-            )));
-            let input_samples_ref = input_samples.clone();
+            if !filled && !peer_paused_ref.load(Ordering::Relaxed) {
+                stats.count_underrun();
+            }
+            if deafened.load(Ordering::Relaxed) {
+                data.fill(0.0);
+            }
+            fade.apply(data);
+            channel_gains.apply(data, stream_channels_ref.load(Ordering::Relaxed));
+            apply_volume(data, playback_volume, limiter);
+            remote_speaking_ref.store(
+                data.iter().any(|f| f.abs() > REMOTE_SPEAKING_LEVEL),
+                Ordering::Relaxed,
+            );
+            #[cfg(feature = "aec")]
+            if let Some(reference) = &echo_reference_ref {
+                reference.push(data);
+            }
+            // After the echo reference, or the echo canceller would take your voice for echo.
+            if let Some(sidetone) = &sidetone_ref {
+                sidetone.mix_into(data);
+            }
+            // After deafening, so you can listen to nothing but your mic.
+            if monitoring.load(Ordering::Relaxed) {
+                monitor_ref.mix_into(data);
+            }
+            if let Some(hook) = &output_hook {
+                hook(data);
+            }
+        };
+        // Cloned for every output stream, so a new device keeps playing from the same buffer.
+        let mut output_stream = self.output.start(
+            &self.output_config,
+            self.opt.dither,
+            self.on_lost(Command::OutputLost),
+            output_data_fn.clone(),
+        )?;
+        // Declared after the stream, so it fades out before the stream is dropped.
+        let _fade_out = fade_out;
 
-            let input_data_fn = move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                if let Ok(mut lock) = input_samples_ref.try_lock() {
-                    let buffer: &mut Vec<f32> = lock.as_mut();
-                    let norm_data = normalize(data);
-                    let final_data: Vec<f32> = norm_data.iter().map(|f| f * VOLUME).collect();
-                    buffer.extend_from_slice(&final_data);
-                }
+        let input_channels = self.input_config.channels as usize;
+        let send_channels = self.send_channels() as usize;
+        // Captured audio is collected here until the next frame is sent. The send rate is at
+        // most the input rate.
+        let (mut input_samples_ref, mut input_samples) = capture_queue::queue(
+            (CAPTURE_QUEUE_TIME * self.input_config.sample_rate.0) as usize * send_channels,
+            send_channels,
+        );
+        let mut processing = InputProcessing::new(
+            &self.opt,
+            &self.input_processors,
+            self.input_config.sample_rate.0,
+            self.input_config.channels as usize,
+        );
+        let input_hook = self.input_hook.clone();
+        let mut send_resampler = self.send_resampler();
+        let half_duplex = self.opt.half_duplex;
+        let muted = self.muted.clone();
+        let sidetone_input = sidetone.clone();
+        let monitor_input = monitor.clone();
+        let monitoring_input = self.monitoring.clone();
+        #[cfg(feature = "aec")]
+        let mut echo_canceller = echo_reference.clone().map(|reference| {
+            EchoCanceller::new(reference, self.input_config.sample_rate.0, input_channels)
+        });
+        let input_data_fn = move |data: &[f32]| {
+            // Runs on every buffer, even if it is dropped below, to stay in step with playback.
+            #[cfg(feature = "aec")]
+            let data = &match &mut echo_canceller {
+                Some(echo_canceller) => echo_canceller.process(data),
+                None => data.to_vec(),
             };
-            let input_stream = self.input_device.build_input_stream(
-                &self.input_config,
-                input_data_fn,
-                |e| eprintln!("Stream error: {e}"),
-                None,
-            )?;
+            let mut final_data = remix(&processing.process(data), input_channels, send_channels);
+            if let Some(resampler) = &mut send_resampler {
+                final_data = resampler.process(&final_data);
+            }
+            if monitoring_input.load(Ordering::Relaxed) {
+                monitor_input.push(&final_data);
+            }
+            if muted.load(Ordering::Relaxed)
+                || half_duplex && remote_speaking.load(Ordering::Relaxed)
+            {
+                // Keep the length, so the muted part still takes up its share of the stream.
+                final_data.fill(0.0);
+            }
+            if let Some(hook) = &input_hook {
+                hook(&final_data);
+            }
+            if let Some(sidetone) = &sidetone_input {
+                sidetone.push(&final_data);
+            }
+            input_samples_ref.push(&final_data);
+        };
+        // Shared, so capturing can continue on another device with the same processing state.
+        let input_data_fn = Arc::new(Mutex::new(input_data_fn));
+        let input_callback = || {
+            let input_data_fn = input_data_fn.clone();
+            move |data: &[f32]| {
+                if let Ok(mut input_data_fn) = input_data_fn.lock() {
+                    input_data_fn(data);
+                }
+            }
+        };
+        let mut capture = self.input.start(
+            &self.input_config,
+            self.on_lost(Command::InputLost),
+            input_callback(),
+        )?; // start recording
 
-            output_stream.play()?; // start playing
-            input_stream.play()?; // start recording
+        let mut reader = FrameReader::default();
+        let mut writer = FrameWriter::default();
+        let mut captured: Vec<f32> = Vec::new();
+        // Read before the call starts, so sending it only copies samples.
+        let mut greeting: Option<VecDeque<f32>> = self.opt.greeting.as_ref().and_then(|path| {
+            match input::read_wav(path, self.send_rate(), "greeting") {
+                Ok((samples, spec)) => {
+                    Some(remix(&samples, spec.channels as usize, send_channels).into())
+                }
+                Err(e) => {
+                    warn!("{e}, calling without it");
+                    None
+                }
+            }
+        });
+        let mut last_received = Instant::now();
+        let mut last_sent = Instant::now();
+        // Whether audio went out since the last end of speech, with `--vad`.
+        let mut speaking = false;
+        let mut last_ping: Option<Instant> = None;
+        let mut last_mute_announcement: Option<Instant> = None;
+        self.peer_muted.store(false, Ordering::Relaxed);
+        let mut next_tick = Instant::now() + SLEEP_DURATION;
+        let mut next_send = Instant::now() + SEND_INTERVAL;
+        // When the input last delivered anything, to tell a paused input from a short gap.
+        let mut last_captured = Instant::now();
+        // Ping timestamps count from here. Only our own clock is compared, never the peer's.
+        let started = Instant::now();
+        let mut reorderer = Reorderer::default();
+        let mut concealer = LossConcealer::new(peer_rate, peer_channels);
+        let mut sequence: u32 = 0;
+        // Silent intervals not sent yet, and how many samples of them go out in one frame.
+        let mut silence = SilenceRun::default();
+        // Whether the peer was told that a pause started, which lasts across held back runs.
+        let mut pausing = false;
+        let max_silence = (MAX_SILENCE_RUN.as_secs_f64()
+            * (self.send_rate() * self.send_channels() as u32) as f64)
+            as usize;
+        let mut throttle = self.opt.max_bitrate.map(Throttle::new);
+        let mut framer = self
+            .opt
+            .frame_samples
+            .map(|samples| Framer::new(samples, self.send_channels() as usize));
+        // The effective send rate is measured over this window.
+        let mut bitrate_window = (Instant::now(), self.stats.bytes_sent());
+        let mut comfort_noise = self.opt.comfort_noise.map(ComfortNoise::new);
+        // Frames are written by `transmit`, next to the loop, so a slow write doesn't hold up
+        // receiving and playing the peer.
+        let (outgoing, queued) = channel::bounded(SEND_QUEUE_LEN);
+        let stats = self.stats.clone();
+        let mut transmit = pin!(transmit(stream, queued, &stats));
 
+        let call = async {
+            // Dropped when the call ends, which lets `transmit` finish.
+            let outgoing = outgoing;
+            loop {
+                let tick = async {
+                    Timer::at(next_tick).await;
+                    Wake::Tick
+                };
+                let send = async {
+                    Timer::at(next_send).await;
+                    Wake::Send
+                };
+                let readable = async {
+                    let _ = stream.readable().await;
+                    Wake::Readable
+                };
+                let shutdown = async {
+                    match &self.shutdown {
+                        Some(shutdown) => {
+                            let _ = shutdown.recv().await;
+                            Wake::Shutdown
+                        }
+                        None => future::pending().await,
+                    }
+                };
+                let command = async {
+                    match self.commands.1.recv().await {
+                        Ok(command) => Wake::Command(command),
+                        Err(_) => future::pending().await,
+                    }
+                };
+                let wake = future::or(
+                    future::or(shutdown, command),
+                    future::or(future::or(tick, send), readable),
+                )
+                .await;
+                match wake {
+                    Wake::Shutdown | Wake::Command(Command::HangUp) => {
+                        info!("Hanging up...");
+                        say_bye(&outgoing, &mut writer).await;
+                        return Ok(CallEnd::HungUp);
+                    }
+                    Wake::Command(Command::SendText(text)) => {
+                        writer.build_text(&text);
+                        writer.queue(&outgoing).await?;
+                        continue;
+                    }
+                    Wake::Command(Command::MuteChanged) => {
+                        last_mute_announcement = None;
+                        continue;
+                    }
+                    Wake::Command(Command::SetPeerGain(id, gain)) => {
+                        writer.build_peer_gain(id, gain);
+                        writer.queue(&outgoing).await?;
+                        continue;
+                    }
+                    Wake::Command(Command::SetPeerPan(id, pan)) => {
+                        writer.build_peer_pan(id, pan);
+                        writer.queue(&outgoing).await?;
+                        continue;
+                    }
+                    Wake::Command(Command::InputLost) => {
+                        warn!("The input device is gone, switching to the default one");
+                        // The default device has to take the config of the lost one, as the peer
+                        // was told the sample rate and channel count in the handshake.
+                        let restarted = get_audio_host(&self.opt)
+                            .and_then(|host| {
+                                get_input_device(&host, "default", self.opt.loopback_input)
+                            })
+                            .and_then(|device| {
+                                let (_, format) = get_input_config(&device, &self.opt)?;
+                                let input = InputSource::Device(device, format);
+                                let capture = input.start(
+                                    &self.input_config,
+                                    self.on_lost(Command::InputLost),
+                                    input_callback(),
+                                )?;
+                                Ok((input, capture))
+                            });
+                        match restarted {
+                            Ok((input, new_capture)) => {
+                                drop(std::mem::replace(&mut capture, new_capture));
+                                self.input = input;
+                            }
+                            Err(e) => {
+                                error!("Failed to use the default input device: {e}");
+                                return Err(VoiceChatError::DeviceLost("input"));
+                            }
+                        }
+                        continue;
+                    }
+                    Wake::Command(
+                        command @ (Command::SetOutputDevice(_) | Command::OutputLost),
+                    ) => {
+                        let name = match &command {
+                            Command::SetOutputDevice(name) => name.as_str(),
+                            _ => {
+                                warn!("The output device is gone, switching to the default one");
+                                "default"
+                            }
+                        };
+                        // The old stream keeps playing until the new one is up.
+                        let switched =
+                            self.find_output_device(name)
+                                .and_then(|(device, config, format)| {
+                                    let output = OutputSink::Device(device, format);
+                                    let stream = output.start(
+                                        &config,
+                                        self.opt.dither,
+                                        self.on_lost(Command::OutputLost),
+                                        output_data_fn.clone(),
+                                    )?;
+                                    Ok((output, config, stream))
+                                });
+                        match switched {
+                            Ok((output, config, stream)) => {
+                                drop(std::mem::replace(&mut output_stream, stream));
+                                if config.sample_rate.0 != output_rate
+                                    || config.channels as usize != output_channels
+                                {
+                                    output_rate = config.sample_rate.0;
+                                    output_channels = config.channels as usize;
+                                    stream_channels.store(output_channels, Ordering::Relaxed);
+                                    resampler =
+                                        Resampler::new(peer_rate, output_rate, output_channels);
+                                    peak_limiter = output_limiter(output_rate, output_channels);
+                                    // Buffered audio is in the old format and can't be kept.
+                                    if let Ok(mut playback) = playback.lock() {
+                                        *playback = PlaybackBuffer::with_drift_correction(
+                                            output_rate,
+                                            output_channels,
+                                        );
+                                        playback.set_min_level(jitter_target);
+                                    }
+                                    #[cfg(feature = "aec")]
+                                    if let Some(reference) = &echo_reference {
+                                        reference.set_output_format(output_rate, output_channels);
+                                    }
+                                    if let Some(sidetone) = &sidetone {
+                                        sidetone.set_output_format(output_rate, output_channels);
+                                    }
+                                    monitor.set_output_format(output_rate, output_channels);
+                                }
+                                self.output = output;
+                                self.output_config = config;
+                            }
+                            Err(e) if matches!(command, Command::OutputLost) => {
+                                error!("Failed to use the default output device: {e}");
+                                return Err(VoiceChatError::DeviceLost("output"));
+                            }
+                            Err(e) => warn!("Keeping the current output device: {e}"),
+                        }
+                        continue;
+                    }
+                    Wake::Tick | Wake::Send | Wake::Readable => {}
+                }
+                if self
+                    .opt
+                    .duration
+                    .is_some_and(|duration| started.elapsed() >= duration)
+                {
+                    info!("Call duration reached, hanging up...");
+                    say_bye(&outgoing, &mut writer).await;
+                    return Ok(CallEnd::HungUp);
+                }
+
+                // Frames that arrived right before the connection closed (like a rejection) are
+                // still handled before the error ends the call.
+                let filled = reader.fill(&mut transport::Reader(stream));
+                let mut received: Vec<f32> = Vec::new();
+                while let Some(frame) = reader.next_frame(&self.stats) {
+                    last_received = Instant::now();
+                    if let Some((frame_sequence, mut audio)) = audio_payload(&frame, peer_format) {
+                        if held_intervals(&frame).is_some() {
+                            // Played already, as the buffer ran dry while the peer held it back.
+                            audio.clear();
+                        } else if peer_paused.swap(false, Ordering::Relaxed)
+                            && let Ok(mut playback) = playback.lock()
+                        {
+                            playback.restart_drift_correction();
+                        }
+                        for audio in reorderer.push(frame_sequence, audio, &self.stats) {
+                            match audio {
+                                Some(audio) if audio.is_empty() => {}
+                                Some(audio) => {
+                                    received.extend(concealer.receive(decode_audio(
+                                        &audio,
+                                        peer_format,
+                                        peer_channels,
+                                        &self.stats,
+                                    )));
+                                }
+                                None => {
+                                    received.extend(concealer.conceal());
+                                    self.stats.count_concealed();
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                    let Some((&kind, body)) = frame.split_first() else {
+                        continue;
+                    };
+                    match FrameType::from_byte(kind) {
+                        Some(FrameType::Ping) => {
+                            writer.build(|frame| {
+                                frame.push(FrameType::Pong as u8);
+                                frame.extend_from_slice(body);
+                            });
+                            writer.queue(&outgoing).await?;
+                        }
+                        Some(FrameType::Text) => {
+                            let text =
+                                String::from_utf8_lossy(&body[..body.len().min(MAX_TEXT_LEN)]);
+                            match &self.text_hook {
+                                Some(hook) => hook(&text),
+                                None => info!("[{peer_label}] {text}"),
+                            }
+                        }
+                        Some(FrameType::MuteState) => {
+                            let muted = body.first().is_some_and(|&muted| muted != 0);
+                            if self.peer_muted.swap(muted, Ordering::Relaxed) != muted {
+                                if muted {
+                                    info!("[{peer_label} muted]");
+                                } else {
+                                    info!("[{peer_label} unmuted]");
+                                }
+                            }
+                        }
+                        Some(FrameType::EndOfSpeech) => {
+                            debug!("Peer stopped talking");
+                            peer_paused.store(true, Ordering::Relaxed);
+                        }
+                        Some(FrameType::Bye) => {
+                            info!("Peer hung up");
+                            return Ok(CallEnd::PeerHungUp);
+                        }
+                        Some(FrameType::Rejected) => {
+                            return Err(VoiceChatError::Rejected(
+                                String::from_utf8_lossy(body).into_owned(),
+                            ));
+                        }
+                        Some(FrameType::Pong) => {
+                            if let Ok(sent) = body.try_into().map(u64::from_le_bytes) {
+                                let now = started.elapsed().as_micros() as u64;
+                                let rtt = Duration::from_micros(now.saturating_sub(sent));
+                                debug!("Round-trip time: {} ms", rtt.as_millis());
+                                self.stats.set_rtt(rtt);
+                            }
+                        }
+                        _ => {} // heartbeats, or frame types of a newer version
+                    }
+                }
+                // A broken connection would keep the socket readable, so any error ends the call.
+                if let Err(e) = filled {
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                        info!("Peer hung up");
+                    }
+                    return Err(VoiceChatError::Network(e));
+                }
+                if last_received.elapsed() > self.opt.timeout {
+                    return Err(VoiceChatError::PeerTimedOut);
+                }
+                if let Some(writer) = &mut dump
+                    && let Err(e) = writer.write_received(&received)
+                {
+                    warn!("Failed to dump samples, stopping the dump: {e}");
+                    dump = None;
+                }
+                if let Some(writer) = &mut recording
+                    && let Err(e) = writer.write_peer(&received)
+                {
+                    warn!("Failed to record the call, stopping the recording: {e}");
+                    recording = None;
+                }
+
+                let mut audio_data =
+                    resampler.process(&remix(&received, peer_channels, output_channels));
+                self.output_processors.process(&mut audio_data, output_rate);
+                if comfort_noise.is_some()
+                    && audio_data.is_empty()
+                    && peer_paused.load(Ordering::Relaxed)
+                {
+                    // Nothing arrives while the peer pauses, so the noise is made here, keeping
+                    // about one send interval of it buffered.
+                    let interval = (SEND_INTERVAL.as_secs_f64() * output_rate as f64) as usize
+                        * output_channels;
+                    if playback
+                        .lock()
+                        .is_ok_and(|playback| playback.len() < interval)
+                    {
+                        audio_data = vec![0.0; interval];
+                    }
+                }
+                if let Some(comfort_noise) = &mut comfort_noise
+                    && audio_data.iter().all(|f| *f == 0.0)
+                {
+                    comfort_noise.fill(&mut audio_data);
+                }
+                peak_limiter.process(&mut audio_data);
+                if let Ok(mut playback) = playback.lock() {
+                    if playback.push(&audio_data) {
+                        warn!("Too much of the peer's audio piled up, dropping the oldest");
+                        self.stats.count_buffer_overflow();
+                    }
+                    self.stats.set_clock_drift(playback.drift_ppm());
+                    let samples_per_second = (output_rate as usize * output_channels) as f64;
+                    let max_len =
+                        (self.opt.max_latency.as_secs_f64() * samples_per_second) as usize;
+                    if playback.len() > max_len {
+                        // Back to the frame that just arrived, which is as live as it gets.
+                        let live_len = audio_data.len()
+                            + (LATENCY_MARGIN.as_secs_f64() * samples_per_second) as usize;
+                        let target_len = live_len.min(max_len);
+                        info!(
+                            "{} ms of audio piled up, skipping ahead to {} ms",
+                            (playback.len() as f64 / samples_per_second * 1000.0) as u64,
+                            (target_len as f64 / samples_per_second * 1000.0) as u64
+                        );
+                        playback.skip_to(target_len);
+                        self.stats.count_latency_skip();
+                    }
+                }
+
+                // Audio goes out every `SEND_INTERVAL`, everything else once per tick.
+                match wake {
+                    Wake::Send => next_send = Instant::now() + SEND_INTERVAL,
+                    Wake::Tick => next_tick += SLEEP_DURATION,
+                    _ => continue,
+                }
+
+                if let Wake::Tick = wake
+                    && let Some(tuner) = &mut jitter_tuner
+                    && let Some(target) = tuner.update(
+                        self.stats.underruns(),
+                        self.stats.latency_skips(),
+                        Instant::now(),
+                    )
+                {
+                    debug!("Keeping {} ms of audio buffered", target.as_millis());
+                    jitter_target = target;
+                    self.stats.set_jitter_target(target);
+                    if let Ok(mut playback) = playback.lock() {
+                        playback.set_min_level(target);
+                    }
+                }
+
+                if let Wake::Tick = wake
+                    && last_mute_announcement
+                        .is_none_or(|announcement| announcement.elapsed() >= MUTE_ANNOUNCE_INTERVAL)
+                {
+                    last_mute_announcement = Some(Instant::now());
+                    let muted = self.muted.load(Ordering::Relaxed);
+                    writer.build(|frame| {
+                        frame.extend_from_slice(&[FrameType::MuteState as u8, muted as u8])
+                    });
+                    writer.queue(&outgoing).await?;
+                }
+
+                if let Wake::Tick = wake
+                    && last_ping.is_none_or(|ping| ping.elapsed() >= PING_INTERVAL)
+                {
+                    last_ping = Some(Instant::now());
+                    let timestamp = started.elapsed().as_micros() as u64;
+                    writer.build(|frame| {
+                        frame.push(FrameType::Ping as u8);
+                        frame.extend_from_slice(&timestamp.to_le_bytes());
+                    });
+                    writer.queue(&outgoing).await?;
+                }
+
+                // Send Samples.
+                captured.clear();
+                input_samples.drain_into(&mut captured);
+                let dropped = input_samples.take_dropped();
+                if dropped > 0 {
+                    warn!("Sending fell behind, dropped {dropped} captured samples");
+                    self.stats.count_dropped(dropped);
+                }
+                if !captured.is_empty() {
+                    last_captured = Instant::now();
+                }
+                if let Some(samples) = &mut greeting {
+                    // Takes the place of the mic until it is through, at the pace the mic captures.
+                    let len = captured.len().min(samples.len());
+                    for (sample, greeted) in captured.iter_mut().zip(samples.drain(..len)) {
+                        *sample = greeted;
+                    }
+                    if samples.is_empty() {
+                        info!("Greeting sent");
+                        greeting = None;
+                    }
+                }
+                if let Some(writer) = &mut dump
+                    && let Err(e) = writer.write_captured(&captured)
+                {
+                    warn!("Failed to dump samples, stopping the dump: {e}");
+                    dump = None;
+                }
+                if let Some(writer) = &mut recording
+                    && let Err(e) = writer.write_mic(&captured)
+                {
+                    warn!("Failed to record the call, stopping the recording: {e}");
+                    recording = None;
+                }
+                let framed = framer.as_mut().map(|framer| {
+                    if captured.is_empty() && last_captured.elapsed() >= SLEEP_DURATION {
+                        // Once the input pauses, what is left goes out padded instead of waiting.
+                        framer.flush().into_iter().collect()
+                    } else if captured.is_empty() {
+                        Vec::new()
+                    } else {
+                        framer.push(&captured)
+                    }
+                });
+                if framed.is_some() {
+                    // The framer keeps what didn't fill a frame.
+                    captured.clear();
+                }
+                let mut frames: Vec<&[f32]> = match &framed {
+                    Some(framed) => framed.iter().map(Vec::as_slice).collect(),
+                    None if captured.is_empty() => Vec::new(),
+                    None => vec![&captured],
+                };
+                if self.opt.vad.is_some() {
+                    // The detector silenced everything that isn't speech.
+                    frames.retain(|samples| samples.iter().any(|f| *f != 0.0));
+                    if frames.is_empty() && speaking {
+                        writer.build(|frame| frame.push(FrameType::EndOfSpeech as u8));
+                        writer.queue(&outgoing).await?;
+                        last_sent = Instant::now();
+                    }
+                    speaking = !frames.is_empty();
+                }
+                if frames.is_empty() && last_sent.elapsed() < HEARTBEAT_INTERVAL {
+                    continue;
+                }
+                last_sent = Instant::now();
+                if frames.is_empty() {
+                    writer.build(|frame| frame.push(FrameType::Heartbeat as u8));
+                    writer.queue(&outgoing).await?;
+                }
+                for samples in frames {
+                    if samples.iter().all(|f| *f == 0.0) {
+                        if !pausing {
+                            pausing = true;
+                            // The peer's buffer runs dry until the pause is sent, on purpose.
+                            writer.build(|frame| frame.push(FrameType::EndOfSpeech as u8));
+                            writer.queue(&outgoing).await?;
+                        }
+                        silence.hold(samples.len());
+                        if silence.len() < max_silence {
+                            continue;
+                        }
+                        writer.build_silence_run(sequence, &mut silence);
+                        sequence = sequence.wrapping_add(1);
+                        writer.queue(&outgoing).await?;
+                        continue;
+                    }
+                    pausing = false;
+                    if !silence.is_empty() {
+                        writer.build_silence_run(sequence, &mut silence);
+                        sequence = sequence.wrapping_add(1);
+                        writer.queue(&outgoing).await?;
+                    }
+                    if throttle.as_mut().is_some_and(|throttle| !throttle.allows()) {
+                        // Silence in its place keeps the timing, so the peer isn't thrown off by a gap.
+                        writer.build_silence(sequence, samples.len());
+                        self.stats.count_throttled();
+                    } else {
+                        writer.build_audio(sequence, samples, self.opt.wire_format);
+                    }
+                    sequence = sequence.wrapping_add(1);
+                    // Dropped rather than waited for, so a slow link can't hold up receiving. The
+                    // peer conceals the gap, as if it was lost.
+                    match writer.try_queue(&outgoing)? {
+                        Some(len) => {
+                            debug!("Queued {len} bytes");
+                            if let Some(throttle) = &mut throttle {
+                                throttle.spend(len);
+                            }
+                        }
+                        None => {
+                            warn!("Sending fell behind, dropped an audio frame");
+                            self.stats.count_dropped(samples.len());
+                        }
+                    }
+                }
+                captured.clear();
+                let (window_start, window_bytes) = bitrate_window;
+                if window_start.elapsed() >= BITRATE_WINDOW {
+                    let bytes = self.stats.bytes_sent() - window_bytes;
+                    let bits_per_second = bytes as f64 * 8.0 / window_start.elapsed().as_secs_f64();
+                    self.stats.set_send_bitrate(bits_per_second as u64);
+                    bitrate_window = (Instant::now(), self.stats.bytes_sent());
+                }
+            }
+        };
+        let sending = async {
+            match transmit.as_mut().await {
+                Err(e) => Err(VoiceChatError::Network(e)),
+                // Only once the call is over.
+                Ok(()) => future::pending().await,
+            }
+        };
+        // A failed write shows up in the call as a closed queue, so its own error comes first.
+        let result = future::or(sending, call).await;
+        // What was queued last, like the bye, still goes out.
+        if result.is_ok()
+            && let Err(e) = transmit.await
+        {
+            debug!("Failed to send the last frames: {e}");
+        }
+        result
+    }
+
+    /// Plays the input device straight back to the output device, without any networking.
+    /// Prints the input level every second so users can check that the mic picks up sound.
+    pub fn loopback(&mut self) -> Result<(), VoiceChatError> {
+        info!("Entering loopback...");
+
+        // Hold at most one second of audio, so a slow output doesn't build up delay.
+        let max_len =
+            (self.output_config.sample_rate.0 * self.output_config.channels as u32) as usize;
+        let queue = Arc::new(Mutex::new(PlaybackBuffer::new(
+            self.output_config.channels as usize,
+        )));
+        let queue_ref = queue.clone();
+        // Sum of squares and number of captured samples since the last level print.
+        let level: Arc<Mutex<(f32, usize)>> = Arc::new(Mutex::new((0.0, 0)));
+        let level_ref = level.clone();
+
+        let input_channels = self.input_config.channels as usize;
+        let send_channels = self.send_channels() as usize;
+        let output_channels = self.output_config.channels as usize;
+        let mut resampler = Resampler::new(
+            self.input_config.sample_rate.0,
+            self.output_config.sample_rate.0,
+            output_channels,
+        );
+        let mut processing = InputProcessing::new(
+            &self.opt,
+            &self.input_processors,
+            self.input_config.sample_rate.0,
+            self.input_config.channels as usize,
+        );
+        let output_processors = self.output_processors.clone();
+        let output_rate = self.output_config.sample_rate.0;
+        let input_hook = self.input_hook.clone();
+        let input_data_fn = move |data: &[f32]| {
+            if let Ok(mut level) = level_ref.lock() {
+                level.0 += data.iter().map(|f| f * f).sum::<f32>();
+                level.1 += data.len();
+            }
+            let final_data = remix(&processing.process(data), input_channels, send_channels);
+            if let Some(hook) = &input_hook {
+                hook(&final_data);
+            }
+            if let Ok(mut queue) = queue_ref.lock() {
+                let mut played =
+                    resampler.process(&remix(&final_data, send_channels, output_channels));
+                output_processors.process(&mut played, output_rate);
+                queue.push(&played);
+                queue.trim_to(max_len);
+            }
+        };
+        let output_hook = self.output_hook.clone();
+        let playback_volume = self.opt.playback_volume;
+        let limiter = self.opt.limiter;
+        let deafened = self.deafened.clone();
+        let channel_gains = self.channel_gains.clone();
+        let output_data_fn = move |data: &mut [f32]| {
+            match queue.lock() {
+                Ok(mut queue) => {
+                    queue.drain_into(data);
+                }
+                Err(_) => data.fill(0.0),
+            }
+            if deafened.load(Ordering::Relaxed) {
+                data.fill(0.0);
+            }
+            channel_gains.apply(data, output_channels);
+            apply_volume(data, playback_volume, limiter);
+            if let Some(hook) = &output_hook {
+                hook(data);
+            }
+        };
+        // Without a call loop to switch devices, losing one ends the loopback.
+        let input_lost = Arc::new(AtomicBool::new(false));
+        let output_lost = Arc::new(AtomicBool::new(false));
+        let lost = |flag: &Arc<AtomicBool>| {
+            let flag = flag.clone();
+            move || flag.store(true, Ordering::Relaxed)
+        };
+        let _playback = self.output.start(
+            &self.output_config,
+            self.opt.dither,
+            lost(&output_lost),
+            output_data_fn,
+        )?;
+        let _capture = self
+            .input
+            .start(&self.input_config, lost(&input_lost), input_data_fn)?;
+
+        let started = Instant::now();
+        loop {
             thread::sleep(SLEEP_DURATION);
+            if input_lost.load(Ordering::Relaxed) {
+                return Err(VoiceChatError::DeviceLost("input"));
+            }
+            if output_lost.load(Ordering::Relaxed) {
+                return Err(VoiceChatError::DeviceLost("output"));
+            }
+            if self
+                .opt
+                .duration
+                .is_some_and(|duration| started.elapsed() >= duration)
+            {
+                info!("Duration reached, stopping loopback...");
+                return Ok(());
+            }
+            if self.shutdown_requested() {
+                info!("Stopping loopback...");
+                return Ok(());
+            }
+            if let Ok(mut level) = level.lock() {
+                let (sum, count) = std::mem::take(&mut *level);
+                let rms = if count == 0 {
+                    0.0
+                } else {
+                    (sum / count as f32).sqrt()
+                };
+                info!("Input level (RMS): {rms:.4}");
+            }
+        }
+    }
+
+    /// Plays a sine on the output device until stopped (or for `--duration`), so users can
+    /// check their speakers or headphones before joining a call.
+    pub fn test_tone(&mut self) -> Result<(), VoiceChatError> {
+        info!("Playing a {TEST_TONE_FREQUENCY} Hz test tone, press Ctrl-C to stop...");
+        let channels = self.output_config.channels as usize;
+        let step =
+            TEST_TONE_FREQUENCY * std::f32::consts::TAU / self.output_config.sample_rate.0 as f32;
+        let mut phase: f32 = 0.0;
+        let output_hook = self.output_hook.clone();
+        let playback_volume = self.opt.playback_volume;
+        let limiter = self.opt.limiter;
+        let channel_gains = self.channel_gains.clone();
+        let output_data_fn = move |data: &mut [f32]| {
+            for frame in data.chunks_mut(channels) {
+                frame.fill(phase.sin() * TEST_TONE_LEVEL);
+                phase = (phase + step) % std::f32::consts::TAU;
+            }
+            channel_gains.apply(data, channels);
+            apply_volume(data, playback_volume, limiter);
+            if let Some(hook) = &output_hook {
+                hook(data);
+            }
+        };
+        let output_lost = Arc::new(AtomicBool::new(false));
+        let output_lost_ref = output_lost.clone();
+        let _playback = self.output.start(
+            &self.output_config,
+            self.opt.dither,
+            move || output_lost_ref.store(true, Ordering::Relaxed),
+            output_data_fn,
+        )?;
+
+        let started = Instant::now();
+        loop {
+            thread::sleep(SLEEP_DURATION);
+            if output_lost.load(Ordering::Relaxed) {
+                return Err(VoiceChatError::DeviceLost("output"));
+            }
+            if self
+                .opt
+                .duration
+                .is_some_and(|duration| started.elapsed() >= duration)
+            {
+                info!("Duration reached, stopping test tone...");
+                return Ok(());
+            }
+            if self.shutdown_requested() {
+                info!("Stopping test tone...");
+                return Ok(());
+            }
+        }
+    }
+
+    /// Measures the round-trip latency of the audio devices: plays short clicks on the output
+    /// and times how long they take to arrive at the input. The input has to hear the output,
+    /// through a cable from the output to the input or with the mic close to the speakers.
+    ///
+    /// Returns the median of the measurements, or `None` if no click was picked up. It is
+    /// timed from the output callback to the input callback, so it includes the device buffers
+    /// on both sides, which is what a call adds on top of the network.
+    pub fn measure_latency(&mut self) -> Result<Option<Duration>, VoiceChatError> {
+        info!("Measuring the latency, please be quiet for a moment...");
+        let probe = Arc::new(Mutex::new(LatencyProbe::default()));
+        let trigger = Arc::new(AtomicBool::new(false));
+
+        let probe_ref = probe.clone();
+        let trigger_ref = trigger.clone();
+        let impulse_len =
+            ((LATENCY_IMPULSE_TIME * self.output_config.sample_rate.0 as f32) as usize).max(1)
+                * self.output_config.channels as usize;
+        let output_hook = self.output_hook.clone();
+        let output_data_fn = move |data: &mut [f32]| {
+            data.fill(0.0);
+            if trigger_ref.swap(false, Ordering::Relaxed) {
+                let len = impulse_len.min(data.len());
+                data[..len].fill(LATENCY_IMPULSE_LEVEL);
+                if let Ok(mut probe) = probe_ref.lock() {
+                    probe.emitted();
+                }
+            }
+            if let Some(hook) = &output_hook {
+                hook(data);
+            }
+        };
+        let probe_ref = probe.clone();
+        let samples_per_second =
+            (self.input_config.sample_rate.0 * self.input_config.channels as u32) as f64;
+        let input_data_fn = move |data: &[f32]| {
+            if let Ok(mut probe) = probe_ref.lock() {
+                probe.captured(data, samples_per_second);
+            }
+        };
+        let input_lost = Arc::new(AtomicBool::new(false));
+        let output_lost = Arc::new(AtomicBool::new(false));
+        let lost = |flag: &Arc<AtomicBool>| {
+            let flag = flag.clone();
+            move || flag.store(true, Ordering::Relaxed)
+        };
+        let _playback = self.output.start(
+            &self.output_config,
+            self.opt.dither,
+            lost(&output_lost),
+            output_data_fn,
+        )?;
+        let _capture = self
+            .input
+            .start(&self.input_config, lost(&input_lost), input_data_fn)?;
+        let check_lost = || {
+            if input_lost.load(Ordering::Relaxed) {
+                return Err(VoiceChatError::DeviceLost("input"));
+            }
+            if output_lost.load(Ordering::Relaxed) {
+                return Err(VoiceChatError::DeviceLost("output"));
+            }
+            Ok(())
+        };
+
+        // The clicks have to stand out from the noise the input picks up anyway.
+        thread::sleep(LATENCY_CALIBRATION_TIME);
+        check_lost()?;
+        if let Ok(mut probe) = probe.lock() {
+            let threshold = probe.calibrate();
+            info!("Detecting clicks above level {threshold:.3}");
+        }
+        for click in 1..=LATENCY_CLICKS {
+            trigger.store(true, Ordering::Relaxed);
+            thread::sleep(LATENCY_CLICK_TIMEOUT);
+            check_lost()?;
+            let Ok(mut probe) = probe.lock() else {
+                continue;
+            };
+            if probe.pending() {
+                probe.cancel();
+                warn!("Click {click} of {LATENCY_CLICKS} wasn't picked up");
+            } else if let Some(latency) = probe.measurements.last() {
+                info!(
+                    "Click {click} of {LATENCY_CLICKS}: {:.1} ms",
+                    latency.as_secs_f64() * 1000.0
+                );
+            }
+        }
+
+        let mut measurements = match probe.lock() {
+            Ok(probe) => probe.measurements.clone(),
+            Err(_) => Vec::new(),
+        };
+        measurements.sort();
+        Ok(measurements.get(measurements.len() / 2).copied())
+    }
+
+    /// Listens to you speaking for a few seconds and sets the mic gain (see `--mic-gain`) so
+    /// the peer gets your voice at an RMS level of about 0.1, like `--agc` aims for, with the
+    /// peaks kept below the limiter. The mic is measured after the noise gate and the
+    /// normalizing or AGC, i.e. as it would be sent.
+    ///
+    /// If it hears nothing but silence, it asks you to speak and tries again a few times.
+    /// Returns the gain it set, or `None` if it never heard you or processing is off (`--raw`),
+    /// in which case the gain stays as it was.
+    pub fn calibrate_mic_gain(&mut self) -> Result<Option<f32>, VoiceChatError> {
+        if self.opt.raw {
+            warn!("Not calibrating the mic gain, --raw sends the mic as captured");
+            return Ok(None);
+        }
+        let mut processing = InputProcessing::new(
+            &self.opt,
+            &self.input_processors,
+            self.input_config.sample_rate.0,
+            self.input_config.channels as usize,
+        );
+        // Measured without the gain, which is what is being picked.
+        processing.gain.gain = 1.0;
+        let peak = Arc::new(LevelMeter::default());
+        let level = Arc::new(LevelStats::default());
+        let peak_ref = peak.clone();
+        let level_ref = level.clone();
+        let input_data_fn = move |data: &[f32]| {
+            let processed = processing.process(data);
+            if data.iter().any(|f| f.abs() > CALIBRATION_SPEECH_LEVEL) {
+                peak_ref.record(&processed);
+                level_ref.record(&processed);
+            }
+        };
+        let input_lost = Arc::new(AtomicBool::new(false));
+        let input_lost_ref = input_lost.clone();
+        let _capture = self.input.start(
+            &self.input_config,
+            move || input_lost_ref.store(true, Ordering::Relaxed),
+            input_data_fn,
+        )?;
+        let min_samples = (CALIBRATION_MIN_SPEECH
+            * (self.input_config.sample_rate.0 * self.input_config.channels as u32) as f32)
+            as u64;
+
+        info!(
+            "Calibrating the mic gain, please speak normally for {} seconds...",
+            CALIBRATION_TIME.as_secs()
+        );
+        for attempt in 1..=CALIBRATION_ATTEMPTS {
+            // Whatever came in while the last attempt was evaluated doesn't count.
+            peak.take();
+            level.take();
+            thread::sleep(CALIBRATION_TIME);
+            if input_lost.load(Ordering::Relaxed) {
+                return Err(VoiceChatError::DeviceLost("input"));
+            }
+            let peak = peak.take();
+            let summary = level.take();
+            if summary.samples >= min_samples && summary.rms > 0.0 {
+                let gain = calibrated_gain(summary.rms, peak);
+                info!(
+                    "Your voice came in at {summary}, peaking at {peak:.2}. Setting the mic gain \
+                     to {gain:.2}, give --mic-gain {gain:.2} to skip this next time"
+                );
+                self.opt.mic_gain = gain;
+                return Ok(Some(gain));
+            }
+            if attempt < CALIBRATION_ATTEMPTS {
+                warn!(
+                    "Didn't hear you ({attempt} of {CALIBRATION_ATTEMPTS}), please speak into \
+                     the mic for {} seconds...",
+                    CALIBRATION_TIME.as_secs()
+                );
+            }
+        }
+        warn!(
+            "Didn't hear you, keeping the mic gain at {}. Check that the right input device is \
+             used and not muted",
+            self.opt.mic_gain
+        );
+        Ok(None)
+    }
+
+    /// Streams the mic as RTP to `address` and plays the RTP stream arriving on `--rtp-port`,
+    /// instead of a call with another instance. For standard tools like `ffmpeg` or a SIP
+    /// client, see the [`rtp`] module for the format. Runs until `--duration` or the shutdown
+    /// channel ends it.
+    #[cfg(feature = "rtp")]
+    pub fn rtp(&mut self) -> Result<(), VoiceChatError> {
+        let remote = self.address.to_socket_addrs()?.next().ok_or_else(|| {
+            VoiceChatError::Network(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("'{}' did not resolve to any address", self.address),
+            ))
+        })?;
+        let local: SocketAddr = if remote.is_ipv4() {
+            ([0, 0, 0, 0], self.opt.rtp_port).into()
+        } else {
+            (std::net::Ipv6Addr::UNSPECIFIED, self.opt.rtp_port).into()
+        };
+        let socket = std::net::UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+        let rate = self.send_rate();
+        let channels = self.send_channels();
+        info!(
+            "Sending RTP to {remote} and receiving on port {}, L16 at {rate} Hz with {channels} \
+             channel(s), payload type {}",
+            self.opt.rtp_port,
+            rtp::payload_type(rate, channels)
+        );
+        info!(
+            "To play the sent stream on {remote}, give this SDP to the receiver:\n{}",
+            rtp::sdp(remote.port(), rate, channels)
+        );
+
+        let input_channels = self.input_config.channels as usize;
+        let send_channels = channels as usize;
+        // Captured audio is queued here, and collected in `pending` until a whole packet can
+        // be sent.
+        let (mut input_samples_ref, mut input_samples) = capture_queue::queue(
+            (CAPTURE_QUEUE_TIME * self.input_config.sample_rate.0) as usize * send_channels,
+            send_channels,
+        );
+        let mut pending: Vec<f32> = Vec::new();
+        let mut processing = InputProcessing::new(
+            &self.opt,
+            &self.input_processors,
+            self.input_config.sample_rate.0,
+            self.input_config.channels as usize,
+        );
+        let input_hook = self.input_hook.clone();
+        let mut send_resampler = self.send_resampler();
+        let muted = self.muted.clone();
+        let input_data_fn = move |data: &[f32]| {
+            let mut final_data = remix(&processing.process(data), input_channels, send_channels);
+            if let Some(resampler) = &mut send_resampler {
+                final_data = resampler.process(&final_data);
+            }
+            if muted.load(Ordering::Relaxed) {
+                final_data.fill(0.0);
+            }
+            if let Some(hook) = &input_hook {
+                hook(&final_data);
+            }
+            input_samples_ref.push(&final_data);
+        };
 
-            // Send Samples
-            if let Ok(inner) = input_samples.lock() {
-                let mut fixed_data_buffer: Vec<u8> = Vec::with_capacity(inner.len() * 4);
-                for f in &inner.to_vec() {
-                    fixed_data_buffer.extend_from_slice(&f.to_le_bytes());
+        let output_rate = self.output_config.sample_rate.0;
+        let output_channels = self.output_config.channels as usize;
+        let playback = Arc::new(Mutex::new(PlaybackBuffer::with_drift_correction(
+            output_rate,
+            output_channels,
+        )));
+        let playback_ref = playback.clone();
+        let output_hook = self.output_hook.clone();
+        let playback_volume = self.opt.playback_volume;
+        let limiter = self.opt.limiter;
+        let deafened = self.deafened.clone();
+        let channel_gains = self.channel_gains.clone();
+        let output_data_fn = move |data: &mut [f32]| {
+            match playback_ref.lock() {
+                Ok(mut playback) => {
+                    playback.drain_into(data);
                 }
-                if stream.write_all(&fixed_data_buffer).is_ok() {
-                    // println!("Sent bytes!");
+                Err(_) => data.fill(0.0),
+            }
+            if deafened.load(Ordering::Relaxed) {
+                data.fill(0.0);
+            }
+            channel_gains.apply(data, output_channels);
+            apply_volume(data, playback_volume, limiter);
+            if let Some(hook) = &output_hook {
+                hook(data);
+            }
+        };
+        // Without a call loop to switch devices, losing one ends the stream.
+        let input_lost = Arc::new(AtomicBool::new(false));
+        let output_lost = Arc::new(AtomicBool::new(false));
+        let lost = |flag: &Arc<AtomicBool>| {
+            let flag = flag.clone();
+            move || flag.store(true, Ordering::Relaxed)
+        };
+        let _playback = self.output.start(
+            &self.output_config,
+            self.opt.dither,
+            lost(&output_lost),
+            output_data_fn,
+        )?;
+        let _capture = self
+            .input
+            .start(&self.input_config, lost(&input_lost), input_data_fn)?;
+
+        let packet_len = (RTP_PACKET_TIME.as_secs_f64() * rate as f64) as usize * send_channels;
+        let mut packetizer = rtp::Packetizer::new(rate, channels, self.nonce);
+        let payload_type = rtp::payload_type(rate, channels);
+        let mut resampler = Resampler::new(rate, output_rate, output_channels);
+        // The stream being played, and its latest sequence number.
+        let mut received: Option<(u32, u16)> = None;
+        let mut packet = vec![0u8; 65536];
+        let started = Instant::now();
+        loop {
+            thread::sleep(RTP_POLL_INTERVAL);
+            if input_lost.load(Ordering::Relaxed) {
+                return Err(VoiceChatError::DeviceLost("input"));
+            }
+            if output_lost.load(Ordering::Relaxed) {
+                return Err(VoiceChatError::DeviceLost("output"));
+            }
+            if self
+                .opt
+                .duration
+                .is_some_and(|duration| started.elapsed() >= duration)
+            {
+                info!("Duration reached, stopping RTP...");
+                return Ok(());
+            }
+            if self.shutdown_requested() {
+                info!("Shutting down...");
+                return Ok(());
+            }
+
+            input_samples.drain_into(&mut pending);
+            let dropped = input_samples.take_dropped();
+            if dropped > 0 {
+                warn!("Sending fell behind, dropped {dropped} captured samples");
+                self.stats.count_dropped(dropped);
+            }
+            let whole = pending.len() / packet_len * packet_len;
+            for samples in pending
+                .drain(..whole)
+                .collect::<Vec<f32>>()
+                .chunks(packet_len)
+            {
+                let bytes = packetizer.packetize(samples);
+                match socket.send_to(&bytes, remote) {
+                    Ok(_) => self.stats.count_sent(bytes.len()),
+                    // Nobody listening yet, e.g. ICMP port unreachable from an earlier packet.
+                    Err(e) => debug!("Failed to send an RTP packet: {e}"),
+                }
+            }
+
+            loop {
+                let len = match socket.recv_from(&mut packet) {
+                    Ok((len, _)) => len,
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        debug!("Failed to receive an RTP packet: {e}");
+                        break;
+                    }
+                };
+                let Some(rtp) = rtp::Packet::parse(&packet[..len]) else {
+                    self.stats.count_corrupt();
+                    continue;
+                };
+                if rtp.payload_type != payload_type {
+                    self.stats.count_undecodable();
+                    continue;
+                }
+                match received {
+                    // Sequence numbers wrap around, so "later" means less than half a turn ahead.
+                    Some((ssrc, last))
+                        if ssrc == rtp.ssrc && (rtp.sequence.wrapping_sub(last) as i16) <= 0 =>
+                    {
+                        self.stats.count_out_of_order();
+                        continue;
+                    }
+                    Some((ssrc, _)) if ssrc == rtp.ssrc => {}
+                    _ => info!("Receiving RTP stream {:08x}", rtp.ssrc),
+                }
+                received = Some((rtp.ssrc, rtp.sequence));
+                self.stats.count_received(len);
+                let audio =
+                    resampler.process(&remix(&rtp.samples(), send_channels, output_channels));
+                if let Ok(mut playback) = playback.lock() {
+                    playback.push(&audio);
+                    let max_len = (self.opt.max_latency.as_secs_f64()
+                        * (output_rate as usize * output_channels) as f64)
+                        as usize;
+                    if playback.len() > max_len {
+                        playback.skip_to(max_len / 2);
+                        self.stats.count_latency_skip();
+                    }
                 }
             }
         }
     }
 
-    pub async fn listen(&mut self) -> Result<(), Box<dyn Error>> {
-        let listener = TcpListener::bind(&self.address)?;
-        let stream = listener.accept()?.0;
+    pub async fn listen(&mut self) -> Result<(), VoiceChatError> {
+        let _disconnect = self.state.disconnect_on_drop();
+        let address = self.address.clone();
+        self.listen_on(&address).await
+    }
+
+    async fn listen_on(&mut self, address: &str) -> Result<(), VoiceChatError> {
+        let listener = Async::new(bind(address, self.opt.auto_port)?)?;
+        let bound_addr = listener.get_ref().local_addr()?;
+        self.bound_addr = Some(bound_addr);
+        info!("Listening to {bound_addr}...");
+        #[cfg(feature = "mdns")]
+        let _advertisement = match discovery::advertise(bound_addr) {
+            Ok(advertisement) => Some(advertisement),
+            Err(e) => {
+                warn!("Failed to advertise the server: {e}");
+                None
+            }
+        };
+        // Only hanging up or a shutdown ends listening, a call that ends otherwise (the peer
+        // hanging up, or an error) waits for the next one.
+        loop {
+            info!("Waiting for a connection...");
+            self.state.set(SessionState::Listening);
+            let accepted = async { Some(listener.accept().await) };
+            let shutdown = async {
+                match &self.shutdown {
+                    Some(shutdown) => {
+                        let _ = shutdown.recv().await;
+                        None
+                    }
+                    None => future::pending().await,
+                }
+            };
+            let Some(accepted) = future::or(accepted, shutdown).await else {
+                info!("Shutting down...");
+                return Ok(());
+            };
+            let (stream, peer_addr) = accepted?;
+            info!("Accepted connection from {peer_addr}");
+            match self.chat(stream.into_inner()?).await {
+                Ok(CallEnd::HungUp) => return Ok(()),
+                Ok(CallEnd::PeerHungUp) => {}
+                Err(e) => warn!("Call ended: {e}"),
+            }
+        }
+    }
+
+    /// Connects to `address`, which may also be a hostname like `alices-laptop.local:8888`.
+    /// If it resolves to several addresses (e.g. IPv4 and IPv6), the first that connects is used.
+    ///
+    /// While the connection is refused, e.g. because the server isn't started yet, it retries
+    /// for up to `--connect-timeout` seconds (default: three times [`Client::timeout`], 30), so
+    /// the two sides can be started in any order. A shutdown while waiting returns `Ok(())`.
+    pub async fn connect(&mut self) -> Result<(), VoiceChatError> {
+        let _disconnect = self.state.disconnect_on_drop();
+        self.state.set(SessionState::Connecting);
+        let connect_timeout = self
+            .opt
+            .connect_timeout
+            .unwrap_or(self.opt.timeout * CONNECT_TIMEOUT_FACTOR);
+        let deadline = Instant::now() + connect_timeout;
+        let mut waiting = false;
+        let stream = loop {
+            match self.open_connection().await {
+                Ok(stream) => break stream,
+                Err(VoiceChatError::Network(e))
+                    if e.kind() == std::io::ErrorKind::ConnectionRefused
+                        && Instant::now() < deadline =>
+                {
+                    if !waiting {
+                        info!("Waiting for server...");
+                        waiting = true;
+                    }
+                    // The last attempt is made at the deadline.
+                    let retry = async {
+                        Timer::at(deadline.min(Instant::now() + CONNECT_RETRY_DELAY)).await;
+                        true
+                    };
+                    let shutdown = async {
+                        match &self.shutdown {
+                            Some(shutdown) => {
+                                let _ = shutdown.recv().await;
+                                false
+                            }
+                            None => future::pending().await,
+                        }
+                    };
+                    if !future::or(retry, shutdown).await {
+                        return Ok(());
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        };
         self.chat(stream).await?;
         Ok(())
     }
 
-    pub async fn connect(&mut self) -> Result<(), Box<dyn Error>> {
-        let stream = TcpStream::connect(&self.address)?;
-        if stream.peer_addr()?.ip() == stream.local_addr()?.ip() {
-            eprintln!(
-                "\nWARNING: It seems like you are connecting to yourself. Unless you specified different output devices for the the chat instances, you may hear a lot of noise and echoes.\n"
-            );
-        }
+    /// Talks to whoever is at `address`, no matter who starts first: connects if somebody is
+    /// listening there, otherwise listens on the same port (on all interfaces) and waits for the
+    /// peer to connect. Both sides can use the same call, e.g. with the other's address.
+    ///
+    /// Only a refused connection leads to listening, other errors are returned. Connecting to
+    /// this very instance is still rejected unless `--allow-loopback` is given.
+    pub async fn call(&mut self) -> Result<(), VoiceChatError> {
+        let _disconnect = self.state.disconnect_on_drop();
+        self.state.set(SessionState::Connecting);
+        let stream = match self.open_connection().await {
+            Ok(stream) => stream,
+            Err(VoiceChatError::Network(e))
+                if e.kind() == std::io::ErrorKind::ConnectionRefused =>
+            {
+                // Same port and IP version as the address, but on all interfaces.
+                let any = match self.address.to_socket_addrs()?.next() {
+                    Some(SocketAddr::V6(addr)) => format!("[::]:{}", addr.port()),
+                    Some(SocketAddr::V4(addr)) => format!("0.0.0.0:{}", addr.port()),
+                    None => String::from("0.0.0.0:0"),
+                };
+                info!(
+                    "Nobody is listening at {}, waiting for the peer instead",
+                    self.address
+                );
+                return self.listen_on(&any).await;
+            }
+            Err(e) => return Err(e),
+        };
         self.chat(stream).await?;
         Ok(())
     }
+
+    /// Connects to `address`, trying every address it resolves to.
+    async fn open_connection(&self) -> Result<TcpStream, VoiceChatError> {
+        let mut last_error = None;
+        let mut connected = None;
+        for addr in self.address.to_socket_addrs()? {
+            match Async::<TcpStream>::connect(addr).await {
+                Ok(stream) => {
+                    connected = Some(stream.into_inner()?);
+                    break;
+                }
+                Err(e) => {
+                    debug!("Failed to connect to {addr}: {e}");
+                    last_error = Some(e);
+                }
+            }
+        }
+        let stream = match (connected, last_error) {
+            (Some(stream), _) => stream,
+            (None, Some(e)) => return Err(VoiceChatError::Network(e)),
+            (None, None) => {
+                return Err(VoiceChatError::Network(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("'{}' did not resolve to any address", self.address),
+                )));
+            }
+        };
+        info!("Connected to {}", stream.peer_addr()?);
+        // IPv4 peers of a dual-stack listener show up as IPv4-mapped IPv6 addresses.
+        let peer_ip = stream.peer_addr()?.ip().to_canonical();
+        if peer_ip.is_loopback() || peer_ip == stream.local_addr()?.ip().to_canonical() {
+            warn!(
+                "It seems like you are connecting to your own machine. Unless you specified different output devices for the the chat instances, you may hear a lot of noise and echoes."
+            );
+        }
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibration_brings_the_voice_to_the_agc_target() {
+        let gain = calibrated_gain(0.02, 0.1);
+        assert!((gain - DEFAULT_AGC_TARGET / 0.02).abs() < 1e-4, "{gain}");
+    }
+
+    #[test]
+    fn calibration_keeps_the_peaks_below_the_limiter() {
+        // Brought to the target, these peaks would be at 1.6.
+        let gain = calibrated_gain(0.05, 0.8);
+        assert!(
+            (gain * 0.8 - DEFAULT_LIMITER_THRESHOLD).abs() < 1e-4,
+            "{gain}"
+        );
+    }
+
+    #[test]
+    fn calibrated_gain_stays_in_range() {
+        assert_eq!(calibrated_gain(0.0001, 0.0001), CALIBRATION_MAX_GAIN);
+        assert_eq!(calibrated_gain(0.5, 20.0), CALIBRATION_MIN_GAIN);
+    }
 }
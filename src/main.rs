@@ -1,50 +1,666 @@
-use local_ip_address::local_ip;
-use std::env::args;
+use async_signal::{Signal, Signals};
+use local_ip_address::{local_ip, local_ipv6};
+use log::{Log, debug, error, info, warn};
+use smol::stream::StreamExt;
+use std::env;
 use std::error::Error;
-use std::net::IpAddr;
-use std::str::FromStr;
-use voice_chat::Client;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use voice_chat::config::Config;
+use voice_chat::log_file::RotatingFile;
+use voice_chat::meter::{self, LevelMeter, LevelStats};
+use voice_chat::session::format_duration;
+use voice_chat::{Client, Mixer, SessionState, args};
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = args().collect();
+/// How often the level meter is redrawn.
+const METER_INTERVAL: Duration = Duration::from_millis(250);
+/// Log target of the `--verbose-audio` summaries, which are still shown with `--quiet`.
+const DIAGNOSTICS_TARGET: &str = "voice_chat::diagnostics";
+/// How often `--verbose-audio` prints a summary.
+const DIAGNOSTICS_INTERVAL: Duration = Duration::from_secs(2);
+/// How often a snapshot of the call's stats goes to `--log-file`.
+const STATS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// How long `--discover` looks for servers.
+#[cfg(feature = "mdns")]
+const DISCOVERY_TIME: Duration = Duration::from_secs(3);
 
-    if args.len() <= 1 {
-        println!("How to use:\nvoice-chat [MODE] [TARGET] (input device) (output device)");
+/// Shows the peak levels of your mic and of the received audio in a single terminal line,
+/// followed by how long the call has been going.
+fn attach_meter(client: &mut Client) {
+    let controls = client.controls();
+    let mic = Arc::new(LevelMeter::default());
+    let peer = Arc::new(LevelMeter::default());
+    let mic_ref = mic.clone();
+    let peer_ref = peer.clone();
+    client.on_input_frame(move |frame| mic_ref.record(frame));
+    client.on_output_frame(move |frame| peer_ref.record(frame));
+
+    thread::spawn(move || {
+        loop {
+            thread::sleep(METER_INTERVAL);
+            let duration = match controls.state() {
+                SessionState::Connected => format_duration(controls.call_duration()),
+                _ => " ".repeat(8),
+            };
+            print!(
+                "\rmic {}  {} {}  {duration}",
+                meter::bar(mic.take()),
+                controls.peer_name().unwrap_or(String::from("peer")),
+                meter::bar(peer.take())
+            );
+            let _ = std::io::stdout().flush();
+        }
+    });
+}
+
+/// Logs the RMS level and the clipped samples of your mic (after gain) and of the played
+/// audio (after volume), to help with setting `--mic-gain`, the noise gate and `--volume`.
+fn attach_audio_diagnostics(client: &mut Client) {
+    let mic = Arc::new(LevelStats::default());
+    let peer = Arc::new(LevelStats::default());
+    let mic_ref = mic.clone();
+    let peer_ref = peer.clone();
+    client.on_input_frame(move |frame| mic_ref.record(frame));
+    client.on_output_frame(move |frame| peer_ref.record(frame));
+
+    thread::spawn(move || {
+        loop {
+            thread::sleep(DIAGNOSTICS_INTERVAL);
+            info!(target: DIAGNOSTICS_TARGET, "mic: {}", mic.take());
+            info!(target: DIAGNOSTICS_TARGET, "played: {}", peer.take());
+        }
+    });
+}
+
+/// Logs the stats of the running call, at debug level so they only end up in `--log-file`
+/// (unless `RUST_LOG` asks for them on the console too).
+fn attach_stats_log(client: &Client) {
+    let controls = client.controls();
+    thread::spawn(move || {
+        loop {
+            thread::sleep(STATS_LOG_INTERVAL);
+            if controls.state() != SessionState::Connected {
+                continue;
+            }
+            let stats = controls.stats();
+            let rtt = stats
+                .rtt()
+                .map_or(String::from("-"), |rtt| format!("{} ms", rtt.as_millis()));
+            debug!(
+                "stats: sent {} frames ({} bytes, {} kbps), received {} frames ({} bytes), \
+                 rtt {rtt}, jitter target {} ms, drift {} ppm, underruns {}, overflows {}, \
+                 latency skips {}, out of order {}, duplicates {}, corrupt {}, undecodable {}, \
+                 concealed {}, throttled {}, dropped samples {}",
+                stats.frames_sent(),
+                stats.bytes_sent(),
+                stats.send_bitrate() / 1000,
+                stats.frames_received(),
+                stats.bytes_received(),
+                stats.jitter_target().as_millis(),
+                stats.clock_drift_ppm(),
+                stats.underruns(),
+                stats.buffer_overflows(),
+                stats.latency_skips(),
+                stats.frames_out_of_order(),
+                stats.duplicate_frames(),
+                stats.corrupt_frames(),
+                stats.undecodable_frames(),
+                stats.concealed_frames(),
+                stats.throttled_frames(),
+                stats.dropped_samples(),
+            );
+        }
+    });
+}
+
+/// Sends every record to the console and to a log file, each with its own filter.
+struct TeeLogger {
+    console: env_logger::Logger,
+    file: env_logger::Logger,
+}
+
+impl Log for TeeLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.console.enabled(metadata) || self.file.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.console.log(record);
+        self.file.log(record);
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+        self.file.flush();
+    }
+}
+
+/// Logs to the console as set by `RUST_LOG` (default: info, or only warnings, errors and
+/// `--verbose-audio` with `--quiet`), and with `--log-file <path>`
+/// also to that file. The file gets debug records of this crate with millisecond timestamps,
+/// each flushed as it is written, so nothing is lost when the program exits or crashes.
+fn init_logging(all_args: &[String]) -> std::io::Result<()> {
+    let default_filter = if args::flag(all_args, "--quiet") {
+        format!("warn,{DIAGNOSTICS_TARGET}=info")
+    } else {
+        String::from("info")
+    };
+    let console =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter))
+            .build();
+    let Some(path) = args::value(all_args, "--log-file") else {
+        log::set_max_level(console.filter());
+        let _ = log::set_boxed_logger(Box::new(console));
+        return Ok(());
+    };
+    let file = env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Info)
+        .filter_module("voice_chat", log::LevelFilter::Debug)
+        .format_timestamp_millis()
+        .target(env_logger::Target::Pipe(Box::new(RotatingFile::open(
+            path,
+        )?)))
+        .build();
+    log::set_max_level(console.filter().max(file.filter()));
+    let _ = log::set_boxed_logger(Box::new(TeeLogger { console, file }));
+    Ok(())
+}
+
+/// Checks that a client target is `IP:PORT` or `HOSTNAME:PORT` and resolves, so typos get a
+/// clear message instead of a low-level connect error.
+fn validate_target(target: &str) -> Result<(), String> {
+    let expected = "expected IP:PORT or HOSTNAME:PORT, e.g. '192.168.121.2:8888'";
+    let Some((host, port)) = target.rsplit_once(':') else {
+        return Err(format!(
+            "Invalid target '{target}': missing port, {expected}"
+        ));
+    };
+    if port.parse::<u16>().is_err() {
+        return Err(format!(
+            "Invalid target '{target}': bad port '{port}', {expected}"
+        ));
+    }
+    // The resolver would read a shortened IP like '192.168.1' as '192.168.0.1'.
+    if host.chars().all(|c| c.is_ascii_digit() || c == '.') && host.parse::<Ipv4Addr>().is_err() {
+        return Err(format!(
+            "Invalid target '{target}': bad IP '{host}', {expected}"
+        ));
+    }
+    match target.to_socket_addrs() {
+        Ok(addrs) if addrs.len() > 0 => Ok(()),
+        Ok(_) => Err(format!(
+            "Invalid target '{target}': '{host}' has no address"
+        )),
+        Err(e) => Err(format!("Invalid target '{target}': {e}, {expected}")),
+    }
+}
+
+/// Lists the servers advertised on the LAN and lets the user pick one.
+#[cfg(feature = "mdns")]
+fn pick_server() -> Result<String, Box<dyn Error>> {
+    info!("Looking for servers...");
+    let servers = voice_chat::discovery::discover(DISCOVERY_TIME)?;
+    if servers.is_empty() {
+        return Err("No servers found!".into());
+    }
+    for (i, server) in servers.iter().enumerate() {
+        println!("  [{}] {} ({})", i + 1, server.name, server.address);
+    }
+    print!("Connect to [1-{}]: ", servers.len());
+    std::io::stdout().flush()?;
+    let mut choice = String::new();
+    std::io::stdin().read_line(&mut choice)?;
+    match choice.trim().parse::<usize>() {
+        Ok(n) if (1..=servers.len()).contains(&n) => Ok(servers[n - 1].address.to_string()),
+        _ => Err(format!("Invalid choice '{}'", choice.trim()).into()),
+    }
+}
+
+#[cfg(not(feature = "mdns"))]
+fn pick_server() -> Result<String, Box<dyn Error>> {
+    Err("--discover needs the mdns feature, build with --features mdns".into())
+}
+
+/// The address a server or mixer listens to. A bare port listens on the local IP, anything
+/// else is bound as given.
+fn listen_address(target: Option<String>, ipv6: bool) -> String {
+    let target = target.unwrap_or(String::from("8888"));
+    match target.parse::<u16>() {
+        Ok(port) => {
+            let ip = if ipv6 {
+                local_ipv6().unwrap_or(IpAddr::V6(Ipv6Addr::LOCALHOST))
+            } else {
+                local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            };
+            SocketAddr::new(ip, port).to_string()
+        }
+        Err(_) => target,
+    }
+}
+
+/// Ends the call (or the wait for one) on Ctrl-C, or when the process is asked to terminate,
+/// as if it was hung up: the peer is told and the audio streams are stopped, so the devices
+/// are free for the next start. A second Ctrl-C exits at once.
+fn stop_on_ctrl_c(client: &mut Client) {
+    #[cfg(unix)]
+    let signals = Signals::new([Signal::Int, Signal::Term]);
+    #[cfg(not(unix))]
+    let signals = Signals::new([Signal::Int]);
+    let mut signals = match signals {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!("Ctrl-C will exit without hanging up: {e}");
+            return;
+        }
+    };
+    let (stop, shutdown) = smol::channel::bounded(1);
+    client.set_shutdown(shutdown);
+    thread::spawn(move || {
+        smol::block_on(async {
+            if signals.next().await.is_some() {
+                // Closed rather than sent to, so it also ends the wait for the next call.
+                stop.close();
+            }
+            if signals.next().await.is_some() {
+                std::process::exit(130);
+            }
+        })
+    });
+}
+
+/// Reads lines from stdin while a call is running: commands, or else text messages for the
+/// peer. Messages from the peer are printed.
+fn attach_hotkeys(client: &mut Client) {
+    let controls = client.controls();
+    let names = controls.clone();
+    client.on_text(move |text| {
+        let name = names.peer_name().unwrap_or(String::from("peer"));
+        println!("[{name}] {text}");
+    });
+    info!(
+        "Type a message and press Enter to send it, 'd' + Enter to deafen/undeafen, 'm' + Enter to mute/unmute, 'o' + Enter to monitor your mic, or 'q' + Enter to hang up"
+    );
+    info!("Through a mixer, 'v <id> <gain>' + Enter sets how loud you hear that client");
+    info!("With --pan, 'p <id> <-1..1|auto>' + Enter sets where you hear them");
+    thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            match line.trim() {
+                "d" => {
+                    if controls.toggle_deafen() {
+                        info!("Deafened, the peer can still hear you");
+                    } else {
+                        info!("Undeafened");
+                    }
+                }
+                "q" => controls.hang_up(),
+                "m" => {
+                    if controls.toggle_mute() {
+                        info!("Muted, the peer no longer hears you");
+                    } else {
+                        info!("Unmuted");
+                    }
+                }
+                "o" => {
+                    if controls.toggle_monitor() {
+                        warn!("Monitoring your mic, use headphones: on speakers it feeds back");
+                    } else {
+                        info!("Stopped monitoring your mic");
+                    }
+                }
+                "" => {}
+                command if command.starts_with("v ") => {
+                    let mut words = command.split_whitespace().skip(1);
+                    match (
+                        words
+                            .next()
+                            .and_then(|id| id.trim_start_matches('#').parse().ok()),
+                        words.next().and_then(|gain| gain.parse::<f32>().ok()),
+                    ) {
+                        (Some(id), Some(gain)) if gain >= 0.0 => {
+                            controls.set_peer_gain(id, gain);
+                            info!("Playing client #{id} at {gain}");
+                        }
+                        _ => error!("Usage: v <id> <gain>, e.g. 'v 3 0.5'"),
+                    }
+                }
+                command if command.starts_with("p ") => {
+                    let mut words = command.split_whitespace().skip(1);
+                    let id = words
+                        .next()
+                        .and_then(|id| id.trim_start_matches('#').parse().ok());
+                    let pan = match words.next() {
+                        Some("auto") => Some(None),
+                        Some(pan) => pan
+                            .parse::<f32>()
+                            .ok()
+                            .filter(|pan| (-1.0..=1.0).contains(pan))
+                            .map(Some),
+                        None => None,
+                    };
+                    match (id, pan) {
+                        (Some(id), Some(Some(pan))) => {
+                            controls.set_peer_pan(id, Some(pan));
+                            info!("Placing client #{id} at {pan}");
+                        }
+                        (Some(id), Some(None)) => {
+                            controls.set_peer_pan(id, None);
+                            info!("Placing client #{id} automatically");
+                        }
+                        _ => error!("Usage: p <id> <-1..1|auto>, e.g. 'p 3 -1' for the left"),
+                    }
+                }
+                _ => controls.send_text(&line),
+            }
+        }
+    });
+}
+
+fn main() {
+    let all_args: Vec<String> = env::args().collect();
+    if let Err(e) = init_logging(&all_args) {
+        eprintln!("Failed to open the log file: {e}");
+        std::process::exit(1);
+    }
+    // Shown with Display, which reads better than the Debug output of returning the error.
+    if let Err(e) = run() {
+        error!("{e}");
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let all_args: Vec<String> = env::args().collect();
+    let args: Vec<String> = args::positional(&all_args);
+    let show_meter = args::flag(&all_args, "--meter");
+    let verbose_audio = args::flag(&all_args, "--verbose-audio");
+    let ipv6 = args::flag(&all_args, "--ipv6");
+    let discover = args::flag(&all_args, "--discover");
+    let calibrate = args::flag(&all_args, "--calibrate");
+    let log_file = args::value(&all_args, "--log-file").is_some();
+    let config = Config::load(&all_args)?;
+    if args::flag(&all_args, "--list-hosts") {
+        let (names, default) = voice_chat::host_names();
+        println!("Audio hosts:");
+        for name in names {
+            let default = if name == default { " (default)" } else { "" };
+            println!("  {name}{default}");
+        }
+        return Ok(());
+    }
+    if args::flag(&all_args, "--list-devices") {
+        let (inputs, outputs) = voice_chat::device_names()?;
+        for (kind, names) in [("Input", inputs), ("Output", outputs)] {
+            println!("{kind} devices:");
+            for (number, name) in (1..).zip(names) {
+                println!("  [{number}] {name}");
+            }
+        }
+        return Ok(());
+    }
+
+    // MODE and TARGET fall back to the config file when missing on the command line.
+    let Some(mode) = args.get(1).cloned().or(config.mode) else {
+        println!("How to use:\nvoice-chat [MODE] [TARGET] (input device) (output device) (flags)");
         println!("MODE:    -s | --server    -> start a server/ listen for connections");
         println!("         -c | --client    -> connect as a client to a server");
-        println!("TARGET:  if SERVER  -> Port to listen to (default: 8888)");
-        println!("         if CLIENT  -> IP:Port to connect to (e.g. '192.168.121.2:8888')");
-        println!("If input and/or output device are not specefied, the default will be used.");
+        println!("         -l | --loopback  -> play your mic on your speakers (no TARGET)");
+        println!("         -m | --mixer     -> start a server for any number of clients");
+        println!("         -t | --test-tone -> play a beep on your speakers (no TARGET)");
+        println!(
+            "         --measure-latency -> time clicks from your speakers to your mic (no TARGET)"
+        );
+        println!("         --rtp            -> stream RTP to TARGET and play RTP from --rtp-port");
+        println!("         --status         -> show who is connected to the mixer at TARGET");
+        println!("TARGET:  if SERVER or MIXER -> Port or IP:Port to listen to (default: 8888)");
+        println!("         if RTP     -> IP:Port to send the RTP stream to");
+        println!(
+            "         if CLIENT or STATUS -> IP:Port or Hostname:Port to connect to (e.g. '192.168.121.2:8888', '[::1]:8888' or 'alices-laptop.local:8888')"
+        );
+        println!(
+            "If input and/or output device are not specefied, the last used (or else the default) will be used."
+        );
+        println!("During a call, type 'd' + Enter to deafen/undeafen (the peer still hears you).");
+        println!("During a call, type 'm' + Enter to mute/unmute (you still hear the peer).");
+        println!("During a call, type 'o' + Enter to start/stop hearing your own mic.");
+        println!("During a call, type 'q' + Enter to hang up.");
+        println!(
+            "Through a mixer, type 'v <id> <gain>' + Enter to set how loud you hear a client."
+        );
+        println!("With --pan, type 'p <id> <-1..1|auto>' + Enter to set where you hear them.");
+        println!("Any other line you type is sent to the peer as a text message.");
+        println!("Devices can be given by name or by their number from --list-devices.");
+        println!("FLAGS:   --allow-loopback -> allow connecting to this instance itself");
+        println!("         --list-devices   -> list the devices with their numbers and exit");
+        println!("         --discover       -> pick a server found on the LAN (no TARGET)");
+        println!("         --channels <n>   -> preferred channel count (e.g. 1 or 2)");
+        println!("         --mono           -> send your mic in mono to save bandwidth");
+        println!("         --send-rate <hz> -> downsample your mic before sending, e.g. 16000");
+        println!("         --half-duplex    -> mute your mic while the peer is heard (no echo)");
+        println!("         --aec            -> cancel the echo of the peer from your mic");
+        println!("         --calibrate      -> set --mic-gain from a few seconds of your voice");
+        println!("         --sidetone <l>   -> hear your own mic at this level (headphones only)");
+        println!(
+            "         --monitor        -> start with monitoring your mic on (headphones only)"
+        );
+        println!("         --monitor-level <l> -> how loud you hear it (default: 1.0)");
+        println!("         --agc            -> level your voice with automatic gain control");
+        println!("         --agc-target <l> -> RMS level the AGC aims for (default: 0.1)");
+        println!("         --meter          -> show the levels of your mic and the peer");
+        println!("         --verbose-audio  -> log RMS levels and clipping every 2 seconds");
+        println!("         --quiet          -> no banner, only log warnings and errors");
+        println!(
+            "         --log-file <path> -> also log in detail to a file, e.g. to report a bug"
+        );
+        println!("         --auto-port      -> listen to another port if the given one is in use");
+        println!(
+            "         --ipv6           -> listen on the local IPv6 address if only a port is given"
+        );
+        println!("         --host <name>    -> audio host to use, e.g. jack or alsa");
+        println!("         --list-hosts     -> list the available audio hosts and exit");
+        println!("         --config <path>  -> config file to use (default: voice-chat.toml)");
+        println!("         --sample-rate <hz> -> preferred sample rate (default: 22050)");
+        println!("         --prefer-sample-rate <hz,...> -> sample rates to try, in order");
+        println!("         --mic-gain <g>   -> how loud you are for the peer (default: 1.0)");
+        println!("         --volume <v>     -> how loud the peer is for you (default: 1.0)");
+        println!("         --channel-gains <l,r> -> how loud each output channel is, e.g. 1.0,0.5");
+        println!("         --no-noise-gate  -> don't silence the quiet parts of your mic");
+        println!("         --raw            -> send your mic unprocessed, e.g. for music");
+        println!("         --gate-open <l>  -> level that opens the noise gate (default: 0.01)");
+        println!("         --gate-close <l> -> level that closes it again (default: 0.005)");
+        println!("         --gate-attack <ms> -> how fast the gate opens (default: 5)");
+        println!("         --gate-release <ms> -> how fast it closes (default: 150)");
+        println!("         --vad            -> only send audio while you are talking");
+        println!("         --vad-aggressiveness <0-3> -> how clear speech must be (default: 1)");
+        println!("         --no-dither      -> don't dither the audio on 16-bit output devices");
+        println!("         --soft-limit     -> round off loud peaks instead of cutting them off");
+        println!(
+            "         --limiter-threshold <l> -> turn down received peaks above (default: 0.89)"
+        );
+        println!("         --limiter-release <ms> -> how fast that recovers (default: 100)");
+        println!("         --low-latency    -> pick the device config with the smallest buffers");
+        println!(
+            "         --loopback-input -> send what your computer plays (monitor sources only)"
+        );
+        println!("         --strict-config  -> fail if the input and output configs don't match");
+        println!(
+            "         --max-latency <ms> -> skip ahead if more audio piles up (default: 3000)"
+        );
+        println!(
+            "         --jitter-target <ms> -> keep this much audio buffered (default: adapts)"
+        );
+        println!("         --buffer-frames <n> -> device buffer size (lower: less latency)");
+        println!("         --input-format <hz:ch[:frames]> -> open the mic in exactly this format");
+        println!("         --output-format <hz:ch[:frames]> -> same for playback");
+        println!("         --best-input     -> use the mic with the highest sample rate/channels");
+        println!("         --best-output    -> same for playback");
+        println!("         --no-tcp-nodelay -> let the OS batch small frames (adds latency)");
+        println!("         --tcp-send-buffer <bytes> -> OS send buffer size of the socket");
+        println!("         --tcp-recv-buffer <bytes> -> OS receive buffer size of the socket");
+        println!("         --duration <s>   -> hang up after this many seconds");
+        println!(
+            "         --connect-timeout <s> -> how long a client waits for the server (default: 30)"
+        );
+        println!("         --timeout <s>    -> hang up on a peer silent this long (default: 10)");
+        println!("         --input-file <path> -> send a WAV file (on a loop) instead of your mic");
+        println!("         --greeting <path> -> send a WAV file once at the start of every call");
+        println!("         --dump-samples <path> -> write sent and received audio to files");
+        println!("         --record-split <path> -> record your mic and the peer to two WAV files");
+        println!("         --wire-format <f> -> send samples as f32 or i16 (half the bandwidth)");
+        println!("         --max-bitrate <kbps> -> cap the average send rate, e.g. on weak Wi-Fi");
+        println!("         --frame-samples <n> -> send frames of exactly n samples per channel");
+        println!("         --forget-devices -> don't use (and forget) the last used devices");
+        println!("         --room <name>    -> room to join on a mixer (default: 'default')");
+        println!("         --name <name>    -> shown to the peer instead of your address");
+        println!(
+            "         --max-clients <n> -> clients a mixer accepts at once (default: no limit)"
+        );
+        println!("         --join-sounds    -> a mixer chimes when someone joins or leaves");
+        println!("         --pan            -> a mixer spreads the voices across the stereo field");
+        println!("         --comfort-noise  -> play soft noise while the peer is silent");
+        println!("         --comfort-noise-level <l> -> level of that noise (default: 0.001)");
         return Ok(());
+    };
+    let target = args.get(2).cloned().or(config.target);
+    if !args::flag(&all_args, "--quiet") {
+        println!(r" _   _       _          _____  _   _   ___ _____ ");
+        println!(r"| | | |     (_)        /  __ \| | | | / _ \_   _|");
+        println!(r"| | | | ___  _  ___ ___| /  \/| |_| |/ /_\ \| |  ");
+        println!(r"| | | |/ _ \| |/ __/ _ \ |    |  _  ||  _  || |  ");
+        println!(r"\ \_/ / (_) | | (_|  __/ \__/\| | | || | | || |  ");
+        println!(r" \___/ \___/|_|\___\___|\____/\_| |_/\_| |_/\_/  ");
     }
-    println!(r" _   _       _          _____  _   _   ___ _____ ");
-    println!(r"| | | |     (_)        /  __ \| | | | / _ \_   _|");
-    println!(r"| | | | ___  _  ___ ___| /  \/| |_| |/ /_\ \| |  ");
-    println!(r"| | | |/ _ \| |/ __/ _ \ |    |  _  ||  _  || |  ");
-    println!(r"\ \_/ / (_) | | (_|  __/ \__/\| | | || | | || |  ");
-    println!(r" \___/ \___/|_|\___\___|\____/\_| |_/\_| |_/\_/  ");
 
-    match args[1].as_str() {
+    match mode.as_str() {
         "-s" | "--server" => {
-            println!("Starting server...");
-            let ip = local_ip().unwrap_or(IpAddr::from_str("127.0.0.1").unwrap());
-            let port: String = args.get(2).unwrap_or(&String::from("8888")).to_string();
-            let mut client = Client::new(format!("{ip}:{port}"))?;
-            println!("Listening to {}...", client.address);
+            info!("Starting server...");
+            let mut client = Client::new(listen_address(target, ipv6))?;
+            if calibrate {
+                client.calibrate_mic_gain()?;
+            }
+            stop_on_ctrl_c(&mut client);
+            if show_meter {
+                attach_meter(&mut client);
+            }
+            if verbose_audio {
+                attach_audio_diagnostics(&mut client);
+            }
+            attach_hotkeys(&mut client);
+            if log_file {
+                attach_stats_log(&client);
+            }
             smol::block_on(async { client.listen().await })?;
         }
+        "-m" | "--mixer" => {
+            info!("Starting mixer...");
+            Mixer::new(listen_address(target, ipv6))?.run()?;
+        }
         "-c" | "--client" => {
-            println!("Starting client...");
-            let address = args
-                .get(2)
-                .unwrap_or(&String::from("127.0.0.1:8888"))
-                .to_string();
+            info!("Starting client...");
+            let address = if discover {
+                pick_server()?
+            } else {
+                target.unwrap_or(String::from("127.0.0.1:8888"))
+            };
+            validate_target(&address)?;
             let mut client = Client::new(address)?;
-            println!("Trying to connect to {}...", client.address);
+            if calibrate {
+                client.calibrate_mic_gain()?;
+            }
+            stop_on_ctrl_c(&mut client);
+            if show_meter {
+                attach_meter(&mut client);
+            }
+            if verbose_audio {
+                attach_audio_diagnostics(&mut client);
+            }
+            attach_hotkeys(&mut client);
+            if log_file {
+                attach_stats_log(&client);
+            }
+            info!("Trying to connect to {}...", client.address);
             smol::block_on(async { client.connect().await })?;
         }
+        "-l" | "--loopback" => {
+            info!("Starting loopback...");
+            let mut client = Client::new(String::new())?;
+            stop_on_ctrl_c(&mut client);
+            if show_meter {
+                attach_meter(&mut client);
+            }
+            if verbose_audio {
+                attach_audio_diagnostics(&mut client);
+            }
+            client.loopback()?;
+        }
+        "-t" | "--test-tone" => {
+            info!("Starting test tone...");
+            let mut client = Client::new(String::new())?;
+            stop_on_ctrl_c(&mut client);
+            if show_meter {
+                attach_meter(&mut client);
+            }
+            if verbose_audio {
+                attach_audio_diagnostics(&mut client);
+            }
+            client.test_tone()?;
+        }
+        #[cfg(feature = "rtp")]
+        "--rtp" => {
+            let Some(address) = target else {
+                return Err("--rtp needs a TARGET to send to, e.g. '192.168.121.2:5004'".into());
+            };
+            validate_target(&address)?;
+            info!("Starting RTP...");
+            let mut client = Client::new(address)?;
+            if calibrate {
+                client.calibrate_mic_gain()?;
+            }
+            stop_on_ctrl_c(&mut client);
+            if show_meter {
+                attach_meter(&mut client);
+            }
+            if verbose_audio {
+                attach_audio_diagnostics(&mut client);
+            }
+            if log_file {
+                attach_stats_log(&client);
+            }
+            client.rtp()?;
+        }
+        #[cfg(not(feature = "rtp"))]
+        "--rtp" => {
+            return Err("--rtp needs the rtp feature, build with --features rtp".into());
+        }
+        "--status" => {
+            let address = target.unwrap_or(String::from("127.0.0.1:8888"));
+            validate_target(&address)?;
+            println!("{}", voice_chat::mixer::query_status(&address)?);
+        }
+        "--measure-latency" => {
+            info!("Starting latency measurement...");
+            let mut client = Client::new(String::new())?;
+            match client.measure_latency()? {
+                Some(latency) => println!(
+                    "Round-trip latency: {:.1} ms",
+                    latency.as_secs_f64() * 1000.0
+                ),
+                None => {
+                    return Err(
+                        "No click was picked up. Connect the output to the input with a \
+                                cable, or hold the mic close to the speakers and turn them up."
+                            .into(),
+                    );
+                }
+            }
+        }
         _ => {
-            eprintln!("Invalid argument '{}'", args[1]);
+            return Err(format!("Invalid argument '{mode}'").into());
         }
     }
 
@@ -0,0 +1,97 @@
+//! Level metering for displaying how loud the captured and received audio is.
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Width of the bar drawn by [`bar`], in characters.
+pub const BAR_WIDTH: usize = 30;
+
+/// Keeps the peak level of all frames recorded since it was last taken.
+///
+/// Cheap enough to be fed from the audio callbacks.
+#[derive(Default)]
+pub struct LevelMeter {
+    /// Bits of the peak as an `f32`. Non-negative floats order like their bits, so
+    /// `fetch_max` works on them.
+    peak: AtomicU32,
+}
+
+impl LevelMeter {
+    pub fn record(&self, frame: &[f32]) {
+        let peak = frame.iter().fold(0.0f32, |peak, f| peak.max(f.abs()));
+        self.peak.fetch_max(peak.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Returns the peak since the last call and resets it.
+    pub fn take(&self) -> f32 {
+        f32::from_bits(self.peak.swap(0, Ordering::Relaxed))
+    }
+}
+
+/// RMS level and clipped samples of all frames recorded since the summary was last taken.
+#[derive(Default)]
+pub struct LevelStats {
+    /// Sum of squares, samples and clipped samples. The lock is only held for a few additions,
+    /// which the audio callbacks can afford.
+    totals: Mutex<(f64, u64, u64)>,
+}
+
+/// What [`LevelStats::take`] returns.
+pub struct LevelSummary {
+    pub rms: f32,
+    /// Samples at or beyond ±1.0.
+    pub clipped: u64,
+    pub samples: u64,
+}
+
+impl LevelStats {
+    pub fn record(&self, frame: &[f32]) {
+        let squares: f64 = frame.iter().map(|f| (*f as f64) * (*f as f64)).sum();
+        let clipped = frame.iter().filter(|f| f.abs() >= 1.0).count() as u64;
+        if let Ok(mut totals) = self.totals.lock() {
+            totals.0 += squares;
+            totals.1 += frame.len() as u64;
+            totals.2 += clipped;
+        }
+    }
+
+    /// Returns the summary since the last call and resets it.
+    pub fn take(&self) -> LevelSummary {
+        let (squares, samples, clipped) = match self.totals.lock() {
+            Ok(mut totals) => std::mem::take(&mut *totals),
+            Err(_) => (0.0, 0, 0),
+        };
+        let rms = if samples == 0 {
+            0.0
+        } else {
+            (squares / samples as f64).sqrt() as f32
+        };
+        LevelSummary {
+            rms,
+            clipped,
+            samples,
+        }
+    }
+}
+
+impl std::fmt::Display for LevelSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let dbfs = 20.0 * self.rms.max(1e-6).log10();
+        write!(
+            f,
+            "RMS {:.4} ({dbfs:.1} dBFS), {} of {} samples clipped",
+            self.rms, self.clipped, self.samples
+        )
+    }
+}
+
+/// Draws a level between 0.0 and 1.0 as a bar, marking clipping (samples hitting ±1.0).
+pub fn bar(level: f32) -> String {
+    let filled = ((level.clamp(0.0, 1.0) * BAR_WIDTH as f32).round() as usize).min(BAR_WIDTH);
+    let clip = if level >= 1.0 { "CLIP" } else { "    " };
+    format!(
+        "[{}{}] {level:.2} {clip}",
+        "#".repeat(filled),
+        " ".repeat(BAR_WIDTH - filled)
+    )
+}
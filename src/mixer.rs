@@ -0,0 +1,1288 @@
+//! A server for more than two people: every client hears the others in its room mixed
+//! together, but not itself.
+
+use super::concealment::LossConcealer;
+use super::error::VoiceChatError;
+use super::protocol::{
+    ByteOrder, FrameReader, FrameType, FrameWriter, Handshake, Reorderer, WireFormat,
+    audio_payload, held_intervals, peer_gain_body,
+};
+use super::resample::Resampler;
+use super::session::format_duration;
+use super::stats::Stats;
+use super::util::{Limiter, decode_audio, limit, remix};
+use super::{Opt, PEER_TIMEOUT, SEND_INTERVAL, SLEEP_DURATION, bind};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::f32::consts::{FRAC_PI_4, SQRT_2};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Rooms are told apart by their names, as sent in the handshake.
+pub type RoomName = String;
+/// Clients are numbered in the order they connect. The IDs are announced in the room, so
+/// clients can refer to each other, e.g. to turn someone down.
+pub type ClientId = u64;
+/// Sent by clients in the handshake and kept across their calls, see [`Handshake::session`].
+type SessionId = u64;
+
+/// How much audio a client may have queued, in mixing intervals (of [`SEND_INTERVAL`]), as a
+/// margin against network jitter. Older audio is dropped, so a client that sends too fast
+/// doesn't build up delay.
+const MAX_QUEUED_INTERVALS: usize = 10;
+/// A client that can't take a frame for this long is dropped, so it can't hold up the others.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(200);
+/// A client that reconnects within this time after leaving gets its slot back: its ID, and
+/// with it the gains the others chose for it, and its own gains.
+const REJOIN_TIMEOUT: Duration = Duration::from_secs(60);
+/// Peak level of the `--join-sounds` chimes, well below speech so they don't cover anyone up.
+const CHIME_LEVEL: f32 = 0.1;
+/// Each chime is two short notes: a rising pair when someone joins, a falling one when they
+/// leave.
+const CHIME_NOTES: [f32; 2] = [660.0, 880.0];
+const CHIME_NOTE_LENGTH: f32 = 0.08;
+/// Fade in and out of each note, against clicks.
+const CHIME_FADE: f32 = 0.005;
+/// With `--pan`, the others are spread over this much of the stereo field on either side of
+/// the middle, as voices coming from only one ear are tiring.
+const PAN_WIDTH: f32 = 0.8;
+
+struct ClientState {
+    id: ClientId,
+    /// Numbers the accepted connections. Unlike the ID, it isn't reused when a client rejoins,
+    /// so the thread of a replaced connection can't remove the new one.
+    connection: u64,
+    session: SessionId,
+    addr: SocketAddr,
+    /// From the handshake, empty if the client has none.
+    name: String,
+    joined: Instant,
+    /// Counted by the client's own thread as it reads.
+    bytes_received: Arc<AtomicU64>,
+    bytes_sent: u64,
+    /// Both counts when the rates were last updated, and the rates since then in bytes per
+    /// second.
+    counted: (u64, u64),
+    rates: (u64, u64),
+    /// Chimes that are still to be played to this client, mixed into its next chunks.
+    chime: VecDeque<f32>,
+    /// Written by the mixing thread and (for pongs) the client's own thread, always under the
+    /// lock, so frames never interleave.
+    stream: TcpStream,
+    /// Received audio that wasn't mixed yet, already in the mixer's format.
+    queue: VecDeque<f32>,
+    sequence: u32,
+    /// How loud this client hears the others, by their ID. Missing ones are played at 1.0.
+    gains: HashMap<ClientId, f32>,
+    /// Where this client hears the others with `--pan`, by their ID, from -1.0 (left) to 1.0
+    /// (right). Missing ones are spread out automatically, see [`auto_pan`].
+    pans: HashMap<ClientId, f32>,
+}
+
+impl ClientState {
+    /// Writes a frame. A client that can't take it is disconnected, as it would otherwise
+    /// read a partial frame; its thread notices the closed socket and removes it.
+    fn send(&mut self, writer: &FrameWriter) {
+        match writer.write(&mut self.stream) {
+            Ok(size) => self.bytes_sent += size as u64,
+            Err(e) => {
+                debug!("Dropping client #{}, which can't keep up: {e}", self.id);
+                let _ = self.stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    /// Called once per [`SLEEP_DURATION`].
+    fn update_rates(&mut self) {
+        let counts = (self.bytes_received.load(Ordering::Relaxed), self.bytes_sent);
+        let per_second =
+            |now: u64, before: u64| ((now - before) as f64 / SLEEP_DURATION.as_secs_f64()) as u64;
+        self.rates = (
+            per_second(counts.0, self.counted.0),
+            per_second(counts.1, self.counted.1),
+        );
+        self.counted = counts;
+    }
+
+    fn status(&self, room: &str) -> ClientStatus {
+        ClientStatus {
+            id: self.id,
+            room: room.to_string(),
+            address: self.addr.to_string(),
+            name: self.name.clone(),
+            connected: self.joined.elapsed().as_secs(),
+            receive_rate: self.rates.0,
+            send_rate: self.rates.1,
+        }
+    }
+}
+
+/// Counts the bytes read through it, for the status.
+struct CountingReader<'a, R> {
+    inner: R,
+    count: &'a AtomicU64,
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// What a mixer tells `--status`, see [`query_status`].
+#[derive(Serialize, Deserialize)]
+pub struct MixerStatus {
+    /// Since the mixer started, in seconds.
+    pub uptime: u64,
+    /// Whether the others are spread across the stereo field (`--pan`).
+    #[serde(default)]
+    pub panning: bool,
+    pub clients: Vec<ClientStatus>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClientStatus {
+    pub id: ClientId,
+    pub room: RoomName,
+    pub address: String,
+    /// Given with `--name`, empty if none was.
+    #[serde(default)]
+    pub name: String,
+    /// Since the client joined, in seconds.
+    pub connected: u64,
+    /// Over the last second, in bytes per second.
+    pub receive_rate: u64,
+    pub send_rate: u64,
+}
+
+impl fmt::Display for MixerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Up for {}, {} client(s)",
+            format_duration(Duration::from_secs(self.uptime)),
+            self.clients.len()
+        )?;
+        if self.panning {
+            write!(f, ", panning voices")?;
+        }
+        for client in &self.clients {
+            write!(
+                f,
+                "\n  {} in '{}' from {}, for {}, receiving {:.1} kB/s, sending {:.1} kB/s",
+                label(client.id, &client.name),
+                client.room,
+                client.address,
+                format_duration(Duration::from_secs(client.connected)),
+                client.receive_rate as f64 / 1000.0,
+                client.send_rate as f64 / 1000.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+type Rooms = Arc<Mutex<HashMap<RoomName, Vec<ClientState>>>>;
+
+/// How clients are named in the announcements and the status, e.g. `#3 (Alice)`, or just
+/// `#3` without a name.
+fn label(id: ClientId, name: &str) -> String {
+    match name {
+        "" => format!("#{id}"),
+        name => format!("#{id} ({name})"),
+    }
+}
+
+/// What the status tells about the mixer itself.
+#[derive(Clone, Copy)]
+struct MixerInfo {
+    started: Instant,
+    panning: bool,
+}
+
+/// The sounds `--join-sounds` plays to a room, in the mixer's format.
+struct Chimes {
+    join: Vec<f32>,
+    leave: Vec<f32>,
+}
+
+impl Chimes {
+    fn new(sample_rate: u32, channels: usize) -> Self {
+        let note_len = (CHIME_NOTE_LENGTH * sample_rate as f32) as usize;
+        let fade_len = (CHIME_FADE * sample_rate as f32).max(1.0);
+        let note = |frequency: f32| {
+            (0..note_len).map(move |i| {
+                let fade = (i.min(note_len - 1 - i) as f32 / fade_len).min(1.0);
+                let phase = std::f32::consts::TAU * frequency * i as f32 / sample_rate as f32;
+                phase.sin() * CHIME_LEVEL * fade
+            })
+        };
+        let chime = |notes: [f32; 2]| {
+            let mono: Vec<f32> = notes.into_iter().flat_map(note).collect();
+            remix(&mono, 1, channels)
+        };
+        let mut falling = CHIME_NOTES;
+        falling.reverse();
+        Chimes {
+            join: chime(CHIME_NOTES),
+            leave: chime(falling),
+        }
+    }
+}
+
+/// Mixes a chime into what is still to be played to each client, so chimes that overlap are
+/// heard together instead of one after the other.
+fn ring(clients: &mut [ClientState], chime: &[f32]) {
+    for client in clients {
+        let queued = client.chime.len();
+        for (pending, sample) in client.chime.iter_mut().zip(chime) {
+            *pending += sample;
+        }
+        client.chime.extend(chime.iter().skip(queued));
+    }
+}
+
+/// A client that left, kept for [`REJOIN_TIMEOUT`] in case it reconnects.
+struct DepartedClient {
+    id: ClientId,
+    gains: HashMap<ClientId, f32>,
+    pans: HashMap<ClientId, f32>,
+    left: Instant,
+}
+
+type Departed = Arc<Mutex<HashMap<SessionId, DepartedClient>>>;
+
+/// Mixes the audio of any number of clients, each of which connects like to a normal server.
+///
+/// Audio is mixed at `--sample-rate` with `--channels` (default: mono, or stereo with
+/// `--pan`). The clients convert to and from that themselves, like in a call between two
+/// people.
+pub struct Mixer {
+    address: String,
+    opt: Opt,
+    nonce: u64,
+    rooms: Rooms,
+    departed: Departed,
+    started: Instant,
+}
+
+impl Mixer {
+    pub fn new(address: String) -> Result<Self, VoiceChatError> {
+        Ok(Mixer::new_with(address, Opt::new()?))
+    }
+
+    /// [`Mixer::new`] with the options given in code, see [`Opt::from_args`].
+    pub fn new_with(address: String, opt: Opt) -> Self {
+        Mixer {
+            address,
+            opt,
+            nonce: RandomState::new().build_hasher().finish(),
+            rooms: Arc::default(),
+            departed: Arc::default(),
+            started: Instant::now(),
+        }
+    }
+
+    fn channels(&self) -> usize {
+        let default = if self.opt.pan { 2 } else { 1 };
+        self.opt.channels.unwrap_or(default) as usize
+    }
+
+    /// Accepts clients and mixes their audio, one thread per client plus one for mixing.
+    /// Only returns if listening fails.
+    pub fn run(&mut self) -> Result<(), VoiceChatError> {
+        let listener = bind(&self.address, self.opt.auto_port)?;
+        let bound_addr = listener.local_addr()?;
+        info!("Mixing for clients on {bound_addr}...");
+        #[cfg(feature = "mdns")]
+        let _advertisement = match super::discovery::advertise(bound_addr) {
+            Ok(advertisement) => Some(advertisement),
+            Err(e) => {
+                warn!("Failed to advertise the mixer: {e}");
+                None
+            }
+        };
+
+        let sample_rate = self.opt.sample_rate;
+        let channels = self.channels();
+        let format = self.opt.wire_format;
+        let rooms = self.rooms.clone();
+        let limiter = self.opt.limiter;
+        let panning = self.opt.pan && channels == 2;
+        if self.opt.pan && !panning {
+            warn!("--pan needs a stereo mixer, but it mixes {channels} channel(s), not panning");
+        }
+        thread::spawn(move || mix(&rooms, (sample_rate, channels), format, limiter, panning));
+        let info = MixerInfo {
+            started: self.started,
+            panning,
+        };
+        let chimes = self
+            .opt
+            .join_sounds
+            .then(|| Arc::new(Chimes::new(sample_rate, channels)));
+
+        // Counted from accepting until the client's thread ends, so clients that are still in
+        // the handshake count as well.
+        let connected = Arc::new(AtomicUsize::new(0));
+        for (connection, stream) in (0..).zip(listener.incoming()) {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept a connection: {e}");
+                    continue;
+                }
+            };
+            let handshake = Handshake {
+                sample_rate,
+                channels: channels as u16,
+                format,
+                byte_order: ByteOrder::Little,
+                nonce: self.nonce,
+                room: String::new(),
+                session: 0,
+                monitor: false,
+                name: String::new(),
+            };
+            let peer_addr = stream
+                .peer_addr()
+                .map_or(String::from("unknown peer"), |addr| addr.to_string());
+            // Covers the handshake as well as the call.
+            if let Err(e) = stream.set_read_timeout(Some(self.opt.timeout)) {
+                warn!("Failed to set up the connection from {peer_addr}: {e}");
+                continue;
+            }
+            let max_clients = self.opt.max_clients.unwrap_or(usize::MAX);
+            let admitted = connected
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                    (n < max_clients).then_some(n + 1)
+                })
+                .is_ok();
+            if !admitted {
+                info!("Turning away {peer_addr}, the mixer is full");
+                let rooms = self.rooms.clone();
+                thread::spawn(move || {
+                    let reason = format!("the mixer is full ({max_clients} clients)");
+                    if let Err(e) = reject(stream, handshake, &reason, &rooms, info) {
+                        debug!("Client {peer_addr}: {e}");
+                    }
+                });
+                continue;
+            }
+            let rooms = self.rooms.clone();
+            let departed = self.departed.clone();
+            let chimes = chimes.clone();
+            let connected = connected.clone();
+            thread::spawn(move || {
+                let result = serve(
+                    stream,
+                    connection,
+                    handshake,
+                    &rooms,
+                    &departed,
+                    chimes.as_deref(),
+                    info,
+                );
+                if let Err(e) = result {
+                    debug!("Client {peer_addr}: {e}");
+                }
+                connected.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Audio samples in one mixing interval, which is as long as a client's [`SEND_INTERVAL`].
+fn interval_len(sample_rate: u32, channels: usize) -> usize {
+    (sample_rate as f64 * SEND_INTERVAL.as_secs_f64()) as usize * channels
+}
+
+/// A client's audio for the next interval: whatever it has queued, up to `chunk_len` samples,
+/// in whole frames of `channels`. A frame that is late only leaves a gap in this client's
+/// voice instead of holding up the mix. `None` if nothing is queued.
+fn take_chunk(queue: &mut VecDeque<f32>, chunk_len: usize, channels: usize) -> Option<Vec<f32>> {
+    let len = queue.len().min(chunk_len);
+    let len = len - len % channels;
+    (len > 0).then(|| queue.drain(..len).collect())
+}
+
+/// What a client had chosen for how it hears the others: their gains and pan positions.
+type Choices = (HashMap<ClientId, f32>, HashMap<ClientId, f32>);
+
+/// The slot a client had before: if it reconnects with the session of a client that left less
+/// than [`REJOIN_TIMEOUT`] ago, or of one that is still connected from the same address. That
+/// connection probably died without the mixer noticing yet, it is closed. Claiming the session
+/// of a client that is connected from elsewhere gets a new slot, and no session.
+fn claim_slot(
+    rooms: &mut HashMap<RoomName, Vec<ClientState>>,
+    departed: &Departed,
+    session: &mut SessionId,
+    addr: SocketAddr,
+) -> Option<(ClientId, Choices)> {
+    if *session == 0 {
+        return None;
+    }
+    for clients in rooms.values_mut() {
+        if let Some(index) = clients.iter().position(|client| client.session == *session) {
+            if clients[index].addr.ip() != addr.ip() {
+                warn!(
+                    "{addr} claims the session of client #{}, which is connected from {}, \
+                     giving it a new one",
+                    clients[index].id, clients[index].addr
+                );
+                *session = 0;
+                return None;
+            }
+            let old = clients.remove(index);
+            let _ = old.stream.shutdown(Shutdown::Both);
+            rooms.retain(|_, clients| !clients.is_empty());
+            return Some((old.id, (old.gains, old.pans)));
+        }
+    }
+    let mut departed = departed.lock().ok()?;
+    departed.retain(|_, client| client.left.elapsed() < REJOIN_TIMEOUT);
+    departed
+        .remove(session)
+        .map(|client| (client.id, (client.gains, client.pans)))
+}
+
+/// Handles one client from the handshake until it leaves.
+fn serve(
+    mut stream: TcpStream,
+    connection: u64,
+    mut handshake: Handshake,
+    rooms: &Rooms,
+    departed: &Departed,
+    chimes: Option<&Chimes>,
+    info: MixerInfo,
+) -> Result<(), VoiceChatError> {
+    let peer_addr = stream.peer_addr()?;
+    // Like the clients, send the mixed audio at once instead of waiting for ACKs.
+    stream.set_nodelay(true)?;
+    let peer = match read_handshake(&mut stream) {
+        Ok(peer) => peer,
+        Err(e @ VoiceChatError::VersionMismatch(_)) => {
+            // Our handshake tells the client why, in a form that every version can read.
+            let _ = stream.write_all(&handshake.encode());
+            return Err(e);
+        }
+        Err(e @ VoiceChatError::Handshake(_)) => {
+            warn!("Refusing {peer_addr}: {e}");
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    // The room is echoed, so the client knows where it ended up.
+    handshake.room = peer.room.clone();
+    stream.write_all(&handshake.encode())?;
+    if peer.monitor {
+        return answer_status(&mut stream, rooms, info);
+    }
+
+    let sample_rate = handshake.sample_rate;
+    let channels = handshake.channels as usize;
+    let max_queued = interval_len(sample_rate, channels) * MAX_QUEUED_INTERVALS;
+    let room = peer.room.clone();
+    let mut id = connection;
+    let bytes_received = Arc::new(AtomicU64::new(0));
+    if let Ok(mut rooms) = rooms.lock() {
+        let mut session = peer.session;
+        let slot = claim_slot(&mut rooms, departed, &mut session, peer_addr);
+        let rejoined = slot.is_some();
+        let (gains, pans) = match slot {
+            Some((old_id, choices)) => {
+                id = old_id;
+                choices
+            }
+            None => Choices::default(),
+        };
+        let clients = rooms.entry(room.clone()).or_default();
+        clients.push(ClientState {
+            id,
+            connection,
+            session,
+            addr: peer_addr,
+            name: peer.name.clone(),
+            joined: Instant::now(),
+            bytes_received: bytes_received.clone(),
+            bytes_sent: 0,
+            counted: (0, 0),
+            rates: (0, 0),
+            chime: VecDeque::new(),
+            stream: stream.try_clone()?,
+            queue: VecDeque::new(),
+            sequence: 0,
+            gains,
+            pans,
+        });
+        let joined = if rejoined { "rejoined" } else { "joined" };
+        info!(
+            "{peer_addr} {joined} room '{room}' as client {}, with {} client(s) in it now",
+            label(id, &peer.name),
+            clients.len()
+        );
+        let others: Vec<String> = clients
+            .iter()
+            .filter(|client| client.id != id)
+            .map(|client| label(client.id, &client.name))
+            .collect();
+        let mut welcome = format!("You are client #{id} in room '{room}'");
+        if !others.is_empty() {
+            welcome.push_str(&format!(", with {}", others.join(", ")));
+        }
+        announce(clients, |client| client == id, &welcome);
+        announce(
+            clients,
+            |client| client != id,
+            &format!("Client {} {joined}", label(id, &peer.name)),
+        );
+        if let Some(chimes) = chimes {
+            ring(clients, &chimes.join);
+        }
+    }
+
+    let result = receive(
+        &mut CountingReader {
+            inner: &mut stream,
+            count: &bytes_received,
+        },
+        &peer,
+        |audio: Vec<f32>| {
+            with_client(rooms, &room, connection, |client| {
+                client.queue.extend(audio);
+                let excess = client.queue.len().saturating_sub(max_queued);
+                client.queue.drain(..excess);
+            });
+        },
+        |pong: &FrameWriter| {
+            with_client(rooms, &room, connection, |client| {
+                client.send(pong);
+            });
+        },
+        // Text goes to everyone else in the room.
+        |text: &FrameWriter| {
+            if let Ok(mut rooms) = rooms.lock()
+                && let Some(clients) = rooms.get_mut(&room)
+            {
+                for client in clients.iter_mut().filter(|client| client.id != id) {
+                    client.send(text);
+                }
+            }
+        },
+        |source: ClientId, choice: Choice| {
+            with_client(rooms, &room, connection, |client| match choice {
+                Choice::Gain(gain) => {
+                    client.gains.insert(source, gain);
+                }
+                Choice::Pan(Some(pan)) => {
+                    client.pans.insert(source, pan);
+                }
+                Choice::Pan(None) => {
+                    client.pans.remove(&source);
+                }
+            });
+        },
+        (sample_rate, channels),
+    );
+
+    if let Ok(mut rooms) = rooms.lock() {
+        if let Some(clients) = rooms.get_mut(&room) {
+            // Not there if a reconnect of the same client took over the slot.
+            if let Some(index) = clients
+                .iter()
+                .position(|client| client.connection == connection)
+            {
+                let client = clients.remove(index);
+                if client.session != 0
+                    && let Ok(mut departed) = departed.lock()
+                {
+                    departed.insert(
+                        client.session,
+                        DepartedClient {
+                            id,
+                            gains: client.gains,
+                            pans: client.pans,
+                            left: Instant::now(),
+                        },
+                    );
+                }
+                announce(
+                    clients,
+                    |_| true,
+                    &format!("Client {} left", label(id, &peer.name)),
+                );
+                if let Some(chimes) = chimes {
+                    ring(clients, &chimes.leave);
+                }
+            }
+            if clients.is_empty() {
+                rooms.remove(&room);
+            }
+        }
+        info!("{peer_addr} left room '{room}'");
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+/// Reads the peer's handshake, giving up after the stream's read timeout.
+fn read_handshake(stream: &mut TcpStream) -> Result<Handshake, VoiceChatError> {
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let receive_error = |e: std::io::Error| {
+        VoiceChatError::Handshake(format!("Failed to receive the peer's handshake: {e}"))
+    };
+    let mut prefix = [0u8; Handshake::PREFIX_SIZE];
+    stream.read_exact(&mut prefix).map_err(receive_error)?;
+    let mut body = vec![0; Handshake::decode_prefix(prefix)?];
+    stream.read_exact(&mut body).map_err(receive_error)?;
+    Handshake::decode(&body)
+}
+
+/// Answers the status requests of a `--status` connection until it hangs up.
+fn answer_status(
+    stream: &mut TcpStream,
+    rooms: &Rooms,
+    info: MixerInfo,
+) -> Result<(), VoiceChatError> {
+    let mut reader = FrameReader::default();
+    let mut writer = FrameWriter::default();
+    let stats = Stats::default();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = match stream.read(&mut chunk) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(VoiceChatError::Network(e)),
+        };
+        reader.push(&chunk[..n])?;
+        while let Some(frame) = reader.next_frame(&stats) {
+            match frame.first().copied().and_then(FrameType::from_byte) {
+                Some(FrameType::StatusRequest) => {
+                    let mut clients = Vec::new();
+                    if let Ok(rooms) = rooms.lock() {
+                        for (room, members) in rooms.iter() {
+                            clients.extend(members.iter().map(|client| client.status(room)));
+                        }
+                    }
+                    clients.sort_by_key(|client| client.id);
+                    let status = MixerStatus {
+                        uptime: info.started.elapsed().as_secs(),
+                        panning: info.panning,
+                        clients,
+                    };
+                    // Every field has a TOML form.
+                    let body = toml::to_string(&status).unwrap_or_default();
+                    writer.build(|frame| {
+                        frame.push(FrameType::Status as u8);
+                        frame.extend_from_slice(body.as_bytes());
+                    });
+                    writer.write(stream)?;
+                }
+                Some(FrameType::Bye) => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Asks the mixer at `address` how it is doing, without joining the audio.
+pub fn query_status(address: &str) -> Result<MixerStatus, VoiceChatError> {
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(PEER_TIMEOUT))?;
+    let handshake = Handshake {
+        sample_rate: 0,
+        channels: 0,
+        format: WireFormat::default(),
+        byte_order: ByteOrder::Little,
+        nonce: RandomState::new().build_hasher().finish(),
+        room: String::new(),
+        session: 0,
+        monitor: true,
+        name: String::new(),
+    };
+    stream.write_all(&handshake.encode())?;
+    read_handshake(&mut stream)?;
+    let mut writer = FrameWriter::default();
+    writer.build(|frame| frame.push(FrameType::StatusRequest as u8));
+    writer.write(&mut stream)?;
+
+    let mut reader = FrameReader::default();
+    let stats = Stats::default();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut chunk)?;
+        if n == 0 {
+            return Err(VoiceChatError::Status(String::from(
+                "the server closed the connection without answering. Only a mixer answers \
+                 status queries",
+            )));
+        }
+        reader.push(&chunk[..n])?;
+        while let Some(frame) = reader.next_frame(&stats) {
+            let Some((&kind, body)) = frame.split_first() else {
+                continue;
+            };
+            match FrameType::from_byte(kind) {
+                Some(FrameType::Status) => {
+                    writer.build(|frame| frame.push(FrameType::Bye as u8));
+                    let _ = writer.write(&mut stream);
+                    let text = String::from_utf8_lossy(body);
+                    return toml::from_str(&text).map_err(|e| {
+                        VoiceChatError::Status(format!("the answer is invalid: {e}"))
+                    });
+                }
+                Some(FrameType::Rejected) => {
+                    return Err(VoiceChatError::Status(
+                        String::from_utf8_lossy(body).into_owned(),
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Completes the handshake, so the client can read frames, and tells it why it can't join.
+/// Status queries are answered all the same, a full mixer is worth looking at.
+fn reject(
+    mut stream: TcpStream,
+    handshake: Handshake,
+    reason: &str,
+    rooms: &Rooms,
+    info: MixerInfo,
+) -> Result<(), VoiceChatError> {
+    // The client's handshake is read first: closing with unread data would reset the
+    // connection, and the client might never see the reason.
+    let peer = read_handshake(&mut stream)?;
+    stream.write_all(&handshake.encode())?;
+    if peer.monitor {
+        return answer_status(&mut stream, rooms, info);
+    }
+    let mut writer = FrameWriter::default();
+    writer.build(|frame| {
+        frame.push(FrameType::Rejected as u8);
+        frame.extend_from_slice(reason.as_bytes());
+    });
+    writer.write(&mut stream)?;
+    stream.shutdown(Shutdown::Write)?;
+    // Wait for the client to close, for the same reason.
+    let _ = std::io::copy(&mut stream, &mut std::io::sink());
+    Ok(())
+}
+
+/// Sends a text message from the mixer to the clients whose IDs pass `to`.
+fn announce(clients: &mut [ClientState], to: impl Fn(ClientId) -> bool, text: &str) {
+    let mut writer = FrameWriter::default();
+    writer.build_text(text);
+    for client in clients.iter_mut().filter(|client| to(client.id)) {
+        client.send(&writer);
+    }
+}
+
+fn with_client(rooms: &Rooms, room: &str, connection: u64, f: impl FnOnce(&mut ClientState)) {
+    if let Ok(mut rooms) = rooms.lock()
+        && let Some(client) = rooms.get_mut(room).and_then(|clients| {
+            clients
+                .iter_mut()
+                .find(|client| client.connection == connection)
+        })
+    {
+        f(client);
+    }
+}
+
+/// How a client asked to hear another one.
+enum Choice {
+    Gain(f32),
+    /// `None` goes back to the automatic position.
+    Pan(Option<f32>),
+}
+
+/// Reads frames until the client hangs up, handing its audio (in the mixer's format) to
+/// `on_audio`, the answers to its pings to `on_pong`, its text messages to `on_text` and its
+/// requests to change how loud or where it hears another client to `on_choice`. The
+/// peer's rate and channel count were checked by [`Handshake::decode`], so the resampler and
+/// remixing can rely on them.
+fn receive(
+    stream: &mut impl Read,
+    peer: &Handshake,
+    mut on_audio: impl FnMut(Vec<f32>),
+    mut on_pong: impl FnMut(&FrameWriter),
+    mut on_text: impl FnMut(&FrameWriter),
+    mut on_choice: impl FnMut(ClientId, Choice),
+    (sample_rate, channels): (u32, usize),
+) -> Result<(), VoiceChatError> {
+    let peer_channels = peer.channels as usize;
+    let mut resampler = Resampler::new(peer.sample_rate, sample_rate, channels);
+    let mut reader = FrameReader::default();
+    let mut writer = FrameWriter::default();
+    let mut reorderer = Reorderer::default();
+    let mut concealer = LossConcealer::new(peer.sample_rate, peer_channels);
+    let stats = Stats::default();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = match stream.read(&mut chunk) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(VoiceChatError::PeerTimedOut);
+            }
+            Err(e) => return Err(VoiceChatError::Network(e)),
+        };
+        reader.push(&chunk[..n])?;
+        let mut received: Vec<f32> = Vec::new();
+        while let Some(frame) = reader.next_frame(&stats) {
+            if let Some((sequence, mut audio)) = audio_payload(&frame, peer.format) {
+                if held_intervals(&frame).is_some() {
+                    // The pause is over, waiting for it would only delay what comes next.
+                    audio.clear();
+                }
+                for audio in reorderer.push(sequence, audio, &stats) {
+                    received.extend(match audio {
+                        Some(audio) if audio.is_empty() => Vec::new(),
+                        Some(audio) => concealer.receive(decode_audio(
+                            &audio,
+                            peer.format,
+                            peer_channels,
+                            &stats,
+                        )),
+                        None => concealer.conceal(),
+                    });
+                }
+                continue;
+            }
+            let Some((&kind, body)) = frame.split_first() else {
+                continue;
+            };
+            match FrameType::from_byte(kind) {
+                Some(FrameType::Bye) => return Ok(()),
+                Some(FrameType::Ping) => {
+                    writer.build(|frame| {
+                        frame.push(FrameType::Pong as u8);
+                        frame.extend_from_slice(body);
+                    });
+                    on_pong(&writer);
+                }
+                Some(FrameType::Text) => {
+                    // Everyone else sees who wrote it, if the sender has a name.
+                    let text = String::from_utf8_lossy(body);
+                    match peer.name.as_str() {
+                        "" => writer.build_text(&text),
+                        name => writer.build_text(&format!("{name}: {text}")),
+                    }
+                    on_text(&writer);
+                }
+                Some(FrameType::PeerGain) => {
+                    if let Some((source, gain)) = peer_gain_body(body)
+                        && gain.is_finite()
+                        && gain >= 0.0
+                    {
+                        on_choice(source, Choice::Gain(gain));
+                    }
+                }
+                Some(FrameType::PeerPan) => {
+                    if let Some((source, pan)) = peer_gain_body(body) {
+                        let pan = (!pan.is_nan()).then(|| pan.clamp(-1.0, 1.0));
+                        on_choice(source, Choice::Pan(pan));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if !received.is_empty() {
+            on_audio(resampler.process(&remix(&received, peer_channels, channels)));
+        }
+    }
+}
+
+/// Left and right gains for a position from -1.0 (left) to 1.0 (right). The power stays the
+/// same wherever a voice is, and in the middle it plays as loud as without panning.
+fn pan_gains(pan: f32) -> (f32, f32) {
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * FRAC_PI_4;
+    (angle.cos() * SQRT_2, angle.sin() * SQRT_2)
+}
+
+/// Where the `index`th of `count` voices (by client ID) is heard unless the listener chose
+/// otherwise: spread evenly over [`PAN_WIDTH`] on both sides, a single one in the middle.
+fn auto_pan(index: usize, count: usize) -> f32 {
+    if count < 2 {
+        return 0.0;
+    }
+    PAN_WIDTH * (2.0 * index as f32 / (count - 1) as f32 - 1.0)
+}
+
+/// What `client` hears of the others' `chunks` (by client ID, `None` for those without
+/// audio this interval): each at the gain the client chose for it and with `panning` at its
+/// position, plus what is left of its chime. Several voices or a chime on top of them add up
+/// beyond full scale, so the sum is limited.
+fn mix_for(
+    client: &mut ClientState,
+    chunks: &[(ClientId, Option<Vec<f32>>)],
+    chunk_len: usize,
+    limiter: Limiter,
+    panning: bool,
+) -> Vec<f32> {
+    let mut mixed = vec![0.0; chunk_len];
+    let sources: Vec<&(ClientId, Option<Vec<f32>>)> = chunks
+        .iter()
+        .filter(|(source, _)| *source != client.id)
+        .collect();
+    for (index, (source, audio)) in sources.iter().enumerate() {
+        let Some(audio) = audio else {
+            continue;
+        };
+        let gain = client.gains.get(source).copied().unwrap_or(1.0);
+        if panning {
+            let pan = client
+                .pans
+                .get(source)
+                .copied()
+                .unwrap_or_else(|| auto_pan(index, sources.len()));
+            let (left, right) = pan_gains(pan);
+            for (sum, frame) in mixed.chunks_exact_mut(2).zip(audio.chunks_exact(2)) {
+                let mono = (frame[0] + frame[1]) / 2.0 * gain;
+                sum[0] += mono * left;
+                sum[1] += mono * right;
+            }
+        } else {
+            for (sum, sample) in mixed.iter_mut().zip(audio) {
+                *sum += sample * gain;
+            }
+        }
+    }
+    let len = chunk_len.min(client.chime.len());
+    for (sum, sample) in mixed.iter_mut().zip(client.chime.drain(..len)) {
+        *sum += sample;
+    }
+    limit(&mut mixed, limiter);
+    mixed
+}
+
+/// Sends every client the sum of the others in its room (each at the gain the client chose for
+/// it, and with `panning` at its position in the stereo field), once per interval.
+fn mix(
+    rooms: &Rooms,
+    (sample_rate, channels): (u32, usize),
+    format: WireFormat,
+    limiter: Limiter,
+    panning: bool,
+) {
+    let chunk_len = interval_len(sample_rate, channels);
+    let mut writer = FrameWriter::default();
+    let mut next_tick = Instant::now() + SEND_INTERVAL;
+    let mut next_rates = Instant::now() + SLEEP_DURATION;
+    loop {
+        if let Some(wait) = next_tick.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        next_tick += SEND_INTERVAL;
+        let update_rates = Instant::now() >= next_rates;
+        if update_rates {
+            next_rates += SLEEP_DURATION;
+        }
+        let Ok(mut rooms) = rooms.lock() else {
+            return;
+        };
+        for clients in rooms.values_mut() {
+            let mut chunks: Vec<(ClientId, Option<Vec<f32>>)> = clients
+                .iter_mut()
+                .map(|client| {
+                    (
+                        client.id,
+                        take_chunk(&mut client.queue, chunk_len, channels),
+                    )
+                })
+                .collect();
+            // By ID, so the automatic positions don't change when someone rejoins.
+            chunks.sort_by_key(|(source, _)| *source);
+            for client in clients.iter_mut() {
+                let mixed = mix_for(client, &chunks, chunk_len, limiter, panning);
+                writer.build_audio(client.sequence, &mixed, format);
+                client.sequence = client.sequence.wrapping_add(1);
+                client.send(&writer);
+                if update_rates {
+                    client.update_rates();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Sends `handshake` over a fresh TCP connection and reads it the way `serve` does.
+    fn read_over_tcp(handshake: &Handshake) -> Result<Handshake, VoiceChatError> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(&handshake.encode()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        read_handshake(&mut stream)
+    }
+
+    fn handshake(sample_rate: u32, channels: u16) -> Handshake {
+        Handshake {
+            sample_rate,
+            channels,
+            format: WireFormat::F32,
+            byte_order: ByteOrder::Little,
+            nonce: 1,
+            room: String::new(),
+            session: 0,
+            monitor: false,
+            name: String::new(),
+        }
+    }
+
+    #[test]
+    fn usable_handshake_is_read() {
+        let peer = read_over_tcp(&handshake(44100, 2)).unwrap();
+        assert_eq!(peer.sample_rate, 44100);
+        assert_eq!(peer.channels, 2);
+    }
+
+    #[test]
+    fn handshake_the_resampler_cannot_use_is_refused() {
+        for (sample_rate, channels) in [(0, 1), (1, 1), (48000, 0)] {
+            assert!(matches!(
+                read_over_tcp(&handshake(sample_rate, channels)),
+                Err(VoiceChatError::Handshake(_))
+            ));
+        }
+    }
+
+    /// A mixer-side client with the given ID, with a real but unused connection.
+    fn client_state(id: ClientId) -> ClientState {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
+        ClientState {
+            id,
+            connection: id,
+            session: 0,
+            addr,
+            name: String::new(),
+            joined: Instant::now(),
+            bytes_received: Arc::new(AtomicU64::new(0)),
+            bytes_sent: 0,
+            counted: (0, 0),
+            rates: (0, 0),
+            chime: VecDeque::new(),
+            stream,
+            queue: VecDeque::new(),
+            sequence: 0,
+            gains: HashMap::new(),
+            pans: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn own_audio_is_left_out() {
+        let mut client = client_state(1);
+        let chunks = vec![(1, Some(vec![0.5; 4])), (2, Some(vec![0.25; 4])), (3, None)];
+        let mixed = mix_for(&mut client, &chunks, 4, Limiter::Clamp, false);
+        assert_eq!(mixed, vec![0.25; 4]);
+    }
+
+    #[test]
+    fn loud_voices_are_limited() {
+        let mut client = client_state(1);
+        let chunks = vec![(2, Some(vec![0.8, -0.8])), (3, Some(vec![0.8, -0.8]))];
+        let mixed = mix_for(&mut client, &chunks, 2, Limiter::Clamp, false);
+        assert_eq!(mixed, vec![1.0, -1.0]);
+        let soft = mix_for(&mut client, &chunks, 2, Limiter::Soft, false);
+        assert!(soft[0] < 1.0 && soft[0] > 0.9, "{soft:?}");
+    }
+
+    #[test]
+    fn each_listener_hears_the_others_at_its_own_gains() {
+        let mut client = client_state(1);
+        client.gains.insert(2, 0.5);
+        client.gains.insert(3, 0.0);
+        let chunks = vec![
+            (2, Some(vec![0.4; 2])),
+            (3, Some(vec![0.4; 2])),
+            (4, Some(vec![0.1; 2])),
+        ];
+        let mixed = mix_for(&mut client, &chunks, 2, Limiter::Clamp, false);
+        assert_eq!(mixed, vec![0.3; 2]);
+        // Someone else's choices don't change this mix.
+        let mut other = client_state(4);
+        let mixed = mix_for(&mut other, &chunks, 2, Limiter::Clamp, false);
+        assert_eq!(mixed, vec![0.8; 2]);
+    }
+
+    /// A client that left `ago`, with session `session`, ID 5 and a gain chosen for client 1.
+    fn departed(session: SessionId, ago: Duration) -> Departed {
+        let client = DepartedClient {
+            id: 5,
+            gains: HashMap::from([(1, 0.5)]),
+            pans: HashMap::new(),
+            left: Instant::now() - ago,
+        };
+        Arc::new(Mutex::new(HashMap::from([(session, client)])))
+    }
+
+    #[test]
+    fn returning_client_gets_its_slot_back() {
+        let addr: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+        let mut rooms = HashMap::new();
+        let departed = departed(42, Duration::from_secs(1));
+        let mut session = 42;
+        let (id, (gains, _)) = claim_slot(&mut rooms, &departed, &mut session, addr).unwrap();
+        assert_eq!((id, gains.get(&1)), (5, Some(&0.5)));
+        assert_eq!(session, 42);
+        // Only once.
+        assert!(claim_slot(&mut rooms, &departed, &mut session, addr).is_none());
+        let mut unknown = 0;
+        assert!(claim_slot(&mut rooms, &departed, &mut unknown, addr).is_none());
+    }
+
+    #[test]
+    fn slot_is_only_kept_for_a_while() {
+        let addr: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+        let departed = departed(42, REJOIN_TIMEOUT + Duration::from_secs(1));
+        let mut session = 42;
+        assert!(claim_slot(&mut HashMap::new(), &departed, &mut session, addr).is_none());
+    }
+
+    #[test]
+    fn connected_session_is_taken_over_only_from_the_same_address() {
+        let mut stale = client_state(3);
+        stale.session = 42;
+        let same_ip = SocketAddr::new(stale.addr.ip(), 1);
+        let mut rooms = HashMap::from([(RoomName::from("default"), vec![stale])]);
+        let departed = Departed::default();
+        let mut session = 42;
+        let other: SocketAddr = "10.0.0.3:4000".parse().unwrap();
+        assert!(claim_slot(&mut rooms, &departed, &mut session, other).is_none());
+        assert_eq!(session, 0);
+        let mut session = 42;
+        let (id, _) = claim_slot(&mut rooms, &departed, &mut session, same_ip).unwrap();
+        assert_eq!(id, 3);
+        assert!(rooms.is_empty());
+    }
+
+    #[test]
+    fn chimes_are_soft_at_the_edges() {
+        let chimes = Chimes::new(8000, 2);
+        let len = 2 * (CHIME_NOTE_LENGTH * 8000.0) as usize * 2;
+        assert_eq!((chimes.join.len(), chimes.leave.len()), (len, len));
+        for chime in [&chimes.join, &chimes.leave] {
+            assert!(chime.iter().all(|f| f.abs() <= CHIME_LEVEL));
+            assert!(
+                chime[..2]
+                    .iter()
+                    .chain(&chime[len - 2..])
+                    .all(|f| f.abs() < 1e-3)
+            );
+        }
+        // The same notes, in the other order.
+        assert_eq!(chimes.join[..len / 2], chimes.leave[len / 2..]);
+    }
+
+    #[test]
+    fn overlapping_chimes_are_heard_together() {
+        let mut clients = [client_state(1)];
+        ring(&mut clients, &[0.1; 4]);
+        clients[0].chime.drain(..2);
+        ring(&mut clients, &[0.2; 4]);
+        assert_eq!(clients[0].chime, [0.3, 0.3, 0.2, 0.2]);
+    }
+
+    #[test]
+    fn chime_is_mixed_into_the_next_chunks() {
+        let mut client = client_state(1);
+        client.chime.extend([0.5; 6]);
+        let chunks = vec![(2, Some(vec![0.25; 4]))];
+        let mixed = mix_for(&mut client, &chunks, 4, Limiter::Clamp, false);
+        assert_eq!(mixed, [0.75; 4]);
+        let mixed = mix_for(&mut client, &chunks, 4, Limiter::Clamp, false);
+        assert_eq!(mixed, [0.75, 0.75, 0.25, 0.25]);
+        assert!(client.chime.is_empty());
+    }
+
+    #[test]
+    fn panning_keeps_the_power() {
+        let (left, right) = pan_gains(-1.0);
+        assert!(
+            (left - SQRT_2).abs() < 1e-6 && right.abs() < 1e-6,
+            "{left} {right}"
+        );
+        let (left, right) = pan_gains(0.0);
+        assert!(
+            (left - 1.0).abs() < 1e-6 && (right - 1.0).abs() < 1e-6,
+            "{left} {right}"
+        );
+        for pan in [-0.7, -0.2, 0.3, 0.9] {
+            let (left, right) = pan_gains(pan);
+            assert!((left * left + right * right - 2.0).abs() < 1e-5, "{pan}");
+        }
+    }
+
+    #[test]
+    fn voices_are_spread_over_the_pan_width() {
+        assert_eq!(auto_pan(0, 1), 0.0);
+        assert_eq!(auto_pan(0, 3), -PAN_WIDTH);
+        assert_eq!(auto_pan(1, 3), 0.0);
+        assert_eq!(auto_pan(2, 3), PAN_WIDTH);
+    }
+
+    #[test]
+    fn voice_panned_hard_left_is_silent_on_the_right() {
+        let mut client = client_state(1);
+        client.pans.insert(2, -1.0);
+        let chunks = vec![(2, Some(vec![0.25; 8])), (3, None)];
+        let mixed = mix_for(&mut client, &chunks, 8, Limiter::Clamp, true);
+        let right: f32 = mixed.iter().skip(1).step_by(2).map(|s| s * s).sum();
+        assert!(right < 1e-12, "{mixed:?}");
+        assert!(mixed.iter().step_by(2).all(|s| *s > 0.3), "{mixed:?}");
+    }
+
+    #[test]
+    fn voices_without_a_chosen_pan_are_spread_out() {
+        let mut client = client_state(1);
+        let chunks = vec![
+            (1, Some(vec![0.5; 2])),
+            (2, Some(vec![0.5; 2])),
+            (3, Some(vec![0.0; 2])),
+        ];
+        // Client 2 is the first of the two others, so it is heard on the left.
+        let mixed = mix_for(&mut client, &chunks, 2, Limiter::Clamp, true);
+        assert!(mixed[0] > mixed[1] && mixed[1] > 0.0, "{mixed:?}");
+    }
+
+    #[test]
+    fn clients_are_labelled_with_their_name() {
+        assert_eq!(label(3, "Alice"), "#3 (Alice)");
+        assert_eq!(label(3, ""), "#3");
+    }
+
+    #[test]
+    fn whatever_is_queued_is_taken() {
+        let mut queue: VecDeque<f32> = (0..7).map(|i| i as f32).collect();
+        assert_eq!(take_chunk(&mut queue, 4, 2), Some(vec![0.0, 1.0, 2.0, 3.0]));
+        // Less than a chunk is taken too, but only in whole frames.
+        assert_eq!(take_chunk(&mut queue, 4, 2), Some(vec![4.0, 5.0]));
+        assert_eq!(take_chunk(&mut queue, 4, 2), None);
+        assert_eq!(queue, [6.0]);
+    }
+}
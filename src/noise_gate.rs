@@ -0,0 +1,160 @@
+use crate::processor::AudioProcessor;
+use std::collections::VecDeque;
+
+/// Time constant of the level detector. Short enough to catch the start of a word, long enough
+/// not to follow the individual waves of low voices.
+const DETECTOR_TIME: f32 = 0.01;
+/// The look-ahead follows the attack time, but is capped at this many seconds, as it delays
+/// the whole mic signal.
+const MAX_LOOKAHEAD: f32 = 0.02;
+
+/// Thresholds (levels between 0.0 and 1.0) and times (in seconds) of the [`NoiseGate`].
+#[derive(Clone, Copy)]
+pub struct GateSettings {
+    /// The gate opens once the level rises above this...
+    pub open: f32,
+    /// ...and only closes again below this (lower) one, so it doesn't flutter on levels
+    /// in between.
+    pub close: f32,
+    /// How long the gate takes to open, and to close.
+    pub attack: f32,
+    pub release: f32,
+}
+
+/// Silences the mic while nobody speaks.
+///
+/// A level detector follows the signal, and the gate opens and closes on it with hysteresis.
+/// The gain fades in over the attack time and out over the release time instead of switching,
+/// so leading consonants and the soft tails of words aren't chopped off.
+///
+/// The audio is delayed by the attack time (the look-ahead), while the detector sees it
+/// undelayed. So the gate has already faded in when the first syllable after a silence comes
+/// out, instead of fading in over it.
+pub struct NoiseGate {
+    settings: GateSettings,
+    /// Smoothed peak level.
+    level: f32,
+    open: bool,
+    gain: f32,
+    detector_coefficient: f32,
+    attack_step: f32,
+    release_step: f32,
+    /// Samples waiting to come out, the look-ahead long.
+    delay: VecDeque<f32>,
+}
+
+impl NoiseGate {
+    /// Creates a closed gate for interleaved audio with `channels` channels.
+    pub fn new(settings: GateSettings, sample_rate: u32, channels: usize) -> Self {
+        // A close threshold above the open one would make the gate flutter after all.
+        let settings = GateSettings {
+            close: settings.close.min(settings.open),
+            ..settings
+        };
+        let samples_per_second = (sample_rate as usize * channels.max(1)) as f32;
+        // Linear fades, clamped so a zero time switches at once.
+        let step = |time: f32| (1.0 / (time * samples_per_second)).min(1.0);
+        // Whole frames, so the channels stay in place.
+        let lookahead = (settings.attack.min(MAX_LOOKAHEAD) * sample_rate as f32) as usize;
+        let delay = VecDeque::from(vec![0.0; lookahead * channels.max(1)]);
+        NoiseGate {
+            settings,
+            level: 0.0,
+            open: false,
+            gain: 0.0,
+            detector_coefficient: 1.0 - (-1.0 / (DETECTOR_TIME * samples_per_second)).exp(),
+            attack_step: step(settings.attack),
+            release_step: step(settings.release),
+            delay,
+        }
+    }
+}
+
+impl AudioProcessor for NoiseGate {
+    fn process(&mut self, samples: &mut [f32], _sample_rate: u32) {
+        for sample in samples {
+            let magnitude = sample.abs();
+            // Peaks are caught at once, the level only decays smoothly.
+            if magnitude > self.level {
+                self.level = magnitude;
+            } else {
+                self.level += (magnitude - self.level) * self.detector_coefficient;
+            }
+
+            if self.open && self.level < self.settings.close {
+                self.open = false;
+            } else if !self.open && self.level > self.settings.open {
+                self.open = true;
+            }
+
+            self.gain = if self.open {
+                (self.gain + self.attack_step).min(1.0)
+            } else {
+                (self.gain - self.release_step).max(0.0)
+            };
+            self.delay.push_back(*sample);
+            *sample = self.delay.pop_front().unwrap_or_default() * self.gain;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 1000;
+
+    /// Runs `level` for `len` samples through the gate.
+    fn run(gate: &mut NoiseGate, level: f32, len: usize) -> Vec<f32> {
+        let mut samples = vec![level; len];
+        gate.process(&mut samples, RATE);
+        samples
+    }
+
+    #[test]
+    fn levels_between_the_thresholds_keep_the_gate_as_it_is() {
+        let settings = GateSettings {
+            open: 0.1,
+            close: 0.05,
+            attack: 0.0,
+            release: 0.0,
+        };
+        let mut gate = NoiseGate::new(settings, RATE, 1);
+        assert!(run(&mut gate, 0.07, 100).iter().all(|&s| s == 0.0));
+        run(&mut gate, 0.2, 10);
+        assert!(run(&mut gate, 0.07, 100).iter().all(|&s| s == 0.07));
+        assert_eq!(*run(&mut gate, 0.01, 100).last().unwrap(), 0.0);
+    }
+
+    #[test]
+    fn gate_has_faded_in_when_the_first_word_comes_out() {
+        // 10 samples of attack, and as much look-ahead.
+        let settings = GateSettings {
+            open: 0.1,
+            close: 0.05,
+            attack: 0.01,
+            release: 0.01,
+        };
+        let mut gate = NoiseGate::new(settings, RATE, 1);
+        run(&mut gate, 0.0, 100);
+        let word = run(&mut gate, 0.5, 30);
+        assert!(word[..10].iter().all(|&s| s == 0.0), "{word:?}");
+        assert!(word[10..].iter().all(|&s| s == 0.5), "{word:?}");
+    }
+
+    #[test]
+    fn gate_fades_out_after_the_word() {
+        let settings = GateSettings {
+            open: 0.1,
+            close: 0.05,
+            attack: 0.0,
+            release: 0.02,
+        };
+        let mut gate = NoiseGate::new(settings, RATE, 1);
+        run(&mut gate, 0.5, 100);
+        let tail = run(&mut gate, 0.01, 200);
+        assert!(tail.windows(2).all(|w| w[1] <= w[0]), "{tail:?}");
+        assert!(tail.iter().filter(|&&s| s > 0.0 && s < 0.01).count() > 5);
+        assert_eq!(*tail.last().unwrap(), 0.0);
+    }
+}
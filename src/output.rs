@@ -0,0 +1,52 @@
+use super::error::VoiceChatError;
+use super::headless::{Attached, HeadlessAudio, PlaybackCallback};
+use super::util::build_output_stream;
+use cpal::traits::StreamTrait;
+use cpal::{Device, SampleFormat, StreamConfig};
+
+/// Where received audio is played.
+pub enum OutputSink {
+    /// A live output device, opened in the given sample format.
+    Device(Device, SampleFormat),
+    /// Taken by the app through [`HeadlessAudio::pull_playback`].
+    Headless(HeadlessAudio),
+}
+
+/// Running playback. Playing stops when this is dropped.
+pub enum Playback {
+    /// Plays while kept alive. Dropping it pauses the stream before closing it, so the device
+    /// is free to be opened again right away.
+    Stream(cpal::Stream),
+    Headless(#[allow(dead_code)] Attached<PlaybackCallback>),
+}
+
+impl Drop for Playback {
+    /// Like `Capture`, stops the stream before it closes the device.
+    fn drop(&mut self) {
+        if let Playback::Stream(stream) = self {
+            let _ = stream.pause();
+        }
+    }
+}
+
+impl OutputSink {
+    /// Starts playing, letting `callback` fill the played audio. With `dither`, 16-bit
+    /// formats are dithered. `on_lost` is called if the device disappears.
+    pub fn start(
+        &self,
+        config: &StreamConfig,
+        dither: bool,
+        on_lost: impl FnMut() + Send + 'static,
+        callback: impl FnMut(&mut [f32]) + Send + 'static,
+    ) -> Result<Playback, VoiceChatError> {
+        match self {
+            OutputSink::Device(device, format) => {
+                let stream =
+                    build_output_stream(device, config, *format, dither, on_lost, callback)?;
+                stream.play()?;
+                Ok(Playback::Stream(stream))
+            }
+            OutputSink::Headless(audio) => Ok(Playback::Headless(audio.start_playback(callback))),
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::VecDeque;
+
+/// How far ahead the limiter looks, so it can turn the gain down before a peak instead of on it.
+const LOOKAHEAD_TIME: f32 = 0.005;
+
+/// Lookahead peak limiter for interleaved audio.
+///
+/// Delays the audio by [`LOOKAHEAD_TIME`] and ramps the gain down ahead of every peak above
+/// the threshold, so loud parts (e.g. several speakers at once) are attenuated smoothly rather
+/// than clipped. Afterwards, the gain recovers with the release time.
+pub struct PeakLimiter {
+    threshold: f32,
+    release_coefficient: f32,
+    channels: usize,
+    gain: f32,
+    /// Samples waiting to be played, oldest first.
+    delay: VecDeque<f32>,
+    /// The gain each delayed frame needs to stay below the threshold.
+    targets: VecDeque<f32>,
+}
+
+impl PeakLimiter {
+    /// Creates a limiter keeping peaks below `threshold`, recovering over `release` seconds.
+    pub fn new(threshold: f32, release: f32, sample_rate: u32, channels: usize) -> Self {
+        let lookahead = ((LOOKAHEAD_TIME * sample_rate as f32) as usize).max(1);
+        PeakLimiter {
+            threshold,
+            release_coefficient: 1.0 - (-1.0 / (release.max(1e-3) * sample_rate as f32)).exp(),
+            channels: channels.max(1),
+            gain: 1.0,
+            delay: VecDeque::from(vec![0.0; lookahead * channels.max(1)]),
+            targets: VecDeque::from(vec![1.0; lookahead]),
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(self.channels) {
+            let peak = frame.iter().fold(0.0f32, |peak, f| peak.max(f.abs()));
+            let target = if peak > self.threshold {
+                self.threshold / peak
+            } else {
+                1.0
+            };
+            self.delay.extend(frame.iter());
+            self.targets.push_back(target);
+
+            // Reach the lowest gain needed within the window exactly when that frame is played.
+            let (position, lowest) =
+                self.targets
+                    .iter()
+                    .copied()
+                    .enumerate()
+                    .fold(
+                        (0, 1.0f32),
+                        |low, (i, t)| if t < low.1 { (i, t) } else { low },
+                    );
+            if lowest < self.gain {
+                self.gain -= (self.gain - lowest) / (position + 1) as f32;
+            } else {
+                self.gain += (lowest - self.gain) * self.release_coefficient;
+            }
+
+            self.targets.pop_front();
+            for (sample, delayed) in frame.iter_mut().zip(self.delay.drain(..self.channels)) {
+                *sample = delayed * self.gain;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    #[test]
+    fn peaks_stay_below_the_threshold() {
+        let mut limiter = PeakLimiter::new(0.5, 0.1, SAMPLE_RATE, 2);
+        // Quiet, then a sudden loud burst, then quiet again.
+        let mut samples: Vec<f32> = (0..SAMPLE_RATE as usize)
+            .map(|i| {
+                let level = if (16000..32000).contains(&(i / 2)) {
+                    2.0
+                } else {
+                    0.2
+                };
+                if i % 4 < 2 { level } else { -level }
+            })
+            .collect();
+        limiter.process(&mut samples);
+        let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!(peak <= 0.5 + 1e-6, "{peak}");
+    }
+
+    #[test]
+    fn quiet_audio_is_only_delayed() {
+        let mut limiter = PeakLimiter::new(0.9, 0.1, SAMPLE_RATE, 1);
+        let input: Vec<f32> = (0..1000).map(|i| (i as f32 * 0.01).sin() * 0.5).collect();
+        let mut output = input.clone();
+        limiter.process(&mut output);
+        let lookahead = (LOOKAHEAD_TIME * SAMPLE_RATE as f32) as usize;
+        assert!(output[..lookahead].iter().all(|s| *s == 0.0));
+        assert_eq!(&output[lookahead..], &input[..input.len() - lookahead]);
+    }
+}
@@ -0,0 +1,604 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The lowest buffer level is measured over windows of this many seconds.
+const DRIFT_WINDOW: f64 = 5.0;
+/// The level the correction aims for at least, in seconds, so a buffer that keeps running
+/// dry is filled up a little. Raised with [`PlaybackBuffer::set_min_level`].
+const MIN_TARGET: f64 = 0.02;
+/// Levels this close to the target (in seconds) are left alone, so network jitter doesn't
+/// cause corrections.
+const DEADBAND: f64 = 0.01;
+/// At most one frame in this many is dropped or repeated, which can't be heard.
+const MAX_CORRECTION: f64 = 1.0 / 500.0;
+
+/// Hard limit on the buffered samples (16 MiB, over 40 seconds of 48 kHz stereo), whatever
+/// `--max-latency` says, so a stall or a sender that is too fast can't use up the memory.
+const MAX_LEN: usize = 4 * 1024 * 1024;
+
+/// The highest gain a single output channel can be set to.
+const MAX_CHANNEL_GAIN: f32 = 4.0;
+
+/// Long enough to avoid a click, too short to be heard as a fade.
+const FADE_TIME: f32 = 0.005;
+/// How long a jump in the playback is smoothed over, see [`Declicker`].
+const DECLICK_TIME: f64 = 0.003;
+/// How long the end of a call waits for the fade-out, which takes at least one output callback.
+const FADE_OUT_WAIT: Duration = Duration::from_millis(50);
+
+/// Fades playback in when the peer's audio starts and out when the call ends, as starting or
+/// stopping in the middle of a wave pops.
+#[derive(Clone)]
+pub struct Fade {
+    step: f32,
+    gain: f32,
+    started: bool,
+    closing: Arc<AtomicBool>,
+}
+
+impl Fade {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        Fade {
+            step: 1.0 / (FADE_TIME * (sample_rate as usize * channels) as f32),
+            gain: 0.0,
+            started: false,
+            closing: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Ramps `samples` from the current gain towards full volume, or towards silence once the
+    /// call ends. Until the first audio arrives, the gain stays at zero.
+    pub fn apply(&mut self, samples: &mut [f32]) {
+        let target = if self.closing.load(Ordering::Relaxed) {
+            0.0
+        } else {
+            1.0
+        };
+        for sample in samples {
+            if !self.started {
+                if *sample == 0.0 {
+                    continue;
+                }
+                self.started = true;
+            }
+            if self.gain < target {
+                self.gain = (self.gain + self.step).min(target);
+            } else if self.gain > target {
+                self.gain = (self.gain - self.step).max(target);
+            }
+            *sample *= self.gain;
+        }
+    }
+
+    /// Fades out (and waits for it) once the returned guard is dropped. It has to be dropped
+    /// before the output stream.
+    pub fn fade_out_on_drop(&self) -> FadeOut {
+        FadeOut(self.closing.clone())
+    }
+}
+
+pub struct FadeOut(Arc<AtomicBool>);
+
+/// Gains of the individual output channels, e.g. to make the left ear louder than the right.
+/// Shared by a client, its [`Controls`](crate::controls::Controls) and the output callbacks.
+#[derive(Clone, Default)]
+pub struct ChannelGains(Arc<Mutex<Vec<f32>>>);
+
+impl ChannelGains {
+    /// Sets the gains, first channel first, each clamped to 0.0 to 4.0. A single gain applies
+    /// to all channels, and channels without a gain keep 1.0.
+    pub fn set(&self, gains: &[f32]) {
+        if let Ok(mut current) = self.0.lock() {
+            *current = gains
+                .iter()
+                .map(|gain| gain.clamp(0.0, MAX_CHANNEL_GAIN))
+                .collect();
+        }
+    }
+
+    /// Scales each channel of the interleaved `samples` by its gain.
+    pub fn apply(&self, samples: &mut [f32], channels: usize) {
+        let Ok(gains) = self.0.lock() else {
+            return;
+        };
+        match gains.as_slice() {
+            [] => {}
+            [gain] => samples.iter_mut().for_each(|sample| *sample *= gain),
+            gains => {
+                for frame in samples.chunks_mut(channels.max(1)) {
+                    for (sample, gain) in frame.iter_mut().zip(gains) {
+                        *sample *= gain;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for FadeOut {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+        thread::sleep(FADE_OUT_WAIT);
+    }
+}
+
+/// Received audio waiting to be played, shared between the network side and the output
+/// callback of a persistent output stream.
+///
+/// Network frames and output callbacks rarely have the same size, so the callback takes
+/// whatever is buffered, across frame boundaries. Only whole multi-channel frames go in and
+/// out, so the channels never get swapped.
+pub struct PlaybackBuffer {
+    samples: VecDeque<f32>,
+    channels: usize,
+    /// The start of a multi-channel frame whose other samples haven't arrived yet.
+    partial: Vec<f32>,
+    drift: Option<DriftCorrection>,
+    declick: Option<Declicker>,
+    /// Whether the next sample played doesn't follow the last one, as frames were dropped or
+    /// the buffer ran dry in between.
+    seam: bool,
+}
+
+/// Smooths the jumps where playback doesn't continue the wave it was playing: where the buffer
+/// runs dry, where audio resumes after a dropout, and where frames are skipped to catch up.
+///
+/// At such a seam, the difference between the last sample played and the next one is added
+/// back, ramping down to nothing over [`DECLICK_TIME`], so the output glides from the old wave
+/// into the new one instead of clicking.
+struct Declicker {
+    channels: usize,
+    /// In frames.
+    len: usize,
+    /// The last frame played.
+    last: Vec<f32>,
+    offset: Vec<f32>,
+    /// Frames left in the current ramp.
+    remaining: usize,
+}
+
+impl Declicker {
+    fn new(sample_rate: u32, channels: usize) -> Self {
+        Declicker {
+            channels,
+            len: ((DECLICK_TIME * sample_rate as f64) as usize).max(1),
+            last: vec![0.0; channels],
+            offset: vec![0.0; channels],
+            remaining: 0,
+        }
+    }
+
+    /// Smooths `output`, where `seams` are the frame indices at which it jumps.
+    fn apply(&mut self, output: &mut [f32], seams: &[usize]) {
+        for (index, frame) in output.chunks_exact_mut(self.channels).enumerate() {
+            if seams.contains(&index) {
+                for ((offset, last), sample) in self.offset.iter_mut().zip(&self.last).zip(&*frame)
+                {
+                    // What is still left of a ramp carries over into the new one.
+                    *offset = last - sample;
+                }
+                self.remaining = self.len;
+            }
+            if self.remaining > 0 {
+                let weight = self.remaining as f32 / self.len as f32;
+                for (sample, offset) in frame.iter_mut().zip(&self.offset) {
+                    *sample += offset * weight;
+                }
+                self.remaining -= 1;
+            }
+            self.last.copy_from_slice(frame);
+        }
+    }
+}
+
+/// Keeps the buffer level steady although the peer's capture clock and our playback clock
+/// never run at exactly the same rate. Otherwise, the buffer would slowly fill up (growing
+/// the delay) or run dry (causing dropouts) over a long call.
+///
+/// The lowest level in every window is compared to the level of the first full window. If
+/// it moved, single frames are dropped or repeated, spread out over the next window.
+struct DriftCorrection {
+    sample_rate: u32,
+    channels: usize,
+    window_len: usize,
+    deadband: usize,
+    min_target: usize,
+    /// Frames played in the current window, and the lowest level (in frames) seen in it.
+    played: usize,
+    low: usize,
+    /// The first window is skipped, as it includes the start of the call.
+    windows: u32,
+    /// The lowest level of the first full window. The level aims for it, or `min_target` if
+    /// that is higher.
+    target: Option<usize>,
+    /// Frames to drop (positive) or repeat (negative) per frame played, and how many of them
+    /// have built up.
+    rate: f64,
+    pending: f64,
+    total_played: u64,
+    total_corrected: i64,
+}
+
+impl PlaybackBuffer {
+    /// A buffer for audio at `sample_rate` with `channels` channels that corrects clock drift.
+    pub fn with_drift_correction(sample_rate: u32, channels: usize) -> Self {
+        let frames = |seconds: f64| (seconds * sample_rate as f64) as usize;
+        PlaybackBuffer {
+            drift: Some(DriftCorrection {
+                sample_rate,
+                channels: channels.max(1),
+                window_len: frames(DRIFT_WINDOW),
+                deadband: frames(DEADBAND),
+                min_target: frames(MIN_TARGET),
+                played: 0,
+                low: usize::MAX,
+                windows: 0,
+                target: None,
+                rate: 0.0,
+                pending: 0.0,
+                total_played: 0,
+                total_corrected: 0,
+            }),
+            declick: Some(Declicker::new(sample_rate, channels.max(1))),
+            ..PlaybackBuffer::new(channels)
+        }
+    }
+
+    /// A buffer for audio with `channels` channels, without drift correction.
+    pub fn new(channels: usize) -> Self {
+        PlaybackBuffer {
+            samples: VecDeque::new(),
+            channels: channels.max(1),
+            partial: Vec::new(),
+            drift: None,
+            declick: None,
+            seam: false,
+        }
+    }
+
+    /// Queues samples for playback. Returns `true` if the buffer overflowed its hard limit,
+    /// in which case the oldest frames were dropped.
+    pub fn push(&mut self, samples: &[f32]) -> bool {
+        if self.partial.is_empty() && samples.len().is_multiple_of(self.channels) {
+            self.samples.extend(samples);
+        } else {
+            self.partial.extend_from_slice(samples);
+            let whole = self.partial.len() / self.channels * self.channels;
+            self.samples.extend(self.partial.drain(..whole));
+        }
+        if self.samples.len() <= MAX_LEN {
+            return false;
+        }
+        self.trim_to(MAX_LEN);
+        true
+    }
+
+    /// Fills `output` with buffered samples. When the buffer runs empty, the rest is filled
+    /// with silence rather than anything left over, and `false` is returned. With drift
+    /// correction, the jumps this and skipped frames cause are smoothed, see [`Declicker`].
+    pub fn drain_into(&mut self, output: &mut [f32]) -> bool {
+        if let Some(drift) = &mut self.drift {
+            drift.correct(&mut self.samples, output.len());
+        }
+        let available = self.samples.len().min(output.len());
+        for (sample, buffered) in output.iter_mut().zip(self.samples.drain(..available)) {
+            *sample = buffered;
+        }
+        output[available..].fill(0.0);
+        let full = available == output.len();
+        if let Some(declick) = &mut self.declick {
+            let start = self.seam.then_some(0);
+            let dry = (!full).then_some(available / self.channels);
+            let seams: Vec<usize> = start.into_iter().chain(dry).collect();
+            declick.apply(output, &seams);
+        }
+        // After running dry, the audio that arrives next starts a new wave.
+        self.seam = !full;
+        full
+    }
+
+    /// Drops the oldest frames until at most `max_len` samples are left.
+    pub fn trim_to(&mut self, max_len: usize) {
+        let excess = self
+            .samples
+            .len()
+            .saturating_sub(max_len)
+            .next_multiple_of(self.channels)
+            .min(self.samples.len());
+        self.samples.drain(..excess);
+        self.seam |= excess > 0;
+    }
+
+    /// Buffered samples.
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Drops the oldest samples until `len` are left, to catch up after audio piled up. The
+    /// drift correction starts over, as its target level was measured with the backlog.
+    pub fn skip_to(&mut self, len: usize) {
+        self.trim_to(len);
+        self.restart_drift_correction();
+    }
+
+    /// Starts measuring the drift over, e.g. after the peer paused sending, which let the
+    /// buffer run empty for reasons other than drift.
+    pub fn restart_drift_correction(&mut self) {
+        if let Some(drift) = &mut self.drift {
+            drift.windows = 0;
+            drift.low = usize::MAX;
+            drift.played = 0;
+            drift.target = None;
+            drift.rate = 0.0;
+        }
+    }
+
+    /// Makes the drift correction keep at least this much audio buffered, as a margin against
+    /// network jitter. The level moves there as slowly as it corrects drift, which can't be
+    /// heard.
+    pub fn set_min_level(&mut self, level: Duration) {
+        if let Some(drift) = &mut self.drift {
+            drift.min_target = (level.as_secs_f64() * drift.sample_rate as f64) as usize;
+        }
+    }
+
+    /// How much faster the peer's clock runs than ours (negative if slower), in parts per
+    /// million, as measured by the corrections so far. 0 without drift correction.
+    pub fn drift_ppm(&self) -> f64 {
+        match &self.drift {
+            Some(drift) if drift.total_played > 0 => {
+                drift.total_corrected as f64 / drift.total_played as f64 * 1e6
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+impl DriftCorrection {
+    /// Drops or repeats frames at the start of `samples` before `len` samples are played.
+    fn correct(&mut self, samples: &mut VecDeque<f32>, len: usize) {
+        let channels = self.channels;
+        let frames = len / channels;
+        self.low = self.low.min(samples.len() / channels);
+
+        self.pending += self.rate * frames as f64;
+        while self.pending >= 1.0 && samples.len() >= len + channels {
+            samples.drain(..channels);
+            self.pending -= 1.0;
+            self.total_corrected += 1;
+        }
+        while self.pending <= -1.0 && samples.len() >= channels {
+            // Pushing the frame's last sample moves the next one to copy to the same index.
+            for _ in 0..channels {
+                samples.push_front(samples[channels - 1]);
+            }
+            self.pending += 1.0;
+            self.total_corrected -= 1;
+        }
+        // Corrections that didn't fit aren't carried into the next window.
+        self.pending = self.pending.clamp(-1.0, 1.0);
+
+        self.played += frames;
+        self.total_played += frames as u64;
+        if self.played >= self.window_len {
+            self.end_window();
+        }
+    }
+
+    fn end_window(&mut self) {
+        self.windows += 1;
+        let low = std::mem::replace(&mut self.low, usize::MAX);
+        let played = std::mem::take(&mut self.played);
+        if self.windows < 2 {
+            return;
+        }
+        let target = (*self.target.get_or_insert(low)).max(self.min_target);
+        let error = low as f64 - target as f64;
+        self.rate = if error.abs() < self.deadband as f64 {
+            0.0
+        } else {
+            (error / played as f64).clamp(-MAX_CORRECTION, MAX_CORRECTION)
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn running_dry_plays_silence() {
+        let mut buffer = PlaybackBuffer::new(1);
+        buffer.push(&[0.5; 3]);
+        let mut output = [1.0; 5];
+        assert!(!buffer.drain_into(&mut output));
+        assert_eq!(output, [0.5, 0.5, 0.5, 0.0, 0.0]);
+        assert_eq!(buffer.len(), 0);
+    }
+
+    #[test]
+    fn full_buffer_fills_the_output() {
+        let mut buffer = PlaybackBuffer::new(2);
+        buffer.push(&[0.1, 0.2, 0.3, 0.4, 0.5, 0.6]);
+        let mut output = [0.0; 4];
+        assert!(buffer.drain_into(&mut output));
+        assert_eq!(output, [0.1, 0.2, 0.3, 0.4]);
+        assert_eq!(buffer.len(), 2);
+    }
+
+    /// Plays `seconds` of audio from a peer whose clock runs `ppm` parts per million faster
+    /// than ours, starting with 40 ms buffered. Returns the buffer and how often it ran dry
+    /// in the second half.
+    fn play_drifting(ppm: f64, seconds: usize) -> (PlaybackBuffer, usize) {
+        const RATE: u32 = 8000;
+        let period = RATE as usize / 100;
+        let mut buffer = PlaybackBuffer::with_drift_correction(RATE, 1);
+        buffer.push(&vec![0.5; period * 4]);
+        let mut sent = 0.0;
+        let mut pushed = 0;
+        let mut dry = 0;
+        let periods = seconds * 100;
+        for i in 0..periods {
+            sent += period as f64 * (1.0 + ppm / 1e6);
+            let now = sent as usize - pushed;
+            buffer.push(&vec![0.5; now]);
+            pushed += now;
+            let mut output = vec![0.0; period];
+            if !buffer.drain_into(&mut output) && i >= periods / 2 {
+                dry += 1;
+            }
+        }
+        (buffer, dry)
+    }
+
+    #[test]
+    fn fast_peer_doesnt_pile_up_delay() {
+        // Uncorrected, two minutes at 1000 ppm would add 120 ms to the 40.
+        let (buffer, dry) = play_drifting(1000.0, 120);
+        assert!(
+            buffer.len() < 8000 * 70 / 1000,
+            "{} samples buffered",
+            buffer.len()
+        );
+        assert_eq!(dry, 0);
+        let ppm = buffer.drift_ppm();
+        assert!(ppm > 300.0 && ppm < 1500.0, "{ppm}");
+    }
+
+    #[test]
+    fn slow_peer_doesnt_run_dry() {
+        // Uncorrected, the 40 ms would be gone after 40 seconds at -1000 ppm.
+        let (buffer, dry) = play_drifting(-1000.0, 80);
+        assert_eq!(dry, 0);
+        assert!(buffer.drift_ppm() < -300.0, "{}", buffer.drift_ppm());
+    }
+
+    #[test]
+    fn skipping_ahead_keeps_the_newest_whole_frames() {
+        let mut buffer = PlaybackBuffer::new(2);
+        let samples: Vec<f32> = (0..10).map(|i| i as f32).collect();
+        buffer.push(&samples);
+        buffer.skip_to(5);
+        assert_eq!(buffer.len(), 4);
+        let mut output = [0.0; 4];
+        assert!(buffer.drain_into(&mut output));
+        assert_eq!(output, [6.0, 7.0, 8.0, 9.0]);
+    }
+
+    #[test]
+    fn playback_fades_in_from_the_first_audio() {
+        // 5 samples of fade at 1000 Hz.
+        let mut fade = Fade::new(1000, 1);
+        let mut samples = [0.0, 0.0, 0.5, 0.5, 0.5, 0.5, 0.5, 0.5, 0.5, 0.5];
+        fade.apply(&mut samples);
+        assert_eq!(&samples[..2], &[0.0, 0.0]);
+        assert!(samples[2] > 0.0 && samples[2] < 0.5, "{samples:?}");
+        assert!(samples[2..].windows(2).all(|w| w[0] <= w[1]), "{samples:?}");
+        assert_eq!(&samples[6..], &[0.5; 4]);
+    }
+
+    #[test]
+    fn playback_fades_out_when_the_call_ends() {
+        let mut fade = Fade::new(1000, 1);
+        fade.apply(&mut [0.5; 10]);
+        drop(fade.fade_out_on_drop());
+        let mut samples = [0.5; 10];
+        fade.apply(&mut samples);
+        assert!(samples[..5].windows(2).all(|w| w[0] > w[1]), "{samples:?}");
+        assert_eq!(&samples[5..], &[0.0; 5]);
+    }
+
+    #[test]
+    fn frames_split_across_pushes_stay_whole() {
+        let mut buffer = PlaybackBuffer::new(2);
+        buffer.push(&[0.1, 0.2, 0.3]);
+        assert_eq!(buffer.len(), 2);
+        buffer.push(&[0.4, 0.5, 0.6]);
+        // Callbacks of other sizes than the network frames take what there is, in order.
+        let mut output = [0.0; 2];
+        assert!(buffer.drain_into(&mut output));
+        assert_eq!(output, [0.1, 0.2]);
+        let mut output = [1.0; 6];
+        assert!(!buffer.drain_into(&mut output));
+        assert_eq!(output, [0.3, 0.4, 0.5, 0.6, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn trimming_drops_whole_frames() {
+        let mut buffer = PlaybackBuffer::new(2);
+        buffer.push(&[0.1, 0.2, 0.3, 0.4, 0.5, 0.6]);
+        buffer.trim_to(3);
+        let mut output = [0.0; 2];
+        assert!(buffer.drain_into(&mut output));
+        assert_eq!(output, [0.5, 0.6]);
+    }
+
+    #[test]
+    fn each_channel_gets_its_gain() {
+        let gains = ChannelGains::default();
+        let mut samples = [0.5; 4];
+        gains.apply(&mut samples, 2);
+        assert_eq!(samples, [0.5; 4]);
+        gains.set(&[0.5, 2.0]);
+        gains.apply(&mut samples, 2);
+        assert_eq!(samples, [0.25, 1.0, 0.25, 1.0]);
+        // Channels without a gain keep theirs, and a single one applies to all.
+        let mut samples = [1.0; 3];
+        gains.apply(&mut samples, 3);
+        assert_eq!(samples, [0.5, 2.0, 1.0]);
+        gains.set(&[0.5]);
+        gains.apply(&mut samples, 3);
+        assert_eq!(samples, [0.25, 1.0, 0.5]);
+    }
+
+    #[test]
+    fn channel_gains_are_clamped() {
+        let gains = ChannelGains::default();
+        gains.set(&[-1.0, 10.0]);
+        let mut samples = [1.0, 1.0];
+        gains.apply(&mut samples, 2);
+        assert_eq!(samples, [0.0, MAX_CHANNEL_GAIN]);
+    }
+
+    #[test]
+    fn buffer_never_grows_beyond_its_limit() {
+        let mut buffer = PlaybackBuffer::new(2);
+        assert!(!buffer.push(&vec![0.1; MAX_LEN]));
+        assert!(buffer.push(&[0.2; 6]));
+        assert_eq!(buffer.len(), MAX_LEN);
+        // The oldest audio went, the newest is still there.
+        buffer.trim_to(6);
+        let mut output = [0.0; 6];
+        buffer.drain_into(&mut output);
+        assert_eq!(output, [0.2; 6]);
+    }
+
+    #[test]
+    fn jumps_are_smoothed() {
+        // 3 frames of ramp at 1000 Hz.
+        let mut declicker = Declicker::new(1000, 1);
+        let mut before = [0.5; 4];
+        declicker.apply(&mut before, &[]);
+        assert_eq!(before, [0.5; 4]);
+        let mut after = [-0.4; 5];
+        declicker.apply(&mut after, &[0]);
+        // It picks up where the last one ended and glides over to the new wave.
+        for (sample, expected) in after.iter().zip([0.5, 0.2, -0.1, -0.4, -0.4]) {
+            assert!((sample - expected).abs() < 1e-6, "{after:?}");
+        }
+    }
+
+    #[test]
+    fn running_dry_fades_out_instead_of_clicking() {
+        let mut buffer = PlaybackBuffer::with_drift_correction(1000, 1);
+        buffer.push(&[0.5; 4]);
+        let mut output = [0.0; 8];
+        assert!(!buffer.drain_into(&mut output));
+        assert_eq!(&output[..5], &[0.5; 5]);
+        assert!(output[4..].windows(2).all(|w| w[1] < w[0]), "{output:?}");
+        assert_eq!(output[7], 0.0);
+    }
+}
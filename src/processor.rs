@@ -0,0 +1,103 @@
+//! Extension point for custom processing of captured and received audio, see
+//! [`Client::add_input_processor`](crate::Client::add_input_processor).
+
+use crate::util::{Limiter, apply_volume, normalize};
+use std::sync::{Arc, Mutex};
+
+/// A step that changes audio in place, e.g. an equalizer or a noise suppressor.
+///
+/// `samples` is interleaved and may be any length; a processor that needs whole blocks has to
+/// buffer them itself. It runs on the audio thread (or the call loop for received audio), so it
+/// must be cheap and must not block.
+pub trait AudioProcessor {
+    fn process(&mut self, samples: &mut [f32], sample_rate: u32);
+}
+
+/// Processors added by the embedding app, kept by the [`Client`](crate::Client) across calls.
+#[derive(Clone, Default)]
+pub(crate) struct ProcessorChain(Arc<Mutex<Vec<Box<dyn AudioProcessor + Send>>>>);
+
+impl ProcessorChain {
+    pub fn push(&self, processor: Box<dyn AudioProcessor + Send>) {
+        if let Ok(mut processors) = self.0.lock() {
+            processors.push(processor);
+        }
+    }
+
+    /// Runs all processors, in the order they were added.
+    pub fn process(&self, samples: &mut [f32], sample_rate: u32) {
+        if let Ok(mut processors) = self.0.lock() {
+            for processor in processors.iter_mut() {
+                processor.process(samples, sample_rate);
+            }
+        }
+    }
+}
+
+/// Stretches every buffer to full scale, what captured audio gets without `--agc`.
+pub(crate) struct Normalize;
+
+impl AudioProcessor for Normalize {
+    fn process(&mut self, samples: &mut [f32], _sample_rate: u32) {
+        samples.copy_from_slice(&normalize(samples));
+    }
+}
+
+/// `--mic-gain`, followed by `--limiter`.
+pub(crate) struct Gain {
+    pub gain: f32,
+    pub limiter: Limiter,
+}
+
+impl AudioProcessor for Gain {
+    fn process(&mut self, samples: &mut [f32], _sample_rate: u32) {
+        apply_volume(samples, self.gain, self.limiter);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Adds `offset`, then doubles (or the other way around), so the order shows in the result.
+    struct Step {
+        offset: f32,
+        first: bool,
+    }
+
+    impl AudioProcessor for Step {
+        fn process(&mut self, samples: &mut [f32], _sample_rate: u32) {
+            for sample in samples {
+                *sample = if self.first {
+                    (*sample + self.offset) * 2.0
+                } else {
+                    *sample * 2.0 + self.offset
+                };
+            }
+        }
+    }
+
+    #[test]
+    fn processors_run_in_the_order_they_were_added() {
+        let chain = ProcessorChain::default();
+        chain.push(Box::new(Step {
+            offset: 1.0,
+            first: true,
+        }));
+        // A clone shares the chain, as the client hands one to each call.
+        chain.clone().push(Box::new(Step {
+            offset: -1.0,
+            first: false,
+        }));
+        let mut samples = [0.0, 0.5];
+        chain.process(&mut samples, 48000);
+        assert_eq!(samples, [3.0, 5.0]);
+    }
+
+    #[test]
+    fn empty_chain_leaves_the_audio_alone() {
+        let mut samples = [0.1, -0.2];
+        ProcessorChain::default().process(&mut samples, 48000);
+        assert_eq!(samples, [0.1, -0.2]);
+    }
+}
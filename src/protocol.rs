@@ -0,0 +1,1027 @@
+use super::error::VoiceChatError;
+use super::stats::Stats;
+use super::transport::{self, Transport};
+use crc::{CRC_32_ISO_HDLC, Crc};
+use serde::{Deserialize, Serialize};
+use smol::channel::{Receiver, Sender, TrySendError};
+use smol::{Timer, future};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+/// Frames larger than this are treated as a corrupt stream.
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+/// `fill` stops reading once this much is waiting to be parsed. The rest stays with the OS,
+/// whose flow control then slows a sender that is too fast.
+const MAX_READ_AHEAD: usize = MAX_FRAME_SIZE + HEADER_SIZE;
+/// Silence frames can't stand for more samples than an audio frame could hold.
+const MAX_SILENT_SAMPLES: usize = MAX_FRAME_SIZE / 4;
+/// Length and checksum.
+const HEADER_SIZE: usize = 8;
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+/// Sent first in the handshake. Bump it whenever the handshake or an existing frame type
+/// changes, so peers with different versions refuse each other instead of misreading the
+/// stream. New frame types need no bump, see [`FrameType`].
+pub const PROTOCOL_VERSION: u8 = 4;
+/// A frame that can't be sent for this long means the peer stopped reading.
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longer text messages are cut to this many bytes.
+pub const MAX_TEXT_LEN: usize = 1000;
+
+/// The longest start of `text` that fits in `max` bytes, without splitting a character.
+fn truncate_utf8(text: &str, max: usize) -> &str {
+    let mut len = text.len().min(max);
+    while !text.is_char_boundary(len) {
+        len -= 1;
+    }
+    &text[..len]
+}
+
+/// How the samples of audio frames are sent.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    /// 32-bit floats, exactly as captured.
+    #[default]
+    F32,
+    /// 16-bit integers, half the size, which is plenty for voice.
+    I16,
+}
+
+impl WireFormat {
+    /// Parses `f32` or `i16`, as given to `--wire-format`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "f32" => Some(WireFormat::F32),
+            "i16" => Some(WireFormat::I16),
+            _ => None,
+        }
+    }
+
+    /// Bytes per sample.
+    pub fn sample_size(self) -> usize {
+        match self {
+            WireFormat::F32 => 4,
+            WireFormat::I16 => 2,
+        }
+    }
+}
+
+/// The byte order of the samples in audio frames. It is always little endian, but the
+/// handshake says so explicitly, so a peer that sends big-endian samples is refused instead of
+/// being played as noise.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ByteOrder {
+    #[default]
+    Little,
+    Big,
+}
+
+/// What both sides send right after connecting, so the receiving side knows what it gets.
+///
+/// On the wire: the [`PROTOCOL_VERSION`] (u8) and the length of the rest (u16, little endian),
+/// then the fields serialized as TOML. It is sent once per call, so its size doesn't matter,
+/// and fields can be added without a new layout: older peers ignore the ones they don't know,
+/// and fields marked `#[serde(default)]` may be missing.
+#[derive(Serialize, Deserialize)]
+pub struct Handshake {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub format: WireFormat,
+    /// Missing from peers that predate it, which all sent little-endian samples.
+    #[serde(default)]
+    pub byte_order: ByteOrder,
+    /// Random per instance, to detect a connection that leads back to this very instance.
+    #[serde(with = "as_i64")]
+    pub nonce: u64,
+    /// Rooms only matter to a mixer, which mixes clients in the same room together.
+    #[serde(default)]
+    pub room: String,
+    /// Random per client and kept across its calls, so a mixer gives a client that reconnects
+    /// its old slot back. 0 if the sender doesn't have one.
+    #[serde(default, with = "as_i64")]
+    pub session: u64,
+    /// Set by `--status`, which only asks a mixer how it is doing, so the connection doesn't
+    /// join a room.
+    #[serde(default)]
+    pub monitor: bool,
+    /// Shown to the peer instead of its address where it helps, empty if the sender has none.
+    /// Cleaned up by [`clean_name`] on both ends.
+    #[serde(default)]
+    pub name: String,
+}
+
+/// `name` without control characters or surrounding spaces, cut to
+/// [`Handshake::MAX_NAME_LEN`] bytes, so a peer can't mess up the terminal or the log.
+pub fn clean_name(name: &str) -> String {
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    truncate_utf8(name.trim(), Handshake::MAX_NAME_LEN)
+        .trim_end()
+        .to_string()
+}
+
+/// TOML only has signed 64-bit integers, so the nonce and the session are sent as ones with
+/// the same bits.
+mod as_i64 {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(*value as i64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        i64::deserialize(deserializer).map(|value| value as u64)
+    }
+}
+
+impl Handshake {
+    /// Size of the version and the length, which are read first.
+    pub const PREFIX_SIZE: usize = 3;
+    /// Room names are cut to this many bytes.
+    pub const MAX_ROOM_LEN: usize = u8::MAX as usize;
+    /// Names are cut to this many bytes.
+    pub const MAX_NAME_LEN: usize = 32;
+    /// Sample rates a peer may announce. Resampling from a rate of 0 would never finish, and
+    /// from a tiny one would blow up every buffer, so anything outside is refused.
+    pub const SAMPLE_RATES: RangeInclusive<u32> = 8000..=192_000;
+    /// Most channels a peer may announce. 0 can't be split into frames.
+    pub const MAX_CHANNELS: u16 = 32;
+
+    pub fn encode(&self) -> Vec<u8> {
+        let handshake = Handshake {
+            room: truncate_utf8(&self.room, Self::MAX_ROOM_LEN).to_string(),
+            name: clean_name(&self.name),
+            ..*self
+        };
+        // Every field has a TOML form, and the room and the name are short enough to fit the
+        // length.
+        let body = toml::to_string(&handshake).unwrap_or_default();
+        let mut bytes = vec![PROTOCOL_VERSION];
+        bytes.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(body.as_bytes());
+        bytes
+    }
+
+    /// Checks the version and returns the length of the rest, unless the peer speaks another
+    /// version.
+    pub fn decode_prefix(prefix: [u8; Self::PREFIX_SIZE]) -> Result<usize, VoiceChatError> {
+        let [version, l0, l1] = prefix;
+        if version != PROTOCOL_VERSION {
+            return Err(VoiceChatError::VersionMismatch(version));
+        }
+        Ok(u16::from_le_bytes([l0, l1]) as usize)
+    }
+
+    /// Decodes the fields that follow the prefix, refusing samples that can't be decoded: a
+    /// format or byte order that isn't known fails to parse, and a known one that isn't
+    /// supported is rejected, as are a sample rate outside [`Handshake::SAMPLE_RATES`] and no
+    /// (or too many) channels. Status queries don't send audio, so their format isn't checked.
+    pub fn decode(body: &[u8]) -> Result<Self, VoiceChatError> {
+        let invalid =
+            |e: String| VoiceChatError::Handshake(format!("the peer's handshake is invalid: {e}"));
+        let text = std::str::from_utf8(body).map_err(|e| invalid(e.to_string()))?;
+        let mut handshake: Handshake = toml::from_str(text).map_err(|e| invalid(e.to_string()))?;
+        handshake.name = clean_name(&handshake.name);
+        if handshake.byte_order != ByteOrder::Little {
+            return Err(invalid(String::from(
+                "it sends big-endian samples, only little-endian ones are supported",
+            )));
+        }
+        if !handshake.monitor {
+            if !Self::SAMPLE_RATES.contains(&handshake.sample_rate) {
+                return Err(invalid(format!(
+                    "its sample rate of {} Hz is outside {} to {} Hz",
+                    handshake.sample_rate,
+                    Self::SAMPLE_RATES.start(),
+                    Self::SAMPLE_RATES.end()
+                )));
+            }
+            if !(1..=Self::MAX_CHANNELS).contains(&handshake.channels) {
+                return Err(invalid(format!(
+                    "it sends {} channels, 1 to {} are supported",
+                    handshake.channels,
+                    Self::MAX_CHANNELS
+                )));
+            }
+        }
+        Ok(handshake)
+    }
+}
+
+/// Builds frames: a 4-byte little-endian length and the CRC32 of the payload (also 4 bytes
+/// little-endian), followed by the payload.
+///
+/// The buffer is reused for every frame instead of starting a new one each time. A queued
+/// frame is a copy, see [`transmit`].
+#[derive(Default)]
+pub struct FrameWriter {
+    frame: Vec<u8>,
+}
+
+impl FrameWriter {
+    /// Starts a new frame, whose payload is appended by `fill`.
+    pub fn build(&mut self, fill: impl FnOnce(&mut Vec<u8>)) {
+        self.frame.clear();
+        self.frame.extend_from_slice(&[0; HEADER_SIZE]);
+        fill(&mut self.frame);
+        let len = (self.frame.len() - HEADER_SIZE) as u32;
+        let checksum = CRC.checksum(&self.frame[HEADER_SIZE..]);
+        self.frame[..4].copy_from_slice(&len.to_le_bytes());
+        self.frame[4..HEADER_SIZE].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    /// Builds an audio frame with the samples in `format`, or a silence frame if all samples
+    /// are zero.
+    pub fn build_audio(&mut self, sequence: u32, samples: &[f32], format: WireFormat) {
+        if samples.iter().all(|f| *f == 0.0) {
+            self.build_silence(sequence, samples.len());
+            return;
+        }
+        self.build(|frame| {
+            frame.push(FrameType::Audio as u8);
+            frame.extend_from_slice(&sequence.to_le_bytes());
+            match format {
+                WireFormat::F32 => {
+                    for f in samples {
+                        frame.extend_from_slice(&f.to_le_bytes());
+                    }
+                }
+                WireFormat::I16 => {
+                    for f in samples {
+                        let sample = (f.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+                        frame.extend_from_slice(&sample.to_le_bytes());
+                    }
+                }
+            }
+        });
+    }
+
+    /// Builds a silence frame standing for `samples` silent samples.
+    pub fn build_silence(&mut self, sequence: u32, samples: usize) {
+        self.build(|frame| {
+            frame.push(FrameType::Silence as u8);
+            frame.extend_from_slice(&sequence.to_le_bytes());
+            frame.extend_from_slice(&(samples as u32).to_le_bytes());
+        });
+    }
+
+    /// Builds one silence frame for all the intervals held back in `run`, and empties it.
+    pub fn build_silence_run(&mut self, sequence: u32, run: &mut SilenceRun) {
+        let SilenceRun { samples, intervals } = std::mem::take(run);
+        self.build(|frame| {
+            frame.push(FrameType::Silence as u8);
+            frame.extend_from_slice(&sequence.to_le_bytes());
+            frame.extend_from_slice(&(samples as u32).to_le_bytes());
+            frame.extend_from_slice(&intervals.to_le_bytes());
+        });
+    }
+
+    /// Builds a text message, cut to [`MAX_TEXT_LEN`].
+    pub fn build_text(&mut self, text: &str) {
+        self.build(|frame| {
+            frame.push(FrameType::Text as u8);
+            frame.extend_from_slice(truncate_utf8(text, MAX_TEXT_LEN).as_bytes());
+        });
+    }
+
+    /// Builds a request to a mixer to play the client with the given ID at `gain`.
+    pub fn build_peer_gain(&mut self, id: u64, gain: f32) {
+        self.build(|frame| {
+            frame.push(FrameType::PeerGain as u8);
+            frame.extend_from_slice(&id.to_le_bytes());
+            frame.extend_from_slice(&gain.to_le_bytes());
+        });
+    }
+
+    /// Builds a request to a mixer to place the client with the given ID at `pan`, or back
+    /// where the mixer puts it with `None`.
+    pub fn build_peer_pan(&mut self, id: u64, pan: Option<f32>) {
+        self.build(|frame| {
+            frame.push(FrameType::PeerPan as u8);
+            frame.extend_from_slice(&id.to_le_bytes());
+            frame.extend_from_slice(&pan.unwrap_or(f32::NAN).to_le_bytes());
+        });
+    }
+
+    /// Writes the frame built last to a blocking stream and returns its size.
+    pub fn write(&self, stream: &mut impl Write) -> io::Result<usize> {
+        stream.write_all(&self.frame)?;
+        Ok(self.frame.len())
+    }
+
+    /// Hands the frame built last to [`transmit`] and returns its size, waiting while the
+    /// queue is full. Fails once `transmit` gave up on the connection.
+    pub async fn queue(&self, queue: &Sender<Vec<u8>>) -> io::Result<usize> {
+        queue
+            .send(self.frame.clone())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(self.frame.len())
+    }
+
+    /// Like [`FrameWriter::queue`], but drops the frame instead of waiting if the queue is
+    /// full, and then returns `None`.
+    pub fn try_queue(&self, queue: &Sender<Vec<u8>>) -> io::Result<Option<usize>> {
+        match queue.try_send(self.frame.clone()) {
+            Ok(()) => Ok(Some(self.frame.len())),
+            Err(TrySendError::Full(_)) => Ok(None),
+            Err(TrySendError::Closed(_)) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+/// Writes the queued frames in order and counts them in `stats`, until every sender of the
+/// queue is gone and the rest is written.
+///
+/// It runs next to the loop that receives and builds frames, so waiting for a slow peer to
+/// take data holds up nothing else. A frame must never be written only partially, so while
+/// the socket's send buffer is full this waits until it is writable again (without spinning).
+/// If that takes longer than `SEND_TIMEOUT`, it fails with `TimedOut`. Any error leaves a
+/// partial frame behind, so the connection can't be used any more and this stops, which the
+/// senders notice as a closed queue.
+pub async fn transmit(
+    transport: &impl Transport,
+    queue: Receiver<Vec<u8>>,
+    stats: &Stats,
+) -> io::Result<()> {
+    while let Ok(frame) = queue.recv().await {
+        let write = transport::write_all(transport, &frame);
+        let timeout = async {
+            Timer::after(SEND_TIMEOUT).await;
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the peer stopped taking data",
+            ))
+        };
+        future::or(write, timeout).await?;
+        stats.count_sent(frame.len());
+    }
+    Ok(())
+}
+
+/// What a frame carries, given by the first byte of its payload.
+///
+/// Receivers ignore frames of types they don't know, so an older peer just misses what a
+/// new type adds (a peer that doesn't show the mute state, a mixer that doesn't pan). Adding
+/// a type therefore keeps the [`PROTOCOL_VERSION`]; changing the body of an existing one
+/// doesn't.
+#[derive(Clone, Copy)]
+pub enum FrameType {
+    /// A sequence number (u32), followed by the samples.
+    Audio = 0,
+    /// Nothing else, it only shows that the peer is still there.
+    Heartbeat = 1,
+    /// The sender's timestamp (u64 microseconds), which the receiver echoes back in a pong.
+    Ping = 2,
+    /// The timestamp of the ping this answers.
+    Pong = 3,
+    /// A sequence number (u32) and a sample count (u32): an audio frame of that many silent
+    /// samples, sent in 9 bytes instead of 4 per sample. A whole pause the sender held back
+    /// adds the number of send intervals it spans (u32), see [`held_intervals`].
+    Silence = 4,
+    /// Why the call was turned down (UTF-8), e.g. because the server is full. The sender
+    /// closes the connection afterwards.
+    Rejected = 5,
+    /// A text message (UTF-8) typed by the peer, at most [`MAX_TEXT_LEN`] bytes.
+    Text = 6,
+    /// A client ID (u64) and a gain (f32): asks a mixer to play that client at this gain in
+    /// the sender's mix. Other peers ignore it.
+    PeerGain = 7,
+    /// Nothing else: the sender stopped talking (see `--vad`) and sends no audio until it
+    /// talks again.
+    EndOfSpeech = 8,
+    /// Whether the sender's mic is muted (u8, 1 if so). Sent when it changes and repeated
+    /// every few seconds, in case the change was missed.
+    MuteState = 9,
+    /// Nothing else: the sender hung up on purpose, so the receiver can end the call instead of
+    /// treating the closed connection as a failure.
+    Bye = 10,
+    /// Nothing else: asks a mixer for its [`MixerStatus`](crate::mixer::MixerStatus), only on a
+    /// connection whose handshake has `monitor` set.
+    StatusRequest = 11,
+    /// The answer to a status request, as TOML.
+    Status = 12,
+    /// A client ID (u64) and a position (f32, -1.0 left to 1.0 right, NaN for automatic):
+    /// asks a mixer with `--pan` to place that client there in the sender's mix.
+    PeerPan = 13,
+}
+
+/// Consecutive silent send intervals, held back while the sender pauses so the whole pause
+/// goes out as one silence frame instead of one per interval, see
+/// [`FrameWriter::build_silence_run`].
+#[derive(Default)]
+pub struct SilenceRun {
+    samples: usize,
+    intervals: u32,
+}
+
+impl SilenceRun {
+    /// Holds back another silent interval of `samples` samples.
+    pub fn hold(&mut self, samples: usize) {
+        self.samples += samples;
+        self.intervals += 1;
+    }
+
+    /// Samples held back so far.
+    pub fn len(&self) -> usize {
+        self.samples
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.intervals == 0
+    }
+}
+
+/// How many send intervals a silence frame stands for if the sender held them back (see
+/// [`SilenceRun`]), `None` for any other frame. Their time has passed by the time the frame
+/// arrives, so they are counted rather than played.
+pub fn held_intervals(payload: &[u8]) -> Option<u32> {
+    let (&kind, body) = payload.split_first()?;
+    if !matches!(FrameType::from_byte(kind)?, FrameType::Silence) {
+        return None;
+    }
+    Some(u32::from_le_bytes(body.get(8..12)?.try_into().ok()?))
+}
+
+/// The client ID and value of a peer gain or peer pan frame's body.
+pub fn peer_gain_body(body: &[u8]) -> Option<(u64, f32)> {
+    let id = u64::from_le_bytes(body.get(..8)?.try_into().ok()?);
+    let gain = f32::from_le_bytes(body.get(8..12)?.try_into().ok()?);
+    Some((id, gain))
+}
+
+/// The sequence number and samples (as little-endian bytes in the sender's `format`) of an
+/// audio or silence frame's payload. Silence is expanded, so it can take the place of audio.
+pub fn audio_payload(payload: &[u8], format: WireFormat) -> Option<(u32, Vec<u8>)> {
+    let (&kind, body) = payload.split_first()?;
+    match FrameType::from_byte(kind)? {
+        FrameType::Audio if body.len() >= 4 => {
+            let sequence = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+            Some((sequence, body[4..].to_vec()))
+        }
+        FrameType::Silence if body.len() >= 8 => {
+            let sequence = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+            let samples = u32::from_le_bytes([body[4], body[5], body[6], body[7]]) as usize;
+            Some((
+                sequence,
+                vec![0; samples.min(MAX_SILENT_SAMPLES) * format.sample_size()],
+            ))
+        }
+        _ => None,
+    }
+}
+
+impl FrameType {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(FrameType::Audio),
+            1 => Some(FrameType::Heartbeat),
+            2 => Some(FrameType::Ping),
+            3 => Some(FrameType::Pong),
+            4 => Some(FrameType::Silence),
+            5 => Some(FrameType::Rejected),
+            6 => Some(FrameType::Text),
+            7 => Some(FrameType::PeerGain),
+            8 => Some(FrameType::EndOfSpeech),
+            9 => Some(FrameType::MuteState),
+            10 => Some(FrameType::Bye),
+            11 => Some(FrameType::StatusRequest),
+            12 => Some(FrameType::Status),
+            13 => Some(FrameType::PeerPan),
+            _ => None,
+        }
+    }
+}
+
+/// Collects bytes from a non-blocking stream and splits them into frames.
+#[derive(Default)]
+pub struct FrameReader {
+    buffer: Vec<u8>,
+}
+
+impl FrameReader {
+    /// Reads what is currently available from the stream, up to `MAX_READ_AHEAD` unparsed bytes.
+    pub fn fill(&mut self, stream: &mut impl Read) -> io::Result<()> {
+        let mut chunk = [0u8; 4096];
+        while self.buffer.len() < MAX_READ_AHEAD {
+            match stream.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.check_size()
+    }
+
+    /// Adds bytes that were read elsewhere, e.g. from a blocking stream.
+    pub fn push(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.buffer.extend_from_slice(bytes);
+        self.check_size()
+    }
+
+    fn check_size(&mut self) -> io::Result<()> {
+        if self.payload_len().is_some_and(|len| len > MAX_FRAME_SIZE) {
+            self.buffer.clear();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "received frame is too large",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Takes the next complete frame's payload, if one has been received, and counts it. Frames
+    /// that fail the checksum are dropped and counted as corrupt.
+    pub fn next_frame(&mut self, stats: &Stats) -> Option<Vec<u8>> {
+        loop {
+            let len = self.payload_len()?;
+            if self.buffer.len() < HEADER_SIZE + len {
+                return None;
+            }
+            let header = &self.buffer[4..HEADER_SIZE];
+            let checksum = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+            let payload = self.buffer[HEADER_SIZE..HEADER_SIZE + len].to_vec();
+            self.buffer.drain(..HEADER_SIZE + len);
+            if CRC.checksum(&payload) == checksum {
+                stats.count_received(HEADER_SIZE + len);
+                return Some(payload);
+            }
+            stats.count_corrupt();
+        }
+    }
+
+    fn payload_len(&self) -> Option<usize> {
+        let header = self.buffer.get(..4)?;
+        Some(u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize)
+    }
+}
+
+/// Number of frames that are held back while waiting for a missing one.
+const REORDER_WINDOW: usize = 3;
+
+/// Puts audio frames back in order of their sequence numbers and drops duplicates.
+#[derive(Default)]
+pub struct Reorderer {
+    /// Sequence number of the next frame to hand out, once the first one has arrived.
+    next: Option<u32>,
+    pending: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Reorderer {
+    /// Takes a received frame and returns all frames that are now ready, in order. Frames that
+    /// were given up on as lost are returned as `None` in their place, up to
+    /// [`REORDER_WINDOW`] in a row.
+    pub fn push(&mut self, sequence: u32, frame: Vec<u8>, stats: &Stats) -> Vec<Option<Vec<u8>>> {
+        let next = *self.next.get_or_insert(sequence);
+        // Distance ahead of the expected frame, robust against wrapping around.
+        let ahead = sequence.wrapping_sub(next) as i32;
+        if ahead < 0 || self.pending.contains_key(&sequence) {
+            stats.count_duplicate();
+            return Vec::new();
+        }
+        if self
+            .pending
+            .keys()
+            .any(|s| s.wrapping_sub(sequence) as i32 > 0)
+        {
+            stats.count_out_of_order();
+        }
+        self.pending.insert(sequence, frame);
+
+        let mut ready = Vec::new();
+        let mut next = next;
+        loop {
+            if let Some(frame) = self.pending.remove(&next) {
+                ready.push(Some(frame));
+                next = next.wrapping_add(1);
+            } else if self.pending.len() > REORDER_WINDOW {
+                // The missing frame is taken as lost, continue with the oldest one we have.
+                let oldest = *self
+                    .pending
+                    .keys()
+                    .min_by_key(|s| s.wrapping_sub(next))
+                    .expect("pending is not empty");
+                let lost = oldest.wrapping_sub(next) as usize;
+                ready.extend(std::iter::repeat_n(None, lost.min(REORDER_WINDOW)));
+                next = oldest;
+            } else {
+                break;
+            }
+        }
+        self.next = Some(next);
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::decode_samples;
+
+    fn handshake() -> Handshake {
+        Handshake {
+            sample_rate: 48000,
+            channels: 2,
+            format: WireFormat::I16,
+            byte_order: ByteOrder::Little,
+            nonce: u64::MAX,
+            room: String::from("kitchen"),
+            session: 7,
+            monitor: false,
+            name: String::from("Alice"),
+        }
+    }
+
+    /// Encodes `handshake` and decodes it the way a peer reads it.
+    fn round_trip(handshake: &Handshake) -> Result<Handshake, VoiceChatError> {
+        let bytes = handshake.encode();
+        let prefix = bytes[..Handshake::PREFIX_SIZE].try_into().unwrap();
+        let len = Handshake::decode_prefix(prefix)?;
+        assert_eq!(len, bytes.len() - Handshake::PREFIX_SIZE);
+        Handshake::decode(&bytes[Handshake::PREFIX_SIZE..])
+    }
+
+    fn assert_refused(body: &str, reason: &str) {
+        match Handshake::decode(body.as_bytes()) {
+            Err(VoiceChatError::Handshake(message)) => {
+                assert!(message.contains(reason), "{message}")
+            }
+            Err(e) => panic!("wrong error: {e}"),
+            Ok(_) => panic!("accepted {body:?}"),
+        }
+    }
+
+    #[test]
+    fn handshake_round_trip() {
+        let decoded = round_trip(&handshake()).unwrap();
+        assert_eq!(decoded.sample_rate, 48000);
+        assert_eq!(decoded.channels, 2);
+        assert!(decoded.format == WireFormat::I16);
+        assert!(decoded.byte_order == ByteOrder::Little);
+        assert_eq!(decoded.nonce, u64::MAX);
+        assert_eq!(decoded.room, "kitchen");
+        assert_eq!(decoded.session, 7);
+        assert!(!decoded.monitor);
+        assert_eq!(decoded.name, "Alice");
+    }
+
+    #[test]
+    fn other_version_is_refused() {
+        let mut bytes = handshake().encode();
+        bytes[0] = PROTOCOL_VERSION + 1;
+        let prefix = bytes[..Handshake::PREFIX_SIZE].try_into().unwrap();
+        assert!(matches!(
+            Handshake::decode_prefix(prefix),
+            Err(VoiceChatError::VersionMismatch(version)) if version == PROTOCOL_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn missing_optional_fields_get_defaults() {
+        let decoded =
+            Handshake::decode(b"sample_rate = 22050\nchannels = 1\nformat = \"f32\"\nnonce = 1\n")
+                .unwrap();
+        assert!(decoded.byte_order == ByteOrder::Little);
+        assert_eq!(decoded.room, "");
+        assert_eq!(decoded.session, 0);
+        assert_eq!(decoded.name, "");
+    }
+
+    #[test]
+    fn zero_sample_rate_is_refused() {
+        assert_refused(
+            "sample_rate = 0\nchannels = 1\nformat = \"f32\"\nnonce = 1\n",
+            "sample rate of 0 Hz",
+        );
+    }
+
+    #[test]
+    fn tiny_sample_rate_is_refused() {
+        assert_refused(
+            "sample_rate = 1\nchannels = 1\nformat = \"f32\"\nnonce = 1\n",
+            "sample rate of 1 Hz",
+        );
+    }
+
+    #[test]
+    fn huge_sample_rate_is_refused() {
+        assert_refused(
+            "sample_rate = 1000000\nchannels = 1\nformat = \"f32\"\nnonce = 1\n",
+            "sample rate of 1000000 Hz",
+        );
+    }
+
+    #[test]
+    fn zero_channels_are_refused() {
+        assert_refused(
+            "sample_rate = 48000\nchannels = 0\nformat = \"f32\"\nnonce = 1\n",
+            "0 channels",
+        );
+    }
+
+    #[test]
+    fn too_many_channels_are_refused() {
+        assert_refused(
+            "sample_rate = 48000\nchannels = 33\nformat = \"f32\"\nnonce = 1\n",
+            "33 channels",
+        );
+    }
+
+    #[test]
+    fn big_endian_is_refused() {
+        assert_refused(
+            "sample_rate = 48000\nchannels = 1\nformat = \"f32\"\nbyte_order = \"big\"\nnonce = 1\n",
+            "big-endian",
+        );
+    }
+
+    #[test]
+    fn byte_order_is_declared() {
+        let bytes = handshake().encode();
+        let body = std::str::from_utf8(&bytes[Handshake::PREFIX_SIZE..]).unwrap();
+        assert!(body.contains("byte_order = \"little\""), "{body}");
+    }
+
+    #[test]
+    fn big_endian_peer_is_refused_before_streaming() {
+        let peer = Handshake {
+            byte_order: ByteOrder::Big,
+            ..handshake()
+        };
+        assert!(matches!(
+            round_trip(&peer),
+            Err(VoiceChatError::Handshake(message)) if message.contains("big-endian")
+        ));
+    }
+
+    #[test]
+    fn unknown_byte_order_is_refused() {
+        assert_refused(
+            "sample_rate = 48000\nchannels = 1\nformat = \"f32\"\nbyte_order = \"middle\"\nnonce = 1\n",
+            "unknown variant",
+        );
+    }
+
+    #[test]
+    fn unknown_format_is_refused() {
+        assert_refused(
+            "sample_rate = 48000\nchannels = 1\nformat = \"f64\"\nnonce = 1\n",
+            "unknown variant",
+        );
+    }
+
+    #[test]
+    fn status_query_needs_no_audio_format() {
+        let query = Handshake {
+            sample_rate: 0,
+            channels: 0,
+            monitor: true,
+            ..handshake()
+        };
+        assert!(round_trip(&query).unwrap().monitor);
+    }
+
+    #[test]
+    fn names_are_cleaned() {
+        assert_eq!(clean_name("  Al\u{7}ice\n "), "Alice");
+        assert_eq!(clean_name(&"é".repeat(40)).len(), 32);
+        let decoded = Handshake::decode(
+            b"sample_rate = 48000\nchannels = 1\nformat = \"f32\"\nnonce = 1\nname = \"\\u001b[31mBob\"\n",
+        )
+        .unwrap();
+        assert_eq!(decoded.name, "[31mBob");
+    }
+
+    /// Pushes frames with the given sequence numbers (each carrying its own number as the
+    /// payload) and returns what came out, `None` for frames given up on.
+    fn reorder(reorderer: &mut Reorderer, sequences: &[u32], stats: &Stats) -> Vec<Option<u32>> {
+        sequences
+            .iter()
+            .flat_map(|&sequence| reorderer.push(sequence, sequence.to_le_bytes().to_vec(), stats))
+            .map(|frame| frame.map(|frame| u32::from_le_bytes(frame.try_into().unwrap())))
+            .collect()
+    }
+
+    #[test]
+    fn frames_in_order_pass_straight_through() {
+        let stats = Stats::default();
+        let out = reorder(&mut Reorderer::default(), &[5, 6, 7], &stats);
+        assert_eq!(out, [Some(5), Some(6), Some(7)]);
+        assert_eq!(stats.frames_out_of_order(), 0);
+    }
+
+    #[test]
+    fn swapped_frames_are_put_back_in_order() {
+        let stats = Stats::default();
+        let out = reorder(&mut Reorderer::default(), &[0, 2, 1, 3], &stats);
+        assert_eq!(out, [Some(0), Some(1), Some(2), Some(3)]);
+        assert_eq!(stats.frames_out_of_order(), 1);
+    }
+
+    #[test]
+    fn duplicates_are_dropped() {
+        let stats = Stats::default();
+        let out = reorder(&mut Reorderer::default(), &[0, 1, 1, 0, 3, 3, 2], &stats);
+        assert_eq!(out, [Some(0), Some(1), Some(2), Some(3)]);
+        assert_eq!(stats.duplicate_frames(), 3);
+    }
+
+    #[test]
+    fn missing_frame_is_given_up_after_the_window() {
+        let stats = Stats::default();
+        let mut reorderer = Reorderer::default();
+        // 1 never arrives: 2 to 4 wait for it, 5 is one too many.
+        assert_eq!(reorder(&mut reorderer, &[0, 2, 3, 4], &stats), [Some(0)]);
+        assert_eq!(
+            reorder(&mut reorderer, &[5], &stats),
+            [None, Some(2), Some(3), Some(4), Some(5)]
+        );
+        // Arriving after all, it is too late.
+        assert!(reorder(&mut reorderer, &[1], &stats).is_empty());
+        assert_eq!(stats.duplicate_frames(), 1);
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let stats = Stats::default();
+        let out = reorder(&mut Reorderer::default(), &[u32::MAX, 1, 0], &stats);
+        assert_eq!(out, [Some(u32::MAX), Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn audio_frames_carry_their_sequence_number() {
+        let mut writer = FrameWriter::default();
+        writer.build_audio(42, &[0.5, -0.5], WireFormat::F32);
+        let mut reader = FrameReader::default();
+        reader.push(&writer.frame).unwrap();
+        let payload = reader.next_frame(&Stats::default()).unwrap();
+        let (sequence, samples) = audio_payload(&payload, WireFormat::F32).unwrap();
+        assert_eq!(sequence, 42);
+        assert_eq!(samples.len(), 8);
+    }
+
+    #[test]
+    fn samples_are_little_endian_on_every_host() {
+        let mut writer = FrameWriter::default();
+        writer.build_audio(1, &[1.0], WireFormat::F32);
+        let mut reader = FrameReader::default();
+        reader.push(&writer.frame).unwrap();
+        let payload = reader.next_frame(&Stats::default()).unwrap();
+        let (_, samples) = audio_payload(&payload, WireFormat::F32).unwrap();
+        assert_eq!(samples, [0x00, 0x00, 0x80, 0x3f]);
+    }
+
+    /// The samples of an audio frame built from `samples` in `format`, as they arrive.
+    fn wire_round_trip(samples: &[f32], format: WireFormat) -> (usize, Vec<f32>) {
+        let mut writer = FrameWriter::default();
+        writer.build_audio(1, samples, format);
+        let mut reader = FrameReader::default();
+        reader.push(&writer.frame).unwrap();
+        let payload = reader.next_frame(&Stats::default()).unwrap();
+        let (_, bytes) = audio_payload(&payload, format).unwrap();
+        (bytes.len(), decode_samples(&bytes, format))
+    }
+
+    #[test]
+    fn i16_samples_take_half_the_bytes() {
+        let samples = [0.5, -0.25, 0.001, -1.0];
+        let (f32_len, exact) = wire_round_trip(&samples, WireFormat::F32);
+        let (i16_len, rounded) = wire_round_trip(&samples, WireFormat::I16);
+        assert_eq!(exact, samples);
+        assert_eq!(i16_len * 2, f32_len);
+        for (sample, rounded) in samples.iter().zip(rounded) {
+            assert!(
+                (sample - rounded).abs() <= 0.5 / i16::MAX as f32,
+                "{rounded}"
+            );
+        }
+    }
+
+    #[test]
+    fn i16_samples_are_clamped() {
+        let (_, rounded) = wire_round_trip(&[3.0, -3.0], WireFormat::I16);
+        assert_eq!(rounded, [1.0, -1.0]);
+    }
+
+    #[test]
+    fn peer_gain_round_trip() {
+        let mut writer = FrameWriter::default();
+        writer.build_peer_gain(7, 0.25);
+        let mut reader = FrameReader::default();
+        reader.push(&writer.frame).unwrap();
+        let payload = reader.next_frame(&Stats::default()).unwrap();
+        assert!(matches!(
+            FrameType::from_byte(payload[0]),
+            Some(FrameType::PeerGain)
+        ));
+        assert_eq!(peer_gain_body(&payload[1..]), Some((7, 0.25)));
+        assert_eq!(peer_gain_body(&payload[1..9]), None);
+    }
+
+    #[test]
+    fn handshake_starts_with_the_version() {
+        assert_eq!(handshake().encode()[0], PROTOCOL_VERSION);
+    }
+
+    fn text_frame(text: &str) -> Vec<u8> {
+        let mut writer = FrameWriter::default();
+        writer.build_text(text);
+        writer.frame
+    }
+
+    #[test]
+    fn frame_buffer_is_reused() {
+        let mut writer = FrameWriter::default();
+        writer.build_text("a longer message first");
+        let buffer = writer.frame.as_ptr();
+        writer.build_text("short");
+        assert_eq!(writer.frame.as_ptr(), buffer);
+        assert_eq!(writer.frame, text_frame("short"));
+    }
+
+    #[test]
+    fn frames_are_split_however_they_arrive() {
+        let stats = Stats::default();
+        let bytes = [text_frame("one"), text_frame("two")].concat();
+        let mut reader = FrameReader::default();
+        for byte in &bytes {
+            reader.push(&[*byte]).unwrap();
+        }
+        assert_eq!(reader.next_frame(&stats).unwrap()[1..], *b"one");
+        assert_eq!(reader.next_frame(&stats).unwrap()[1..], *b"two");
+        assert!(reader.next_frame(&stats).is_none());
+        assert_eq!(stats.frames_received(), 2);
+    }
+
+    #[test]
+    fn corrupt_frame_is_dropped() {
+        let stats = Stats::default();
+        let mut corrupt = text_frame("hello");
+        *corrupt.last_mut().unwrap() ^= 1;
+        let mut reader = FrameReader::default();
+        reader
+            .push(&[corrupt, text_frame("next")].concat())
+            .unwrap();
+        assert_eq!(reader.next_frame(&stats).unwrap()[1..], *b"next");
+        assert_eq!(stats.corrupt_frames(), 1);
+        assert_eq!(stats.frames_received(), 1);
+    }
+
+    #[test]
+    fn oversized_frame_is_refused() {
+        let mut reader = FrameReader::default();
+        let len = (MAX_FRAME_SIZE as u32 + 1).to_le_bytes();
+        let error = reader.push(&[&len[..], &[0; 4]].concat()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn silence_is_sent_as_a_sample_count() {
+        let mut writer = FrameWriter::default();
+        writer.build_audio(3, &[0.0; 960], WireFormat::I16);
+        let payload = &writer.frame[HEADER_SIZE..];
+        assert_eq!(payload.len(), 9);
+        assert_eq!(payload[0], FrameType::Silence as u8);
+        let (sequence, samples) = audio_payload(payload, WireFormat::I16).unwrap();
+        assert_eq!(sequence, 3);
+        assert_eq!(samples, vec![0; 960 * 2]);
+    }
+
+    #[test]
+    fn silence_is_expanded_to_a_bounded_size() {
+        let mut writer = FrameWriter::default();
+        writer.build_silence(0, u32::MAX as usize);
+        let (_, samples) = audio_payload(&writer.frame[HEADER_SIZE..], WireFormat::F32).unwrap();
+        assert_eq!(samples.len(), MAX_SILENT_SAMPLES * 4);
+    }
+
+    #[test]
+    fn silent_intervals_go_out_as_one_frame() {
+        let mut run = SilenceRun::default();
+        for _ in 0..25 {
+            run.hold(320);
+        }
+        let mut writer = FrameWriter::default();
+        writer.build_silence_run(8, &mut run);
+        assert!(run.is_empty());
+        let payload = &writer.frame[HEADER_SIZE..];
+        assert_eq!(payload.len(), 13);
+        assert_eq!(held_intervals(payload), Some(25));
+        let (sequence, samples) = audio_payload(payload, WireFormat::I16).unwrap();
+        assert_eq!(sequence, 8);
+        assert_eq!(samples, vec![0; 25 * 320 * 2]);
+    }
+
+    #[test]
+    fn silence_of_a_single_interval_is_not_held() {
+        let mut writer = FrameWriter::default();
+        writer.build_audio(3, &[0.0; 320], WireFormat::F32);
+        assert_eq!(held_intervals(&writer.frame[HEADER_SIZE..]), None);
+        writer.build_audio(3, &[0.5; 320], WireFormat::F32);
+        assert_eq!(held_intervals(&writer.frame[HEADER_SIZE..]), None);
+    }
+}
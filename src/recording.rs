@@ -0,0 +1,122 @@
+//! Recording both directions of a call to separate WAV files, to remix the conversation later.
+
+use super::error::VoiceChatError;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use log::info;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How often the WAV headers are brought up to date, so a recording stays readable up to
+/// about this point even if the program is killed.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+type Track = WavWriter<BufWriter<File>>;
+
+/// 16-bit WAV files with your mic as it is sent and the peer's audio as it arrives, each at
+/// the rate and channel count of its direction.
+///
+/// The files are finalized when the recording is dropped, i.e. when the call ends.
+pub struct SplitRecording {
+    mic: Track,
+    peer: Track,
+    last_flush: Instant,
+}
+
+/// `<path>.<track>.wav`, so both files of a recording end up next to each other.
+fn track_path(path: &Path, track: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{track}.wav"));
+    PathBuf::from(name)
+}
+
+fn create(path: &Path, (sample_rate, channels): (u32, usize)) -> Result<Track, VoiceChatError> {
+    let spec = WavSpec {
+        channels: channels as u16,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+    WavWriter::create(path, spec).map_err(|e| {
+        VoiceChatError::RecordingFile(format!("Failed to create '{}': {e}", path.display()))
+    })
+}
+
+impl SplitRecording {
+    /// Creates (or overwrites) `<path>.mic.wav` for the audio that is sent and
+    /// `<path>.peer.wav` for the audio from the peer.
+    pub fn create(
+        path: &Path,
+        mic_format: (u32, usize),
+        peer_format: (u32, usize),
+    ) -> Result<Self, VoiceChatError> {
+        let mic_path = track_path(path, "mic");
+        let peer_path = track_path(path, "peer");
+        let recording = SplitRecording {
+            mic: create(&mic_path, mic_format)?,
+            peer: create(&peer_path, peer_format)?,
+            last_flush: Instant::now(),
+        };
+        info!(
+            "Recording your mic to '{}' and the peer to '{}'",
+            mic_path.display(),
+            peer_path.display()
+        );
+        Ok(recording)
+    }
+
+    pub fn write_mic(&mut self, samples: &[f32]) -> hound::Result<()> {
+        write_samples(&mut self.mic, samples)?;
+        self.flush_if_due()
+    }
+
+    pub fn write_peer(&mut self, samples: &[f32]) -> hound::Result<()> {
+        write_samples(&mut self.peer, samples)?;
+        self.flush_if_due()
+    }
+
+    fn flush_if_due(&mut self) -> hound::Result<()> {
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.last_flush = Instant::now();
+            self.mic.flush()?;
+            self.peer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn write_samples(track: &mut Track, samples: &[f32]) -> hound::Result<()> {
+    for sample in samples {
+        track.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hound::WavReader;
+    use std::fs;
+
+    #[test]
+    fn both_directions_go_to_their_own_track() {
+        let path = std::env::temp_dir().join(format!("voice-chat-{}-split", std::process::id()));
+        let mut recording = SplitRecording::create(&path, (16000, 1), (48000, 2)).unwrap();
+        recording.write_mic(&[0.5, -0.5, 2.0]).unwrap();
+        recording.write_peer(&[0.25; 4]).unwrap();
+        drop(recording);
+
+        let mic = WavReader::open(track_path(&path, "mic")).unwrap();
+        assert_eq!((mic.spec().sample_rate, mic.spec().channels), (16000, 1));
+        let samples: Vec<i16> = mic.into_samples().map(Result::unwrap).collect();
+        assert_eq!(samples, [16383, -16383, i16::MAX]);
+        let peer = WavReader::open(track_path(&path, "peer")).unwrap();
+        assert_eq!((peer.spec().sample_rate, peer.spec().channels), (48000, 2));
+        assert_eq!(peer.len(), 4);
+        for track in ["mic", "peer"] {
+            fs::remove_file(track_path(&path, track)).unwrap();
+        }
+    }
+}
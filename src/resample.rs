@@ -0,0 +1,235 @@
+/// Taps of the anti-aliasing filter. With the Blackman window, its transition band is about
+/// 5.5 / TAPS of the input rate wide, and the stop band is about 70 dB down.
+const TAPS: usize = 128;
+
+/// Linear interpolating resampler for interleaved audio.
+///
+/// Keeps the last frame and the fractional read position between calls, so consecutive
+/// buffers join up without clicks and the output length follows the rate ratio over time.
+///
+/// When converting down, everything above the new Nyquist frequency is filtered out first.
+/// Interpolating alone would fold it back into the audible range as aliasing.
+pub struct Resampler {
+    from_rate: u32,
+    to_rate: u32,
+    channels: usize,
+    /// Input frames consumed per output frame.
+    step: f64,
+    /// Read position, where index 0 is `last` and index 1 is the first frame of the next buffer.
+    position: f64,
+    last: Vec<f32>,
+    low_pass: Option<LowPass>,
+}
+
+impl Resampler {
+    /// # Panics
+    ///
+    /// If either rate is 0, as the read position would never advance. Peers' rates are
+    /// checked by [`Handshake::decode`](crate::protocol::Handshake::decode) first.
+    pub fn new(from_rate: u32, to_rate: u32, channels: usize) -> Self {
+        assert!(
+            from_rate > 0 && to_rate > 0,
+            "Can't resample from {from_rate} Hz to {to_rate} Hz"
+        );
+        let channels = channels.max(1);
+        Resampler {
+            from_rate,
+            to_rate,
+            channels,
+            step: from_rate as f64 / to_rate as f64,
+            position: 0.0,
+            last: vec![0.0; channels],
+            low_pass: (to_rate < from_rate).then(|| LowPass::new(from_rate, to_rate, channels)),
+        }
+    }
+
+    /// Converts a buffer from `from_rate` to `to_rate`.
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        if self.from_rate == self.to_rate {
+            return input.to_vec();
+        }
+
+        let filtered;
+        let input = match &mut self.low_pass {
+            Some(low_pass) => {
+                filtered = low_pass.process(input);
+                &filtered
+            }
+            None => input,
+        };
+
+        let channels = self.channels;
+        let frames = input.len() / channels;
+        let mut output = Vec::with_capacity(((frames as f64 / self.step) as usize + 1) * channels);
+
+        let last = &self.last;
+        let sample = |frame: usize, channel: usize| {
+            if frame == 0 {
+                last[channel]
+            } else {
+                input[(frame - 1) * channels + channel]
+            }
+        };
+
+        let mut position = self.position;
+        while position < frames as f64 {
+            let index = position as usize;
+            let fraction = (position - index as f64) as f32;
+            for channel in 0..channels {
+                let a = sample(index, channel);
+                let b = sample(index + 1, channel);
+                output.push(a + (b - a) * fraction);
+            }
+            position += self.step;
+        }
+
+        if frames > 0 {
+            self.position = position - frames as f64;
+            self.last
+                .copy_from_slice(&input[(frames - 1) * channels..frames * channels]);
+        }
+        output
+    }
+}
+
+/// Windowed-sinc FIR low-pass that ends its transition band at the Nyquist frequency of the
+/// target rate.
+struct LowPass {
+    taps: Vec<f32>,
+    channels: usize,
+    /// The last `TAPS` frames, interleaved, as a ring ending before `next`.
+    history: Vec<f32>,
+    next: usize,
+}
+
+impl LowPass {
+    fn new(from_rate: u32, to_rate: u32, channels: usize) -> Self {
+        let transition = 5.5 / TAPS as f64;
+        // In cycles per input sample.
+        let cutoff =
+            (to_rate as f64 / from_rate as f64 / 2.0 - transition / 2.0).max(transition / 2.0);
+        let middle = (TAPS - 1) as f64 / 2.0;
+        let mut taps: Vec<f64> = (0..TAPS)
+            .map(|i| {
+                let x = i as f64 - middle;
+                let sinc = if x == 0.0 {
+                    2.0 * cutoff
+                } else {
+                    (std::f64::consts::TAU * cutoff * x).sin() / (std::f64::consts::PI * x)
+                };
+                let phase = std::f64::consts::TAU * i as f64 / (TAPS - 1) as f64;
+                sinc * (0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos())
+            })
+            .collect();
+        // Unity gain for low frequencies.
+        let sum: f64 = taps.iter().sum();
+        taps.iter_mut().for_each(|tap| *tap /= sum);
+        LowPass {
+            taps: taps.into_iter().map(|tap| tap as f32).collect(),
+            channels,
+            history: vec![0.0; TAPS * channels],
+            next: 0,
+        }
+    }
+
+    fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        let channels = self.channels;
+        let mut output = Vec::with_capacity(input.len());
+        for frame in input.chunks_exact(channels) {
+            self.history[self.next * channels..(self.next + 1) * channels].copy_from_slice(frame);
+            self.next = (self.next + 1) % TAPS;
+            for channel in 0..channels {
+                // The oldest frame is at `next`, and the taps are symmetric.
+                let sum: f32 = self
+                    .taps
+                    .iter()
+                    .enumerate()
+                    .map(|(i, tap)| tap * self.history[(self.next + i) % TAPS * channels + channel])
+                    .sum();
+                output.push(sum);
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(hz: f32, rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (std::f32::consts::TAU * hz * i as f32 / rate as f32).sin())
+            .collect()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn same_rate_is_passed_through() {
+        let input = tone(440.0, 48000, 480);
+        assert_eq!(Resampler::new(48000, 48000, 1).process(&input), input);
+    }
+
+    #[test]
+    fn output_length_follows_the_rate_ratio() {
+        for (from, to) in [
+            (48000, 22050),
+            (22050, 48000),
+            (44100, 48000),
+            (8000, 192_000),
+        ] {
+            let mut resampler = Resampler::new(from, to, 2);
+            // Odd buffer sizes, so the fractional position has to carry over.
+            let output: usize = (0..100)
+                .map(|_| resampler.process(&vec![0.0; 2 * 441]).len())
+                .sum();
+            let expected = 100.0 * 441.0 * to as f64 / from as f64 * 2.0;
+            assert!(
+                (output as f64 - expected).abs() <= 4.0,
+                "{from} to {to} Hz: {output} samples instead of {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn dc_level_is_kept() {
+        for (from, to) in [(48000, 16000), (16000, 48000)] {
+            let mut resampler = Resampler::new(from, to, 1);
+            let output: Vec<f32> = (0..10)
+                .flat_map(|_| resampler.process(&[0.5; 480]))
+                .collect();
+            for sample in &output[output.len() / 2..] {
+                assert!((sample - 0.5).abs() < 1e-3, "{from} to {to} Hz: {sample}");
+            }
+        }
+    }
+
+    #[test]
+    fn channels_stay_apart() {
+        let input: Vec<f32> = (0..960).flat_map(|_| [0.25, -0.75]).collect();
+        let output = Resampler::new(48000, 44100, 2).process(&input);
+        for frame in output.chunks_exact(2).skip(output.len() / 4) {
+            assert!((frame[0] - 0.25).abs() < 1e-3);
+            assert!((frame[1] + 0.75).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn tones_above_the_new_nyquist_frequency_are_filtered_out() {
+        let mut resampler = Resampler::new(48000, 16000, 1);
+        let heard = resampler.process(&tone(1000.0, 48000, 4800));
+        let mut resampler = Resampler::new(48000, 16000, 1);
+        let aliased = resampler.process(&tone(11000.0, 48000, 4800));
+        assert!(rms(&heard[800..]) > 0.6);
+        assert!(rms(&aliased[800..]) < 0.01);
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't resample")]
+    fn zero_rate_is_refused() {
+        Resampler::new(0, 48000, 1);
+    }
+}
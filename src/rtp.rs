@@ -0,0 +1,184 @@
+//! RTP (RFC 3550) packets with uncompressed audio, to talk to standard VoIP tools.
+//!
+//! The payload is L16 (RFC 3551): 16-bit signed samples, big endian, interleaved. At 44.1 kHz
+//! it has the static payload types 11 (mono) and 10 (stereo), every other format is sent with
+//! the dynamic payload type 96, whose format the receiver has to be told (see [`sdp`]).
+
+/// The version in the top two bits of every header.
+const VERSION: u8 = 2;
+/// Fixed header without CSRCs.
+const HEADER_SIZE: usize = 12;
+/// Payload type for L16 in any format but the two static ones.
+pub const DYNAMIC_PAYLOAD_TYPE: u8 = 96;
+
+/// The payload type for L16 audio in this format.
+pub fn payload_type(sample_rate: u32, channels: u16) -> u8 {
+    match (sample_rate, channels) {
+        (44100, 1) => 11,
+        (44100, 2) => 10,
+        _ => DYNAMIC_PAYLOAD_TYPE,
+    }
+}
+
+/// A session description for the stream sent to `port`, e.g. for
+/// `ffplay -protocol_whitelist file,udp,rtp stream.sdp`.
+pub fn sdp(port: u16, sample_rate: u32, channels: u16) -> String {
+    let payload_type = payload_type(sample_rate, channels);
+    format!(
+        "v=0\r\n\
+         o=- 0 0 IN IP4 0.0.0.0\r\n\
+         s=voice-chat\r\n\
+         c=IN IP4 0.0.0.0\r\n\
+         t=0 0\r\n\
+         m=audio {port} RTP/AVP {payload_type}\r\n\
+         a=rtpmap:{payload_type} L16/{sample_rate}/{channels}\r\n"
+    )
+}
+
+/// Turns captured audio into RTP packets of one stream.
+pub struct Packetizer {
+    payload_type: u8,
+    channels: usize,
+    ssrc: u32,
+    sequence: u16,
+    /// In frames, as RFC 3551 has it for audio.
+    timestamp: u32,
+}
+
+impl Packetizer {
+    /// `seed` picks the SSRC and the first sequence number and timestamp, which RFC 3550 wants
+    /// to be random.
+    pub fn new(sample_rate: u32, channels: u16, seed: u64) -> Self {
+        Packetizer {
+            payload_type: payload_type(sample_rate, channels),
+            channels: channels.max(1) as usize,
+            ssrc: seed as u32,
+            sequence: (seed >> 24) as u16,
+            timestamp: (seed >> 32) as u32,
+        }
+    }
+
+    /// Builds the next packet, holding `samples` (whole frames).
+    pub fn packetize(&mut self, samples: &[f32]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_SIZE + samples.len() * 2);
+        packet.push(VERSION << 6);
+        packet.push(self.payload_type);
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        for sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            packet.extend_from_slice(&sample.to_be_bytes());
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self
+            .timestamp
+            .wrapping_add((samples.len() / self.channels) as u32);
+        packet
+    }
+}
+
+/// The parts of a received packet that matter here.
+pub struct Packet<'a> {
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub ssrc: u32,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    /// Parses a packet, skipping CSRCs, a header extension and padding. `None` if it isn't a
+    /// valid RTP packet.
+    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < HEADER_SIZE || bytes[0] >> 6 != VERSION {
+            return None;
+        }
+        let padding = bytes[0] & 0x20 != 0;
+        let extension = bytes[0] & 0x10 != 0;
+        let csrc_count = (bytes[0] & 0x0f) as usize;
+        let mut start = HEADER_SIZE + csrc_count * 4;
+        if extension {
+            let words = bytes.get(start + 2..start + 4)?;
+            start += 4 + u16::from_be_bytes([words[0], words[1]]) as usize * 4;
+        }
+        let mut end = bytes.len();
+        if padding {
+            end = end.checked_sub(*bytes.last()? as usize)?;
+        }
+        Some(Packet {
+            payload_type: bytes[1] & 0x7f,
+            sequence: u16::from_be_bytes([bytes[2], bytes[3]]),
+            ssrc: u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            payload: bytes.get(start..end)?,
+        })
+    }
+
+    /// The L16 samples of the payload.
+    pub fn samples(&self) -> Vec<f32> {
+        self.payload
+            .chunks_exact(2)
+            .map(|pair| i16::from_be_bytes([pair[0], pair[1]]) as f32 / i16::MAX as f32)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_round_trip() {
+        let mut packetizer = Packetizer::new(48000, 2, 0x0102_0304_0506_0708);
+        let first = packetizer.packetize(&[0.5, -0.5, 0.25, -0.25]);
+        let second = packetizer.packetize(&[0.0, 0.0]);
+        let packet = Packet::parse(&first).unwrap();
+        assert_eq!(packet.payload_type, DYNAMIC_PAYLOAD_TYPE);
+        assert_eq!(packet.ssrc, 0x0506_0708);
+        for (sample, expected) in packet.samples().iter().zip([0.5, -0.5, 0.25, -0.25]) {
+            assert!((sample - expected).abs() < 1e-4, "{sample}");
+        }
+        let next = Packet::parse(&second).unwrap();
+        assert_eq!(next.sequence, packet.sequence.wrapping_add(1));
+        // Two frames later, in the big-endian header.
+        let timestamp = |bytes: &[u8]| u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        assert_eq!(timestamp(&second), timestamp(&first).wrapping_add(2));
+    }
+
+    #[test]
+    fn samples_are_big_endian() {
+        let packet = Packetizer::new(44100, 1, 0).packetize(&[1.0]);
+        assert_eq!(packet[1], 11);
+        assert_eq!(&packet[HEADER_SIZE..], &i16::MAX.to_be_bytes());
+    }
+
+    #[test]
+    fn csrcs_extension_and_padding_are_skipped() {
+        let mut bytes = vec![VERSION << 6 | 0x20 | 0x10 | 1, 10, 0, 7];
+        bytes.extend_from_slice(&[0; 8]);
+        // One CSRC, an extension header with one word, two samples, two bytes of padding.
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&[0xbe, 0xde, 0, 1, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&[0x40, 0x00, 0xc0, 0x00]);
+        bytes.extend_from_slice(&[0, 2]);
+        let packet = Packet::parse(&bytes).unwrap();
+        assert_eq!(packet.sequence, 7);
+        assert_eq!(packet.payload, &[0x40, 0x00, 0xc0, 0x00]);
+    }
+
+    #[test]
+    fn other_data_is_not_a_packet() {
+        assert!(Packet::parse(&[0x80; 11]).is_none());
+        assert!(Packet::parse(&[0x40; 20]).is_none());
+        // More padding than there are bytes.
+        let mut bytes = vec![VERSION << 6 | 0x20; 12];
+        bytes.push(200);
+        assert!(Packet::parse(&bytes).is_none());
+    }
+
+    #[test]
+    fn session_description_names_the_format() {
+        let sdp = sdp(5004, 48000, 2);
+        assert!(sdp.contains("m=audio 5004 RTP/AVP 96\r\n"), "{sdp}");
+        assert!(sdp.contains("a=rtpmap:96 L16/48000/2\r\n"), "{sdp}");
+    }
+}
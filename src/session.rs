@@ -0,0 +1,97 @@
+//! Where a [`Client`](crate::Client) is in a call, e.g. for showing the call status in a GUI.
+
+use log::info;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionState {
+    /// No call was started yet.
+    Idle,
+    /// Waiting for a peer to connect.
+    Listening,
+    /// Trying to reach the peer.
+    Connecting,
+    /// In a call, after the handshake.
+    Connected,
+    /// The last call (or attempt) is over, because it was hung up or failed.
+    Disconnected,
+}
+
+/// Who ended a call that ended without an error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallEnd {
+    /// This side hung up, was shut down or reached `--duration`.
+    HungUp,
+    /// The peer said goodbye.
+    PeerHungUp,
+}
+
+/// Formats a duration as `HH:MM:SS`, e.g. `00:03:42`.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// The state shared by a client and its [`Controls`](crate::controls::Controls).
+#[derive(Clone, Default)]
+pub(crate) struct SharedState(Arc<Shared>);
+
+#[derive(Default)]
+pub(crate) struct Shared {
+    state: AtomicU8,
+    /// When the current call connected, and how long the last one lasted.
+    call: Mutex<(Option<Instant>, Duration)>,
+}
+
+impl SharedState {
+    pub fn get(&self) -> SessionState {
+        match self.0.state.load(Ordering::Relaxed) {
+            0 => SessionState::Idle,
+            1 => SessionState::Listening,
+            2 => SessionState::Connecting,
+            3 => SessionState::Connected,
+            _ => SessionState::Disconnected,
+        }
+    }
+
+    pub fn set(&self, state: SessionState) {
+        if let Ok(mut call) = self.0.call.lock() {
+            if state == SessionState::Connected {
+                call.0.get_or_insert_with(Instant::now);
+            } else if let Some(started) = call.0.take() {
+                call.1 = started.elapsed();
+                info!("Call lasted {}", format_duration(call.1));
+            }
+        }
+        self.0.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    /// How long the current call has been connected, or else how long the last one lasted.
+    pub fn call_duration(&self) -> Duration {
+        match self.0.call.lock() {
+            Ok(call) => call.0.map_or(call.1, |started| started.elapsed()),
+            Err(_) => Duration::ZERO,
+        }
+    }
+
+    /// Sets the state to `Disconnected` once the returned guard is dropped, so it is right even
+    /// if an error ends the call early or its future is dropped.
+    pub fn disconnect_on_drop(&self) -> DisconnectOnDrop {
+        DisconnectOnDrop(self.clone())
+    }
+}
+
+pub(crate) struct DisconnectOnDrop(SharedState);
+
+impl Drop for DisconnectOnDrop {
+    fn drop(&mut self) {
+        self.0.set(SessionState::Disconnected);
+    }
+}
@@ -0,0 +1,118 @@
+use super::resample::Resampler;
+use super::util::remix;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// More queued sidetone than this means playback fell behind, the oldest part is dropped. Any
+/// later than this and your own voice sounds like an echo.
+const MAX_DELAY: f32 = 0.05;
+
+/// Captured audio on its way to the local output, so you hear yourself.
+pub struct Sidetone {
+    queue: Mutex<Queue>,
+    input_rate: u32,
+    input_channels: usize,
+    level: f32,
+}
+
+/// Queued audio at the output format, and how it gets there.
+struct Queue {
+    samples: VecDeque<f32>,
+    resampler: Resampler,
+    output_channels: usize,
+    max_len: usize,
+}
+
+impl Queue {
+    fn new(input_rate: u32, output_rate: u32, output_channels: usize) -> Self {
+        Queue {
+            samples: VecDeque::new(),
+            resampler: Resampler::new(input_rate, output_rate, output_channels),
+            output_channels,
+            max_len: (MAX_DELAY * output_rate as f32) as usize * output_channels,
+        }
+    }
+}
+
+impl Sidetone {
+    pub fn new(
+        level: f32,
+        input_rate: u32,
+        input_channels: usize,
+        output_rate: u32,
+        output_channels: usize,
+    ) -> Self {
+        Sidetone {
+            queue: Mutex::new(Queue::new(input_rate, output_rate, output_channels)),
+            input_rate,
+            input_channels,
+            level,
+        }
+    }
+
+    /// Called with every processed buffer of captured audio.
+    pub fn push(&self, captured: &[f32]) {
+        if let Ok(mut queue) = self.queue.lock() {
+            let queue = &mut *queue;
+            let remixed = remix(captured, self.input_channels, queue.output_channels);
+            queue.samples.extend(queue.resampler.process(&remixed));
+            // Whole frames, so the channels stay in place.
+            let excess = queue
+                .samples
+                .len()
+                .saturating_sub(queue.max_len)
+                .next_multiple_of(queue.output_channels);
+            queue.samples.drain(..excess.min(queue.samples.len()));
+        }
+    }
+
+    /// Follows a switch to an output device with a different format.
+    pub fn set_output_format(&self, output_rate: u32, output_channels: usize) {
+        if let Ok(mut queue) = self.queue.lock() {
+            *queue = Queue::new(self.input_rate, output_rate, output_channels);
+        }
+    }
+
+    /// Adds the queued sidetone, at its level, to a buffer for the output device. Whatever
+    /// isn't there yet is left out rather than waited for.
+    pub fn mix_into(&self, data: &mut [f32]) {
+        if let Ok(mut queue) = self.queue.lock() {
+            let available = data.len().min(queue.samples.len());
+            for (sample, own) in data.iter_mut().zip(queue.samples.drain(..available)) {
+                *sample = (*sample + own * self.level).clamp(-1.0, 1.0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn own_voice_is_mixed_in_at_its_level() {
+        let sidetone = Sidetone::new(0.5, 8000, 1, 8000, 2);
+        sidetone.push(&[0.4; 10]);
+        let mut output = [0.1; 24];
+        sidetone.mix_into(&mut output);
+        // Mono on both channels, and nothing where there is no sidetone yet.
+        assert!(
+            output[..20].iter().all(|f| (f - 0.3).abs() < 1e-6),
+            "{output:?}"
+        );
+        assert_eq!(&output[20..], &[0.1; 4]);
+    }
+
+    #[test]
+    fn sidetone_is_never_late() {
+        let sidetone = Sidetone::new(1.0, 8000, 1, 8000, 1);
+        // A whole second while playback doesn't run: only the newest 50 ms are kept.
+        let captured: Vec<f32> = (0..8000).map(|i| i as f32 / 8000.0).collect();
+        sidetone.push(&captured);
+        let mut output = [0.0; 1000];
+        sidetone.mix_into(&mut output);
+        assert_eq!(output[0], captured[7600]);
+        assert_eq!(output[399], captured[7999]);
+        assert!(output[400..].iter().all(|&f| f == 0.0));
+    }
+}
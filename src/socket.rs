@@ -0,0 +1,52 @@
+//! Options for the TCP socket of a call.
+
+use socket2::SockRef;
+use std::fmt;
+use std::io;
+use std::net::TcpStream;
+
+/// The options in effect on a call's socket, as the OS reports them.
+#[derive(Clone, Copy, Debug)]
+pub struct SocketOptions {
+    /// Whether Nagle's algorithm is off, so small frames go out at once.
+    pub nodelay: bool,
+    /// Size of the OS send buffer in bytes.
+    pub send_buffer: usize,
+    /// Size of the OS receive buffer in bytes.
+    pub recv_buffer: usize,
+}
+
+impl fmt::Display for SocketOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "nodelay {}, send buffer {} bytes, receive buffer {} bytes",
+            if self.nodelay { "on" } else { "off" },
+            self.send_buffer,
+            self.recv_buffer
+        )
+    }
+}
+
+/// Applies the requested options and returns the ones in effect. The OS may round the buffer
+/// sizes (Linux doubles them), so the result is read back instead of assumed.
+pub(crate) fn configure(
+    stream: &TcpStream,
+    nodelay: bool,
+    send_buffer: Option<usize>,
+    recv_buffer: Option<usize>,
+) -> io::Result<SocketOptions> {
+    stream.set_nodelay(nodelay)?;
+    let socket = SockRef::from(stream);
+    if let Some(size) = send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    Ok(SocketOptions {
+        nodelay: stream.nodelay()?,
+        send_buffer: socket.send_buffer_size()?,
+        recv_buffer: socket.recv_buffer_size()?,
+    })
+}
@@ -0,0 +1,85 @@
+//! Remembers the last devices that were used, so they don't have to be given on every launch.
+//!
+//! The state lives in `voice-chat/devices.toml` under the platform's config directory.
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Device names as given by the user. The default devices are stored as `None`.
+#[derive(Serialize, Deserialize, Default, PartialEq)]
+pub struct DeviceState {
+    pub input_device: Option<String>,
+    pub output_device: Option<String>,
+}
+
+/// `$XDG_CONFIG_HOME` (or `~/.config`) on Linux and BSD, `~/Library/Application Support` on
+/// macOS and `%APPDATA%` on Windows.
+fn path() -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        env::var_os("HOME").map(|home| PathBuf::from(home).join("Library/Application Support"))
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+    Some(dir?.join("voice-chat").join("devices.toml"))
+}
+
+impl DeviceState {
+    /// Loads the remembered devices. Without a (valid) state file, none are remembered.
+    pub fn load() -> Self {
+        let Some(path) = path() else {
+            return DeviceState::default();
+        };
+        let Ok(text) = fs::read_to_string(&path) else {
+            return DeviceState::default();
+        };
+        toml::from_str(&text).unwrap_or_else(|e| {
+            warn!("Ignoring invalid state file '{}': {e}", path.display());
+            DeviceState::default()
+        })
+    }
+
+    /// Records the devices that were opened. The input is `None` if it isn't a device, so the
+    /// remembered one is kept.
+    pub fn remember(input_device: Option<&str>, output_device: &str) {
+        let remembered = DeviceState::load();
+        let named = |name: &str| (name != "default").then(|| name.to_string());
+        let state = DeviceState {
+            input_device: match input_device {
+                Some(name) => named(name),
+                None => remembered.input_device.clone(),
+            },
+            output_device: named(output_device),
+        };
+        if state == remembered {
+            return;
+        }
+        if let Err(e) = state.save() {
+            warn!("Failed to remember the devices: {e}");
+        }
+    }
+
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let path = path().ok_or("no config directory")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Deletes the state file, if there is one.
+    pub fn forget() {
+        if let Some(path) = path()
+            && fs::remove_file(&path).is_ok()
+        {
+            info!("Forgot the last used devices");
+        }
+    }
+}
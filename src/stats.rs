@@ -0,0 +1,215 @@
+//! Counters describing the health of a call, updated by the send and receive paths.
+
+use crate::socket::SocketOptions;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+#[derive(Default)]
+pub struct Stats {
+    frames_out_of_order: AtomicU64,
+    duplicate_frames: AtomicU64,
+    underruns: AtomicU64,
+    corrupt_frames: AtomicU64,
+    undecodable_frames: AtomicU64,
+    concealed_frames: AtomicU64,
+    throttled_frames: AtomicU64,
+    latency_skips: AtomicU64,
+    buffer_overflows: AtomicU64,
+    dropped_samples: AtomicU64,
+    bytes_sent: AtomicU64,
+    frames_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    frames_received: AtomicU64,
+    /// Send rate over the last few seconds, in bits per second.
+    send_bitrate: AtomicU64,
+    /// Clock drift between the peer and our playback, in parts per million.
+    clock_drift_ppm: AtomicI64,
+    /// Latest round-trip time in microseconds, 0 until the first one was measured.
+    rtt_micros: AtomicU64,
+    /// Current target playback level in microseconds.
+    jitter_target_micros: AtomicU64,
+    socket_options: Mutex<Option<SocketOptions>>,
+}
+
+impl Stats {
+    /// Frames that arrived after a later frame. They are put back in order if possible.
+    pub fn frames_out_of_order(&self) -> u64 {
+        self.frames_out_of_order.load(Ordering::Relaxed)
+    }
+
+    /// Frames that were received more than once or too late, and were dropped.
+    pub fn duplicate_frames(&self) -> u64 {
+        self.duplicate_frames.load(Ordering::Relaxed)
+    }
+
+    /// Output callbacks that had to play (partial) silence because no audio was buffered.
+    pub fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    /// Frames that failed the checksum and were dropped.
+    pub fn corrupt_frames(&self) -> u64 {
+        self.corrupt_frames.load(Ordering::Relaxed)
+    }
+
+    /// Audio frames that passed the checksum but couldn't be valid audio, e.g. with a partial
+    /// sample or non-finite samples. They were played as silence.
+    pub fn undecodable_frames(&self) -> u64 {
+        self.undecodable_frames.load(Ordering::Relaxed)
+    }
+
+    /// Lost frames whose gap was filled by fading out the previous frame.
+    pub fn concealed_frames(&self) -> u64 {
+        self.concealed_frames.load(Ordering::Relaxed)
+    }
+
+    /// Audio frames that were sent as silence to stay under `--max-bitrate`.
+    pub fn throttled_frames(&self) -> u64 {
+        self.throttled_frames.load(Ordering::Relaxed)
+    }
+
+    /// How often playback skipped ahead because too much audio piled up (`--max-latency`).
+    pub fn latency_skips(&self) -> u64 {
+        self.latency_skips.load(Ordering::Relaxed)
+    }
+
+    /// How often the received audio hit the hard limit of the playback buffer (over 40 seconds,
+    /// whatever `--max-latency` says) and the oldest of it was dropped.
+    pub fn buffer_overflows(&self) -> u64 {
+        self.buffer_overflows.load(Ordering::Relaxed)
+    }
+
+    /// Captured samples that were never sent because sending fell behind, either in the capture
+    /// queue or as audio frames that didn't fit the send queue.
+    pub fn dropped_samples(&self) -> u64 {
+        self.dropped_samples.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of frames sent, of any type.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn frames_sent(&self) -> u64 {
+        self.frames_sent.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of frames received that passed the checksum, of any type.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn frames_received(&self) -> u64 {
+        self.frames_received.load(Ordering::Relaxed)
+    }
+
+    /// The effective send rate in bits per second, averaged over the last few seconds.
+    pub fn send_bitrate(&self) -> u64 {
+        self.send_bitrate.load(Ordering::Relaxed)
+    }
+
+    /// How much faster the peer's capture clock runs than our playback clock (negative if
+    /// slower), in parts per million. Playback drops or repeats single frames to make up for
+    /// it, so the delay stays the same over a long call.
+    pub fn clock_drift_ppm(&self) -> i64 {
+        self.clock_drift_ppm.load(Ordering::Relaxed)
+    }
+
+    /// The latest round-trip time to the peer, measured every few seconds. `None` until the
+    /// first measurement.
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt_micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    /// How much of the peer's audio playback keeps buffered at least, as a margin against
+    /// network jitter. Adapts to the network unless pinned with `--jitter-target`.
+    pub fn jitter_target(&self) -> Duration {
+        Duration::from_micros(self.jitter_target_micros.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_jitter_target(&self, target: Duration) {
+        self.jitter_target_micros
+            .store(target.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// The options in effect on the socket of the latest call. `None` until a call started.
+    pub fn socket_options(&self) -> Option<SocketOptions> {
+        self.socket_options.lock().ok().and_then(|options| *options)
+    }
+
+    pub(crate) fn set_socket_options(&self, options: SocketOptions) {
+        if let Ok(mut slot) = self.socket_options.lock() {
+            *slot = Some(options);
+        }
+    }
+
+    pub(crate) fn set_rtt(&self, rtt: Duration) {
+        self.rtt_micros
+            .store((rtt.as_micros() as u64).max(1), Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_out_of_order(&self) {
+        self.frames_out_of_order.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_duplicate(&self) {
+        self.duplicate_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_corrupt(&self) {
+        self.corrupt_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_undecodable(&self) {
+        self.undecodable_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_concealed(&self) {
+        self.concealed_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_throttled(&self) {
+        self.throttled_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_buffer_overflow(&self) {
+        self.buffer_overflows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_dropped(&self, samples: usize) {
+        self.dropped_samples
+            .fetch_add(samples as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_latency_skip(&self) {
+        self.latency_skips.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.frames_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.frames_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_clock_drift(&self, ppm: f64) {
+        self.clock_drift_ppm
+            .store(ppm.round() as i64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_send_bitrate(&self, bits_per_second: u64) {
+        self.send_bitrate.store(bits_per_second, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,39 @@
+use std::time::Instant;
+
+/// How much unused budget can be saved up, in seconds of the rate.
+const BURST_TIME: f64 = 2.0;
+
+/// Keeps the average send rate under a ceiling.
+///
+/// Works like a token bucket that may go into debt: a frame may be sent whenever the budget
+/// isn't used up, even if it is larger than what is left. The following frames then have to
+/// wait until the debt is earned back, so the average stays under the rate.
+pub struct Throttle {
+    bytes_per_second: f64,
+    budget: f64,
+    refilled: Instant,
+}
+
+impl Throttle {
+    pub fn new(bits_per_second: f64) -> Self {
+        Throttle {
+            bytes_per_second: bits_per_second / 8.0,
+            budget: 0.0,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Whether a frame may be sent now.
+    pub fn allows(&mut self) -> bool {
+        let now = Instant::now();
+        let earned = (now - self.refilled).as_secs_f64() * self.bytes_per_second;
+        self.budget = (self.budget + earned).min(self.bytes_per_second * BURST_TIME);
+        self.refilled = now;
+        self.budget >= 0.0
+    }
+
+    /// Takes a sent frame out of the budget.
+    pub fn spend(&mut self, bytes: usize) {
+        self.budget -= bytes as f64;
+    }
+}
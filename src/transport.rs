@@ -0,0 +1,353 @@
+//! What a call runs over: a TCP connection, a Unix domain socket, or an in-memory pipe.
+//!
+//! [`Client::chat_over`](crate::Client::chat_over) takes any [`Transport`], so a call can run
+//! without sockets, e.g. between two headless clients in the same process, where everything
+//! that flows in either direction can be looked at.
+//!
+//! Other links (a serial line, a WebSocket) can be plugged in by implementing [`Transport`]
+//! for them. Anything that `smol::Async` can wrap only needs to forward the four methods, like
+//! the implementations below. [`Transport`] itself can't be a trait object, as the waiting
+//! methods are async, so one chosen at runtime is boxed as a [`DynTransport`] instead:
+//! `Box<dyn DynTransport>` is a [`Transport`] too.
+
+use smol::Async;
+use smol::channel::{self, Receiver, Sender};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::pin::Pin;
+use std::sync::Mutex;
+
+/// A byte stream to the peer, read and written without blocking.
+pub trait Transport: Sync {
+    /// Reads what is available, failing with `WouldBlock` if nothing is. `Ok(0)` means the
+    /// peer closed the connection.
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Writes as much as fits right now, failing with `WouldBlock` if nothing does.
+    fn write(&self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Waits until `read` has something to return.
+    fn readable(&self) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Waits until `write` can take something.
+    fn writable(&self) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Whether the connection leads back to itself.
+    fn is_loopback(&self) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+/// A [`Transport`] that can be boxed, with the waiting futures boxed as well. Every transport
+/// is one. The methods are named apart from [`Transport`]'s so both traits can be in scope.
+pub trait DynTransport: Send + Sync {
+    /// See [`Transport::read`].
+    fn dyn_read(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// See [`Transport::write`].
+    fn dyn_write(&self, buf: &[u8]) -> io::Result<usize>;
+
+    /// See [`Transport::readable`].
+    fn dyn_readable(&self) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>>;
+
+    /// See [`Transport::writable`].
+    fn dyn_writable(&self) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>>;
+
+    /// See [`Transport::is_loopback`].
+    fn dyn_is_loopback(&self) -> io::Result<bool>;
+}
+
+impl<T: Transport + Send> DynTransport for T {
+    fn dyn_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Transport::read(self, buf)
+    }
+
+    fn dyn_write(&self, buf: &[u8]) -> io::Result<usize> {
+        Transport::write(self, buf)
+    }
+
+    fn dyn_readable(&self) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+        Box::pin(Transport::readable(self))
+    }
+
+    fn dyn_writable(&self) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+        Box::pin(Transport::writable(self))
+    }
+
+    fn dyn_is_loopback(&self) -> io::Result<bool> {
+        Transport::is_loopback(self)
+    }
+}
+
+impl Transport for Box<dyn DynTransport> {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).dyn_read(buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        (**self).dyn_write(buf)
+    }
+
+    fn readable(&self) -> impl Future<Output = io::Result<()>> + Send {
+        (**self).dyn_readable()
+    }
+
+    fn writable(&self) -> impl Future<Output = io::Result<()>> + Send {
+        (**self).dyn_writable()
+    }
+
+    fn is_loopback(&self) -> io::Result<bool> {
+        (**self).dyn_is_loopback()
+    }
+}
+
+impl Transport for Async<TcpStream> {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.get_ref().read(buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.get_ref().write(buf)
+    }
+
+    fn readable(&self) -> impl Future<Output = io::Result<()>> + Send {
+        Async::readable(self)
+    }
+
+    fn writable(&self) -> impl Future<Output = io::Result<()>> + Send {
+        Async::writable(self)
+    }
+
+    fn is_loopback(&self) -> io::Result<bool> {
+        Ok(self.get_ref().peer_addr()? == self.get_ref().local_addr()?)
+    }
+}
+
+/// E.g. to call a local process without a network port: `voice-chat` itself only speaks TCP,
+/// but an embedding app can connect or accept the socket and hand it to `chat_over`.
+#[cfg(unix)]
+impl Transport for Async<UnixStream> {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.get_ref().read(buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.get_ref().write(buf)
+    }
+
+    fn readable(&self) -> impl Future<Output = io::Result<()>> + Send {
+        Async::readable(self)
+    }
+
+    fn writable(&self) -> impl Future<Output = io::Result<()>> + Send {
+        Async::writable(self)
+    }
+}
+
+/// Writes all of `bytes`, waiting whenever the transport is full.
+pub(crate) async fn write_all(transport: &impl Transport, mut bytes: &[u8]) -> io::Result<()> {
+    while !bytes.is_empty() {
+        match transport.write(bytes) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => bytes = &bytes[n..],
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => transport.writable().await?,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Fills all of `buf`, waiting for the rest whenever nothing is available.
+pub(crate) async fn read_exact(transport: &impl Transport, mut buf: &mut [u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        match transport.read(buf) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => buf = &mut buf[n..],
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => transport.readable().await?,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Lets a transport be read like a file, for [`FrameReader::fill`](crate::protocol::FrameReader).
+pub(crate) struct Reader<'a, T>(pub &'a T);
+
+impl<T: Transport> Read for Reader<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+/// One end of an in-memory pipe, see [`MemoryTransport::pair`]. Writes never wait, and what is
+/// written arrives at the other end in order.
+pub struct MemoryTransport {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+    /// Received bytes that weren't read yet.
+    pending: Mutex<VecDeque<u8>>,
+}
+
+impl MemoryTransport {
+    /// Two connected ends. Dropping one closes the pipe: the other end reads the rest, then
+    /// `Ok(0)`, and fails to write.
+    pub fn pair() -> (Self, Self) {
+        let (a_sender, b_receiver) = channel::unbounded();
+        let (b_sender, a_receiver) = channel::unbounded();
+        let end = |sender, receiver| MemoryTransport {
+            sender,
+            receiver,
+            pending: Mutex::new(VecDeque::new()),
+        };
+        (end(a_sender, a_receiver), end(b_sender, b_receiver))
+    }
+
+    fn pending(&self) -> io::Result<std::sync::MutexGuard<'_, VecDeque<u8>>> {
+        self.pending
+            .lock()
+            .map_err(|_| io::Error::other("the pipe's buffer is poisoned"))
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pending = self.pending()?;
+        while let Ok(bytes) = self.receiver.try_recv() {
+            pending.extend(bytes);
+        }
+        if pending.is_empty() {
+            if self.receiver.is_closed() {
+                return Ok(0);
+            }
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(pending.len());
+        for (slot, byte) in buf.iter_mut().zip(pending.drain(..len)) {
+            *slot = byte;
+        }
+        Ok(len)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.sender
+            .try_send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    async fn readable(&self) -> io::Result<()> {
+        if !self.pending()?.is_empty() {
+            return Ok(());
+        }
+        // A closed pipe is readable too, `read` then reports the end.
+        if let Ok(bytes) = self.receiver.recv().await {
+            self.pending()?.extend(bytes);
+        }
+        Ok(())
+    }
+
+    async fn writable(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::transmit;
+    use crate::stats::Stats;
+
+    /// Takes at most 3 bytes per write, and is full on every other one, like a congested socket.
+    #[derive(Default)]
+    struct Congested {
+        written: Mutex<Vec<u8>>,
+        full: Mutex<bool>,
+    }
+
+    impl Transport for Congested {
+        fn read(&self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+
+        fn write(&self, buf: &[u8]) -> io::Result<usize> {
+            let mut full = self.full.lock().unwrap();
+            *full = !*full;
+            if !*full {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let len = buf.len().min(3);
+            self.written.lock().unwrap().extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        async fn readable(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        async fn writable(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn partial_writes_are_continued() {
+        let transport = Congested::default();
+        let bytes: Vec<u8> = (0..100).collect();
+        smol::block_on(write_all(&transport, &bytes)).unwrap();
+        assert_eq!(*transport.written.lock().unwrap(), bytes);
+    }
+
+    #[test]
+    fn queued_frames_are_written_whole_and_in_order() {
+        let transport = Congested::default();
+        let stats = Stats::default();
+        let (queue, queued) = channel::bounded(4);
+        let frames = [vec![1; 10], vec![2; 7], vec![3; 1]];
+        for frame in &frames {
+            queue.try_send(frame.clone()).unwrap();
+        }
+        drop(queue);
+        smol::block_on(transmit(&transport, queued, &stats)).unwrap();
+        assert_eq!(*transport.written.lock().unwrap(), frames.concat());
+        assert_eq!(stats.frames_sent(), 3);
+        assert_eq!(stats.bytes_sent(), 18);
+    }
+
+    #[test]
+    fn pipe_carries_bytes_in_order() {
+        let (a, b) = MemoryTransport::pair();
+        let mut buf = [0; 8];
+        assert_eq!(
+            b.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        a.write(&[1, 2, 3]).unwrap();
+        a.write(&[4, 5]).unwrap();
+        assert_eq!(b.read(&mut buf[..4]).unwrap(), 4);
+        assert_eq!(b.read(&mut buf[4..]).unwrap(), 1);
+        assert_eq!(buf[..5], [1, 2, 3, 4, 5]);
+        // And the other way around.
+        b.write(&[6]).unwrap();
+        smol::block_on(read_exact(&a, &mut buf[..1])).unwrap();
+        assert_eq!(buf[0], 6);
+    }
+
+    #[test]
+    fn closed_pipe_reads_the_rest_then_the_end() {
+        let (a, b) = MemoryTransport::pair();
+        a.write(&[1, 2]).unwrap();
+        drop(a);
+        let mut buf = [0; 4];
+        smol::block_on(b.readable()).unwrap();
+        assert_eq!(b.read(&mut buf).unwrap(), 2);
+        smol::block_on(b.readable()).unwrap();
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+        assert_eq!(b.write(&[3]).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -1,28 +1,84 @@
 use super::Opt;
+use super::error::VoiceChatError;
+use super::protocol::WireFormat;
+use super::stats::Stats;
 use cpal::traits::{DeviceTrait, HostTrait};
-use cpal::{Device, Host, StreamConfig};
-use std::error::Error;
+use cpal::{
+    BufferSize, Device, FromSample, Host, SampleFormat, SizedSample, Stream, StreamConfig,
+    StreamError, SupportedBufferSize, SupportedStreamConfigRange,
+};
+use log::{error, info, warn};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
 
-const NOISE_THRESHOLD: f32 = 0.20;
+/// Sample formats streams can be opened in. The audio is converted to and from `f32` for all
+/// but `F32`, which is preferred.
+const SAMPLE_FORMATS: &[SampleFormat] = &[
+    SampleFormat::F32,
+    SampleFormat::I16,
+    SampleFormat::U16,
+    SampleFormat::I32,
+];
 
-/// Normalizes the audio data and filters out most noise.
-pub fn normalize(vector: &[f32]) -> Vec<f32> {
-    // filter out (most) noise
-    let mut vector: Vec<f32> = vector
-        .iter()
-        .map(|f| if f.abs() < 1e-4f32 { &0.0f32 } else { f })
-        .copied()
-        .collect();
+/// Buffer size used by `--low-latency`, if the device allows it: about 12 ms at 22050 Hz.
+/// Even smaller buffers tend to glitch.
+const LOW_LATENCY_FRAMES: u32 = 256;
+
+/// The step between two 16-bit sample values, in `f32` samples.
+const I16_STEP: f32 = 1.0 / 32768.0;
+
+/// xorshift32: fast, and random enough for noise and dither.
+pub struct Rng {
+    /// Never zero, or it would stay zero.
+    state: u32,
+}
+
+impl Rng {
+    /// Seeded differently every time.
+    pub fn new() -> Self {
+        Rng::with_seed(RandomState::new().build_hasher().finish() as u32)
+    }
+
+    /// Gives the same numbers every time for the same seed.
+    pub fn with_seed(seed: u32) -> Self {
+        Rng { state: seed.max(1) }
+    }
+
+    /// Uniform in [0.0, 1.0].
+    pub fn next_f32(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32
+    }
+}
 
-    if vector.iter().filter(|f| **f == 0.0f32).count()
-        > (vector.len() as f32 * NOISE_THRESHOLD) as usize
-    {
-        vector = Vec::new();
+/// Triangular-PDF dither for converting to 16-bit samples. Quantizing quiet audio on its own
+/// distorts it; the added noise (about one step) turns that into a steady, faint hiss.
+pub struct Dither {
+    rng: Rng,
+}
+
+impl Dither {
+    pub fn new() -> Self {
+        Dither { rng: Rng::new() }
     }
 
+    /// The difference of two uniform values is triangular in [-1.0, 1.0] steps, so the noise
+    /// averages out and doesn't depend on the signal.
+    pub fn apply(&mut self, sample: f32) -> f32 {
+        let noise = (self.rng.next_f32() - self.rng.next_f32()) * I16_STEP;
+        (sample + noise).clamp(-1.0, 1.0)
+    }
+}
+
+/// Normalizes the audio data to [-1.0, 1.0]. Silence stays silent.
+pub fn normalize(vector: &[f32]) -> Vec<f32> {
     let mut min: f32 = 100.0;
     let mut max: f32 = -100.0;
-    for i in &vector {
+    for i in vector {
         let f = *i;
         if f < min {
             min = f;
@@ -32,130 +88,1028 @@ pub fn normalize(vector: &[f32]) -> Vec<f32> {
         }
     }
 
+    if max <= min {
+        return vec![0.0; vector.len()];
+    }
+
     let norm = |f: &f32| 2.0 * ((f - min) / (max - min)) - 1.0; // [-1.0, 1.0]
     vector.iter().map(norm).collect()
 }
 
-/// Converts the byte array sent over the TcpStream to audio data (Vec<f32>).
-pub fn buffer_to_audio_data(buffer: &[u8]) -> Vec<f32> {
-    let mut audio_data: Vec<f32> = Vec::with_capacity(buffer.len() / 4);
-    for i in (0..buffer.len()).step_by(4) {
-        let flt = f32::from_le_bytes([buffer[i], buffer[i + 1], buffer[i + 2], buffer[i + 3]]);
-        audio_data.push(flt);
-    }
-    audio_data
-}
-
-#[allow(unused_variables)]
-pub fn get_audio_host(opt: &Opt) -> Host {
-    // Conditionally compile with jack if the feature is specified.
-    #[cfg(all(
-        any(
-            target_os = "linux",
-            target_os = "dragonfly",
-            target_os = "freebsd",
-            target_os = "netbsd"
-        ),
-        feature = "jack"
-    ))]
-    // Manually check for flags. Can be passed through cargo with -- e.g.
-    // cargo run --release --example beep --features jack -- --jack
-    let audio_host = if opt.jack {
-        println!("HINT: using jack");
-        cpal::host_from_id(cpal::available_hosts()
-            .into_iter()
-            .find(|id| *id == cpal::HostId::Jack)
-            .expect(
-                "Make sure --features jack is specified. Only works on OSes where jack is available!",
-            )).expect("jack host unavailable!")
-    } else {
-        cpal::default_host()
+/// Converts the samples of an audio frame, sent in `format`, to audio data. A partial sample
+/// at the end is ignored.
+pub fn decode_samples(buffer: &[u8], format: WireFormat) -> Vec<f32> {
+    match format {
+        WireFormat::F32 => buffer
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+        WireFormat::I16 => buffer
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / i16::MAX as f32)
+            .collect(),
+    }
+}
+
+/// Received samples beyond this can't come from a peer, which limits what it sends to about
+/// ±1.0. Played, they would be a burst of noise.
+const MAX_RECEIVED_LEVEL: f32 = 2.0;
+
+/// Decodes the samples of a received audio frame with `channels` channels. Frames that can't
+/// be valid audio (not made of whole multi-channel frames, or with samples that aren't finite
+/// or far beyond full scale) turn into silence of the same length and are counted in
+/// [`Stats::undecodable_frames`], so a malformed frame is never played as noise.
+pub fn decode_audio(buffer: &[u8], format: WireFormat, channels: usize, stats: &Stats) -> Vec<f32> {
+    let channels = channels.max(1);
+    let samples = decode_samples(buffer, format);
+    let valid = buffer.len().is_multiple_of(format.sample_size() * channels)
+        && samples
+            .iter()
+            .all(|f| f.is_finite() && f.abs() <= MAX_RECEIVED_LEVEL);
+    if valid {
+        return samples;
+    }
+    stats.count_undecodable();
+    vec![0.0; samples.len() / channels * channels]
+}
+
+/// How samples are kept within [-1.0, 1.0] after gain has been applied.
+#[derive(Clone, Copy)]
+pub enum Limiter {
+    /// Cut off everything beyond ±1.0.
+    Clamp,
+    /// Round off peaks with `tanh`, which sounds less harsh but also colors quieter samples.
+    Soft,
+}
+
+/// Bounds the samples to [-1.0, 1.0], so over-driven audio doesn't clip on the receiver.
+pub fn limit(samples: &mut [f32], limiter: Limiter) {
+    for sample in samples {
+        *sample = match limiter {
+            Limiter::Clamp => sample.clamp(-1.0, 1.0),
+            Limiter::Soft => sample.tanh(),
+        };
+    }
+}
+
+/// Scales the samples by a volume factor and keeps them within [-1.0, 1.0].
+pub fn apply_volume(samples: &mut [f32], volume: f32, limiter: Limiter) {
+    for sample in samples.iter_mut() {
+        *sample *= volume;
+    }
+    limit(samples, limiter);
+}
+
+/// Maps interleaved audio from one channel count to another. Extra output channels repeat
+/// the input channels (mono is duplicated), missing ones are averaged together (stereo to mono).
+pub fn remix(samples: &[f32], from_channels: usize, to_channels: usize) -> Vec<f32> {
+    if from_channels == to_channels || from_channels == 0 || to_channels == 0 {
+        return samples.to_vec();
+    }
+    let mut remixed = Vec::with_capacity(samples.len() / from_channels * to_channels);
+    for frame in samples.chunks_exact(from_channels) {
+        for channel in 0..to_channels {
+            if to_channels > from_channels {
+                remixed.push(frame[channel % from_channels]);
+            } else {
+                let sources = frame.iter().skip(channel).step_by(to_channels);
+                let count = sources.clone().count();
+                remixed.push(sources.sum::<f32>() / count as f32);
+            }
+        }
+    }
+    remixed
+}
+
+/// Picks the audio host given by `--host`, e.g. `jack` or `alsa`. Without one, JACK is used if
+/// the `jack` feature is enabled, and the platform's default host otherwise.
+pub fn get_audio_host(opt: &Opt) -> Result<Host, VoiceChatError> {
+    let name = match &opt.host {
+        Some(name) => name.as_str(),
+        None if opt.jack => "jack",
+        None => "default",
     };
-    #[cfg(any(
-        not(any(
-            target_os = "linux",
-            target_os = "dragonfly",
-            target_os = "freebsd",
-            target_os = "netbsd"
-        )),
-        not(feature = "jack")
-    ))]
-    let audio_host = cpal::default_host();
-
-    audio_host
-}
-
-/// Set up the input device and stream with the default input config.
-pub fn get_input_device(audio_host: &Host, opt: &Opt) -> Result<Device, Box<dyn Error>> {
-    let input_device = if opt.input_device == "default" {
-        audio_host.default_input_device()
-    } else {
-        audio_host
-            .input_devices()?
-            .find(|x| x.name().map(|y| y == opt.input_device).unwrap_or(false))
+    if name == "default" {
+        return Ok(cpal::default_host());
     }
-    .expect("Failed to find input device!");
-    println!("Input device: {}", input_device.name()?);
+    let hosts = cpal::available_hosts();
+    match hosts.iter().find(|id| id.name().eq_ignore_ascii_case(name)) {
+        Some(id) => {
+            info!("Using {}", id.name());
+            Ok(cpal::host_from_id(*id)?)
+        }
+        None => {
+            warn!("Available audio hosts:");
+            for id in &hosts {
+                warn!("  {}", id.name().to_lowercase());
+            }
+            Err(VoiceChatError::HostNotFound(name.to_string()))
+        }
+    }
+}
+
+/// Parts of the names hosts give to devices that capture what is played rather than a mic:
+/// PulseAudio and PipeWire monitor sources, Windows' "Stereo Mix", and virtual loopback
+/// drivers such as BlackHole on macOS.
+const LOOPBACK_NAMES: [&str; 5] = [
+    "monitor",
+    "loopback",
+    "stereo mix",
+    "what u hear",
+    "blackhole",
+];
+
+/// Whether the input device with this name looks like it captures system audio.
+pub fn is_loopback_source(name: &str) -> bool {
+    let name = name.to_lowercase();
+    LOOPBACK_NAMES.iter().any(|part| name.contains(part))
+}
+
+/// The input devices, or with `loopback_only` only those that look like they capture system
+/// audio (see [`is_loopback_source`]).
+pub fn input_devices(
+    audio_host: &Host,
+    loopback_only: bool,
+) -> Result<impl Iterator<Item = Device>, VoiceChatError> {
+    Ok(audio_host.input_devices()?.filter(move |device| {
+        !loopback_only || device.name().is_ok_and(|name| is_loopback_source(&name))
+    }))
+}
+
+/// Looks up the input device by name or number, `"default"` being the default one of
+/// `audio_host` (not of the platform's default host, if `--host` picked another).
+///
+/// With `loopback_only`, only system audio sources count (numbered among themselves), and
+/// `"default"` is the first of them.
+pub fn get_input_device(
+    audio_host: &Host,
+    name: &str,
+    loopback_only: bool,
+) -> Result<Device, VoiceChatError> {
+    let input_device = if loopback_only && name == "default" {
+        match input_devices(audio_host, true)?.next() {
+            Some(device) => device,
+            None => {
+                warn!("No input device looks like a monitor or loopback source");
+                return Err(VoiceChatError::DeviceNotFound {
+                    kind: "loopback input",
+                    name: name.to_string(),
+                });
+            }
+        }
+    } else if name == "default" {
+        audio_host
+            .default_input_device()
+            .ok_or_else(|| VoiceChatError::NoInputDevice(audio_host.id().name().to_string()))?
+    } else if loopback_only {
+        find_device(input_devices(audio_host, true)?, name, "loopback input")?
+    } else {
+        find_device(audio_host.input_devices()?, name, "input")?
+    };
+    info!("Input device: {}", input_device.name()?);
     Ok(input_device)
 }
 
-/// Set up the output device and stream with the default output config.
-pub fn get_output_device(audio_host: &Host, opt: &Opt) -> Result<Device, Box<dyn Error>> {
-    let output_device = if opt.output_device == "default" {
-        audio_host.default_output_device()
+/// Looks up the output device by name or number, `"default"` being the default one of
+/// `audio_host`.
+pub fn get_output_device(audio_host: &Host, name: &str) -> Result<Device, VoiceChatError> {
+    let output_device = if name == "default" {
+        audio_host
+            .default_output_device()
+            .ok_or_else(|| VoiceChatError::NoOutputDevice(audio_host.id().name().to_string()))?
     } else {
-        for dev in audio_host.output_devices()? {
-            println!("{}", dev.name()?);
+        find_device(audio_host.output_devices()?, name, "output")?
+    };
+    info!("Output device: {}", output_device.name()?);
+    Ok(output_device)
+}
+
+/// Names of the devices, in the order they are numbered in.
+pub fn device_names(devices: impl Iterator<Item = Device>) -> Vec<String> {
+    devices
+        .map(|device| device.name().unwrap_or_default())
+        .collect()
+}
+
+/// Where the device called `name` is among `names`, or the one with that number (counting
+/// from 1, as `--list-devices` shows them) if `name` is a number.
+fn device_index(names: &[String], name: &str) -> Option<usize> {
+    match name.parse::<usize>() {
+        Ok(number) => number.checked_sub(1).filter(|&index| index < names.len()),
+        Err(_) => names.iter().position(|device_name| device_name == name),
+    }
+}
+
+/// Looks up a device by name or number, see [`device_index`]. If there is none, the
+/// available devices are listed so the user can correct the name.
+fn find_device(
+    devices: impl Iterator<Item = Device>,
+    name: &str,
+    kind: &'static str,
+) -> Result<Device, VoiceChatError> {
+    let mut devices: Vec<Device> = devices.collect();
+    let names = device_names(devices.iter().cloned());
+    if let Some(index) = device_index(&names, name) {
+        return Ok(devices.swap_remove(index));
+    }
+    warn!("Available {kind} devices:");
+    for (number, device_name) in (1..).zip(names) {
+        warn!("  [{number}] {device_name}");
+    }
+    Err(VoiceChatError::DeviceNotFound {
+        kind,
+        name: name.to_string(),
+    })
+}
+
+/// The best format among `supported_configs`: the highest sample rate, then the most channels
+/// at it. `None` if there is no config with a usable sample format.
+pub fn best_format(supported_configs: &[SupportedStreamConfigRange]) -> Option<StreamFormat> {
+    supported_configs
+        .iter()
+        .filter(|cfg| SAMPLE_FORMATS.contains(&cfg.sample_format()))
+        .map(|cfg| (cfg.max_sample_rate().0, cfg.channels()))
+        .max()
+        .map(|(sample_rate, channels)| StreamFormat {
+            sample_rate,
+            channels,
+            buffer_frames: None,
+        })
+}
+
+/// The device among `devices` with the best format (see [`best_format`]) in
+/// `supported_configs`, for `--best-input` and `--best-output`. Ties go to the one named
+/// `default_name`, then to the first one listed, so the same devices always give the same
+/// choice.
+pub fn best_device(
+    devices: impl Iterator<Item = Device>,
+    default_name: Option<String>,
+    kind: &'static str,
+    supported_configs: impl Fn(&Device) -> Result<Vec<SupportedStreamConfigRange>, VoiceChatError>,
+) -> Result<Device, VoiceChatError> {
+    let mut best: Option<(Device, StreamFormat, String)> = None;
+    let mut count = 0;
+    for device in devices {
+        let name = device.name().unwrap_or_default();
+        let format = match supported_configs(&device) {
+            Ok(configs) => best_format(&configs),
+            Err(e) => {
+                warn!("Skipping {kind} device '{name}': {e}");
+                None
+            }
+        };
+        let Some(format) = format else {
+            continue;
+        };
+        count += 1;
+        let better = match &best {
+            None => true,
+            Some((_, best_format, best_name)) => is_better(
+                (&format, &name),
+                (best_format, best_name),
+                default_name.as_deref(),
+            ),
+        };
+        if better {
+            best = Some((device, format, name));
         }
-        audio_host
-            .output_devices()?
-            .find(|x| x.name().map(|y| y == opt.output_device).unwrap_or(false))
     }
-    .expect("Failed to find output device!");
-    println!("Output device: {}", output_device.name()?);
-    Ok(output_device)
+    let Some((device, format, name)) = best else {
+        warn!("No {kind} device has a config with a supported sample format");
+        return Err(VoiceChatError::DeviceNotFound {
+            kind,
+            name: String::from("best"),
+        });
+    };
+    info!("Picked {kind} device '{name}' for its {format}, the best of {count} device(s)");
+    Ok(device)
 }
 
-/// Get the input config for the input device.
-pub fn get_input_config(device: &Device) -> StreamConfig {
-    let mut supported_configs_range = device
-        .supported_input_configs()
-        .expect("Error while querying configs!");
-    let supported_config = if let Some(cfg) = supported_configs_range
-        .next()
-        .expect("No supported config!")
-        .try_with_sample_rate(cpal::SampleRate(22050))
-    {
-        cfg
-    } else {
-        eprintln!("Failed to use 22.05 kHz SR!");
-        supported_configs_range
-            .next()
-            .expect("No supported config!")
-            .with_max_sample_rate()
+/// Whether the device `candidate` (its best format and name) beats the best one so far, see
+/// [`best_device`].
+fn is_better(
+    candidate: (&StreamFormat, &str),
+    best: (&StreamFormat, &str),
+    default_name: Option<&str>,
+) -> bool {
+    let quality = |format: &StreamFormat| (format.sample_rate, format.channels);
+    quality(candidate.0) > quality(best.0)
+        || (quality(candidate.0) == quality(best.0)
+            && default_name == Some(candidate.1)
+            && default_name != Some(best.1))
+}
+
+/// A capture or playback format forced with `--input-format` or `--output-format`, given as
+/// `RATE:CHANNELS` or `RATE:CHANNELS:BUFFER_FRAMES`, e.g. `48000:2:960`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StreamFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// The device's default buffer size if not given.
+    pub buffer_frames: Option<u32>,
+}
+
+impl FromStr for StreamFormat {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = spec.split(':').map(str::trim).collect();
+        let number = |part: &str, what: &str| match part.parse::<u32>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("bad {what} '{part}' in '{spec}'")),
+        };
+        let (rate, channels, buffer_frames) = match parts[..] {
+            [rate, channels] => (rate, channels, None),
+            [rate, channels, frames] => (rate, channels, Some(number(frames, "buffer size")?)),
+            _ => {
+                return Err(format!(
+                    "expected RATE:CHANNELS[:BUFFER_FRAMES], got '{spec}'"
+                ));
+            }
+        };
+        Ok(StreamFormat {
+            sample_rate: number(rate, "sample rate")?,
+            channels: u16::try_from(number(channels, "channel count")?)
+                .map_err(|_| format!("bad channel count '{channels}' in '{spec}'"))?,
+            buffer_frames,
+        })
+    }
+}
+
+impl fmt::Display for StreamFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} Hz, {} channel(s)", self.sample_rate, self.channels)?;
+        if let Some(frames) = self.buffer_frames {
+            write!(f, ", {frames}-frame buffer")?;
+        }
+        Ok(())
+    }
+}
+
+/// Get the input config for the input device, and the sample format to open it in.
+pub fn get_input_config(
+    device: &Device,
+    opt: &Opt,
+) -> Result<(StreamConfig, SampleFormat), VoiceChatError> {
+    let supported_configs: Vec<_> = device.supported_input_configs()?.collect();
+    match (opt.input_format, best_format(&supported_configs)) {
+        (Some(format), _) => exact_config(supported_configs, format, "as given"),
+        (None, Some(format)) if opt.best_input => {
+            exact_config(supported_configs, format, "the best it supports")
+        }
+        (None, _) => select_config(supported_configs, opt),
+    }
+}
+
+/// Get the output config for the output device, and the sample format to open it in.
+pub fn get_output_config(
+    device: &Device,
+    opt: &Opt,
+) -> Result<(StreamConfig, SampleFormat), VoiceChatError> {
+    let supported_configs: Vec<_> = device.supported_output_configs()?.collect();
+    match (opt.output_format, best_format(&supported_configs)) {
+        (Some(format), _) => exact_config(supported_configs, format, "as given"),
+        (None, Some(format)) if opt.best_output => {
+            exact_config(supported_configs, format, "the best it supports")
+        }
+        (None, _) => select_config(supported_configs, opt),
+    }
+}
+
+/// Builds the config for exactly this format, preferring `f32` samples, or fails with what the
+/// device supports instead. `origin` says where the format came from, for the log.
+fn exact_config(
+    supported_configs: Vec<SupportedStreamConfigRange>,
+    format: StreamFormat,
+    origin: &str,
+) -> Result<(StreamConfig, SampleFormat), VoiceChatError> {
+    let mut candidates: Vec<&SupportedStreamConfigRange> = supported_configs
+        .iter()
+        .filter(|cfg| SAMPLE_FORMATS.contains(&cfg.sample_format()))
+        .collect();
+    candidates.sort_by_key(|cfg| cfg.sample_format() != SampleFormat::F32);
+    let fits = |cfg: &SupportedStreamConfigRange| {
+        let buffer_fits = match (format.buffer_frames, cfg.buffer_size()) {
+            (Some(frames), SupportedBufferSize::Range { min, max }) => {
+                (*min..=*max).contains(&frames)
+            }
+            _ => true,
+        };
+        cfg.channels() == format.channels
+            && (cfg.min_sample_rate().0..=cfg.max_sample_rate().0).contains(&format.sample_rate)
+            && buffer_fits
+    };
+    let Some(supported_config) = candidates.into_iter().find(|cfg| fits(cfg)) else {
+        let supported: Vec<String> = supported_configs
+            .iter()
+            .map(|cfg| {
+                let buffer = match cfg.buffer_size() {
+                    SupportedBufferSize::Range { min, max } => format!("{min}-{max} frames"),
+                    SupportedBufferSize::Unknown => String::from("any buffer size"),
+                };
+                format!(
+                    "{}-{} Hz, {} channel(s), {}, {buffer}",
+                    cfg.min_sample_rate().0,
+                    cfg.max_sample_rate().0,
+                    cfg.channels(),
+                    cfg.sample_format()
+                )
+            })
+            .collect();
+        return Err(VoiceChatError::UnsupportedConfig(format!(
+            "the device doesn't support {format}. It supports: {}",
+            supported.join("; ")
+        )));
+    };
+    let sample_format = supported_config.sample_format();
+    let mut config: StreamConfig = supported_config
+        .with_sample_rate(cpal::SampleRate(format.sample_rate))
+        .into();
+    config.buffer_size = format
+        .buffer_frames
+        .map_or(BufferSize::Default, BufferSize::Fixed);
+    info!("Config: {format} ({origin}), {sample_format}");
+    Ok((config, sample_format))
+}
+
+/// Picks a config with the requested channel count (if any), preferring the requested sample
+/// rates in order and `f32` samples, and applies the requested buffer size if the device
+/// supports it. If the device supports none of the rates, its highest one is used.
+///
+/// With `--low-latency`, configs that allow the smallest buffers win over the sample format,
+/// and a small buffer is used unless `--buffer-frames` is given.
+fn select_config(
+    supported_configs: Vec<SupportedStreamConfigRange>,
+    opt: &Opt,
+) -> Result<(StreamConfig, SampleFormat), VoiceChatError> {
+    let supported_configs: Vec<SupportedStreamConfigRange> = supported_configs
+        .into_iter()
+        .filter(|cfg| SAMPLE_FORMATS.contains(&cfg.sample_format()))
+        .collect();
+    let mut candidates: Vec<SupportedStreamConfigRange> = match opt.channels {
+        Some(n) if supported_configs.iter().any(|cfg| cfg.channels() == n) => supported_configs
+            .into_iter()
+            .filter(|cfg| cfg.channels() == n)
+            .collect(),
+        Some(n) => {
+            warn!("No config with {n} channels!");
+            supported_configs
+        }
+        None => supported_configs,
     };
-    let config: StreamConfig = supported_config.into();
-    config
-}
-
-/// Get the output config for the output device.
-pub fn get_output_config(device: &Device) -> StreamConfig {
-    let mut supported_configs_range = device
-        .supported_output_configs()
-        .expect("Error while querying configs!");
-    let supported_config = if let Some(cfg) = supported_configs_range
-        .next()
-        .expect("No supported config!")
-        .try_with_sample_rate(cpal::SampleRate(22050))
-    {
-        cfg
+    // Stable, so the device's order is kept otherwise.
+    candidates.sort_by_key(|cfg| cfg.sample_format() != SampleFormat::F32);
+    let rates = &opt.sample_rates;
+    if opt.low_latency {
+        // Configs without a requested rate come last, so the rate stays reasonable.
+        candidates.sort_by_key(|cfg| {
+            (
+                !rates
+                    .iter()
+                    .any(|&rate| cfg.try_with_sample_rate(cpal::SampleRate(rate)).is_some()),
+                min_buffer_frames(cfg),
+            )
+        });
+    }
+    let first = *candidates.first().ok_or_else(|| {
+        VoiceChatError::UnsupportedConfig(String::from(
+            "no config with a supported sample format (f32, i16, u16 or i32) available",
+        ))
+    })?;
+    let supported_config = rates
+        .iter()
+        .find_map(|&rate| {
+            candidates
+                .iter()
+                .find_map(|cfg| cfg.try_with_sample_rate(cpal::SampleRate(rate)))
+        })
+        .unwrap_or_else(|| {
+            let fallback = first.with_max_sample_rate();
+            let rates: Vec<String> = rates.iter().map(u32::to_string).collect();
+            warn!(
+                "Failed to use {} Hz SR, using {} Hz instead!",
+                rates.join("/"),
+                fallback.sample_rate().0
+            );
+            fallback
+        });
+
+    let buffer_size = match (opt.buffer_frames, supported_config.buffer_size()) {
+        (None, SupportedBufferSize::Range { min, max }) if opt.low_latency => {
+            BufferSize::Fixed(LOW_LATENCY_FRAMES.clamp(*min, *max))
+        }
+        (None, _) => BufferSize::Default,
+        (Some(frames), SupportedBufferSize::Range { min, max })
+            if !(*min..=*max).contains(&frames) =>
+        {
+            warn!("Buffer size of {frames} frames is not supported (range: {min}-{max})!");
+            BufferSize::Default
+        }
+        (Some(frames), _) => BufferSize::Fixed(frames),
+    };
+    let sample_format = supported_config.sample_format();
+    if sample_format != SampleFormat::F32 {
+        info!("Sample format: {sample_format}, converting from/to f32");
+    }
+    let mut config: StreamConfig = supported_config.into();
+    config.buffer_size = buffer_size;
+    info!(
+        "Config: {} Hz, {} channel(s), {sample_format}",
+        config.sample_rate.0, config.channels
+    );
+    match config.buffer_size {
+        BufferSize::Fixed(frames) => info!("Buffer size: {frames} frames"),
+        BufferSize::Default => info!("Buffer size: device default"),
+    }
+    Ok((config, sample_format))
+}
+
+/// Voices sound muffled below this input sample rate.
+const MIN_VOICE_RATE: u32 = 16000;
+
+/// Describes the differences between the chosen input and output configs that can be heard,
+/// with what to do about them. Empty if they match.
+pub fn config_mismatches(input: &StreamConfig, output: &StreamConfig) -> Vec<String> {
+    let mut mismatches = Vec::new();
+    if input.sample_rate != output.sample_rate || input.channels != output.channels {
+        mismatches.push(format!(
+            "Input is {}ch/{} Hz, output is {}ch/{} Hz: audio is resampled and remixed in \
+             between, which costs a little quality. Pass a --sample-rate (and --channels) both \
+             devices support to avoid it.",
+            input.channels, input.sample_rate.0, output.channels, output.sample_rate.0
+        ));
+    }
+    if input.sample_rate.0 < MIN_VOICE_RATE {
+        mismatches.push(format!(
+            "Input is only {} Hz, voices will sound muffled. Pass --sample-rate 48000 if the \
+             device supports it.",
+            input.sample_rate.0
+        ));
+    }
+    mismatches
+}
+
+/// How much faster the peer's audio would play at `output_rate` without resampling: 0.5 is
+/// half as fast, an octave lower.
+fn pitch_factor(peer_rate: u32, output_rate: u32) -> f64 {
+    output_rate as f64 / peer_rate as f64
+}
+
+/// Explains what a peer sending at `peer_rate` would sound like played at `output_rate`, and
+/// that it is resampled so it doesn't.
+pub fn rate_mismatch(peer_rate: u32, output_rate: u32) -> String {
+    let factor = pitch_factor(peer_rate, output_rate);
+    let (factor, pace) = if factor < 1.0 {
+        (1.0 / factor, "slow")
     } else {
-        eprintln!("Failed to use 22.05 kHz SR!");
-        supported_configs_range
-            .next()
-            .expect("No supported config!")
-            .with_max_sample_rate()
+        (factor, "fast")
     };
-    let config: StreamConfig = supported_config.into();
-    config
+    format!(
+        "Peer sends at {peer_rate} Hz, this plays at {output_rate} Hz: played as is, its audio \
+         would sound ~{factor:.2}x too {pace}, so it is resampled"
+    )
+}
+
+/// The smallest buffer a config allows, in frames. Unknown sizes sort last.
+fn min_buffer_frames(config: &SupportedStreamConfigRange) -> u32 {
+    match config.buffer_size() {
+        SupportedBufferSize::Range { min, .. } => *min,
+        SupportedBufferSize::Unknown => u32::MAX,
+    }
+}
+
+/// Logs stream errors, and calls `on_lost` if the device is gone (e.g. unplugged).
+fn error_callback(mut on_lost: impl FnMut() + Send + 'static) -> impl FnMut(StreamError) {
+    move |e| {
+        error!("Stream error: {e}");
+        if let StreamError::DeviceNotAvailable = e {
+            on_lost();
+        }
+    }
+}
+
+/// Opens an input stream in `format`, handing the captured audio to `callback` as `f32`.
+/// `on_lost` is called if the device disappears.
+pub fn build_input_stream(
+    device: &Device,
+    config: &StreamConfig,
+    format: SampleFormat,
+    on_lost: impl FnMut() + Send + 'static,
+    callback: impl FnMut(&[f32]) + Send + 'static,
+) -> Result<Stream, VoiceChatError> {
+    match format {
+        SampleFormat::F32 => input_stream::<f32>(device, config, on_lost, callback),
+        SampleFormat::I16 => input_stream::<i16>(device, config, on_lost, callback),
+        SampleFormat::U16 => input_stream::<u16>(device, config, on_lost, callback),
+        SampleFormat::I32 => input_stream::<i32>(device, config, on_lost, callback),
+        other => Err(VoiceChatError::UnsupportedConfig(format!(
+            "sample format {other} is not supported"
+        ))),
+    }
+}
+
+fn input_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    on_lost: impl FnMut() + Send + 'static,
+    mut callback: impl FnMut(&[f32]) + Send + 'static,
+) -> Result<Stream, VoiceChatError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let mut converted: Vec<f32> = Vec::new();
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            converted.clear();
+            converted.extend(data.iter().map(|sample| sample.to_sample::<f32>()));
+            callback(&converted);
+        },
+        error_callback(on_lost),
+        None,
+    )?;
+    Ok(stream)
+}
+
+/// Opens an output stream in `format`, letting `callback` fill the played audio as `f32`.
+/// With `dither`, 16-bit formats are dithered. `on_lost` is called if the device disappears.
+pub fn build_output_stream(
+    device: &Device,
+    config: &StreamConfig,
+    format: SampleFormat,
+    dither: bool,
+    on_lost: impl FnMut() + Send + 'static,
+    callback: impl FnMut(&mut [f32]) + Send + 'static,
+) -> Result<Stream, VoiceChatError> {
+    let dither = dither.then(Dither::new);
+    match format {
+        SampleFormat::F32 => output_stream::<f32>(device, config, None, on_lost, callback),
+        SampleFormat::I16 => output_stream::<i16>(device, config, dither, on_lost, callback),
+        SampleFormat::U16 => output_stream::<u16>(device, config, dither, on_lost, callback),
+        SampleFormat::I32 => output_stream::<i32>(device, config, None, on_lost, callback),
+        other => Err(VoiceChatError::UnsupportedConfig(format!(
+            "sample format {other} is not supported"
+        ))),
+    }
+}
+
+fn output_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    mut dither: Option<Dither>,
+    on_lost: impl FnMut() + Send + 'static,
+    mut callback: impl FnMut(&mut [f32]) + Send + 'static,
+) -> Result<Stream, VoiceChatError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let mut unconverted: Vec<f32> = Vec::new();
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            unconverted.clear();
+            unconverted.resize(data.len(), 0.0);
+            callback(&mut unconverted);
+            for (out, sample) in data.iter_mut().zip(&unconverted) {
+                *out = match &mut dither {
+                    Some(dither) => T::from_sample(dither.apply(*sample)),
+                    None => T::from_sample(*sample),
+                };
+            }
+        },
+        error_callback(on_lost),
+        None,
+    )?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_keeps_the_length() {
+        assert_eq!(normalize(&[0.0; 480]), vec![0.0; 480]);
+        assert_eq!(normalize(&[0.3; 7]), vec![0.0; 7]);
+        assert!(normalize(&[]).is_empty());
+        let quiet = [0.0, 0.0, 0.0, 0.001, -0.001, 0.0];
+        assert_eq!(normalize(&quiet).len(), quiet.len());
+    }
+
+    #[test]
+    fn normalize_spans_full_scale() {
+        let normalized = normalize(&[-0.1, 0.0, 0.2]);
+        assert_eq!(normalized[0], -1.0);
+        assert_eq!(normalized[2], 1.0);
+        assert!(normalized.iter().all(|f| (-1.0..=1.0).contains(f)));
+    }
+
+    #[test]
+    fn clamping_cuts_off_beyond_full_scale() {
+        let mut samples = [-3.0, -0.5, 0.0, 0.5, 3.0];
+        limit(&mut samples, Limiter::Clamp);
+        assert_eq!(samples, [-1.0, -0.5, 0.0, 0.5, 1.0]);
+    }
+
+    #[test]
+    fn soft_limiting_rounds_off_peaks() {
+        let mut samples = [-3.0, 0.0, 0.01, 3.0];
+        limit(&mut samples, Limiter::Soft);
+        assert!(samples.iter().all(|f| (-1.0..=1.0).contains(f)));
+        assert!(samples[3] > 0.99 && samples[0] < -0.99);
+        assert!((samples[2] - 0.01).abs() < 1e-5);
+    }
+
+    #[test]
+    fn volume_is_applied_then_limited() {
+        let mut samples = [0.1, -0.2, 0.6];
+        apply_volume(&mut samples, 2.0, Limiter::Clamp);
+        assert_eq!(samples, [0.2, -0.4, 1.0]);
+    }
+
+    #[test]
+    fn mono_is_duplicated_to_stereo() {
+        assert_eq!(remix(&[0.1, 0.2], 1, 2), [0.1, 0.1, 0.2, 0.2]);
+    }
+
+    #[test]
+    fn stereo_is_averaged_to_mono() {
+        assert_eq!(remix(&[0.2, 0.4, -1.0, 1.0], 2, 1), [0.3, 0.0]);
+    }
+
+    #[test]
+    fn surround_is_folded_onto_the_channels_there_are() {
+        // 4 channels onto 2: the first and third go left, the second and fourth right.
+        assert_eq!(remix(&[0.2, 0.4, 0.6, 0.8], 4, 2), [0.4, 0.6]);
+        assert_eq!(remix(&[0.2, 0.4], 2, 4), [0.2, 0.4, 0.2, 0.4]);
+        // Incomplete frames at the end don't get played with the channels shifted.
+        assert_eq!(remix(&[0.2, 0.4, 0.6], 2, 1), [0.3f32]);
+    }
+
+    #[test]
+    fn same_channel_count_is_passed_through() {
+        let samples = [0.1, -0.2, 0.3, -0.4];
+        assert_eq!(remix(&samples, 2, 2), samples);
+    }
+
+    /// A device config range with any buffer size.
+    fn range(channels: u16, rates: (u32, u32), format: SampleFormat) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            channels,
+            cpal::SampleRate(rates.0),
+            cpal::SampleRate(rates.1),
+            SupportedBufferSize::Unknown,
+            format,
+        )
+    }
+
+    /// The options for these `flags`.
+    fn opt(flags: &[&str]) -> Opt {
+        let args: Vec<String> = ["voice-chat", "-c", "x"]
+            .iter()
+            .chain(flags)
+            .map(|arg| String::from(*arg))
+            .collect();
+        Opt::from_args(&args).unwrap()
+    }
+
+    #[test]
+    fn device_without_f32_is_converted() {
+        let configs = vec![range(2, (8000, 48000), SampleFormat::I16)];
+        let (config, format) = select_config(configs, &opt(&["--sample-rate", "44100"])).unwrap();
+        assert_eq!(format, SampleFormat::I16);
+        assert_eq!(config.sample_rate.0, 44100);
+    }
+
+    #[test]
+    fn f32_is_preferred() {
+        let configs = vec![
+            range(2, (8000, 48000), SampleFormat::I32),
+            range(2, (8000, 48000), SampleFormat::F32),
+        ];
+        let (_, format) = select_config(configs, &opt(&[])).unwrap();
+        assert_eq!(format, SampleFormat::F32);
+    }
+
+    #[test]
+    fn unusable_formats_are_an_error() {
+        let configs = vec![range(2, (8000, 48000), SampleFormat::U8)];
+        assert!(matches!(
+            select_config(configs, &opt(&[])),
+            Err(VoiceChatError::UnsupportedConfig(_))
+        ));
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_numbers() {
+        let (mut a, mut b) = (Rng::with_seed(7), Rng::with_seed(7));
+        for _ in 0..100 {
+            let value = a.next_f32();
+            assert_eq!(value, b.next_f32());
+            assert!((0.0..=1.0).contains(&value), "{value}");
+        }
+        // A zero state would only ever give zeros.
+        assert_ne!(Rng::with_seed(0).next_f32(), 0.0);
+    }
+
+    #[test]
+    fn dither_adds_at_most_a_step() {
+        let mut dither = Dither::new();
+        let dithered: Vec<f32> = (0..10000).map(|_| dither.apply(0.25)).collect();
+        assert!(dithered.iter().all(|s| (s - 0.25).abs() <= I16_STEP));
+        assert!(dithered.iter().any(|&s| s != 0.25));
+        let mean = dithered.iter().sum::<f32>() / dithered.len() as f32;
+        assert!((mean - 0.25).abs() < I16_STEP / 10.0, "{mean}");
+    }
+
+    #[test]
+    fn dither_stays_within_full_scale() {
+        let mut dither = Dither::new();
+        assert!((0..1000).all(|_| dither.apply(1.0) <= 1.0 && dither.apply(-1.0) >= -1.0));
+    }
+
+    #[test]
+    fn devices_are_found_by_name_or_number() {
+        let names = ["Speakers", "Headset", "USB Mic"].map(String::from);
+        assert_eq!(device_index(&names, "Headset"), Some(1));
+        assert_eq!(device_index(&names, "1"), Some(0));
+        assert_eq!(device_index(&names, "3"), Some(2));
+        for missing in ["0", "4", "headset", ""] {
+            assert_eq!(device_index(&names, missing), None, "{missing}");
+        }
+    }
+
+    /// A device config range that allows buffers of `min` to `max` frames.
+    fn buffered(
+        channels: u16,
+        format: SampleFormat,
+        min: u32,
+        max: u32,
+    ) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            channels,
+            cpal::SampleRate(8000),
+            cpal::SampleRate(48000),
+            SupportedBufferSize::Range { min, max },
+            format,
+        )
+    }
+
+    #[test]
+    fn low_latency_prefers_small_buffers() {
+        let configs = || {
+            vec![
+                buffered(2, SampleFormat::F32, 512, 4096),
+                buffered(2, SampleFormat::I16, 64, 4096),
+            ]
+        };
+        let (config, format) = select_config(configs(), &opt(&[])).unwrap();
+        assert_eq!(format, SampleFormat::F32);
+        assert_eq!(config.buffer_size, BufferSize::Default);
+        let (config, format) = select_config(configs(), &opt(&["--low-latency"])).unwrap();
+        assert_eq!(format, SampleFormat::I16);
+        assert_eq!(config.buffer_size, BufferSize::Fixed(LOW_LATENCY_FRAMES));
+        let flags = ["--low-latency", "--buffer-frames", "128"];
+        let (config, _) = select_config(configs(), &opt(&flags)).unwrap();
+        assert_eq!(config.buffer_size, BufferSize::Fixed(128));
+    }
+
+    #[test]
+    fn preferred_rates_are_tried_in_order() {
+        let configs = || {
+            vec![
+                range(2, (8000, 48000), SampleFormat::F32),
+                range(2, (88200, 96000), SampleFormat::I32),
+            ]
+        };
+        let flags = ["--prefer-sample-rate", "96000,48000"];
+        let (config, format) = select_config(configs(), &opt(&flags)).unwrap();
+        assert_eq!(config.sample_rate.0, 96000);
+        assert_eq!(format, SampleFormat::I32);
+        let flags = ["--prefer-sample-rate", "192000,44100"];
+        let (config, _) = select_config(configs(), &opt(&flags)).unwrap();
+        assert_eq!(config.sample_rate.0, 44100);
+        // None of them: the highest rate of the first config.
+        let flags = ["--prefer-sample-rate", "192000"];
+        let (config, _) = select_config(configs(), &opt(&flags)).unwrap();
+        assert_eq!(config.sample_rate.0, 48000);
+    }
+
+    /// Little-endian `f32` bytes of `samples`, as they arrive.
+    fn wire_bytes(samples: &[f32]) -> Vec<u8> {
+        samples.iter().flat_map(|f| f.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn valid_audio_is_decoded() {
+        let stats = Stats::default();
+        let decoded = decode_audio(&wire_bytes(&[0.5, -0.5]), WireFormat::F32, 2, &stats);
+        assert_eq!(decoded, [0.5, -0.5]);
+        assert_eq!(stats.undecodable_frames(), 0);
+    }
+
+    #[test]
+    fn broken_audio_turns_into_silence() {
+        let stats = Stats::default();
+        for samples in [[0.5, f32::NAN], [0.5, f32::INFINITY], [0.5, 1000.0]] {
+            let decoded = decode_audio(&wire_bytes(&samples), WireFormat::F32, 1, &stats);
+            assert_eq!(decoded, [0.0, 0.0]);
+        }
+        // Three samples can't be stereo, and two bytes no f32 sample.
+        let decoded = decode_audio(&wire_bytes(&[0.5; 3]), WireFormat::F32, 2, &stats);
+        assert_eq!(decoded, [0.0, 0.0]);
+        assert!(decode_audio(&[1, 2], WireFormat::F32, 1, &stats).is_empty());
+        assert_eq!(stats.undecodable_frames(), 5);
+    }
+
+    #[test]
+    fn stream_formats_are_parsed() {
+        let format = |spec: &str| spec.parse::<StreamFormat>();
+        assert_eq!(
+            format("48000:2"),
+            Ok(StreamFormat {
+                sample_rate: 48000,
+                channels: 2,
+                buffer_frames: None,
+            })
+        );
+        assert_eq!(format(" 44100 : 1 : 512").unwrap().buffer_frames, Some(512));
+        for bad in [
+            "48000",
+            "48000:0",
+            "0:2",
+            "48000:2:x",
+            "48000:70000",
+            "1:2:3:4",
+        ] {
+            assert!(format(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn exact_format_is_used_or_refused() {
+        let configs = || {
+            vec![
+                buffered(2, SampleFormat::I16, 64, 1024),
+                buffered(2, SampleFormat::F32, 256, 4096),
+            ]
+        };
+        let wanted = "44100:2:512".parse().unwrap();
+        let (config, format) = exact_config(configs(), wanted, "--output-format").unwrap();
+        assert_eq!(format, SampleFormat::F32);
+        assert_eq!((config.sample_rate.0, config.channels), (44100, 2));
+        assert_eq!(config.buffer_size, BufferSize::Fixed(512));
+        // Only the i16 config allows this small a buffer.
+        let small = "44100:2:128".parse().unwrap();
+        let (_, format) = exact_config(configs(), small, "--output-format").unwrap();
+        assert_eq!(format, SampleFormat::I16);
+        for unsupported in ["44100:1", "96000:2", "44100:2:8192"] {
+            let result = exact_config(configs(), unsupported.parse().unwrap(), "--output-format");
+            assert!(
+                matches!(&result, Err(VoiceChatError::UnsupportedConfig(e)) if e.contains("It supports")),
+                "{unsupported}"
+            );
+        }
+    }
+
+    #[test]
+    fn best_format_has_the_highest_rate_then_the_most_channels() {
+        let configs = [
+            range(2, (8000, 48000), SampleFormat::F32),
+            range(1, (8000, 96000), SampleFormat::I16),
+            range(8, (8000, 96000), SampleFormat::I32),
+            range(16, (8000, 192000), SampleFormat::U8),
+        ];
+        let best = best_format(&configs).unwrap();
+        assert_eq!((best.sample_rate, best.channels), (96000, 8));
+        assert_eq!(best_format(&configs[3..]), None);
+    }
+
+    #[test]
+    fn ties_go_to_the_default_device_then_the_first() {
+        let format = |sample_rate, channels| StreamFormat {
+            sample_rate,
+            channels,
+            buffer_frames: None,
+        };
+        let (good, better) = (format(48000, 2), format(96000, 2));
+        assert!(is_better((&better, "b"), (&good, "a"), None));
+        assert!(!is_better((&good, "b"), (&better, "a"), Some("b")));
+        assert!(!is_better((&good, "b"), (&good, "a"), None));
+        assert!(is_better((&good, "b"), (&good, "a"), Some("b")));
+        assert!(!is_better((&good, "b"), (&good, "b"), Some("b")));
+    }
+
+    #[test]
+    fn pitch_shift_of_mismatched_rates() {
+        assert_eq!(pitch_factor(44100, 22050), 0.5);
+        assert_eq!(pitch_factor(16000, 48000), 3.0);
+        assert_eq!(
+            rate_mismatch(44100, 22050),
+            "Peer sends at 44100 Hz, this plays at 22050 Hz: played as is, its audio would \
+             sound ~2.00x too slow, so it is resampled"
+        );
+        assert!(rate_mismatch(32000, 48000).contains("~1.50x too fast"));
+    }
 }
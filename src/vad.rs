@@ -0,0 +1,151 @@
+use crate::processor::AudioProcessor;
+
+/// Length of the blocks speech is detected in.
+const BLOCK_TIME: f32 = 0.01;
+/// How long audio keeps going out after the last speech, so word endings aren't cut off.
+const HANGOVER_TIME: f32 = 0.3;
+/// Time constant the noise floor rises with. It falls at once, to the quietest block.
+const NOISE_RISE_TIME: f32 = 5.0;
+/// Blocks below this power (about -60 dBFS) are never speech.
+const MIN_SPEECH_POWER: f32 = 1e-6;
+/// How far above the noise floor a block's power has to be to count as speech, and the
+/// highest share of zero crossings it may have (hiss crosses zero far more often than
+/// voices do), by aggressiveness.
+const THRESHOLDS: [(f32, f32); 4] = [(2.0, 1.0), (4.0, 0.6), (8.0, 0.5), (16.0, 0.4)];
+
+/// Voice activity detection: silences captured audio that doesn't look like speech, so it
+/// isn't sent at all.
+///
+/// Each block is compared against a noise floor that follows the quietest blocks, and counts
+/// as speech if it is loud enough above it without crossing zero as often as noise does.
+/// Runs before any gain, so the floor is measured on the raw mic signal.
+pub struct VoiceDetector {
+    power_ratio: f32,
+    max_crossings: f32,
+    channels: usize,
+    block_len: usize,
+    hangover_len: usize,
+    /// Samples left to pass since the last speech.
+    remaining: usize,
+    noise_power: f32,
+    noise_coefficient: f32,
+}
+
+impl VoiceDetector {
+    /// Creates a detector for interleaved audio with `channels` channels. `aggressiveness`
+    /// goes from 0 (lets the most through) to 3 (needs the clearest speech).
+    pub fn new(aggressiveness: u8, sample_rate: u32, channels: usize) -> Self {
+        let (power_ratio, max_crossings) = THRESHOLDS[(aggressiveness as usize).min(3)];
+        let channels = channels.max(1);
+        let samples = |time: f32| ((time * sample_rate as f32) as usize).max(1) * channels;
+        let block_len = samples(BLOCK_TIME);
+        VoiceDetector {
+            power_ratio,
+            max_crossings,
+            channels,
+            block_len,
+            hangover_len: samples(HANGOVER_TIME),
+            remaining: 0,
+            noise_power: 1.0,
+            noise_coefficient: 1.0 - (-BLOCK_TIME / NOISE_RISE_TIME).exp(),
+        }
+    }
+
+    fn is_speech(&mut self, block: &[f32]) -> bool {
+        let power = block.iter().map(|f| f * f).sum::<f32>() / block.len() as f32;
+        let pairs = block.len().saturating_sub(self.channels).max(1);
+        let crossings = block
+            .iter()
+            .zip(block.iter().skip(self.channels))
+            .filter(|(a, b)| (**a < 0.0) != (**b < 0.0))
+            .count() as f32
+            / pairs as f32;
+        let speech = power > MIN_SPEECH_POWER
+            && power > self.noise_power * self.power_ratio
+            && crossings <= self.max_crossings;
+        if power < self.noise_power {
+            self.noise_power = power;
+        } else {
+            self.noise_power += (power - self.noise_power) * self.noise_coefficient;
+        }
+        speech
+    }
+}
+
+impl AudioProcessor for VoiceDetector {
+    fn process(&mut self, samples: &mut [f32], _sample_rate: u32) {
+        for block in samples.chunks_mut(self.block_len) {
+            if self.is_speech(block) {
+                self.remaining = self.hangover_len;
+            } else {
+                self.remaining = self.remaining.saturating_sub(block.len());
+            }
+            if self.remaining == 0 {
+                block.fill(0.0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::Rng;
+    use std::f32::consts::TAU;
+
+    const RATE: u32 = 8000;
+
+    /// `seconds` of white noise in ±`level`.
+    fn noise(level: f32, seconds: f32) -> Vec<f32> {
+        let mut rng = Rng::with_seed(1);
+        let len = (seconds * RATE as f32) as usize;
+        (0..len)
+            .map(|_| (rng.next_f32() * 2.0 - 1.0) * level)
+            .collect()
+    }
+
+    /// `seconds` of a 200 Hz tone, about as often as a low voice crosses zero.
+    fn voice(seconds: f32) -> Vec<f32> {
+        let len = (seconds * RATE as f32) as usize;
+        (0..len)
+            .map(|i| 0.3 * (TAU * 200.0 * i as f32 / RATE as f32).sin())
+            .collect()
+    }
+
+    #[test]
+    fn speech_passes_and_background_noise_is_silenced() {
+        let mut detector = VoiceDetector::new(1, RATE, 1);
+        let mut background = noise(0.01, 1.0);
+        detector.process(&mut background, RATE);
+        assert!(background[RATE as usize / 2..].iter().all(|&f| f == 0.0));
+        let speech = voice(0.5);
+        let mut passed = speech.clone();
+        detector.process(&mut passed, RATE);
+        assert_eq!(passed, speech);
+    }
+
+    #[test]
+    fn word_endings_are_kept_for_the_hangover() {
+        let mut detector = VoiceDetector::new(1, RATE, 1);
+        detector.process(&mut noise(0.01, 1.0), RATE);
+        detector.process(&mut voice(0.5), RATE);
+        let mut after = noise(0.01, 0.5);
+        detector.process(&mut after, RATE);
+        let hangover = (0.25 * RATE as f32) as usize;
+        assert!(after[..hangover].iter().all(|&f| f != 0.0));
+        assert!(
+            after[hangover + RATE as usize / 10..]
+                .iter()
+                .all(|&f| f == 0.0)
+        );
+    }
+
+    #[test]
+    fn loud_hiss_is_not_speech() {
+        let mut detector = VoiceDetector::new(3, RATE, 1);
+        detector.process(&mut vec![0.0; RATE as usize], RATE);
+        let mut hiss = noise(0.3, 0.5);
+        detector.process(&mut hiss, RATE);
+        assert!(hiss.iter().all(|&f| f == 0.0));
+    }
+}
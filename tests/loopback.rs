@@ -0,0 +1,546 @@
+//! A whole call between two headless clients in the same process, over an in-memory pipe.
+
+use std::f32::consts::TAU;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use voice_chat::controls::Controls;
+use voice_chat::headless::HeadlessAudio;
+use voice_chat::transport::{DynTransport, MemoryTransport, Transport};
+use voice_chat::{Client, Opt, SessionState, VoiceChatError};
+
+const SAMPLE_RATE: u32 = 16000;
+const TONE_HZ: f32 = 440.0;
+/// How much audio is pushed and pulled at a time, like a device's 20 ms period.
+const PERIOD: Duration = Duration::from_millis(20);
+const CALL_LENGTH: Duration = Duration::from_secs(3);
+/// Sending, the network and the playback margin together may delay audio up to this much.
+const MAX_DELAY: Duration = Duration::from_millis(200);
+
+/// A headless client at `SAMPLE_RATE`, without processing, with further `flags`.
+fn client(flags: &[&str]) -> Client {
+    let rate = SAMPLE_RATE.to_string();
+    let args = [
+        "voice-chat",
+        "-c",
+        "memory",
+        "--raw",
+        "--sample-rate",
+        &rate,
+    ]
+    .iter()
+    .chain(flags)
+    .map(|arg| String::from(*arg))
+    .collect::<Vec<_>>();
+    Client::new_headless_with(String::from("memory"), Opt::from_args(&args).unwrap()).unwrap()
+}
+
+fn tone(i: usize) -> f32 {
+    0.5 * (TAU * TONE_HZ * i as f32 / SAMPLE_RATE as f32).sin()
+}
+
+fn rms(samples: &[f32]) -> f32 {
+    (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+}
+
+/// Plays `signal` (by interleaved sample index) into the speaking client and records what the
+/// listening one plays, both paced like a device, then hangs up. The speaker's and the
+/// listener's headless audio have the given numbers of `channels`.
+fn talk(
+    signal: impl Fn(usize) -> f32,
+    channels: (usize, usize),
+    capture: HeadlessAudio,
+    playback: HeadlessAudio,
+    controls: Controls,
+) -> Vec<f32> {
+    let period = (SAMPLE_RATE as f64 * PERIOD.as_secs_f64()) as usize;
+    let mut played = Vec::new();
+    let mut pushed = 0;
+    let start = Instant::now();
+    while start.elapsed() < CALL_LENGTH {
+        let captured: Vec<f32> = (pushed..pushed + period * channels.0)
+            .map(&signal)
+            .collect();
+        pushed += captured.len();
+        capture.push_capture(&captured);
+        let mut chunk = vec![0.0; period * channels.1];
+        playback.pull_playback(&mut chunk);
+        played.extend(chunk);
+        thread::sleep(PERIOD);
+    }
+    controls.hang_up();
+    played
+}
+
+/// Runs a call between two mono clients in which one speaks `signal`, see [`talk`].
+fn call(signal: impl Fn(usize) -> f32) -> Vec<f32> {
+    call_with(&[], &[], (1, 1), signal)
+}
+
+/// [`call`] with further flags for the speaking and the listening client, whose headless
+/// audio has the given numbers of `channels`.
+fn call_with(
+    speaker_flags: &[&str],
+    listener_flags: &[&str],
+    channels: (usize, usize),
+    signal: impl Fn(usize) -> f32,
+) -> Vec<f32> {
+    let (a, b) = MemoryTransport::pair();
+    let mut speaker = client(speaker_flags);
+    let mut listener = client(listener_flags);
+    // The calls hold on to the clients, so their audio and controls are taken first.
+    let capture = speaker.headless_audio().unwrap();
+    let playback = listener.headless_audio().unwrap();
+    let controls = speaker.controls();
+    thread::scope(|scope| {
+        let speaking = scope.spawn(|| smol::block_on(speaker.chat_over(&a)));
+        let listening = scope.spawn(|| smol::block_on(listener.chat_over(&b)));
+        let played = talk(signal, channels, capture, playback, controls);
+        speaking.join().unwrap().unwrap();
+        listening.join().unwrap().unwrap();
+        played
+    })
+}
+
+#[test]
+fn tone_goes_through_a_call() {
+    let played = call(tone);
+    let heard = &played[played.len() / 2..];
+    let rms = rms(heard);
+    assert!(rms > 0.1, "Only heard an RMS level of {rms}");
+    // A 440 Hz tone crosses zero 880 times a second.
+    let crossings = heard
+        .windows(2)
+        .filter(|w| (w[0] < 0.0) != (w[1] < 0.0))
+        .count();
+    let expected = 2.0 * TONE_HZ * heard.len() as f32 / SAMPLE_RATE as f32;
+    assert!(
+        (crossings as f32 - expected).abs() < expected * 0.1,
+        "{crossings} zero crossings instead of about {expected}"
+    );
+}
+
+#[test]
+fn audio_arrives_without_piling_up_delay() {
+    // Silence first, so the call has settled when the tone starts.
+    let start = 2 * SAMPLE_RATE as usize;
+    let played = call(|i| if i < start { 0.0 } else { tone(i) });
+    let heard = played[start..]
+        .iter()
+        .position(|sample| sample.abs() > 0.1)
+        .expect("The tone never arrived");
+    let delay = Duration::from_secs_f64(heard as f64 / SAMPLE_RATE as f64);
+    assert!(delay < MAX_DELAY, "The tone took {delay:?} to arrive");
+}
+
+#[test]
+fn stereo_channels_stay_apart() {
+    let stereo = ["--channels", "2"];
+    // The tone on the left, silence on the right.
+    let played = call_with(&stereo, &stereo, (2, 2), |i| {
+        if i % 2 == 0 { tone(i / 2) } else { 0.0 }
+    });
+    let heard = &played[played.len() / 2..];
+    let left: Vec<f32> = heard.iter().step_by(2).copied().collect();
+    let right: Vec<f32> = heard.iter().skip(1).step_by(2).copied().collect();
+    assert!(rms(&left) > 0.1, "Only heard {} on the left", rms(&left));
+    assert!(rms(&right) < 0.01, "Heard {} on the right", rms(&right));
+}
+
+#[test]
+fn mono_is_played_on_both_channels() {
+    let played = call_with(&[], &["--channels", "2"], (1, 2), tone);
+    let heard = &played[played.len() / 2..];
+    for frame in heard.chunks_exact(2) {
+        assert_eq!(frame[0], frame[1]);
+    }
+    assert!(
+        rms(heard) > 0.1,
+        "Only heard an RMS level of {}",
+        rms(heard)
+    );
+}
+
+#[test]
+fn mono_flag_sends_a_downmix() {
+    let played = call_with(
+        &["--channels", "2", "--mono"],
+        &["--channels", "2"],
+        (2, 2),
+        |i| if i % 2 == 0 { tone(i / 2) } else { 0.0 },
+    );
+    let heard = &played[played.len() / 2..];
+    for frame in heard.chunks_exact(2) {
+        assert_eq!(frame[0], frame[1]);
+    }
+    // The tone is averaged with the silent right channel, which halves it.
+    let rms = rms(heard);
+    assert!((rms - 0.5 / 2.0 / 2f32.sqrt()).abs() < 0.03, "{rms}");
+}
+
+#[test]
+fn muted_speaker_is_silent_and_says_so() {
+    let (a, b) = MemoryTransport::pair();
+    let mut speaker = client(&[]);
+    let mut listener = client(&[]);
+    let capture = speaker.headless_audio().unwrap();
+    let playback = listener.headless_audio().unwrap();
+    let controls = speaker.controls();
+    let listener_controls = listener.controls();
+    speaker.set_muted(true);
+    let played = thread::scope(|scope| {
+        let speaking = scope.spawn(|| smol::block_on(speaker.chat_over(&a)));
+        let listening = scope.spawn(|| smol::block_on(listener.chat_over(&b)));
+        let played = talk(tone, (1, 1), capture, playback, controls);
+        speaking.join().unwrap().unwrap();
+        listening.join().unwrap().unwrap();
+        played
+    });
+    let rms = rms(&played);
+    assert!(rms < 0.001, "Heard an RMS level of {rms}");
+    assert!(listener_controls.peer_muted());
+}
+
+#[test]
+fn hanging_up_ends_the_peers_call_too() {
+    let (a, b) = MemoryTransport::pair();
+    let mut caller = client(&[]);
+    let mut peer = client(&[]);
+    let controls = caller.controls();
+    let peer_controls = peer.controls();
+    thread::scope(|scope| {
+        let calling = scope.spawn(|| smol::block_on(caller.chat_over(&a)));
+        let answering = scope.spawn(|| smol::block_on(peer.chat_over(&b)));
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(peer_controls.state(), SessionState::Connected);
+        let hung_up = Instant::now();
+        controls.hang_up();
+        calling.join().unwrap().unwrap();
+        // Told so, the peer doesn't wait for the connection to time out, nor call it a failure.
+        answering.join().unwrap().unwrap();
+        assert!(hung_up.elapsed() < MAX_DELAY * 5, "{:?}", hung_up.elapsed());
+    });
+    assert_eq!(peer_controls.state(), SessionState::Disconnected);
+}
+
+#[test]
+fn send_rate_lowers_the_bandwidth_of_the_audio() {
+    let send_rate = ["--send-rate", "8000"];
+    let voice = call_with(&send_rate, &[], (1, 1), tone);
+    let voice = &voice[voice.len() / 2..];
+    assert!(rms(voice) > 0.1, "Only heard {}", rms(voice));
+    // Sent at 8 kHz, nothing above 4 kHz comes through.
+    let high = |i: usize| 0.5 * (TAU * 6000.0 * i as f32 / SAMPLE_RATE as f32).sin();
+    let played = call_with(&send_rate, &[], (1, 1), high);
+    let heard = rms(&played[played.len() / 2..]);
+    assert!(heard < 0.05, "Heard {heard} of a 6 kHz tone");
+}
+
+#[test]
+fn stereo_is_played_mixed_down_on_a_mono_output() {
+    let played = call_with(&["--channels", "2"], &[], (2, 1), |i| {
+        if i % 2 == 0 { tone(i / 2) } else { 0.0 }
+    });
+    // At the right speed, and half as loud, being averaged with the silent right channel.
+    let heard = &played[played.len() / 2..];
+    let crossings = heard
+        .windows(2)
+        .filter(|w| (w[0] < 0.0) != (w[1] < 0.0))
+        .count();
+    let expected = 2.0 * TONE_HZ * heard.len() as f32 / SAMPLE_RATE as f32;
+    assert!(
+        (crossings as f32 - expected).abs() < expected * 0.1,
+        "{crossings} zero crossings instead of about {expected}"
+    );
+    let rms = rms(heard);
+    assert!((rms - 0.5 / 2.0 / 2f32.sqrt()).abs() < 0.03, "{rms}");
+}
+
+#[test]
+fn greeting_is_sent_before_the_mic() {
+    let path = std::env::temp_dir().join(format!("voice-chat-{}-greeting.wav", std::process::id()));
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut wav = hound::WavWriter::create(&path, spec).unwrap();
+    // A second of the tone, louder than the mic will be.
+    for i in 0..SAMPLE_RATE as usize {
+        wav.write_sample(tone(i)).unwrap();
+    }
+    wav.finalize().unwrap();
+    let greeting = ["--greeting", path.to_str().unwrap()];
+    let played = call_with(&greeting, &[], (1, 1), |i| 0.2 * tone(i));
+    std::fs::remove_file(&path).unwrap();
+    let start = played
+        .iter()
+        .position(|sample| sample.abs() > 0.05)
+        .expect("Nothing arrived");
+    let period = SAMPLE_RATE as usize / 10;
+    let first = rms(&played[start..start + period]);
+    assert!((first - 0.5 / 2f32.sqrt()).abs() < 0.05, "Began at {first}");
+    let mic = rms(&played[played.len() - period..]);
+    assert!(
+        (mic - 0.2 * 0.5 / 2f32.sqrt()).abs() < 0.02,
+        "Ended at {mic}"
+    );
+}
+
+/// An end of a [`MemoryTransport`] that stops hearing the peer once `deaf` is set, as if the
+/// peer had gone quiet without hanging up.
+struct Deafened {
+    inner: MemoryTransport,
+    deaf: AtomicBool,
+}
+
+impl Transport for Deafened {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.deaf.load(Ordering::Relaxed) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.inner.read(buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    async fn readable(&self) -> io::Result<()> {
+        if self.deaf.load(Ordering::Relaxed) {
+            std::future::pending::<()>().await;
+        }
+        self.inner.readable().await
+    }
+
+    async fn writable(&self) -> io::Result<()> {
+        self.inner.writable().await
+    }
+}
+
+#[test]
+fn silent_peer_times_out() {
+    let (a, b) = MemoryTransport::pair();
+    let a = Deafened {
+        inner: a,
+        deaf: AtomicBool::new(false),
+    };
+    let mut caller = client(&["--timeout", "4"]);
+    let mut peer = client(&[]);
+    let controls = caller.controls();
+    let peer_controls = peer.controls();
+    thread::scope(|scope| {
+        let calling = scope.spawn(|| smol::block_on(caller.chat_over(&a)));
+        let answering = scope.spawn(|| smol::block_on(peer.chat_over(&b)));
+        let start = Instant::now();
+        while controls.state() != SessionState::Connected {
+            assert!(start.elapsed() < CALL_LENGTH, "The call never came up");
+            thread::sleep(PERIOD);
+        }
+        a.deaf.store(true, Ordering::Relaxed);
+        let silent = Instant::now();
+        let ended = calling.join().unwrap();
+        let waited = silent.elapsed();
+        peer_controls.hang_up();
+        let _ = answering.join().unwrap();
+        assert!(
+            matches!(ended, Err(VoiceChatError::PeerTimedOut)),
+            "{ended:?}"
+        );
+        assert!(
+            waited >= Duration::from_secs(4) && waited < Duration::from_secs(6),
+            "{waited:?}"
+        );
+    });
+    assert_eq!(controls.state(), SessionState::Disconnected);
+}
+
+/// An end of a [`MemoryTransport`] that can't write while `stalled` is set, like a link that
+/// stopped taking data for a while. `stalls` counts the writes turned away.
+struct Stalled {
+    inner: MemoryTransport,
+    stalled: AtomicBool,
+    stalls: AtomicUsize,
+}
+
+impl Transport for Stalled {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        if self.stalled.load(Ordering::Relaxed) {
+            self.stalls.fetch_add(1, Ordering::Relaxed);
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.inner.write(buf)
+    }
+
+    async fn readable(&self) -> io::Result<()> {
+        self.inner.readable().await
+    }
+
+    async fn writable(&self) -> io::Result<()> {
+        while self.stalled.load(Ordering::Relaxed) {
+            smol::Timer::after(PERIOD).await;
+        }
+        self.inner.writable().await
+    }
+}
+
+#[test]
+fn stalled_writes_lose_no_captured_audio() {
+    let (a, b) = MemoryTransport::pair();
+    let a = Stalled {
+        inner: a,
+        stalled: AtomicBool::new(false),
+        stalls: AtomicUsize::new(0),
+    };
+    let mut speaker = client(&[]);
+    let mut listener = client(&[]);
+    let capture = speaker.headless_audio().unwrap();
+    let playback = listener.headless_audio().unwrap();
+    let controls = speaker.controls();
+    thread::scope(|scope| {
+        let speaking = scope.spawn(|| smol::block_on(speaker.chat_over(&a)));
+        let listening = scope.spawn(|| smol::block_on(listener.chat_over(&b)));
+        scope.spawn(|| {
+            thread::sleep(Duration::from_secs(1));
+            a.stalled.store(true, Ordering::Relaxed);
+            thread::sleep(Duration::from_millis(500));
+            a.stalled.store(false, Ordering::Relaxed);
+        });
+        let played = talk(tone, (1, 1), capture, playback, controls.clone());
+        speaking.join().unwrap().unwrap();
+        listening.join().unwrap().unwrap();
+        assert!(
+            a.stalls.load(Ordering::Relaxed) > 0,
+            "Writing never stalled"
+        );
+        assert_eq!(controls.stats().dropped_samples(), 0);
+        // The audio held up by the stall still arrives.
+        let rms = rms(&played[played.len() / 2..]);
+        assert!((rms - 0.5 / 2f32.sqrt()).abs() < 0.05, "{rms}");
+    });
+}
+
+#[test]
+fn call_runs_over_boxed_transports() {
+    let (a, b) = MemoryTransport::pair();
+    let (a, b): (Box<dyn DynTransport>, Box<dyn DynTransport>) = (Box::new(a), Box::new(b));
+    let mut speaker = client(&[]);
+    let mut listener = client(&[]);
+    let capture = speaker.headless_audio().unwrap();
+    let playback = listener.headless_audio().unwrap();
+    let controls = speaker.controls();
+    let played = thread::scope(|scope| {
+        let speaking = scope.spawn(|| smol::block_on(speaker.chat_over(&a)));
+        let listening = scope.spawn(|| smol::block_on(listener.chat_over(&b)));
+        let played = talk(tone, (1, 1), capture, playback, controls);
+        speaking.join().unwrap().unwrap();
+        listening.join().unwrap().unwrap();
+        played
+    });
+    let rms = rms(&played[played.len() / 2..]);
+    assert!(rms > 0.1, "Only heard an RMS level of {rms}");
+}
+
+#[test]
+fn monitoring_mixes_the_mic_into_the_output() {
+    let (a, b) = MemoryTransport::pair();
+    let mut monitored = client(&["--monitor-level", "0.5"]);
+    let mut peer = client(&[]);
+    let audio = monitored.headless_audio().unwrap();
+    let controls = monitored.controls();
+    // The peer stays silent, so all that plays is the monitored mic.
+    let (before, after) = thread::scope(|scope| {
+        let calling = scope.spawn(|| smol::block_on(monitored.chat_over(&a)));
+        let answering = scope.spawn(|| smol::block_on(peer.chat_over(&b)));
+        let period = (SAMPLE_RATE as f64 * PERIOD.as_secs_f64()) as usize;
+        let mut played = (Vec::new(), Vec::new());
+        let mut pushed = 0;
+        let start = Instant::now();
+        while start.elapsed() < CALL_LENGTH {
+            let on = start.elapsed() >= CALL_LENGTH / 2;
+            controls.set_monitoring(on);
+            let captured: Vec<f32> = (pushed..pushed + period).map(tone).collect();
+            pushed += period;
+            audio.push_capture(&captured);
+            let mut chunk = vec![0.0; period];
+            audio.pull_playback(&mut chunk);
+            if on { &mut played.1 } else { &mut played.0 }.extend(chunk);
+            thread::sleep(PERIOD);
+        }
+        controls.hang_up();
+        calling.join().unwrap().unwrap();
+        answering.join().unwrap().unwrap();
+        played
+    });
+    let before = rms(&before);
+    assert!(before < 0.001, "Heard {before} before monitoring");
+    // Once the toggle has taken effect, at half the level of the tone.
+    let after = rms(&after[after.len() / 2..]);
+    assert!((after - 0.25 / 2f32.sqrt()).abs() < 0.03, "{after}");
+}
+
+#[test]
+fn audio_is_released_for_the_next_call() {
+    let mut speaker = client(&[]);
+    let mut listener = client(&[]);
+    let capture = speaker.headless_audio().unwrap();
+    let playback = listener.headless_audio().unwrap();
+    for call in 1..=2 {
+        let (a, b) = MemoryTransport::pair();
+        let controls = speaker.controls();
+        let played = thread::scope(|scope| {
+            let speaking = scope.spawn(|| smol::block_on(speaker.chat_over(&a)));
+            let listening = scope.spawn(|| smol::block_on(listener.chat_over(&b)));
+            let played = talk(tone, (1, 1), capture.clone(), playback.clone(), controls);
+            speaking.join().unwrap().unwrap();
+            listening.join().unwrap().unwrap();
+            played
+        });
+        let rms = rms(&played[played.len() / 2..]);
+        assert!(rms > 0.1, "Call {call} only heard an RMS level of {rms}");
+        // Between calls, nothing holds on to the audio any more.
+        let mut after = vec![1.0; 160];
+        playback.pull_playback(&mut after);
+        assert!(after.iter().all(|sample| *sample == 0.0), "Call {call}");
+    }
+}
+
+#[test]
+fn names_are_exchanged() {
+    let (a, b) = MemoryTransport::pair();
+    let mut caller = client(&["--name", "Alice"]);
+    let mut peer = client(&[]);
+    let controls = caller.controls();
+    let peer_controls = peer.controls();
+    thread::scope(|scope| {
+        let calling = scope.spawn(|| smol::block_on(caller.chat_over(&a)));
+        let answering = scope.spawn(|| smol::block_on(peer.chat_over(&b)));
+        thread::sleep(Duration::from_millis(500));
+        let names = (controls.peer_name(), peer_controls.peer_name());
+        controls.hang_up();
+        calling.join().unwrap().unwrap();
+        answering.join().unwrap().unwrap();
+        // Without a name, the peer shows up by its address.
+        assert_eq!(names, (None, Some(String::from("Alice"))));
+    });
+}
+
+#[test]
+fn comfort_noise_plays_through_a_pause() {
+    let played = call_with(&[], &["--comfort-noise"], (1, 1), |_| 0.0);
+    let heard = &played[played.len() / 2..];
+    assert!(rms(heard) < 0.01, "{}", rms(heard));
+    // Without gaps, although the peer sends next to nothing while it is silent.
+    let period = SAMPLE_RATE as usize / 50;
+    let gaps = heard
+        .chunks(period)
+        .filter(|chunk| chunk.iter().all(|sample| *sample == 0.0))
+        .count();
+    assert!(gaps <= 2, "{gaps} silent periods");
+}
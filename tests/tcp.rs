@@ -0,0 +1,321 @@
+//! Calls between two headless clients over real TCP connections on this machine.
+
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+use voice_chat::controls::Controls;
+use voice_chat::{Client, Mixer, Opt, SessionState, VoiceChatError};
+
+/// How long a call may take to come up.
+const SETUP_TIME: Duration = Duration::from_secs(5);
+
+/// A headless client for `address`, with further `flags`.
+fn client(address: &str, flags: &[&str]) -> Client {
+    let args = ["voice-chat", "-c", address, "--raw"]
+        .iter()
+        .chain(flags)
+        .map(|arg| String::from(*arg))
+        .collect::<Vec<_>>();
+    Client::new_headless_with(address.to_string(), Opt::from_args(&args).unwrap()).unwrap()
+}
+
+/// Starts a mixer on `address` with these `flags`, running until the test ends.
+fn mixer(address: &str, flags: &[&str]) {
+    let args = ["voice-chat"]
+        .iter()
+        .chain(flags)
+        .map(|arg| String::from(*arg))
+        .collect::<Vec<_>>();
+    let mut mixer = Mixer::new_with(address.to_string(), Opt::from_args(&args).unwrap());
+    thread::spawn(move || mixer.run());
+}
+
+/// Waits until the client with these `controls` is in a call, for at most [`SETUP_TIME`].
+fn wait_connected(controls: &Controls) -> bool {
+    let start = Instant::now();
+    while controls.state() != SessionState::Connected {
+        if start.elapsed() > SETUP_TIME {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+/// A port nobody listens on right now.
+fn free_port(ip: &str) -> u16 {
+    TcpListener::bind((ip, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Lets `caller` connect and `server` listen `server_delay` later, then hangs up as soon as
+/// both are in the call. Returns how both calls ended, and whether they got connected at all.
+fn call(
+    server: &mut Client,
+    caller: &mut Client,
+    server_delay: Duration,
+) -> (Result<(), VoiceChatError>, Result<(), VoiceChatError>, bool) {
+    let server_controls = server.controls();
+    let caller_controls = caller.controls();
+    // Closed once the caller is done, which stops listening, as the server waits for the next
+    // call after the caller hangs up.
+    let (stop, stopped) = smol::channel::bounded(1);
+    server.set_shutdown(stopped);
+    thread::scope(|scope| {
+        let listening = scope.spawn(|| {
+            thread::sleep(server_delay);
+            smol::block_on(server.listen())
+        });
+        let connecting = scope.spawn(|| smol::block_on(caller.connect()));
+        let start = Instant::now();
+        let connected = loop {
+            let both = [&server_controls, &caller_controls]
+                .iter()
+                .all(|controls| controls.state() == SessionState::Connected);
+            if both || start.elapsed() > server_delay + SETUP_TIME {
+                break both;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        caller_controls.hang_up();
+        let caller_result = connecting.join().unwrap();
+        drop(stop);
+        (listening.join().unwrap(), caller_result, connected)
+    })
+}
+
+#[test]
+fn ipv6_call() {
+    let address = format!("[::1]:{}", free_port("::1"));
+    let (server, caller, connected) = call(
+        &mut client(&address, &[]),
+        &mut client(&address, &[]),
+        Duration::ZERO,
+    );
+    assert!(connected);
+    server.unwrap();
+    caller.unwrap();
+}
+
+#[test]
+fn hostname_is_resolved() {
+    let port = free_port("127.0.0.1");
+    let (server, caller, connected) = call(
+        &mut client(&format!("127.0.0.1:{port}"), &[]),
+        &mut client(&format!("localhost:{port}"), &[]),
+        Duration::ZERO,
+    );
+    assert!(connected);
+    server.unwrap();
+    caller.unwrap();
+}
+
+#[test]
+fn unknown_hostname_is_an_error() {
+    let mut caller = client("nothing.invalid:8888", &[]);
+    assert!(smol::block_on(caller.connect()).is_err());
+}
+
+#[test]
+fn full_mixer_turns_callers_away() {
+    let address = format!("127.0.0.1:{}", free_port("127.0.0.1"));
+    mixer(&address, &["--max-clients", "1"]);
+    let mut first = client(&address, &[]);
+    let controls = first.controls();
+    thread::scope(|scope| {
+        let calling = scope.spawn(|| smol::block_on(first.connect()));
+        let connected = wait_connected(&controls);
+        let turned_away = smol::block_on(client(&address, &[]).connect());
+        controls.hang_up();
+        calling.join().unwrap().unwrap();
+        assert!(connected);
+        assert!(
+            matches!(turned_away, Err(VoiceChatError::Rejected(_))),
+            "{turned_away:?}"
+        );
+    });
+}
+
+#[test]
+fn caller_waits_for_the_server_to_come_up() {
+    let port = free_port("127.0.0.1");
+    let address = format!("127.0.0.1:{port}");
+    let (server, caller, connected) = call(
+        &mut client(&address, &[]),
+        &mut client(&address, &[]),
+        Duration::from_secs(2),
+    );
+    assert!(connected);
+    server.unwrap();
+    caller.unwrap();
+}
+
+#[test]
+fn caller_gives_up_after_the_connect_timeout() {
+    let address = format!("127.0.0.1:{}", free_port("127.0.0.1"));
+    let mut caller = client(&address, &["--connect-timeout", "1"]);
+    let start = Instant::now();
+    assert!(smol::block_on(caller.connect()).is_err());
+    let waited = start.elapsed();
+    assert!(
+        waited >= Duration::from_secs(1) && waited < SETUP_TIME,
+        "{waited:?}"
+    );
+}
+
+#[test]
+fn mixer_reports_its_clients() {
+    let address = format!("127.0.0.1:{}", free_port("127.0.0.1"));
+    mixer(&address, &[]);
+    let mut member = client(&address, &["--room", "lobby", "--name", "Alice"]);
+    let controls = member.controls();
+    thread::scope(|scope| {
+        let calling = scope.spawn(|| smol::block_on(member.connect()));
+        assert!(wait_connected(&controls));
+        let status = voice_chat::mixer::query_status(&address);
+        controls.hang_up();
+        calling.join().unwrap().unwrap();
+        let status = status.unwrap();
+        assert_eq!(status.clients.len(), 1);
+        assert_eq!(status.clients[0].room, "lobby");
+        assert_eq!(status.clients[0].name, "Alice");
+        assert!(
+            status.to_string().contains("#0 (Alice) in 'lobby'"),
+            "{status}"
+        );
+        assert!(!status.panning);
+    });
+}
+
+/// How much audio is pushed and pulled at a time, like a device's 20 ms period.
+const PERIOD: Duration = Duration::from_millis(20);
+const SAMPLE_RATE: u32 = 16000;
+const RATE: [&str; 2] = ["--sample-rate", "16000"];
+
+/// Lets a client with `speaker_flags` (which, like `listener_flags`, should include
+/// `--sample-rate 16000`) speak `signal` (by sample index) into a mixer on
+/// `address`, for three seconds, and returns what a client with `listener_flags` played.
+fn talk_through_mixer(
+    address: &str,
+    speaker_flags: &[&str],
+    listener_flags: &[&str],
+    signal: impl Fn(usize) -> f32,
+) -> Vec<f32> {
+    let mut speaker = client(address, speaker_flags);
+    let mut listener = client(address, listener_flags);
+    let capture = speaker.headless_audio().unwrap();
+    let playback = listener.headless_audio().unwrap();
+    let controls = [speaker.controls(), listener.controls()];
+    thread::scope(|scope| {
+        let speaking = scope.spawn(|| smol::block_on(speaker.connect()));
+        let listening = scope.spawn(|| smol::block_on(listener.connect()));
+        // Pulled from the start, so nothing piles up while the calls come up.
+        let period = (SAMPLE_RATE as f64 * PERIOD.as_secs_f64()) as usize;
+        let mut played = Vec::new();
+        let start = Instant::now();
+        // Paced by the clock, like a device, as the mixer is too.
+        let mut next = start;
+        while start.elapsed() < Duration::from_secs(3) {
+            let pushed = played.len();
+            let captured: Vec<f32> = (pushed..pushed + period).map(&signal).collect();
+            capture.push_capture(&captured);
+            let mut chunk = vec![0.0; period];
+            playback.pull_playback(&mut chunk);
+            played.extend(chunk);
+            next += PERIOD;
+            thread::sleep(next.saturating_duration_since(Instant::now()));
+        }
+        let connected = controls
+            .iter()
+            .all(|controls| controls.state() == SessionState::Connected);
+        controls.iter().for_each(Controls::hang_up);
+        assert!(connected);
+        speaking.join().unwrap().unwrap();
+        listening.join().unwrap().unwrap();
+        played
+    })
+}
+
+fn tone(i: usize) -> f32 {
+    0.5 * (std::f32::consts::TAU * 440.0 * i as f32 / SAMPLE_RATE as f32).sin()
+}
+
+#[test]
+fn mixer_passes_audio_on_within_a_few_intervals() {
+    let address = format!("127.0.0.1:{}", free_port("127.0.0.1"));
+    mixer(&address, &RATE);
+    // Silence first, so everything has settled when the tone starts.
+    let start = 2 * SAMPLE_RATE as usize;
+    let played = talk_through_mixer(&address, &RATE, &RATE, |i| {
+        if i < start { 0.0 } else { tone(i) }
+    });
+    let heard = played[start..]
+        .iter()
+        .position(|sample| sample.abs() > 0.1)
+        .expect("The tone never arrived");
+    let delay = Duration::from_secs_f64(heard as f64 / SAMPLE_RATE as f64);
+    // A few send intervals, plus the listener's margin against jitter.
+    assert!(
+        delay < Duration::from_millis(200),
+        "The tone took {delay:?}"
+    );
+}
+
+#[test]
+fn rooms_are_kept_apart() {
+    let address = format!("127.0.0.1:{}", free_port("127.0.0.1"));
+    mixer(&address, &RATE);
+    let played = talk_through_mixer(
+        &address,
+        &[&RATE[..], &["--room", "a"]].concat(),
+        &[&RATE[..], &["--room", "b"]].concat(),
+        tone,
+    );
+    assert!(played.iter().all(|sample| *sample == 0.0));
+}
+
+#[test]
+fn server_takes_the_next_call_after_a_caller_hangs_up() {
+    let address = format!("127.0.0.1:{}", free_port("127.0.0.1"));
+    let mut server = client(&address, &RATE);
+    let capture = server.headless_audio().unwrap();
+    let server_controls = server.controls();
+    let (stop, stopped) = smol::channel::bounded(1);
+    server.set_shutdown(stopped);
+    let mut first = client(&address, &RATE);
+    let first_controls = first.controls();
+    let mut second = client(&address, &[&RATE[..], &["--connect-timeout", "5"]].concat());
+    let playback = second.headless_audio().unwrap();
+    let second_controls = second.controls();
+    thread::scope(|scope| {
+        let listening = scope.spawn(|| smol::block_on(server.listen()));
+        let calling = scope.spawn(|| smol::block_on(first.connect()));
+        assert!(wait_connected(&first_controls) && wait_connected(&server_controls));
+        first_controls.hang_up();
+        calling.join().unwrap().unwrap();
+
+        let calling = scope.spawn(|| smol::block_on(second.connect()));
+        assert!(wait_connected(&second_controls));
+        let period = (SAMPLE_RATE as f64 * PERIOD.as_secs_f64()) as usize;
+        let mut played = Vec::new();
+        let start = Instant::now();
+        let mut next = start;
+        while start.elapsed() < Duration::from_secs(1) {
+            let pushed = played.len();
+            capture.push_capture(&(pushed..pushed + period).map(tone).collect::<Vec<_>>());
+            let mut chunk = vec![0.0; period];
+            playback.pull_playback(&mut chunk);
+            played.extend(chunk);
+            next += PERIOD;
+            thread::sleep(next.saturating_duration_since(Instant::now()));
+        }
+        second_controls.hang_up();
+        calling.join().unwrap().unwrap();
+        drop(stop);
+        listening.join().unwrap().unwrap();
+        assert!(played.iter().any(|sample| sample.abs() > 0.1));
+    });
+}